@@ -0,0 +1,315 @@
+use std::collections::BTreeSet;
+
+use crate::asm::disassemble;
+use crate::opcodes::Inst;
+use crate::{Fault, LC3};
+
+// Ahead of time translation of an LC-3 image to Rust source. Code is found
+// by following control flow from the entry points: both ways out of a
+// branch, into a JSR and back out of it, and on past any TRAP but HALT.
+// JMP, RET, JSRR and RTI go wherever a register says, so the analysis stops
+// at them, as it does at the reserved opcode and the device registers.
+//
+// Each basic block becomes a function that checks its words are still the
+// ones translated, then runs them through `LC3::run_translated`, which does
+// all `run_step` does but decode them. Whatever the translation doesn't
+// cover is left to the interpreter: a PC that isn't the start of a block, a
+// block that has been written over, the rest of a block a store has just
+// written into, and the step an interrupt is taken on.
+
+// Where translation stops looking for code.
+const DEVICES: u16 = 0xFE00;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub start: u16,
+    pub words: Vec<u16>,
+}
+
+impl Block {
+    pub fn end(&self) -> u16 {
+        self.start + self.words.len() as u16
+    }
+}
+
+// Where control can go after the instruction at `addr`, as far as the
+// image itself says, and whether it ends a block.
+fn successors(addr: u16, inst: &Inst) -> (Vec<u16>, bool) {
+    let next = addr.wrapping_add(1);
+    let target = |offset: i16| next.wrapping_add(offset as u16);
+    match *inst {
+        Inst::BR { cond, .. } if !(cond.n || cond.z || cond.p) => (vec![next], false),
+        // BRnzp too, as nothing is set until the first result
+        Inst::BR { pc_offset, .. } => (vec![target(pc_offset), next], true),
+        Inst::JSR { pc_offset } => (vec![target(pc_offset), next], true),
+        Inst::JSRr { .. } => (vec![next], true),
+        Inst::TRAP { trap_vect: 0x25 } => (vec![], true),
+        Inst::TRAP { .. } => (vec![next], true),
+        Inst::JMP { .. } | Inst::RTI => (vec![], true),
+        _ => (vec![next], false),
+    }
+}
+
+// The basic blocks reachable from `entries`, in address order.
+pub fn discover(memory: &[u16; 65536], entries: &[u16]) -> Vec<Block> {
+    let mut code = BTreeSet::new();
+    let mut leaders: BTreeSet<u16> = entries.iter().copied().collect();
+    let mut ends = BTreeSet::new();
+    let mut work = entries.to_vec();
+    while let Some(addr) = work.pop() {
+        let raw = memory[addr as usize];
+        if addr >= DEVICES || raw >> 12 == 0b1101 || !code.insert(addr) {
+            continue;
+        }
+        let (next, end) = successors(addr, &Inst::from(raw));
+        if end {
+            ends.insert(addr);
+            leaders.extend(&next);
+        }
+        work.extend(next);
+    }
+
+    let mut blocks = Vec::new();
+    for &start in leaders.iter().filter(|a| code.contains(a)) {
+        let mut words = vec![memory[start as usize]];
+        let mut addr = start;
+        while !ends.contains(&addr) {
+            addr += 1;
+            if !code.contains(&addr) || leaders.contains(&addr) {
+                break;
+            }
+            words.push(memory[addr as usize]);
+        }
+        blocks.push(Block { start, words });
+    }
+    blocks
+}
+
+fn reg(r: i16) -> String {
+    format!("lc3.registers[{}]", r)
+}
+
+// What `run_instruction` does for `inst` at `addr`, as the lines of a
+// closure's body.
+fn body(addr: u16, raw: u16, inst: &Inst) -> Vec<String> {
+    let next = addr.wrapping_add(1);
+    let target = |offset: i16| next.wrapping_add(offset as u16);
+    let cost = |name: &str| format!("lc3.cycles += lc3.cost.{};", name);
+    let cc = |dr: i16| format!("lc3.set_condition({});", reg(dr));
+    let mut lines = match *inst {
+        Inst::ADD { dr, sr1, sr2 } => vec![cost("operate"), format!("{} = {}.wrapping_add({});", reg(dr), reg(sr1), reg(sr2)), cc(dr)],
+        Inst::ADDi { dr, sr, imm } => vec![cost("operate"), format!("{} = {}.wrapping_add({});", reg(dr), reg(sr), imm), cc(dr)],
+        Inst::AND { dr, sr1, sr2 } => vec![cost("operate"), format!("{} = {} & {};", reg(dr), reg(sr1), reg(sr2)), cc(dr)],
+        Inst::ANDi { dr, sr, imm } => vec![cost("operate"), format!("{} = {} & {};", reg(dr), reg(sr), imm), cc(dr)],
+        Inst::NOT { dr, sr } => vec![cost("operate"), format!("{} = !{};", reg(dr), reg(sr)), cc(dr)],
+        Inst::LEA { dr, pc_offset } => vec![cost("operate"), format!("{} = 0x{:04X}_u16 as i16;", reg(dr), target(pc_offset)), cc(dr)],
+        Inst::BR { cond, pc_offset } => {
+            let flags = [(cond.n, "n"), (cond.z, "z"), (cond.p, "p")];
+            let tests: Vec<String> = flags.iter().filter(|f| f.0).map(|f| format!("lc3.condition.{}", f.1)).collect();
+            let mut lines = vec![cost("branch")];
+            if !tests.is_empty() {
+                lines.push(format!("if {} {{", tests.join(" || ")));
+                lines.push(format!("    lc3.pc = 0x{:04X};", target(pc_offset)));
+                lines.push(format!("    {}", cost("branch_taken")));
+                lines.push("}".to_string());
+            }
+            lines
+        }
+        Inst::JMP { base_r } => vec![cost("jump"), format!("lc3.pc = {} as u16;", reg(base_r))],
+        Inst::JSR { pc_offset } => vec![
+            cost("call"),
+            format!("{} = 0x{:04X}_u16 as i16;", reg(7), next),
+            format!("lc3.pc = 0x{:04X};", target(pc_offset)),
+        ],
+        Inst::JSRr { base_r } => vec![
+            cost("call"),
+            format!("let target = {} as u16;", reg(base_r)),
+            format!("{} = 0x{:04X}_u16 as i16;", reg(7), next),
+            "lc3.pc = target;".to_string(),
+        ],
+        Inst::LD { dr, pc_offset } => vec![cost("load_store"), format!("{} = lc3.read(0x{:04X})? as i16;", reg(dr), target(pc_offset)), cc(dr)],
+        Inst::LDI { dr, pc_offset } => vec![
+            cost("indirect"),
+            format!("let addr = lc3.read(0x{:04X})?;", target(pc_offset)),
+            format!("{} = lc3.read(addr)? as i16;", reg(dr)),
+            cc(dr),
+        ],
+        Inst::LDR { dr, base_r, offset } => vec![
+            cost("load_store"),
+            format!("{} = lc3.read({}.wrapping_add({}) as u16)? as i16;", reg(dr), reg(base_r), offset),
+            cc(dr),
+        ],
+        Inst::ST { sr, pc_offset } => vec![cost("load_store"), format!("lc3.write(0x{:04X}, {} as u16)?;", target(pc_offset), reg(sr))],
+        Inst::STI { sr, pc_offset } => vec![
+            cost("indirect"),
+            format!("let addr = lc3.read(0x{:04X})?;", target(pc_offset)),
+            format!("lc3.write(addr, {} as u16)?;", reg(sr)),
+        ],
+        Inst::STR { sr, base_r, offset } => vec![
+            cost("load_store"),
+            format!("lc3.write({}.wrapping_add({}) as u16, {} as u16)?;", reg(base_r), offset, reg(sr)),
+        ],
+        // services and the stack, as the interpreter has them
+        Inst::TRAP { .. } | Inst::RTI => return vec![format!("lc3.run_instruction(Inst::from(0x{:04X}))", raw)],
+    };
+    lines.push("Ok(())".to_string());
+    lines
+}
+
+// A Rust module for the code reachable from `entries`. It has a `block`
+// function to hand to `run`.
+pub fn translate(memory: &[u16; 65536], entries: &[u16]) -> String {
+    let blocks = discover(memory, entries);
+    let names: Vec<String> = entries.iter().map(|e| format!("x{:04X}", e)).collect();
+    let mut out = format!("// Translated from an LC-3 image, from {}, by `lc3_vm translate`.\n", names.join(", "));
+    out += "// Regenerate it rather than edit it.\n#![allow(clippy::all)]\n\n";
+    let services = blocks.iter().flat_map(|b| &b.words).any(|&w| matches!(w >> 12, 0b1000 | 0b1111));
+    if services {
+        out += "use lc3_tools::opcodes::Inst;\n";
+    }
+    out += "use lc3_tools::{Fault, LC3};\n\n";
+
+    out += "// Runs the block starting at the PC, or returns None for the interpreter\n// to take a step.\n";
+    out += "pub fn block(lc3: &mut LC3) -> Option<Result<(), Fault>> {\n    match lc3.pc {\n";
+    for block in &blocks {
+        out += &format!("        0x{:04X} => block_{:04x}(lc3),\n", block.start, block.start);
+    }
+    out += "        _ => None,\n    }\n}\n";
+
+    for block in &blocks {
+        let (start, end) = (block.start, block.end());
+        out += &format!("\nconst CODE_{:04X}: [u16; {}] = [", start, block.words.len());
+        let words: Vec<String> = block.words.iter().map(|w| format!("0x{:04X}", w)).collect();
+        out += &words.join(", ");
+        out += "];\n\n";
+        out += &format!("fn block_{:04x}(lc3: &mut LC3) -> Option<Result<(), Fault>> {{\n", start);
+        out += &format!("    if lc3.memory[0x{:04X}..0x{:04X}] != CODE_{:04X} {{\n        return None;\n    }}\n", start, end, start);
+        for (i, &raw) in block.words.iter().enumerate() {
+            let addr = start + i as u16;
+            let inst = Inst::from(raw);
+            out += &format!("    // x{:04X}  {}\n", addr, disassemble(raw));
+            let last = addr + 1 == end;
+            out += if last { "    lc3.run_translated(|lc3| {\n" } else { "    match lc3.run_translated(|lc3| {\n" };
+            for line in body(addr, raw, &inst) {
+                out += &format!("        {}\n", line);
+            }
+            if last {
+                out += "    })\n";
+                continue;
+            }
+            let mut guard = format!("lc3.pc == 0x{:04X} && !lc3.halted", addr + 1);
+            // a store may have written over what's left
+            if matches!(inst, Inst::ST { .. } | Inst::STI { .. } | Inst::STR { .. }) {
+                guard += &format!(" && lc3.memory[0x{:04X}..0x{:04X}] == CODE_{:04X}[{}..]", addr + 1, end, start, i + 1);
+            }
+            out += &format!("    }}) {{\n        Some(Ok(())) if {} => {{}}\n        other => return other,\n    }}\n", guard);
+        }
+        out += "}\n";
+    }
+    out
+}
+
+impl LC3 {
+    // `run_step` for an instruction translated ahead of time, `body` doing
+    // what `run_instruction` would with the instruction at the PC. When an
+    // interrupt is due it does nothing and returns None, leaving the step to
+    // `run_step`.
+    pub fn run_translated(&mut self, body: impl FnOnce(&mut LC3) -> Result<(), Fault>) -> Option<Result<(), Fault>> {
+        if self.peek_interrupt().is_some() {
+            return None;
+        }
+        let result = self.fetch().and_then(|_| body(self));
+        Some(self.end_step(result))
+    }
+}
+
+// Runs until HALT with translated code where `block` has some and the
+// interpreter elsewhere, returning how many instructions were translated.
+pub fn run(lc3: &mut LC3, block: fn(&mut LC3) -> Option<Result<(), Fault>>) -> Result<u64, Fault> {
+    let mut translated = 0;
+    while !lc3.halted {
+        let executed = lc3.executed;
+        let result = block(lc3);
+        translated += lc3.executed - executed;
+        match result {
+            Some(result) => result?,
+            None => lc3.run_step()?,
+        }
+    }
+    Ok(translated)
+}
+
+// `lc3_vm translate src/aot/sample.asm -o src/aot/sample.rs`
+#[cfg(test)]
+mod sample;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::console::Console;
+    use crate::device::Keyboard;
+    use crate::timer::{self, Timer};
+
+    const SAMPLE: &str = include_str!("aot/sample.asm");
+
+    fn load(mut lc3: LC3) -> LC3 {
+        assemble(SAMPLE).unwrap().load_into(&mut lc3);
+        lc3
+    }
+
+    #[test]
+    fn test_sample_is_current() {
+        let lc3 = load(LC3::default());
+        assert_eq!(translate(&lc3.memory, &[0x3000]), include_str!("aot/sample.rs"));
+    }
+
+    #[test]
+    fn test_discover() {
+        let program = assemble(SAMPLE).unwrap();
+        let lc3 = load(LC3::default());
+        let blocks = discover(&lc3.memory, &[0x3000]);
+        let starts: Vec<u16> = blocks.iter().map(|b| b.start).collect();
+        assert_eq!(starts, [0x3000, 0x3002, 0x3005, 0x3006, 0x3009, 0x3016, 0x3018, 0x301A, 0x301D]);
+        // only JSRR reaches HIDDEN, and nothing runs into the data
+        let hidden = program.symbols["HIDDEN"].addr;
+        assert!(blocks.iter().all(|b| !(b.start..b.end()).contains(&hidden)));
+        assert_eq!(blocks.last().unwrap().end(), hidden);
+        assert_eq!(blocks[0].words, lc3.memory[0x3000..0x3002]);
+    }
+
+    #[test]
+    fn test_translated_runs_as_interpreted() {
+        let machines: [fn() -> LC3; 2] = [
+            || LC3 {
+                console: Console::buffer(&[]),
+                ..LC3::default()
+            },
+            // the OS's services, keyboard interrupts first thing and a timer
+            // interrupting blocks part way through
+            || {
+                let mut lc3 = LC3::with_os();
+                lc3.console = Console::buffer(b"ab");
+                lc3.devices.get_mut::<Keyboard>().unwrap().interrupt_enable = true;
+                let timer = lc3.devices.get_mut::<Timer>().unwrap();
+                timer.control = timer::ENABLE | timer::INTERRUPT_ENABLE | timer::PERIODIC | 1 << 8 | timer::DEFAULT_VECTOR as u16;
+                timer.interval = 7;
+                timer.remaining = 7;
+                lc3
+            },
+        ];
+        for machine in machines.iter() {
+            let (mut interpreted, mut translated) = (load(machine()), load(machine()));
+            interpreted.run().unwrap();
+            let count = run(&mut translated, sample::block).unwrap();
+            assert!(count > 0 && count < translated.executed, "{}", count);
+            assert_eq!(translated.registers, interpreted.registers);
+            assert!(translated.memory[..] == interpreted.memory[..]);
+            assert_eq!(translated.pc, interpreted.pc);
+            assert_eq!(translated.executed, interpreted.executed);
+            assert_eq!(translated.cycles, interpreted.cycles);
+            assert_eq!(translated.console.output(), interpreted.console.output());
+            assert!(translated.console.output().starts_with(b"hi\n"));
+        }
+    }
+}
@@ -0,0 +1,42 @@
+        .ORIG x3000
+        LEA R0, HELLO
+        PUTS
+        AND R1, R1, #0
+        ADD R1, R1, #5
+        AND R2, R2, #0
+LOOP    JSR SQUARE
+        ADD R2, R2, R3
+        ADD R1, R1, #-1
+        BRp LOOP
+        ST R2, RESULT
+        LDI R4, PTR
+        NOT R4, R4
+        STI R4, PTR
+        LEA R5, TABLE
+        LDR R4, R5, #1
+        STR R4, R5, #0
+        LD R0, PATCH
+        ST R0, SLOT
+        AND R1, R1, #0
+SLOT    ADD R1, R1, #1
+        LEA R0, HIDDEN
+        JSRR R0
+        ST R1, RESULT2
+        HALT
+SQUARE  AND R3, R3, #0
+        ADD R4, R1, #0
+SQLOOP  ADD R3, R3, R1
+        ADD R4, R4, #-1
+        BRp SQLOOP
+        RET
+HIDDEN  ADD R1, R1, R1
+        RET
+PATCH   ADD R1, R1, #7
+RESULT  .BLKW 1
+RESULT2 .BLKW 1
+PTR     .FILL VALUE
+VALUE   .FILL x00F0
+TABLE   .FILL 1
+        .FILL 2
+HELLO   .STRINGZ "hi\n"
+        .END
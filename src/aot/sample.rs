@@ -0,0 +1,363 @@
+// Translated from an LC-3 image, from x3000, by `lc3_vm translate`.
+// Regenerate it rather than edit it.
+#![allow(clippy::all)]
+
+use lc3_tools::opcodes::Inst;
+use lc3_tools::{Fault, LC3};
+
+// Runs the block starting at the PC, or returns None for the interpreter
+// to take a step.
+pub fn block(lc3: &mut LC3) -> Option<Result<(), Fault>> {
+    match lc3.pc {
+        0x3000 => block_3000(lc3),
+        0x3002 => block_3002(lc3),
+        0x3005 => block_3005(lc3),
+        0x3006 => block_3006(lc3),
+        0x3009 => block_3009(lc3),
+        0x3016 => block_3016(lc3),
+        0x3018 => block_3018(lc3),
+        0x301A => block_301a(lc3),
+        0x301D => block_301d(lc3),
+        _ => None,
+    }
+}
+
+const CODE_3000: [u16; 2] = [0xE026, 0xF022];
+
+fn block_3000(lc3: &mut LC3) -> Option<Result<(), Fault>> {
+    if lc3.memory[0x3000..0x3002] != CODE_3000 {
+        return None;
+    }
+    // x3000  LEA r0 #38
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.operate;
+        lc3.registers[0] = 0x3027_u16 as i16;
+        lc3.set_condition(lc3.registers[0]);
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x3001 && !lc3.halted => {}
+        other => return other,
+    }
+    // x3001  TRAP #34
+    lc3.run_translated(|lc3| {
+        lc3.run_instruction(Inst::from(0xF022))
+    })
+}
+
+const CODE_3002: [u16; 3] = [0x5260, 0x1265, 0x54A0];
+
+fn block_3002(lc3: &mut LC3) -> Option<Result<(), Fault>> {
+    if lc3.memory[0x3002..0x3005] != CODE_3002 {
+        return None;
+    }
+    // x3002  AND r1 r1 #0
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.operate;
+        lc3.registers[1] = lc3.registers[1] & 0;
+        lc3.set_condition(lc3.registers[1]);
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x3003 && !lc3.halted => {}
+        other => return other,
+    }
+    // x3003  ADD r1 r1 #5
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.operate;
+        lc3.registers[1] = lc3.registers[1].wrapping_add(5);
+        lc3.set_condition(lc3.registers[1]);
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x3004 && !lc3.halted => {}
+        other => return other,
+    }
+    // x3004  AND r2 r2 #0
+    lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.operate;
+        lc3.registers[2] = lc3.registers[2] & 0;
+        lc3.set_condition(lc3.registers[2]);
+        Ok(())
+    })
+}
+
+const CODE_3005: [u16; 1] = [0x4812];
+
+fn block_3005(lc3: &mut LC3) -> Option<Result<(), Fault>> {
+    if lc3.memory[0x3005..0x3006] != CODE_3005 {
+        return None;
+    }
+    // x3005  JSR #18
+    lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.call;
+        lc3.registers[7] = 0x3006_u16 as i16;
+        lc3.pc = 0x3018;
+        Ok(())
+    })
+}
+
+const CODE_3006: [u16; 3] = [0x1483, 0x127F, 0x03FC];
+
+fn block_3006(lc3: &mut LC3) -> Option<Result<(), Fault>> {
+    if lc3.memory[0x3006..0x3009] != CODE_3006 {
+        return None;
+    }
+    // x3006  ADD r2 r2 r3
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.operate;
+        lc3.registers[2] = lc3.registers[2].wrapping_add(lc3.registers[3]);
+        lc3.set_condition(lc3.registers[2]);
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x3007 && !lc3.halted => {}
+        other => return other,
+    }
+    // x3007  ADD r1 r1 #-1
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.operate;
+        lc3.registers[1] = lc3.registers[1].wrapping_add(-1);
+        lc3.set_condition(lc3.registers[1]);
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x3008 && !lc3.halted => {}
+        other => return other,
+    }
+    // x3008  BRp #-4
+    lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.branch;
+        if lc3.condition.p {
+            lc3.pc = 0x3005;
+            lc3.cycles += lc3.cost.branch_taken;
+        }
+        Ok(())
+    })
+}
+
+const CODE_3009: [u16; 13] = [0x3417, 0xA818, 0x993F, 0xB816, 0xEA17, 0x6941, 0x7940, 0x200F, 0x3001, 0x5260, 0x1261, 0xE009, 0x4000];
+
+fn block_3009(lc3: &mut LC3) -> Option<Result<(), Fault>> {
+    if lc3.memory[0x3009..0x3016] != CODE_3009 {
+        return None;
+    }
+    // x3009  ST r2, #23
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.load_store;
+        lc3.write(0x3021, lc3.registers[2] as u16)?;
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x300A && !lc3.halted && lc3.memory[0x300A..0x3016] == CODE_3009[1..] => {}
+        other => return other,
+    }
+    // x300A  LDI r4 #24
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.indirect;
+        let addr = lc3.read(0x3023)?;
+        lc3.registers[4] = lc3.read(addr)? as i16;
+        lc3.set_condition(lc3.registers[4]);
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x300B && !lc3.halted => {}
+        other => return other,
+    }
+    // x300B  NOT r4 r4
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.operate;
+        lc3.registers[4] = !lc3.registers[4];
+        lc3.set_condition(lc3.registers[4]);
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x300C && !lc3.halted => {}
+        other => return other,
+    }
+    // x300C  STI r4 #22
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.indirect;
+        let addr = lc3.read(0x3023)?;
+        lc3.write(addr, lc3.registers[4] as u16)?;
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x300D && !lc3.halted && lc3.memory[0x300D..0x3016] == CODE_3009[4..] => {}
+        other => return other,
+    }
+    // x300D  LEA r5 #23
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.operate;
+        lc3.registers[5] = 0x3025_u16 as i16;
+        lc3.set_condition(lc3.registers[5]);
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x300E && !lc3.halted => {}
+        other => return other,
+    }
+    // x300E  LDR r4 r5 #1
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.load_store;
+        lc3.registers[4] = lc3.read(lc3.registers[5].wrapping_add(1) as u16)? as i16;
+        lc3.set_condition(lc3.registers[4]);
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x300F && !lc3.halted => {}
+        other => return other,
+    }
+    // x300F  STR r4 r5 #0
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.load_store;
+        lc3.write(lc3.registers[5].wrapping_add(0) as u16, lc3.registers[4] as u16)?;
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x3010 && !lc3.halted && lc3.memory[0x3010..0x3016] == CODE_3009[7..] => {}
+        other => return other,
+    }
+    // x3010  LD r0 #15
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.load_store;
+        lc3.registers[0] = lc3.read(0x3020)? as i16;
+        lc3.set_condition(lc3.registers[0]);
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x3011 && !lc3.halted => {}
+        other => return other,
+    }
+    // x3011  ST r0, #1
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.load_store;
+        lc3.write(0x3013, lc3.registers[0] as u16)?;
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x3012 && !lc3.halted && lc3.memory[0x3012..0x3016] == CODE_3009[9..] => {}
+        other => return other,
+    }
+    // x3012  AND r1 r1 #0
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.operate;
+        lc3.registers[1] = lc3.registers[1] & 0;
+        lc3.set_condition(lc3.registers[1]);
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x3013 && !lc3.halted => {}
+        other => return other,
+    }
+    // x3013  ADD r1 r1 #1
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.operate;
+        lc3.registers[1] = lc3.registers[1].wrapping_add(1);
+        lc3.set_condition(lc3.registers[1]);
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x3014 && !lc3.halted => {}
+        other => return other,
+    }
+    // x3014  LEA r0 #9
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.operate;
+        lc3.registers[0] = 0x301E_u16 as i16;
+        lc3.set_condition(lc3.registers[0]);
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x3015 && !lc3.halted => {}
+        other => return other,
+    }
+    // x3015  JSRR r0
+    lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.call;
+        let target = lc3.registers[0] as u16;
+        lc3.registers[7] = 0x3016_u16 as i16;
+        lc3.pc = target;
+        Ok(())
+    })
+}
+
+const CODE_3016: [u16; 2] = [0x320B, 0xF025];
+
+fn block_3016(lc3: &mut LC3) -> Option<Result<(), Fault>> {
+    if lc3.memory[0x3016..0x3018] != CODE_3016 {
+        return None;
+    }
+    // x3016  ST r1, #11
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.load_store;
+        lc3.write(0x3022, lc3.registers[1] as u16)?;
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x3017 && !lc3.halted && lc3.memory[0x3017..0x3018] == CODE_3016[1..] => {}
+        other => return other,
+    }
+    // x3017  TRAP #37
+    lc3.run_translated(|lc3| {
+        lc3.run_instruction(Inst::from(0xF025))
+    })
+}
+
+const CODE_3018: [u16; 2] = [0x56E0, 0x1860];
+
+fn block_3018(lc3: &mut LC3) -> Option<Result<(), Fault>> {
+    if lc3.memory[0x3018..0x301A] != CODE_3018 {
+        return None;
+    }
+    // x3018  AND r3 r3 #0
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.operate;
+        lc3.registers[3] = lc3.registers[3] & 0;
+        lc3.set_condition(lc3.registers[3]);
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x3019 && !lc3.halted => {}
+        other => return other,
+    }
+    // x3019  ADD r4 r1 #0
+    lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.operate;
+        lc3.registers[4] = lc3.registers[1].wrapping_add(0);
+        lc3.set_condition(lc3.registers[4]);
+        Ok(())
+    })
+}
+
+const CODE_301A: [u16; 3] = [0x16C1, 0x193F, 0x03FD];
+
+fn block_301a(lc3: &mut LC3) -> Option<Result<(), Fault>> {
+    if lc3.memory[0x301A..0x301D] != CODE_301A {
+        return None;
+    }
+    // x301A  ADD r3 r3 r1
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.operate;
+        lc3.registers[3] = lc3.registers[3].wrapping_add(lc3.registers[1]);
+        lc3.set_condition(lc3.registers[3]);
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x301B && !lc3.halted => {}
+        other => return other,
+    }
+    // x301B  ADD r4 r4 #-1
+    match lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.operate;
+        lc3.registers[4] = lc3.registers[4].wrapping_add(-1);
+        lc3.set_condition(lc3.registers[4]);
+        Ok(())
+    }) {
+        Some(Ok(())) if lc3.pc == 0x301C && !lc3.halted => {}
+        other => return other,
+    }
+    // x301C  BRp #-3
+    lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.branch;
+        if lc3.condition.p {
+            lc3.pc = 0x301A;
+            lc3.cycles += lc3.cost.branch_taken;
+        }
+        Ok(())
+    })
+}
+
+const CODE_301D: [u16; 1] = [0xC1C0];
+
+fn block_301d(lc3: &mut LC3) -> Option<Result<(), Fault>> {
+    if lc3.memory[0x301D..0x301E] != CODE_301D {
+        return None;
+    }
+    // x301D  JMP r7
+    lc3.run_translated(|lc3| {
+        lc3.cycles += lc3.cost.jump;
+        lc3.pc = lc3.registers[7] as u16;
+        Ok(())
+    })
+}
@@ -0,0 +1,1185 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::path::Path;
+
+use crate::opcodes::Inst;
+use crate::preprocess::{self, EvalError};
+use crate::utils::{parse_number, unescape, Condition};
+use crate::LC3;
+
+pub const OPCODES: &[&str] = &[
+    "ADD", "AND", "BR", "JMP", "JSR", "JSRR", "LD", "LDI", "LDR", "LEA", "NOT", "RET", "RTI", "ST", "STI", "STR",
+    "TRAP",
+];
+
+pub const TRAP_ALIASES: &[(&str, u16)] = &[
+    ("GETC", 0x20),
+    ("OUT", 0x21),
+    ("PUTS", 0x22),
+    ("IN", 0x23),
+    ("PUTSP", 0x24),
+    ("HALT", 0x25),
+];
+
+pub const DIRECTIVES: &[&str] = &[
+    ".ORIG", ".END", ".FILL", ".BLKW", ".STRINGZ", ".INCLUDE", ".EQU", ".SET", ".MACRO", ".ENDM", ".IF", ".ELSE",
+    ".ENDIF", ".EXTERNAL",
+];
+
+// Keeps label and .EXTERNAL terms apart when working out what an operand
+// refers to, well clear of any real address arithmetic.
+const BIAS: i32 = 1 << 20;
+
+// A token's text and its byte columns within the source line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Token<'a> {
+    pub text: &'a str,
+    pub start: usize,
+    pub end: usize,
+}
+
+// Splits a line on whitespace and commas, keeping string literals whole and
+// dropping the comment.
+pub fn tokenize(line: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut stop = line.len();
+    let mut chars = line.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            ';' => {
+                stop = i;
+                break;
+            }
+            '"' if start.is_none() => {
+                let mut end = line.len();
+                let mut escaped = false;
+                for (j, c) in chars.by_ref() {
+                    if c == '"' && !escaped {
+                        end = j + 1;
+                        break;
+                    }
+                    escaped = c == '\\' && !escaped;
+                }
+                tokens.push(Token { text: &line[i..end], start: i, end });
+            }
+            c if c.is_whitespace() || c == ',' => {
+                if let Some(s) = start.take() {
+                    tokens.push(Token { text: &line[s..i], start: s, end: i });
+                }
+            }
+            _ => {
+                if start.is_none() {
+                    start = Some(i);
+                }
+            }
+        }
+    }
+    if let Some(s) = start {
+        tokens.push(Token { text: &line[s..stop], start: s, end: stop });
+    }
+    tokens
+}
+
+pub fn br_condition(op: &str) -> Option<Condition> {
+    let upper = op.to_ascii_uppercase();
+    let flags = upper.strip_prefix("BR")?;
+    if flags.is_empty() {
+        return Some(Condition { n: true, z: true, p: true });
+    }
+    let cond = Condition {
+        n: flags.contains('N'),
+        z: flags.contains('Z'),
+        p: flags.contains('P'),
+    };
+    let canonical: String = [(cond.n, 'N'), (cond.z, 'Z'), (cond.p, 'P')]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, c)| *c)
+        .collect();
+    if canonical == flags {
+        Some(cond)
+    } else {
+        None
+    }
+}
+
+pub fn is_mnemonic(name: &str) -> bool {
+    let upper = name.to_ascii_uppercase();
+    OPCODES.contains(&upper.as_str())
+        || TRAP_ALIASES.iter().any(|(alias, _)| *alias == upper)
+        || DIRECTIVES.contains(&upper.as_str())
+        || br_condition(&upper).is_some()
+}
+
+pub fn is_label(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !is_mnemonic(name)
+        && parse_register(name).is_none()
+}
+
+pub fn parse_register(name: &str) -> Option<i16> {
+    let upper = name.to_ascii_uppercase();
+    let digit = upper.strip_prefix('R')?;
+    match digit.parse::<i16>() {
+        Ok(r) if (0..8).contains(&r) && digit.len() == 1 => Some(r),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement<'a> {
+    pub label: Option<Token<'a>>,
+    pub op: Option<Token<'a>>,
+    pub operands: Vec<Token<'a>>,
+}
+
+pub fn parse_line(line: &str) -> Statement<'_> {
+    let mut tokens = tokenize(line).into_iter().peekable();
+    let label = match tokens.peek() {
+        Some(t) if !is_mnemonic(t.text) => tokens.next(),
+        _ => None,
+    };
+    let op = tokens.next();
+    Statement {
+        label,
+        op,
+        operands: tokens.collect(),
+    }
+}
+
+// A problem at `line` of `file`, or of the main source when `file` is None.
+// `site` is the main source line it traces back to, which differs for code
+// from an include or a macro.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub file: Option<String>,
+    pub line: usize,
+    pub site: usize,
+    pub start: usize,
+    pub end: usize,
+    pub message: String,
+}
+
+impl Diagnostic {
+    // Prefixed with the file it belongs to, naming the main source `main`.
+    pub fn in_file(&self, main: &str) -> String {
+        format!("{}:{}", self.file.as_deref().unwrap_or(main), self)
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Symbol {
+    pub addr: u16,
+    pub line: usize,
+}
+
+// One .ORIG block. `lines[i]` is the source line that produced `words[i]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub origin: u16,
+    pub words: Vec<u16>,
+    pub lines: Vec<usize>,
+}
+
+impl Section {
+    pub fn contains(&self, addr: u16) -> bool {
+        addr >= self.origin && ((addr - self.origin) as usize) < self.words.len()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelocKind {
+    PcOffset9,
+    PcOffset11,
+    Fill,
+}
+
+impl RelocKind {
+    fn name(self) -> &'static str {
+        match self {
+            RelocKind::PcOffset9 => "PC9",
+            RelocKind::PcOffset11 => "PC11",
+            RelocKind::Fill => "FILL",
+        }
+    }
+}
+
+// A word the linker has to patch. `symbol` is an .EXTERNAL name, whose
+// address plus `addend` goes into the field, or None for a .FILL of a local
+// address that moves along with the program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    pub addr: u16,
+    pub kind: RelocKind,
+    pub symbol: Option<String>,
+    pub addend: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Program {
+    pub sections: Vec<Section>,
+    pub symbols: BTreeMap<String, Symbol>,
+    pub externals: BTreeSet<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+// What an operand expression's value depends on.
+enum Target {
+    Const,
+    Local,
+    External(String),
+}
+
+fn hex(text: &str) -> Option<u16> {
+    u16::from_str_radix(text.trim_start_matches(['x', 'X']), 16).ok()
+}
+
+impl Program {
+    pub fn entry(&self) -> u16 {
+        self.sections.first().map_or(0x3000, |s| s.origin)
+    }
+
+    // An object file covering every section, with any gaps between them
+    // zero filled.
+    pub fn to_obj(&self) -> Vec<u8> {
+        let start = self.sections.iter().map(|s| s.origin).min().unwrap_or(0);
+        let end = self
+            .sections
+            .iter()
+            .map(|s| s.origin as usize + s.words.len())
+            .max()
+            .unwrap_or(start as usize);
+        let mut image = vec![0u16; end - start as usize];
+        for s in &self.sections {
+            let at = (s.origin - start) as usize;
+            image[at..at + s.words.len()].copy_from_slice(&s.words);
+        }
+        std::iter::once(start)
+            .chain(image)
+            .flat_map(u16::to_be_bytes)
+            .collect()
+    }
+
+    // Copies every section into memory and points the PC at the entry.
+    pub fn load_into(&self, lc3: &mut LC3) -> u16 {
+        for s in &self.sections {
+            let at = s.origin as usize;
+            lc3.memory[at..at + s.words.len()].copy_from_slice(&s.words);
+        }
+        lc3.pc = self.entry();
+        lc3.pc
+    }
+
+    pub fn line_of(&self, addr: u16) -> Option<usize> {
+        let s = self.sections.iter().find(|s| s.contains(addr))?;
+        Some(s.lines[(addr - s.origin) as usize])
+    }
+
+    // The first address produced by `line` or, failing that, by the nearest
+    // following line. Returns the address and the line it came from.
+    pub fn addr_of_line(&self, line: usize) -> Option<(u16, usize)> {
+        self.sections
+            .iter()
+            .flat_map(|s| s.lines.iter().enumerate().map(move |(i, l)| (s.origin + i as u16, *l)))
+            .filter(|(_, l)| *l >= line)
+            .min_by_key(|(_, l)| *l)
+    }
+
+    // The symbol table in the layout written by the standard lc3as tool.
+    pub fn sym(&self) -> String {
+        let mut out = String::from("// Symbol table\n// Scope level 0:\n");
+        out += "//\tSymbol Name       Page Address\n//\t----------------  ------------\n";
+        for (name, symbol) in &self.symbols {
+            out += &format!("//\t{:<16}  {:04X}\n", name, symbol.addr);
+        }
+        out.push('\n');
+        out
+    }
+
+    // Reads a .sym file back as a program with symbols but no code. The
+    // symbols have no source line, so `line` is left as 0.
+    pub fn from_sym(text: &str) -> Result<Program, Diagnostic> {
+        let mut symbols = BTreeMap::new();
+        for (idx, line) in text.lines().enumerate() {
+            let body = line.trim().trim_start_matches("//").trim();
+            let fields: Vec<&str> = body.split_whitespace().collect();
+            let addr = match fields.as_slice() {
+                [] => continue,
+                [name, addr] if is_label(name) => hex(addr),
+                _ => None,
+            };
+            match addr {
+                Some(addr) => {
+                    symbols.insert(fields[0].to_string(), Symbol { addr, line: 0 });
+                }
+                None if body.starts_with("Symbol") || body.starts_with("Scope") || body.starts_with('-') => {}
+                None => {
+                    return Err(Diagnostic {
+                        file: None,
+                        line: idx + 1,
+                        site: idx + 1,
+                        start: 0,
+                        end: line.len(),
+                        message: "expected a symbol name and a hex address".to_string(),
+                    })
+                }
+            }
+        }
+        Ok(Program {
+            symbols,
+            ..Program::default()
+        })
+    }
+
+    // The relocatable object format read by the linker: the sections as
+    // assembled, every label, the .EXTERNAL names and the words that need
+    // patching once everything has an address.
+    pub fn to_rel(&self) -> String {
+        let mut out = String::from("LC3REL 1\n");
+        for s in &self.sections {
+            out += &format!("SECTION x{:04X} {}\n", s.origin, s.words.len());
+            for chunk in s.words.chunks(8) {
+                let words: Vec<String> = chunk.iter().map(|w| format!("{:04X}", w)).collect();
+                out += &format!("  {}\n", words.join(" "));
+            }
+        }
+        for (name, symbol) in &self.symbols {
+            out += &format!("SYMBOL {} x{:04X} {}\n", name, symbol.addr, symbol.line);
+        }
+        for name in &self.externals {
+            out += &format!("EXTERNAL {}\n", name);
+        }
+        for r in &self.relocations {
+            let symbol = r.symbol.as_deref().unwrap_or("-");
+            out += &format!("RELOC x{:04X} {} {} {}\n", r.addr, r.kind.name(), symbol, r.addend);
+        }
+        out
+    }
+
+    pub fn from_rel(text: &str) -> Result<Program, Diagnostic> {
+        let mut program = Program::default();
+        let mut missing = 0;
+        let mut header = false;
+        for (idx, line) in text.lines().enumerate() {
+            let fail = |message: &str| Diagnostic {
+                file: None,
+                line: idx + 1,
+                site: idx + 1,
+                start: 0,
+                end: line.len(),
+                message: message.to_string(),
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            if !header {
+                if fields != ["LC3REL", "1"] {
+                    return Err(fail("not an LC-3 relocatable object"));
+                }
+                header = true;
+                continue;
+            }
+            if missing > 0 {
+                let words: Option<Vec<u16>> = fields.iter().map(|w| hex(w)).collect();
+                let words = words.filter(|w| w.len() <= missing).ok_or_else(|| fail("expected section words"))?;
+                missing -= words.len();
+                let section = program.sections.last_mut().unwrap();
+                section.lines.extend(std::iter::repeat_n(0, words.len()));
+                section.words.extend(words);
+                continue;
+            }
+            match fields.as_slice() {
+                ["SECTION", origin, count] => {
+                    let origin = hex(origin).ok_or_else(|| fail("expected a section origin"))?;
+                    missing = count.parse().map_err(|_| fail("expected a word count"))?;
+                    program.sections.push(Section { origin, words: Vec::new(), lines: Vec::new() });
+                }
+                ["SYMBOL", name, addr, rest @ ..] => {
+                    let addr = hex(addr).ok_or_else(|| fail("expected a symbol address"))?;
+                    let line = rest.first().and_then(|l| l.parse().ok()).unwrap_or(0);
+                    program.symbols.insert(name.to_string(), Symbol { addr, line });
+                }
+                ["EXTERNAL", name] => {
+                    program.externals.insert(name.to_string());
+                }
+                ["RELOC", addr, kind, symbol, addend] => {
+                    let kind = [RelocKind::PcOffset9, RelocKind::PcOffset11, RelocKind::Fill]
+                        .iter()
+                        .copied()
+                        .find(|k| k.name() == *kind)
+                        .ok_or_else(|| fail("unknown relocation kind"))?;
+                    program.relocations.push(Relocation {
+                        addr: hex(addr).ok_or_else(|| fail("expected a relocation address"))?,
+                        kind,
+                        symbol: Some(symbol.to_string()).filter(|s| s != "-"),
+                        addend: addend.parse().map_err(|_| fail("expected a relocation addend"))?,
+                    });
+                }
+                _ => return Err(fail("unrecognised record")),
+            }
+        }
+        if missing > 0 {
+            let line = text.lines().count();
+            return Err(Diagnostic {
+                file: None,
+                line,
+                site: line,
+                start: 0,
+                end: 0,
+                message: format!("object ends {} words short of its last section", missing),
+            });
+        }
+        Ok(program)
+    }
+
+    // Each source line next to the address and machine words it produced,
+    // laid out like an lc3as listing.
+    pub fn listing(&self, src: &str) -> String {
+        let mut words: BTreeMap<usize, Vec<(u16, u16)>> = BTreeMap::new();
+        for s in &self.sections {
+            for (i, (line, word)) in s.lines.iter().zip(&s.words).enumerate() {
+                words.entry(*line).or_default().push((s.origin + i as u16, *word));
+            }
+        }
+        let mut out = String::new();
+        for (idx, text) in src.lines().enumerate() {
+            let line = idx + 1;
+            let stmt = parse_line(text);
+            let origin = match stmt.op {
+                Some(op) if op.text.eq_ignore_ascii_case(".ORIG") => {
+                    stmt.operands.first().and_then(|t| parse_number(t.text)).map(|a| a as u16)
+                }
+                _ => None,
+            };
+            let (first, rest) = match (words.get(&line), origin) {
+                (Some(w), _) => (Some(w[0]), &w[1..]),
+                (None, Some(origin)) => (Some((0, origin)), &[][..]),
+                (None, None) => (None, &[][..]),
+            };
+            let code = match first {
+                Some((addr, word)) => format!("  ({:04X}) {:04X}  {:016b} ", addr, word, word),
+                None => " ".repeat(32),
+            };
+            out += format!("{}({:>4}) {}", code, line, text).trim_end();
+            out.push('\n');
+            for (addr, word) in rest {
+                out += &format!("  ({:04X}) {:04X}  {:016b}\n", addr, word, word);
+            }
+        }
+        out
+    }
+
+    // Where each label is defined and every line that refers to it.
+    pub fn cross_reference(&self, src: &str) -> String {
+        let mut uses: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+        for (idx, text) in src.lines().enumerate() {
+            for tok in parse_line(text).operands {
+                if let Some((name, _)) = self.symbols.get_key_value(tok.text) {
+                    uses.entry(name.as_str()).or_default().push(idx + 1);
+                }
+            }
+        }
+        let mut out = format!("{:<16}  {:<7}  {:<7}  {}\n", "Symbol", "Address", "Defined", "Referenced");
+        for (name, symbol) in &self.symbols {
+            let lines: Vec<String> = uses.get(name.as_str()).map_or(Vec::new(), |l| l.iter().map(usize::to_string).collect());
+            let refs = if lines.is_empty() { "-".to_string() } else { lines.join(", ") };
+            out += &format!("{:<16}  x{:04X}    {:<7}  {}\n", name, symbol.addr, symbol.line, refs);
+        }
+        out
+    }
+
+    // The closest label at or before `addr`, and how far past it `addr` is.
+    pub fn label_before(&self, addr: u16) -> Option<(&str, u16)> {
+        self.symbols
+            .iter()
+            .filter(|(_, s)| s.addr <= addr)
+            .max_by_key(|(_, s)| s.addr)
+            .map(|(name, s)| (name.as_str(), addr - s.addr))
+    }
+}
+
+struct Assembler<'a> {
+    lines: Vec<Statement<'a>>,
+    // the main source line each statement traces back to
+    sites: Vec<usize>,
+    addrs: Vec<Option<u16>>,
+    sizes: Vec<u32>,
+    symbols: BTreeMap<String, Symbol>,
+    externals: BTreeMap<&'a str, (usize, Token<'a>)>,
+    relocations: Vec<Relocation>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+// `line` counts preprocessed statements until `analyze` maps it back.
+fn error(line: usize, tok: &Token, message: String) -> Diagnostic {
+    Diagnostic {
+        file: None,
+        line,
+        site: line,
+        start: tok.start,
+        end: tok.end,
+        message,
+    }
+}
+
+impl<'a> Assembler<'a> {
+    fn size_of(&mut self, line: usize, op: &Token, operands: &[Token]) -> u32 {
+        match op.text.to_ascii_uppercase().as_str() {
+            ".FILL" => 1,
+            // the count is settled here, so it may only use labels defined above
+            ".BLKW" => match operands.first().map(|t| self.number(line, t, 0, 0xFFFF)) {
+                Some(Ok(n)) => n as u32,
+                Some(Err(diagnostic)) => {
+                    self.diagnostics.push(diagnostic);
+                    0
+                }
+                None => 0,
+            },
+            ".STRINGZ" => operands
+                .first()
+                .and_then(|t| unescape(t.text))
+                .map_or(0, |s| s.len() as u32 + 1),
+            _ => 1,
+        }
+    }
+
+    // Assigns addresses and collects labels.
+    fn first_pass(&mut self) {
+        let mut pc: Option<u32> = None;
+        let lines = std::mem::take(&mut self.lines);
+        for (idx, stmt) in lines.iter().enumerate() {
+            let line = idx + 1;
+            self.addrs.push(pc.filter(|&a| a <= 0xFFFF).map(|a| a as u16));
+            self.sizes.push(0);
+            if let Some(label) = &stmt.label {
+                if !is_label(label.text) {
+                    self.diagnostics
+                        .push(error(line, label, format!("'{}' is not a valid label or opcode", label.text)));
+                } else if pc.is_none() {
+                    self.diagnostics
+                        .push(error(line, label, format!("label '{}' is outside of a .ORIG block", label.text)));
+                } else if let Some(prev) = self.symbols.get(label.text) {
+                    let message = format!("label '{}' is already defined on line {}", label.text, prev.line);
+                    self.diagnostics.push(error(line, label, message));
+                } else {
+                    let addr = pc.unwrap() as u16;
+                    let line = self.sites[idx];
+                    self.symbols.insert(label.text.to_string(), Symbol { addr, line });
+                }
+            }
+            let op = match &stmt.op {
+                Some(op) => op,
+                None => continue,
+            };
+            match (op.text.to_ascii_uppercase().as_str(), pc) {
+                (".EXTERNAL", _) => {
+                    if stmt.operands.is_empty() {
+                        self.diagnostics.push(error(line, op, ".EXTERNAL needs at least one name".to_string()));
+                    }
+                    for name in &stmt.operands {
+                        if is_label(name.text) {
+                            self.externals.insert(name.text, (line, *name));
+                        } else {
+                            self.diagnostics.push(error(line, name, format!("'{}' is not a valid label", name.text)));
+                        }
+                    }
+                }
+                (".ORIG", None) => {
+                    pc = stmt
+                        .operands
+                        .first()
+                        .and_then(|t| preprocess::eval(t.text, |_| None).ok())
+                        .map(|(a, _)| a)
+                        .filter(|a| (0..=0xFFFF).contains(a))
+                        .map(|a| a as u32);
+                    if pc.is_none() {
+                        self.diagnostics
+                            .push(error(line, op, ".ORIG needs an address between x0000 and xFFFF".to_string()));
+                        pc = Some(0x3000);
+                    }
+                    self.addrs[idx] = pc.map(|a| a as u16);
+                }
+                (".ORIG", Some(_)) => {
+                    self.diagnostics
+                        .push(error(line, op, ".ORIG inside another .ORIG block, missing .END?".to_string()));
+                }
+                (".END", _) => pc = None,
+                (_, None) => {
+                    self.diagnostics
+                        .push(error(line, op, format!("'{}' is outside of a .ORIG block", op.text)));
+                }
+                (_, Some(addr)) => {
+                    self.sizes[idx] = self.size_of(line, op, &stmt.operands);
+                    let next = addr + self.sizes[idx];
+                    if next > 0x10000 {
+                        self.diagnostics
+                            .push(error(line, op, "program runs past the end of memory".to_string()));
+                    }
+                    pc = Some(next);
+                }
+            }
+        }
+        self.lines = lines;
+        for (name, (line, tok)) in &self.externals {
+            if let Some(symbol) = self.symbols.get(*name) {
+                let message = format!("'{}' is declared .EXTERNAL but defined on line {}", name, symbol.line);
+                self.diagnostics.push(error(*line, tok, message));
+            }
+        }
+    }
+
+    fn register(&self, line: usize, tok: &Token) -> Result<i16, Diagnostic> {
+        parse_register(tok.text).ok_or_else(|| error(line, tok, format!("expected a register, found '{}'", tok.text)))
+    }
+
+    // An operand expression's value, whether it refers to any labels, and
+    // what the linker would have to do with it. .EXTERNAL names count as 0,
+    // leaving just the addend.
+    fn value(&self, line: usize, tok: &Token) -> Result<(i32, bool, Target), Diagnostic> {
+        let used = RefCell::new(BTreeSet::new());
+        let eval = |label_bias: i32, extern_bias: i32| {
+            preprocess::eval(tok.text, |name| match self.symbols.get(name) {
+                Some(s) => Some(s.addr as i32 + label_bias),
+                None if self.externals.contains_key(name) => {
+                    used.borrow_mut().insert(name.to_string());
+                    Some(extern_bias)
+                }
+                None => None,
+            })
+            .map_err(|e| match e {
+                EvalError::Undefined(name) => error(line, tok, format!("undefined label '{}'", name)),
+                EvalError::Invalid(message) => error(line, tok, message),
+            })
+        };
+        let (val, symbolic) = eval(0, 0)?;
+        let (moved, _) = eval(BIAS, 0)?;
+        let used = used.take();
+        if used.len() > 1 {
+            return Err(error(line, tok, format!("'{}' refers to more than one .EXTERNAL name", tok.text)));
+        }
+        let target = match used.into_iter().next() {
+            Some(name) => {
+                if eval(0, BIAS)?.0 - val != BIAS || moved != val {
+                    let message = format!("'{}' can only add a constant to .EXTERNAL '{}'", tok.text, name);
+                    return Err(error(line, tok, message));
+                }
+                Target::External(name)
+            }
+            None if moved - val == BIAS => Target::Local,
+            None => Target::Const,
+        };
+        Ok((val, symbolic, target))
+    }
+
+    fn number(&self, line: usize, tok: &Token, min: i32, max: i32) -> Result<i32, Diagnostic> {
+        let (val, _, target) = self.value(line, tok)?;
+        if let Target::External(name) = target {
+            return Err(error(line, tok, format!("'{}' is .EXTERNAL, so it can only be used as an address", name)));
+        }
+        if val < min || val > max {
+            return Err(error(line, tok, format!("{} is out of range [{}, {}]", val, min, max)));
+        }
+        Ok(val)
+    }
+
+    // A PC-relative operand. Expressions over labels are addresses, while
+    // plain numbers are taken as the offset itself.
+    fn offset(&mut self, line: usize, tok: &Token, bits: u32, pc: u16) -> Result<i16, Diagnostic> {
+        let (min, max) = (-(1 << (bits - 1)), (1 << (bits - 1)) - 1);
+        let (target, symbolic, kind) = self.value(line, tok)?;
+        if let Target::External(name) = kind {
+            self.relocations.push(Relocation {
+                addr: pc,
+                kind: if bits == 9 { RelocKind::PcOffset9 } else { RelocKind::PcOffset11 },
+                symbol: Some(name),
+                addend: target,
+            });
+            return Ok(0);
+        }
+        if !symbolic {
+            return Ok(self.number(line, tok, min, max)? as i16);
+        }
+        let offset = target - (pc as i32 + 1);
+        if offset < min || offset > max {
+            return Err(error(
+                line,
+                tok,
+                format!("'{}' is {} words away, which does not fit in a {}-bit offset", tok.text, offset, bits),
+            ));
+        }
+        Ok(offset as i16)
+    }
+
+    fn encode(&mut self, line: usize, stmt: &Statement, pc: u16) -> Result<Vec<u16>, Diagnostic> {
+        let op = stmt.op.as_ref().unwrap();
+        let ops = &stmt.operands;
+        let upper = op.text.to_ascii_uppercase();
+        let expect = |n: usize| {
+            if ops.len() == n {
+                return Ok(());
+            }
+            let at = ops.get(n).unwrap_or(op);
+            Err(error(line, at, format!("{} expects {} operand(s), found {}", upper, n, ops.len())))
+        };
+
+        if let Some(cond) = br_condition(&upper) {
+            expect(1)?;
+            return Ok(vec![Inst::BR { cond, pc_offset: self.offset(line, &ops[0], 9, pc)? }.encode()]);
+        }
+        if let Some((_, vect)) = TRAP_ALIASES.iter().find(|(alias, _)| *alias == upper) {
+            expect(0)?;
+            return Ok(vec![Inst::TRAP { trap_vect: *vect as i16 }.encode()]);
+        }
+
+        let inst = match upper.as_str() {
+            "ADD" | "AND" => {
+                expect(3)?;
+                let (dr, sr) = (self.register(line, &ops[0])?, self.register(line, &ops[1])?);
+                match (parse_register(ops[2].text), upper.as_str()) {
+                    (Some(sr2), "ADD") => Inst::ADD { dr, sr1: sr, sr2 },
+                    (Some(sr2), _) => Inst::AND { dr, sr1: sr, sr2 },
+                    (None, "ADD") => Inst::ADDi { dr, sr, imm: self.number(line, &ops[2], -16, 15)? as i16 },
+                    (None, _) => Inst::ANDi { dr, sr, imm: self.number(line, &ops[2], -16, 15)? as i16 },
+                }
+            }
+            "NOT" => {
+                expect(2)?;
+                Inst::NOT { dr: self.register(line, &ops[0])?, sr: self.register(line, &ops[1])? }
+            }
+            "JMP" => {
+                expect(1)?;
+                Inst::JMP { base_r: self.register(line, &ops[0])? }
+            }
+            "RET" => {
+                expect(0)?;
+                Inst::JMP { base_r: 7 }
+            }
+            "JSR" => {
+                expect(1)?;
+                Inst::JSR { pc_offset: self.offset(line, &ops[0], 11, pc)? }
+            }
+            "JSRR" => {
+                expect(1)?;
+                Inst::JSRr { base_r: self.register(line, &ops[0])? }
+            }
+            "LD" | "LDI" | "LEA" | "ST" | "STI" => {
+                expect(2)?;
+                let r = self.register(line, &ops[0])?;
+                let pc_offset = self.offset(line, &ops[1], 9, pc)?;
+                match upper.as_str() {
+                    "LD" => Inst::LD { dr: r, pc_offset },
+                    "LDI" => Inst::LDI { dr: r, pc_offset },
+                    "LEA" => Inst::LEA { dr: r, pc_offset },
+                    "ST" => Inst::ST { sr: r, pc_offset },
+                    _ => Inst::STI { sr: r, pc_offset },
+                }
+            }
+            "LDR" | "STR" => {
+                expect(3)?;
+                let r = self.register(line, &ops[0])?;
+                let base_r = self.register(line, &ops[1])?;
+                let offset = self.number(line, &ops[2], -32, 31)? as i16;
+                if upper == "LDR" {
+                    Inst::LDR { dr: r, base_r, offset }
+                } else {
+                    Inst::STR { sr: r, base_r, offset }
+                }
+            }
+            "RTI" => {
+                expect(0)?;
+                Inst::RTI
+            }
+            "TRAP" => {
+                expect(1)?;
+                Inst::TRAP { trap_vect: self.number(line, &ops[0], 0, 0xFF)? as i16 }
+            }
+            ".FILL" => {
+                expect(1)?;
+                let (val, _, target) = self.value(line, &ops[0])?;
+                let symbol = match target {
+                    Target::Const => return Ok(vec![self.number(line, &ops[0], -0x8000, 0xFFFF)? as u16]),
+                    Target::Local => None,
+                    Target::External(name) => Some(name),
+                };
+                let word = if symbol.is_some() { 0 } else { val as u16 };
+                self.relocations.push(Relocation { addr: pc, kind: RelocKind::Fill, symbol, addend: val });
+                return Ok(vec![word]);
+            }
+            ".BLKW" => {
+                if ops.is_empty() || ops.len() > 2 {
+                    return Err(error(line, op, format!(".BLKW expects 1 or 2 operand(s), found {}", ops.len())));
+                }
+                let count = self.sizes[line - 1] as usize;
+                let fill = match ops.get(1) {
+                    Some(tok) => self.number(line, tok, -0x8000, 0xFFFF)? as u16,
+                    None => 0,
+                };
+                return Ok(vec![fill; count]);
+            }
+            ".STRINGZ" => {
+                expect(1)?;
+                let text = unescape(ops[0].text)
+                    .ok_or_else(|| error(line, &ops[0], format!("expected a string, found {}", ops[0].text)))?;
+                return Ok(text.iter().map(|b| *b as u16).chain(std::iter::once(0)).collect());
+            }
+            _ => return Err(error(line, op, format!("unknown opcode '{}'", op.text))),
+        };
+        Ok(vec![inst.encode()])
+    }
+
+    fn second_pass(&mut self) -> Vec<Section> {
+        let mut sections: Vec<Section> = Vec::new();
+        let mut in_section = false;
+        let mut failed = 0;
+        let lines = std::mem::take(&mut self.lines);
+        for (idx, stmt) in lines.iter().enumerate() {
+            let line = idx + 1;
+            let op = match &stmt.op {
+                Some(op) => op,
+                None => continue,
+            };
+            match op.text.to_ascii_uppercase().as_str() {
+                ".ORIG" => {
+                    if let Some(origin) = self.addrs[idx] {
+                        sections.push(Section { origin, words: Vec::new(), lines: Vec::new() });
+                        in_section = true;
+                    }
+                    continue;
+                }
+                ".END" => {
+                    in_section = false;
+                    continue;
+                }
+                ".EXTERNAL" => continue,
+                _ => {}
+            }
+            let pc = match self.addrs[idx] {
+                Some(pc) if in_section => pc,
+                _ => continue,
+            };
+            match self.encode(line, stmt, pc) {
+                Ok(words) => {
+                    let section = sections.last_mut().unwrap();
+                    // lines that failed to encode still keep their addresses
+                    let gap = ((pc - section.origin) as usize).saturating_sub(section.words.len());
+                    section.lines.extend(std::iter::repeat_n(failed, gap));
+                    section.words.extend(std::iter::repeat_n(0, gap));
+                    section.lines.extend(std::iter::repeat_n(self.sites[idx], words.len()));
+                    section.words.extend(words);
+                }
+                Err(diagnostic) => {
+                    failed = self.sites[idx];
+                    self.diagnostics.push(diagnostic);
+                }
+            }
+        }
+        self.lines = lines;
+        sections
+    }
+}
+
+// Assembles as much of `src` as possible, returning whatever was built along
+// with every problem found. Lines with errors produce no words. `path` is
+// where the source lives, for resolving .INCLUDE.
+pub fn analyze_at(src: &str, path: Option<&Path>) -> (Program, Vec<Diagnostic>) {
+    let (lines, mut diagnostics) = preprocess::preprocess(src, path);
+    let mut asm = Assembler {
+        lines: lines.iter().map(|l| parse_line(&l.text)).collect(),
+        sites: lines.iter().map(|l| l.site).collect(),
+        addrs: Vec::new(),
+        sizes: Vec::new(),
+        symbols: BTreeMap::new(),
+        externals: BTreeMap::new(),
+        relocations: Vec::new(),
+        diagnostics: Vec::new(),
+    };
+    asm.first_pass();
+    let sections = asm.second_pass();
+    diagnostics.extend(asm.diagnostics.into_iter().map(|d| match lines.get(d.line - 1) {
+        Some(line) => line.diagnostic(d.start, d.end, d.message),
+        None => d,
+    }));
+    if sections.is_empty() && diagnostics.is_empty() {
+        diagnostics.push(Diagnostic {
+            file: None,
+            line: 1,
+            site: 1,
+            start: 0,
+            end: 0,
+            message: "no .ORIG block found".to_string(),
+        });
+    }
+    diagnostics.sort_by(|a, b| (a.site, &a.file, a.line, a.start).cmp(&(b.site, &b.file, b.line, b.start)));
+    let program = Program {
+        sections,
+        symbols: asm.symbols,
+        externals: asm.externals.keys().map(|name| name.to_string()).collect(),
+        relocations: asm.relocations,
+    };
+    (program, diagnostics)
+}
+
+pub fn analyze(src: &str) -> (Program, Vec<Diagnostic>) {
+    analyze_at(src, None)
+}
+
+pub fn assemble_at(src: &str, path: &Path) -> Result<Program, Vec<Diagnostic>> {
+    match analyze_at(src, Some(path)) {
+        (program, diagnostics) if diagnostics.is_empty() => Ok(program),
+        (_, diagnostics) => Err(diagnostics),
+    }
+}
+
+pub fn assemble(src: &str) -> Result<Program, Vec<Diagnostic>> {
+    match analyze(src) {
+        (program, diagnostics) if diagnostics.is_empty() => Ok(program),
+        (_, diagnostics) => Err(diagnostics),
+    }
+}
+
+// Disassembles an object file, one word per line, naming labelled
+// addresses and the targets of PC-relative instructions from `symbols`.
+pub fn disassemble_obj(obj: &[u8], symbols: &Program) -> String {
+    let mut words = obj.chunks_exact(2).map(|w| u16::from_be_bytes([w[0], w[1]]));
+    let origin = match words.next() {
+        Some(origin) => origin,
+        None => return String::new(),
+    };
+    let mut out = String::new();
+    for (i, word) in words.enumerate() {
+        let addr = origin.wrapping_add(i as u16);
+        let label = match symbols.label_before(addr) {
+            Some((name, 0)) => name,
+            _ => "",
+        };
+        let text = disassemble(word);
+        let offset = match word >> 12 {
+            0b1101 => None,
+            _ => match Inst::from(word) {
+                Inst::BR { pc_offset, .. } | Inst::JSR { pc_offset } => Some(pc_offset),
+                Inst::LD { pc_offset, .. } | Inst::LDI { pc_offset, .. } | Inst::LEA { pc_offset, .. } => Some(pc_offset),
+                Inst::ST { pc_offset, .. } | Inst::STI { pc_offset, .. } => Some(pc_offset),
+                _ => None,
+            },
+        };
+        let target = offset.and_then(|off| {
+            let target = addr.wrapping_add(1).wrapping_add(off as u16);
+            symbols.label_before(target).filter(|(_, d)| *d == 0).map(|(name, _)| name)
+        });
+        match target {
+            Some(name) => out += &format!("x{:04X}  x{:04X}  {:<16}  {:<20} ; {}\n", addr, word, label, text, name),
+            None => out += &format!("x{:04X}  x{:04X}  {:<16}  {}\n", addr, word, label, text),
+        }
+    }
+    out
+}
+
+// Like `Inst`'s Display, but shows the reserved opcode as data rather than
+// panicking on it.
+pub fn disassemble(word: u16) -> String {
+    match word >> 12 {
+        0b1101 => format!(".FILL x{:04X}", word),
+        _ => Inst::from(word).to_string(),
+    }
+}
+
+#[allow(clippy::unusual_byte_groupings)]
+#[cfg(test)]
+mod tests {
+    use super::{analyze, assemble, disassemble_obj, parse_line, tokenize, Program, RelocKind, Token};
+    use crate::console::Console;
+    use crate::LC3;
+
+    const COUNTDOWN: &str = r#"
+        .ORIG x3000
+        LD R1, COUNT      ; loop counter
+LOOP    LEA R0, MSG
+        PUTS
+        ADD R1, R1, #-1
+        BRp LOOP
+        HALT
+COUNT   .FILL #3
+MSG     .STRINGZ "hi\n"
+        .END
+    "#;
+
+    #[test]
+    fn test_tokenize() {
+        let tokens = tokenize("LOOP ADD R1,R1, #-1 ; comment");
+        let texts: Vec<&str> = tokens.iter().map(|t| t.text).collect();
+        assert_eq!(texts, vec!["LOOP", "ADD", "R1", "R1", "#-1"]);
+        assert_eq!(tokens[4], Token { text: "#-1", start: 16, end: 19 });
+        assert_eq!(tokenize(r#" .STRINGZ "a; \"b\"" ;x"#)[1].text, r#""a; \"b\"""#);
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let stmt = parse_line("  BRnz DONE");
+        assert!(stmt.label.is_none());
+        assert_eq!(stmt.op.unwrap().text, "BRnz");
+        let stmt = parse_line("DONE HALT");
+        assert_eq!(stmt.label.unwrap().text, "DONE");
+        assert_eq!(parse_line("ONLY").label.unwrap().text, "ONLY");
+    }
+
+    #[test]
+    fn test_assemble_and_run() {
+        let program = assemble(COUNTDOWN).unwrap();
+        let section = &program.sections[0];
+        assert_eq!(section.origin, 0x3000);
+        assert_eq!(section.words[0], 0b0010_001_000000101);
+        assert_eq!(section.words[4], 0b0000_001_111111100);
+        assert_eq!(section.words[6], 3);
+        assert_eq!(&section.words[7..], &[b'h' as u16, b'i' as u16, b'\n' as u16, 0]);
+        assert_eq!(program.symbols["LOOP"].addr, 0x3001);
+        assert_eq!(program.line_of(0x3001), Some(4));
+        assert_eq!(program.addr_of_line(2), Some((0x3000, 3)));
+        assert_eq!(program.label_before(0x3003), Some(("LOOP", 2)));
+
+        let mut lc3 = LC3 {
+            console: Console::buffer(&[]),
+            ..LC3::default()
+        };
+        program.load_into(&mut lc3);
+        lc3.run().unwrap();
+        assert_eq!(lc3.console.output(), b"hi\nhi\nhi\n");
+
+        let obj = program.to_obj();
+        assert_eq!(&obj[..4], &[0x30, 0x00, 0x22, 0x05]);
+    }
+
+    #[test]
+    fn test_sym_listing_and_xref() {
+        let program = assemble(COUNTDOWN).unwrap();
+        let sym = program.sym();
+        assert!(sym.contains("//\tLOOP              3001\n"));
+        let loaded = Program::from_sym(&sym).unwrap();
+        assert_eq!(loaded.label_before(0x3008), Some(("MSG", 1)));
+        assert_eq!(loaded.symbols["COUNT"].addr, 0x3006);
+        assert!(Program::from_sym("// Symbol table\nLOOP\n").is_err());
+
+        let listing = program.listing(COUNTDOWN);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[1], "  (0000) 3000  0011000000000000 (   2)         .ORIG x3000");
+        assert_eq!(lines[3], "  (3001) E005  1110000000000101 (   4) LOOP    LEA R0, MSG");
+        assert_eq!(lines[10], "  (3008) 0069  0000000001101001");
+        assert_eq!(lines[0], format!("{:32}(   1)", ""));
+
+        let xref = program.cross_reference(COUNTDOWN);
+        assert_eq!(xref.lines().nth(2), Some("LOOP              x3001    4        7"));
+        assert_eq!(xref.lines().nth(3), Some("MSG               x3007    10       4"));
+
+        let text = disassemble_obj(&program.to_obj(), &loaded);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[1], "x3001  xE005  LOOP              LEA r0 #5            ; MSG");
+        assert_eq!(lines[4], "x3004  x03FC                    BRp #-4              ; LOOP");
+    }
+
+    #[test]
+    fn test_includes_macros_and_expressions() {
+        let dir = std::env::temp_dir().join(format!("lc3-asm-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let lib = ".MACRO PUSH reg\n  ADD R6, R6, #-1\n  STR \\reg, R6, #0\n.ENDM\n.MACRO BAD\n  ADD R0, R0, #99\n.ENDM\nNL .EQU x0A\n";
+        std::fs::write(dir.join("lib.asm"), lib).unwrap();
+        let main = dir.join("main.asm");
+
+        let src = r#"        .INCLUDE "lib.asm"
+        .ORIG x3000
+COUNT   .SET 3
+        ADD R0, R0, #COUNT*2-1
+        LD R1, TABLE+1
+        PUSH R1
+        .IF COUNT-3
+        HALT
+        .ELSE
+        TRAP x21
+        .ENDIF
+TABLE   .FILL NL
+        .FILL TABLE+2
+        .END
+"#;
+        let program = super::assemble_at(src, &main).unwrap();
+        assert_eq!(
+            program.sections[0].words,
+            vec![0x1025, 0x2204, 0x1DBF, 0x7380, 0xF021, 0x000A, 0x3007]
+        );
+        assert_eq!(program.line_of(0x3003), Some(6));
+        assert_eq!(program.symbols["TABLE"].addr, 0x3005);
+
+        let errors = super::assemble_at(".INCLUDE \"lib.asm\"\n.ORIG x3000\nBAD\n.END\n", &main).unwrap_err();
+        let lib_path = dir.join("lib.asm").to_string_lossy().into_owned();
+        assert_eq!(errors[0].file.as_deref(), Some(lib_path.as_str()));
+        assert_eq!((errors[0].line, errors[0].site), (6, 3));
+        assert_eq!(
+            errors[0].in_file("main.asm"),
+            format!("{}:line 6: 99 is out of range [-16, 15] (in macro 'BAD' expanded on line 3)", lib_path)
+        );
+        let errors = assemble(".INCLUDE \"missing.asm\"").unwrap_err();
+        assert!(errors[0].to_string().starts_with("line 1: cannot include 'missing.asm'"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let src = ".ORIG x3000\nADD R1, R2\nBRz NOWHERE\nLD R0, FAR\nAND R0, R0, #16\n.BLKW x200\nFAR .FILL 0\n.END";
+        let errors = assemble(src).unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "line 2: ADD expects 3 operand(s), found 2",
+                "line 3: undefined label 'NOWHERE'",
+                "line 4: 'FAR' is 513 words away, which does not fit in a 9-bit offset",
+                "line 5: 16 is out of range [-16, 15]",
+            ]
+        );
+        assert_eq!((errors[1].start, errors[1].end), (4, 11));
+
+        let errors = assemble("ADD R0, R0, R0").unwrap_err();
+        assert_eq!(errors[0].message, "'ADD' is outside of a .ORIG block");
+        let errors = assemble(".ORIG x3000\nX .FILL 1\nX .FILL 2\n.END").unwrap_err();
+        assert_eq!(errors[0].message, "label 'X' is already defined on line 2");
+    }
+
+    #[test]
+    fn test_externals_and_rel() {
+        let src = ".ORIG x3000\n.EXTERNAL PUTX\nJSR PUTX\nBR LOOP\nLOOP LEA R0, PUTX+2\nP .FILL LOOP\n.END";
+        let program = assemble(src).unwrap();
+        assert_eq!(program.externals.iter().collect::<Vec<_>>(), vec!["PUTX"]);
+        assert_eq!(program.sections[0].words[..2], [0x4800, 0x0E00]);
+        let reloc = &program.relocations[1];
+        assert_eq!((reloc.addr, reloc.kind, reloc.addend), (0x3002, RelocKind::PcOffset9, 2));
+        assert_eq!(reloc.symbol.as_deref(), Some("PUTX"));
+        assert_eq!(program.relocations[2].symbol, None);
+
+        let rel = program.to_rel();
+        assert!(rel.contains("RELOC x3000 PC11 PUTX 0\n"));
+        assert_eq!(Program::from_rel(&rel).unwrap().to_rel(), rel);
+        assert!(Program::from_rel("LC3REL 1\nSECTION x3000 3\n  0001 0002\n").is_err());
+
+        let errors = analyze(".ORIG x3000\n.EXTERNAL A, B, C\nLD R0, A+B\nADD R0, R0, A\n.FILL 2*A\nC .FILL 0\n.END").1;
+        let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "'C' is declared .EXTERNAL but defined on line 6",
+                "'A+B' refers to more than one .EXTERNAL name",
+                "'A' is .EXTERNAL, so it can only be used as an address",
+                "'2*A' can only add a constant to .EXTERNAL 'A'",
+            ]
+        );
+    }
+}
@@ -0,0 +1,8 @@
+use lc3_tools::lsp;
+
+fn main() {
+    if let Err(e) = lsp::serve_stdio() {
+        eprintln!("lc3_lsp: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,539 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use crate::asm::{br_condition, Program, Section, Symbol};
+use crate::opcodes::Inst;
+use crate::utils::unescape;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BuildError {
+    pub addr: u16,
+    pub message: String,
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "x{:04X}: {}", self.addr, self.message)
+    }
+}
+
+// A field's name, value and allowed range.
+type Field = (&'static str, i16, i16, i16);
+
+// A label reference to fill in once every label is known. `bits` is the
+// width of the PC offset field, or 16 for a whole .FILL word.
+struct Fixup {
+    addr: u16,
+    label: String,
+    bits: u32,
+}
+
+// Builds a program one word at a time from Rust, the way the assembler would
+// from source. Labels may be used before they are defined; offsets are
+// patched in and range checked by `build`.
+pub struct Builder {
+    origin: u16,
+    words: Vec<u16>,
+    labels: BTreeMap<String, u16>,
+    fixups: Vec<Fixup>,
+    errors: Vec<BuildError>,
+}
+
+impl Builder {
+    pub fn new(origin: u16) -> Builder {
+        Builder {
+            origin,
+            words: Vec::new(),
+            labels: BTreeMap::new(),
+            fixups: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    // The address of the next word.
+    pub fn here(&self) -> u16 {
+        self.origin.wrapping_add(self.words.len() as u16)
+    }
+
+    fn fail(&mut self, message: String) {
+        self.errors.push(BuildError {
+            addr: self.here(),
+            message,
+        });
+    }
+
+    fn check(&mut self, what: &str, val: i16, min: i16, max: i16) {
+        if val < min || val > max {
+            self.fail(format!("{} {} is out of range [{}, {}]", what, val, min, max));
+        }
+    }
+
+    pub fn label(&mut self, name: &str) -> &mut Self {
+        match self.labels.get(name) {
+            Some(addr) => {
+                let message = format!("label '{}' is already defined at x{:04X}", name, addr);
+                self.fail(message);
+            }
+            None => {
+                let addr = self.here();
+                self.labels.insert(name.to_string(), addr);
+            }
+        }
+        self
+    }
+
+    // Emits `inst` as given, after checking every register and field fits.
+    pub fn inst(&mut self, inst: Inst) -> &mut Self {
+        let (regs, fields): (Vec<i16>, Vec<Field>) = match inst {
+            Inst::ADD { dr, sr1, sr2 } | Inst::AND { dr, sr1, sr2 } => (vec![dr, sr1, sr2], vec![]),
+            Inst::ADDi { dr, sr, imm } | Inst::ANDi { dr, sr, imm } => (vec![dr, sr], vec![("imm5", imm, -16, 15)]),
+            Inst::BR { pc_offset, .. } => (vec![], vec![("PC offset", pc_offset, -256, 255)]),
+            Inst::JMP { base_r } | Inst::JSRr { base_r } => (vec![base_r], vec![]),
+            Inst::JSR { pc_offset } => (vec![], vec![("PC offset", pc_offset, -1024, 1023)]),
+            Inst::LD { dr, pc_offset } | Inst::LDI { dr, pc_offset } | Inst::LEA { dr, pc_offset } => {
+                (vec![dr], vec![("PC offset", pc_offset, -256, 255)])
+            }
+            Inst::ST { sr, pc_offset } | Inst::STI { sr, pc_offset } => {
+                (vec![sr], vec![("PC offset", pc_offset, -256, 255)])
+            }
+            Inst::LDR { dr: r, base_r, offset } | Inst::STR { sr: r, base_r, offset } => {
+                (vec![r, base_r], vec![("offset6", offset, -32, 31)])
+            }
+            Inst::NOT { dr, sr } => (vec![dr, sr], vec![]),
+            Inst::RTI => (vec![], vec![]),
+            Inst::TRAP { trap_vect } => (vec![], vec![("trap vector", trap_vect, 0, 0xFF)]),
+        };
+        for r in regs {
+            if !(0..8).contains(&r) {
+                self.fail(format!("R{} is not a register", r));
+            }
+        }
+        for (what, val, min, max) in fields {
+            self.check(what, val, min, max);
+        }
+        self.words.push(inst.encode());
+        self
+    }
+
+    // Emits the instruction `make` builds, with its PC offset pointing at
+    // `label`.
+    pub fn to(&mut self, label: &str, make: impl FnOnce(i16) -> Inst) -> &mut Self {
+        let inst = make(0);
+        let bits = match inst {
+            Inst::JSR { .. } => 11,
+            Inst::BR { .. } | Inst::LD { .. } | Inst::LDI { .. } | Inst::LEA { .. } | Inst::ST { .. } | Inst::STI { .. } => 9,
+            _ => {
+                self.fail(format!("{:?} has no PC offset to point at '{}'", inst, label));
+                return self.inst(inst);
+            }
+        };
+        self.fixups.push(Fixup {
+            addr: self.here(),
+            label: label.to_string(),
+            bits,
+        });
+        self.inst(inst)
+    }
+
+    // A branch spelled the assembler's way, so BRnz, BRp or plain BR.
+    pub fn br(&mut self, op: &str, label: &str) -> &mut Self {
+        match br_condition(op) {
+            Some(cond) => self.to(label, |pc_offset| Inst::BR { cond, pc_offset }),
+            None => {
+                self.fail(format!("'{}' is not a branch", op));
+                self.words.push(0);
+                self
+            }
+        }
+    }
+
+    pub fn fill(&mut self, value: i32) -> &mut Self {
+        if !(-0x8000..=0xFFFF).contains(&value) {
+            self.fail(format!("{} is out of range [-32768, 65535]", value));
+        }
+        self.words.push(value as u16);
+        self
+    }
+
+    // A word holding the address of `label`.
+    pub fn fill_label(&mut self, label: &str) -> &mut Self {
+        self.fixups.push(Fixup {
+            addr: self.here(),
+            label: label.to_string(),
+            bits: 16,
+        });
+        self.words.push(0);
+        self
+    }
+
+    pub fn blkw(&mut self, count: u16) -> &mut Self {
+        self.words.extend(std::iter::repeat_n(0, count as usize));
+        self
+    }
+
+    // The bytes of `text`, null terminated.
+    pub fn stringz(&mut self, text: &str) -> &mut Self {
+        self.words.extend(text.bytes().map(u16::from));
+        self.words.push(0);
+        self
+    }
+
+    // Takes a string literal as written in Rust source, quotes included.
+    pub fn stringz_literal(&mut self, literal: &str) -> &mut Self {
+        match unescape(literal) {
+            Some(bytes) => {
+                self.words.extend(bytes.into_iter().map(u16::from));
+                self.words.push(0);
+            }
+            None => self.fail(format!("{} is not a string", literal)),
+        }
+        self
+    }
+
+    // Resolves every label reference and returns the finished program, or
+    // every problem found along the way.
+    pub fn build(&self) -> Result<Program, Vec<BuildError>> {
+        let mut errors = self.errors.clone();
+        let mut words = self.words.clone();
+        if self.origin as usize + words.len() > 0x10000 {
+            errors.push(BuildError {
+                addr: self.origin,
+                message: "program runs past the end of memory".to_string(),
+            });
+        }
+        for fixup in &self.fixups {
+            let fail = |message: String| BuildError {
+                addr: fixup.addr,
+                message,
+            };
+            let target = match self.labels.get(&fixup.label) {
+                Some(&target) => target,
+                None => {
+                    errors.push(fail(format!("undefined label '{}'", fixup.label)));
+                    continue;
+                }
+            };
+            let at = fixup.addr.wrapping_sub(self.origin) as usize;
+            if fixup.bits == 16 {
+                words[at] = target;
+                continue;
+            }
+            let offset = target as i32 - (fixup.addr as i32 + 1);
+            if offset < -(1 << (fixup.bits - 1)) || offset >= 1 << (fixup.bits - 1) {
+                errors.push(fail(format!(
+                    "'{}' is {} words away, which does not fit in a {}-bit offset",
+                    fixup.label, offset, fixup.bits
+                )));
+                continue;
+            }
+            let mask = (1 << fixup.bits) - 1;
+            words[at] = (words[at] & !mask) | (offset as u16 & mask);
+        }
+        if !errors.is_empty() {
+            errors.sort_by_key(|e| e.addr);
+            return Err(errors);
+        }
+        let symbols = self
+            .labels
+            .iter()
+            .map(|(name, &addr)| (name.clone(), Symbol { addr, line: 0 }))
+            .collect();
+        let lines = vec![0; words.len()];
+        Ok(Program {
+            sections: vec![Section {
+                origin: self.origin,
+                words,
+                lines,
+            }],
+            symbols,
+            ..Program::default()
+        })
+    }
+}
+
+// Assembly-like syntax for building programs inside Rust, one statement per
+// `;`. It expands into `Builder` calls and evaluates to the result of
+// `Builder::build`:
+//
+//     let program = lc3! {
+//         .ORIG 0x3000;
+//         LD R1, count;
+//     again: ADD R1, R1, #-1;
+//         BRp again;
+//         HALT;
+//     count: .FILL 3;
+//     };
+//
+// Registers are R0 to R7. PC offsets take a label or `#n`, and .FILL takes
+// a number or a label.
+#[macro_export]
+macro_rules! lc3 {
+    (. ORIG $origin:literal; $($rest:tt)*) => {{
+        let mut builder = $crate::builder::Builder::new($origin);
+        $crate::lc3!(@stmts builder; $($rest)*);
+        builder.build()
+    }};
+
+    (@reg R0) => { 0 };
+    (@reg R1) => { 1 };
+    (@reg R2) => { 2 };
+    (@reg R3) => { 3 };
+    (@reg R4) => { 4 };
+    (@reg R5) => { 5 };
+    (@reg R6) => { 6 };
+    (@reg R7) => { 7 };
+
+    (@stmts $b:ident;) => {};
+    (@stmts $b:ident; $label:ident : $($rest:tt)*) => {
+        $b.label(stringify!($label));
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; . FILL $label:ident; $($rest:tt)*) => {
+        $b.fill_label(stringify!($label));
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; . FILL $value:literal; $($rest:tt)*) => {
+        $b.fill($value);
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; . BLKW $count:literal; $($rest:tt)*) => {
+        $b.blkw($count);
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; . STRINGZ $text:literal; $($rest:tt)*) => {
+        $b.stringz_literal(stringify!($text));
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; ADD $dr:ident, $sr:ident, # $imm:literal; $($rest:tt)*) => {
+        $crate::lc3!(@inst $b; ADDi { dr: $crate::lc3!(@reg $dr), sr: $crate::lc3!(@reg $sr), imm: $imm });
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; ADD $dr:ident, $sr1:ident, $sr2:ident; $($rest:tt)*) => {
+        $crate::lc3!(@inst $b; ADD {
+            dr: $crate::lc3!(@reg $dr),
+            sr1: $crate::lc3!(@reg $sr1),
+            sr2: $crate::lc3!(@reg $sr2),
+        });
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; AND $dr:ident, $sr:ident, # $imm:literal; $($rest:tt)*) => {
+        $crate::lc3!(@inst $b; ANDi { dr: $crate::lc3!(@reg $dr), sr: $crate::lc3!(@reg $sr), imm: $imm });
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; AND $dr:ident, $sr1:ident, $sr2:ident; $($rest:tt)*) => {
+        $crate::lc3!(@inst $b; AND {
+            dr: $crate::lc3!(@reg $dr),
+            sr1: $crate::lc3!(@reg $sr1),
+            sr2: $crate::lc3!(@reg $sr2),
+        });
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; NOT $dr:ident, $sr:ident; $($rest:tt)*) => {
+        $crate::lc3!(@inst $b; NOT { dr: $crate::lc3!(@reg $dr), sr: $crate::lc3!(@reg $sr) });
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; LDR $dr:ident, $base:ident, # $offset:literal; $($rest:tt)*) => {
+        $crate::lc3!(@inst $b; LDR { dr: $crate::lc3!(@reg $dr), base_r: $crate::lc3!(@reg $base), offset: $offset });
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; STR $sr:ident, $base:ident, # $offset:literal; $($rest:tt)*) => {
+        $crate::lc3!(@inst $b; STR { sr: $crate::lc3!(@reg $sr), base_r: $crate::lc3!(@reg $base), offset: $offset });
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; JMP $base:ident; $($rest:tt)*) => {
+        $crate::lc3!(@inst $b; JMP { base_r: $crate::lc3!(@reg $base) });
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; JSRR $base:ident; $($rest:tt)*) => {
+        $crate::lc3!(@inst $b; JSRr { base_r: $crate::lc3!(@reg $base) });
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; RET; $($rest:tt)*) => {
+        $crate::lc3!(@inst $b; JMP { base_r: 7 });
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; RTI; $($rest:tt)*) => {
+        $b.inst($crate::opcodes::Inst::RTI);
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; TRAP $vect:literal; $($rest:tt)*) => {
+        $crate::lc3!(@inst $b; TRAP { trap_vect: $vect });
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; GETC; $($rest:tt)*) => { $crate::lc3!(@stmts $b; TRAP 0x20; $($rest)*); };
+    (@stmts $b:ident; OUT; $($rest:tt)*) => { $crate::lc3!(@stmts $b; TRAP 0x21; $($rest)*); };
+    (@stmts $b:ident; PUTS; $($rest:tt)*) => { $crate::lc3!(@stmts $b; TRAP 0x22; $($rest)*); };
+    (@stmts $b:ident; IN; $($rest:tt)*) => { $crate::lc3!(@stmts $b; TRAP 0x23; $($rest)*); };
+    (@stmts $b:ident; PUTSP; $($rest:tt)*) => { $crate::lc3!(@stmts $b; TRAP 0x24; $($rest)*); };
+    (@stmts $b:ident; HALT; $($rest:tt)*) => { $crate::lc3!(@stmts $b; TRAP 0x25; $($rest)*); };
+    (@stmts $b:ident; JSR # $offset:literal; $($rest:tt)*) => {
+        $crate::lc3!(@inst $b; JSR { pc_offset: $offset });
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; JSR $label:ident; $($rest:tt)*) => {
+        $b.to(stringify!($label), |pc_offset| $crate::opcodes::Inst::JSR { pc_offset });
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; $op:ident $r:ident, # $offset:literal; $($rest:tt)*) => {
+        $crate::lc3!(@pc $b; $op $r; |make: fn(i16, i16) -> $crate::opcodes::Inst| {
+            $b.inst(make($crate::lc3!(@reg $r), $offset));
+        });
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; $op:ident $r:ident, $label:ident; $($rest:tt)*) => {
+        $crate::lc3!(@pc $b; $op $r; |make: fn(i16, i16) -> $crate::opcodes::Inst| {
+            $b.to(stringify!($label), |pc_offset| make($crate::lc3!(@reg $r), pc_offset));
+        });
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; $br:ident # $offset:literal; $($rest:tt)*) => {
+        match $crate::asm::br_condition(stringify!($br)) {
+            Some(cond) => $b.inst($crate::opcodes::Inst::BR { cond, pc_offset: $offset }),
+            None => $b.br(stringify!($br), ""),
+        };
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+    (@stmts $b:ident; $br:ident $label:ident; $($rest:tt)*) => {
+        $b.br(stringify!($br), stringify!($label));
+        $crate::lc3!(@stmts $b; $($rest)*);
+    };
+
+    (@inst $b:ident; $variant:ident { $($field:tt)* }) => {
+        $b.inst($crate::opcodes::Inst::$variant { $($field)* })
+    };
+
+    // LD, LDI, LEA, ST and STI share a shape: a register and a PC offset.
+    (@pc $b:ident; LD $r:ident; $emit:expr) => {
+        ($emit)(|dr, pc_offset| $crate::opcodes::Inst::LD { dr, pc_offset })
+    };
+    (@pc $b:ident; LDI $r:ident; $emit:expr) => {
+        ($emit)(|dr, pc_offset| $crate::opcodes::Inst::LDI { dr, pc_offset })
+    };
+    (@pc $b:ident; LEA $r:ident; $emit:expr) => {
+        ($emit)(|dr, pc_offset| $crate::opcodes::Inst::LEA { dr, pc_offset })
+    };
+    (@pc $b:ident; ST $r:ident; $emit:expr) => {
+        ($emit)(|sr, pc_offset| $crate::opcodes::Inst::ST { sr, pc_offset })
+    };
+    (@pc $b:ident; STI $r:ident; $emit:expr) => {
+        ($emit)(|sr, pc_offset| $crate::opcodes::Inst::STI { sr, pc_offset })
+    };
+}
+
+#[cfg(test)]
+#[allow(clippy::unusual_byte_groupings)]
+mod tests {
+    use super::Builder;
+    use crate::console::Console;
+    use crate::opcodes::Inst;
+    use crate::LC3;
+
+    #[test]
+    fn test_builder_backpatches_labels() {
+        let mut b = Builder::new(0x3000);
+        b.to("count", |pc_offset| Inst::LD { dr: 1, pc_offset })
+            .label("loop")
+            .to("msg", |pc_offset| Inst::LEA { dr: 0, pc_offset })
+            .inst(Inst::TRAP { trap_vect: 0x22 })
+            .inst(Inst::ADDi { dr: 1, sr: 1, imm: -1 })
+            .br("BRp", "loop")
+            .inst(Inst::TRAP { trap_vect: 0x25 })
+            .label("count")
+            .fill(2)
+            .label("msg")
+            .stringz("ok\n")
+            .fill_label("msg");
+        let program = b.build().unwrap();
+        let words = &program.sections[0].words;
+        assert_eq!(words[0], 0b0010_001_000000101);
+        assert_eq!(words[4], 0b0000_001_111111100);
+        assert_eq!(words[11], 0x3007);
+        assert_eq!(program.symbols["loop"].addr, 0x3001);
+
+        let mut lc3 = LC3 {
+            console: Console::buffer(&[]),
+            ..LC3::default()
+        };
+        program.load_into(&mut lc3);
+        lc3.run().unwrap();
+        assert_eq!(lc3.console.output(), b"ok\nok\n");
+    }
+
+    #[test]
+    fn test_builder_errors() {
+        let mut b = Builder::new(0x3000);
+        b.inst(Inst::ADDi { dr: 0, sr: 0, imm: 16 })
+            .inst(Inst::NOT { dr: 8, sr: 0 })
+            .to("far", |pc_offset| Inst::LD { dr: 0, pc_offset })
+            .to("nowhere", |pc_offset| Inst::JSR { pc_offset })
+            .br("BRq", "far")
+            .label("far")
+            .label("far");
+        b.blkw(0x200).label("far_end").to("far_end", |_| Inst::RTI);
+        let messages: Vec<String> = b.build().unwrap_err().iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "x3000: imm5 16 is out of range [-16, 15]",
+                "x3001: R8 is not a register",
+                "x3003: undefined label 'nowhere'",
+                "x3004: 'BRq' is not a branch",
+                "x3005: label 'far' is already defined at x3005",
+                "x3205: RTI has no PC offset to point at 'far_end'",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lc3_macro() {
+        let program = lc3! {
+            .ORIG 0x3000;
+            LD R1, count;
+            AND R2, R2, #0;
+        again: LEA R0, msg;
+            PUTS;
+            ADD R2, R2, R1;
+            ADD R1, R1, #-1;
+            BRp again;
+            ST R2, total;
+            LDR R3, R6, #0;
+            HALT;
+        count: .FILL 3;
+        total: .BLKW 1;
+        msg: .STRINGZ "hi\n";
+        ptr: .FILL msg;
+        }
+        .unwrap();
+        assert_eq!(program.sections[0].words[0], 0b0010_001_000001001);
+        assert_eq!(program.sections[0].words[6], 0b0000_001_111111011);
+
+        let mut lc3 = LC3 {
+            console: Console::buffer(&[]),
+            ..LC3::default()
+        };
+        program.load_into(&mut lc3);
+        lc3.run().unwrap();
+        assert_eq!(lc3.console.output(), b"hi\nhi\nhi\n");
+        assert_eq!(lc3.memory[0x300B], 6);
+        assert_eq!(lc3.memory[0x3010], 0x300C);
+
+        let errors = lc3! {
+            .ORIG 0x3000;
+            BRz #300;
+            LD R0, far;
+            .BLKW 300;
+        far: .FILL 1;
+            BRx far;
+        }
+        .unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "x3000: PC offset 300 is out of range [-256, 255]",
+                "x3001: 'far' is 300 words away, which does not fit in a 9-bit offset",
+                "x312F: 'BRx' is not a branch",
+            ]
+        );
+    }
+}
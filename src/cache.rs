@@ -0,0 +1,450 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+
+use crate::{Fault, LC3};
+
+// A model of caches between the LC-3 and its memory, fed every instruction
+// fetch and data access `LC3::run_step` makes, TRAP services and interrupt
+// entries included. It only counts: memory is read and written exactly as
+// it is without it. Device registers aren't cached and aren't counted.
+//
+// Sizes are in words. Split caches are each of the configured size, one
+// for fetches and one for data. A write-back cache allocates a line on a
+// write miss and writes it back when a dirty line is evicted; a
+// write-through one passes every write on and allocates nothing for it.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Fetch,
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub kind: Kind,
+    pub addr: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replacement {
+    Lru,
+    Fifo,
+    // chosen by a generator started from `Config::seed`
+    Random,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub size: usize,
+    pub line: usize,
+    pub ways: usize,
+    pub replacement: Replacement,
+    pub seed: u64,
+    pub write_back: bool,
+    pub split: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            size: 256,
+            line: 8,
+            ways: 2,
+            replacement: Replacement::Lru,
+            seed: 1,
+            write_back: true,
+            split: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let number = || value.parse().map_err(|_| format!("{}: expected a number", name));
+        match (name, value) {
+            ("size", _) => self.size = number()?,
+            ("line", _) => self.line = number()?,
+            ("ways", _) => self.ways = number()?,
+            ("seed", _) => self.seed = number()? as u64,
+            ("replacement", "lru") => self.replacement = Replacement::Lru,
+            ("replacement", "fifo") => self.replacement = Replacement::Fifo,
+            ("replacement", "random") => self.replacement = Replacement::Random,
+            ("write", "back") => self.write_back = true,
+            ("write", "through") => self.write_back = false,
+            ("caches", "unified") => self.split = false,
+            ("caches", "split") => self.split = true,
+            ("replacement", _) => return Err("replacement: expected lru, fifo or random".to_string()),
+            ("write", _) => return Err("write: expected back or through".to_string()),
+            ("caches", _) => return Err("caches: expected unified or split".to_string()),
+            _ => return Err(format!("no cache setting called {}", name)),
+        }
+        Ok(())
+    }
+
+    // Settings like `size=1024,ways=4`, checking the shape they make.
+    pub fn parse(&mut self, settings: &str) -> Result<(), String> {
+        for setting in settings.split(',') {
+            let (name, value) = setting.split_once('=').ok_or_else(|| format!("expected a setting and =, got {}", setting))?;
+            self.set(name, value)?;
+        }
+        self.check()
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        for (name, value) in [("size", self.size), ("line", self.line), ("ways", self.ways)] {
+            if !value.is_power_of_two() {
+                return Err(format!("{}: expected a power of two, got {}", name, value));
+            }
+        }
+        if self.line * self.ways > self.size {
+            return Err(format!("{} ways of {} word lines don't fit in {} words", self.ways, self.line, self.size));
+        }
+        Ok(())
+    }
+
+    fn sets(&self) -> usize {
+        self.size / (self.line * self.ways)
+    }
+}
+
+impl Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let replacement = match self.replacement {
+            Replacement::Lru => "LRU".to_string(),
+            Replacement::Fifo => "FIFO".to_string(),
+            Replacement::Random => format!("random from seed {}", self.seed),
+        };
+        let write = if self.write_back { "write-back" } else { "write-through" };
+        write!(f, "{} words, {} word lines, {}-way, {}, {}", self.size, self.line, self.ways, replacement, write)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Counts {
+    pub accesses: u64,
+    pub misses: u64,
+}
+
+impl Counts {
+    fn count(&mut self, hit: bool) {
+        self.accesses += 1;
+        self.misses += !hit as u64;
+    }
+
+    pub fn hit_rate(&self) -> f64 {
+        100.0 * (self.accesses - self.misses) as f64 / self.accesses.max(1) as f64
+    }
+}
+
+impl Display for Counts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>10} {:>10} {:>9.1}%", self.accesses, self.misses, self.hit_rate())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Line {
+    valid: bool,
+    dirty: bool,
+    tag: usize,
+    used: u64,
+    filled: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cache {
+    sets: Vec<Vec<Line>>,
+    // fetches, reads and writes
+    pub counts: [Counts; 3],
+    // lines read from memory and written back to it, and words written
+    // through
+    pub fills: u64,
+    pub write_backs: u64,
+    pub written_through: u64,
+    clock: u64,
+    rng: u64,
+}
+
+impl Cache {
+    fn new(config: &Config) -> Cache {
+        Cache {
+            sets: vec![vec![Line::default(); config.ways]; config.sets()],
+            counts: [Counts::default(); 3],
+            fills: 0,
+            write_backs: 0,
+            written_through: 0,
+            clock: 0,
+            // xorshift needs a seed that isn't 0
+            rng: config.seed.max(1),
+        }
+    }
+
+    // Whether `addr` was in the cache, bringing it in if the policy says to.
+    fn access(&mut self, config: &Config, kind: Kind, addr: u16) -> bool {
+        self.clock += 1;
+        let block = addr as usize / config.line;
+        let (set, tag) = (block % self.sets.len(), block / self.sets.len());
+        let write = kind == Kind::Write;
+        if write && !config.write_back {
+            self.written_through += 1;
+        }
+        let lines = &mut self.sets[set];
+        if let Some(line) = lines.iter_mut().find(|l| l.valid && l.tag == tag) {
+            line.used = self.clock;
+            line.dirty |= write && config.write_back;
+            self.counts[kind as usize].count(true);
+            return true;
+        }
+        self.counts[kind as usize].count(false);
+        if write && !config.write_back {
+            return false;
+        }
+        let way = match lines.iter().position(|l| !l.valid) {
+            Some(way) => way,
+            None => match config.replacement {
+                Replacement::Lru => (0..lines.len()).min_by_key(|&w| lines[w].used).unwrap(),
+                Replacement::Fifo => (0..lines.len()).min_by_key(|&w| lines[w].filled).unwrap(),
+                Replacement::Random => {
+                    self.rng ^= self.rng << 13;
+                    self.rng ^= self.rng >> 7;
+                    self.rng ^= self.rng << 17;
+                    (self.rng % lines.len() as u64) as usize
+                }
+            },
+        };
+        self.write_backs += lines[way].dirty as u64;
+        self.fills += 1;
+        lines[way] = Line {
+            valid: true,
+            dirty: write,
+            tag,
+            used: self.clock,
+            filled: self.clock,
+        };
+        false
+    }
+
+    pub fn total(&self) -> Counts {
+        let (accesses, misses) = self.counts.iter().fold((0, 0), |(a, m), c| (a + c.accesses, m + c.misses));
+        Counts { accesses, misses }
+    }
+}
+
+// The regions of the memory map, as the book lays it out.
+pub const REGIONS: [(&str, u16, u16); 4] = [
+    ("trap vector table", 0x0000, 0x00FF),
+    ("interrupt vector table", 0x0100, 0x01FF),
+    ("operating system", 0x0200, 0x2FFF),
+    ("user program", 0x3000, 0xFDFF),
+];
+
+pub struct Caches {
+    pub config: Config,
+    // one unified cache, or the instruction cache then the data cache
+    pub caches: Vec<Cache>,
+    pub regions: [Counts; 4],
+    // by the address of the instruction making the accesses, its fetch
+    // included; those of an interrupt entry, which come before any fetch,
+    // go under None
+    pub instructions: BTreeMap<Option<u16>, Counts>,
+}
+
+impl Caches {
+    pub fn new(config: Config) -> Result<Caches, String> {
+        config.check()?;
+        let caches = vec![Cache::new(&config); if config.split { 2 } else { 1 }];
+        Ok(Caches {
+            config,
+            caches,
+            regions: [Counts::default(); 4],
+            instructions: BTreeMap::new(),
+        })
+    }
+
+    // Takes a step with `run`, passing what it accessed through the caches.
+    pub fn step(&mut self, lc3: &mut LC3, run: impl FnOnce(&mut LC3) -> Result<(), Fault>) -> Result<(), Fault> {
+        let mut accesses = lc3.accesses.take().unwrap_or_default();
+        accesses.clear();
+        lc3.accesses = Some(accesses);
+        let result = run(lc3);
+        let accesses = lc3.accesses.as_ref().unwrap();
+        let mut instruction = None;
+        for &Access { kind, addr } in accesses {
+            if kind == Kind::Fetch {
+                instruction = Some(addr);
+            }
+            self.access(instruction, kind, addr);
+        }
+        result
+    }
+
+    pub fn run(&mut self, lc3: &mut LC3) -> Result<(), Fault> {
+        while !lc3.halted {
+            self.step(lc3, LC3::run_step)?;
+        }
+        Ok(())
+    }
+
+    pub fn access(&mut self, instruction: Option<u16>, kind: Kind, addr: u16) {
+        let region = match REGIONS.iter().position(|&(_, start, end)| (start..=end).contains(&addr)) {
+            Some(region) => region,
+            None => return,
+        };
+        let cache = match kind {
+            Kind::Fetch => self.caches.first_mut(),
+            _ => self.caches.last_mut(),
+        };
+        let hit = cache.unwrap().access(&self.config, kind, addr);
+        self.regions[region].count(hit);
+        self.instructions.entry(instruction).or_default().count(hit);
+    }
+
+    // Each cache's hit rates and traffic to memory, then the regions, then
+    // the instructions that missed most.
+    pub fn report(&self, instructions: usize) -> String {
+        let mut out = String::new();
+        let names: &[&str] = if self.config.split { &["instruction cache", "data cache"] } else { &["cache"] };
+        for (name, cache) in names.iter().zip(&self.caches) {
+            out += &format!("{}: {}\n", name, self.config);
+            out += &format!("{:<24} {:>10} {:>10} {:>10}\n", "", "accesses", "misses", "hit rate");
+            for (kind, counts) in ["fetches", "reads", "writes"].iter().zip(&cache.counts) {
+                if counts.accesses > 0 {
+                    out += &format!("{:<24} {}\n", kind, counts);
+                }
+            }
+            out += &format!("{:<24} {}\n", "total", cache.total());
+            out += &format!(
+                "memory traffic: {} line fills, {} write-backs, {} words written through\n\n",
+                cache.fills, cache.write_backs, cache.written_through
+            );
+        }
+        out += &format!("{:<24} {:>10} {:>10} {:>10}\n", "region", "accesses", "misses", "hit rate");
+        for (&(name, _, _), counts) in REGIONS.iter().zip(&self.regions) {
+            if counts.accesses > 0 {
+                out += &format!("{:<24} {}\n", name, counts);
+            }
+        }
+        out += &format!("\n{:<24} {:>10} {:>10} {:>10}\n", "instruction", "accesses", "misses", "hit rate");
+        let mut worst: Vec<(&Option<u16>, &Counts)> = self.instructions.iter().collect();
+        worst.sort_by_key(|(addr, c)| (std::cmp::Reverse(c.misses), std::cmp::Reverse(c.accesses), **addr));
+        for (addr, counts) in worst.into_iter().take(instructions) {
+            let name = addr.map_or("interrupt entry".to_string(), |a| format!("x{:04X}", a));
+            out += &format!("{:<24} {}\n", name, counts);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::console::Console;
+
+    // Sums 64 words, stepping through them by `stride`.
+    fn sum(stride: i16) -> (LC3, u16) {
+        let src = format!(
+            r#"
+            .ORIG x3000
+            LD R1, ARRAY
+            AND R2, R2, #0
+            LD R3, COUNT
+LOOP        LDR R4, R1, #0
+            ADD R2, R2, R4
+            ADD R1, R1, #{}
+            ADD R3, R3, #-1
+            BRp LOOP
+            HALT
+ARRAY       .FILL x4000
+COUNT       .FILL #64
+            .END
+            "#,
+            stride
+        );
+        let program = assemble(&src).unwrap();
+        let mut lc3 = LC3 {
+            console: Console::buffer(&[]),
+            ..LC3::default()
+        };
+        program.load_into(&mut lc3);
+        for i in 0..0x200 {
+            lc3.memory[0x4000 + i] = 1;
+        }
+        (lc3, program.symbols["LOOP"].addr)
+    }
+
+    fn cached(stride: i16) -> (LC3, Caches) {
+        let mut config = Config::default();
+        config.parse("caches=split,size=64,line=8").unwrap();
+        let mut caches = Caches::new(config).unwrap();
+        let (mut lc3, _) = sum(stride);
+        caches.run(&mut lc3).unwrap();
+        (lc3, caches)
+    }
+
+    #[test]
+    fn test_locality() {
+        let (lc3, sequential) = cached(1);
+        let (_, strided) = cached(8);
+        let (mut plain, load) = sum(1);
+        let reads = |c: &Caches| c.caches[1].counts[Kind::Read as usize];
+        // ARRAY and COUNT share a line, then there's a miss a line going
+        // through in order and one every time going a line at a time
+        assert_eq!(reads(&sequential), Counts { accesses: 66, misses: 1 + 8 });
+        assert_eq!(reads(&strided).misses, 1 + 64);
+        assert_eq!(sequential.caches[0].counts, strided.caches[0].counts);
+        assert_eq!(sequential.instructions[&Some(load)], Counts { accesses: 128, misses: 8 });
+        let total = sequential.caches[0].total().misses + sequential.caches[1].total().misses;
+        assert_eq!(sequential.regions[3].misses, total);
+
+        // the caches only watch
+        plain.run().unwrap();
+        assert_eq!((lc3.registers, lc3.executed, lc3.memory), (plain.registers, plain.executed, plain.memory));
+
+        let report = sequential.report(1);
+        assert!(report.starts_with("instruction cache: 64 words, 8 word lines, 2-way, LRU, write-back\n"));
+        assert!(report.contains(&format!("x{:04X}                           128          8      93.8%", load)));
+    }
+
+    fn pattern(config: &str, accesses: &[(Kind, u16)]) -> Cache {
+        let mut settings = Config::default();
+        settings.parse(config).unwrap();
+        let mut caches = Caches::new(settings).unwrap();
+        for &(kind, addr) in accesses {
+            caches.access(Some(0x3000), kind, addr);
+        }
+        caches.caches.remove(0)
+    }
+
+    #[test]
+    fn test_replacement() {
+        // A B A C A in a single set of two lines
+        let reads = [0x3000, 0x3001, 0x3000, 0x3002, 0x3000].map(|a| (Kind::Read, a));
+        let hits = |c: &Cache| c.total().accesses - c.total().misses;
+        assert_eq!(hits(&pattern("size=2,line=1,ways=2,replacement=lru", &reads)), 2);
+        assert_eq!(hits(&pattern("size=2,line=1,ways=2,replacement=fifo", &reads)), 1);
+        let random = |seed: u64| pattern(&format!("size=2,line=1,ways=2,replacement=random,seed={}", seed), &reads).counts;
+        assert_eq!(random(7), random(7));
+
+        // a dirty line written back when it's evicted
+        let writes = [(Kind::Write, 0x3000), (Kind::Read, 0x3000), (Kind::Read, 0x3001)];
+        let back = pattern("size=1,line=1,ways=1", &writes);
+        assert_eq!((back.total().misses, back.fills, back.write_backs, back.written_through), (2, 2, 1, 0));
+        let through = pattern("size=1,line=1,ways=1,write=through", &writes);
+        assert_eq!((through.total().misses, through.fills, through.write_backs, through.written_through), (3, 2, 0, 1));
+    }
+
+    #[test]
+    fn test_settings() {
+        let error = |settings| Config::default().parse(settings).unwrap_err();
+        assert_eq!(error("size=100"), "size: expected a power of two, got 100");
+        assert_eq!(error("size=16,line=8,ways=4"), "4 ways of 8 word lines don't fit in 16 words");
+        assert_eq!(error("replacement=mru"), "replacement: expected lru, fifo or random");
+        assert_eq!(error("ways=two"), "ways: expected a number");
+        assert_eq!(error("banks=2"), "no cache setting called banks");
+        // device registers go around the cache
+        let cache = pattern("size=2,line=1,ways=2", &[(Kind::Read, 0xFE00), (Kind::Write, 0xFE06)]);
+        assert_eq!(cache.total().accesses, 0);
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::thread;
+
+// Where the console traps read keystrokes from and write characters to.
+#[derive(Default)]
+pub enum Console {
+    #[default]
+    Stdio,
+    Buffer {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    },
+}
+
+impl Console {
+    pub fn buffer(input: &[u8]) -> Self {
+        Console::Buffer {
+            input: input.iter().copied().collect(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn get_char(&mut self) -> Option<u8> {
+        match self {
+            Console::Stdio => Keys::stdin().get(),
+            Console::Buffer { input, .. } => input.pop_front(),
+        }
+    }
+
+    // Whether a read would find a key waiting, or the end of the input.
+    pub fn has_input(&self) -> bool {
+        match self {
+            Console::Stdio => Keys::stdin().ready(),
+            Console::Buffer { input, .. } => !input.is_empty(),
+        }
+    }
+
+    // Reads a whole line from a terminal but only keeps its first character.
+    pub fn get_line_char(&mut self) -> Option<u8> {
+        match self {
+            Console::Stdio => Keys::stdin().get_line(),
+            Console::Buffer { .. } => self.get_char(),
+        }
+    }
+
+    pub fn put(&mut self, bytes: &[u8]) {
+        match self {
+            Console::Stdio => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(bytes).unwrap();
+                stdout.flush().unwrap();
+            }
+            Console::Buffer { output, .. } => output.extend_from_slice(bytes),
+        }
+    }
+
+    pub fn output(&self) -> &[u8] {
+        match self {
+            Console::Stdio => &[],
+            Console::Buffer { output, .. } => output,
+        }
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        match self {
+            Console::Stdio => Vec::new(),
+            Console::Buffer { output, .. } => std::mem::take(output),
+        }
+    }
+}
+
+// Keys typed at the terminal, taken from stdin by a thread of its own so
+// the keyboard can say whether one is waiting without waiting for it.
+#[derive(Default)]
+struct Keys {
+    // what has arrived, and whether stdin has closed
+    state: Mutex<(VecDeque<u8>, bool)>,
+    arrived: Condvar,
+}
+
+impl Keys {
+    fn stdin() -> &'static Keys {
+        static STDIN: OnceLock<Keys> = OnceLock::new();
+        STDIN.get_or_init(|| {
+            thread::spawn(|| {
+                let mut buf = [0; 256];
+                loop {
+                    match std::io::stdin().lock().read(&mut buf) {
+                        Ok(0) | Err(_) => return Keys::stdin().close(),
+                        Ok(n) => Keys::stdin().push(&buf[..n]),
+                    }
+                }
+            });
+            Keys::default()
+        })
+    }
+
+    fn push(&self, bytes: &[u8]) {
+        self.state.lock().unwrap().0.extend(bytes);
+        self.arrived.notify_all();
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().1 = true;
+        self.arrived.notify_all();
+    }
+
+    fn ready(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        !state.0.is_empty() || state.1
+    }
+
+    // Waits for a key, or None once there won't be any more.
+    fn get(&self) -> Option<u8> {
+        let mut state = self.state.lock().unwrap();
+        while state.0.is_empty() && !state.1 {
+            state = self.arrived.wait(state).unwrap();
+        }
+        state.0.pop_front()
+    }
+
+    fn get_line(&self) -> Option<u8> {
+        let first = self.get()?;
+        let mut c = first;
+        while c != b'\n' {
+            match self.get() {
+                Some(next) => c = next,
+                None => break,
+            }
+        }
+        Some(first)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::{Console, Keys};
+
+    #[test]
+    fn test_buffer_console() {
+        let mut console = Console::buffer(b"ab");
+        assert_eq!(console.get_char(), Some(b'a'));
+        assert_eq!(console.get_line_char(), Some(b'b'));
+        assert_eq!(console.get_char(), None);
+        console.put(b"hi");
+        assert_eq!(console.output(), b"hi");
+        assert_eq!(console.take_output(), b"hi".to_vec());
+        assert_eq!(console.output(), b"");
+    }
+
+    #[test]
+    fn test_keys_from_a_reader() {
+        let keys = Arc::new(Keys::default());
+        assert!(!keys.ready());
+        let reader = {
+            let keys = Arc::clone(&keys);
+            thread::spawn(move || keys.get_line())
+        };
+        keys.push(b"yes\nn");
+        assert_eq!(reader.join().unwrap(), Some(b'y'));
+        assert!(keys.ready());
+        assert_eq!(keys.get(), Some(b'n'));
+        assert!(!keys.ready());
+        // the end of the input is ready too, so reads fail rather than wait
+        keys.close();
+        assert!(keys.ready());
+        assert_eq!(keys.get(), None);
+    }
+}
@@ -0,0 +1,781 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use crate::asm::{self, Program};
+use crate::console::Console;
+use crate::json::{self, Json};
+use crate::opcodes::Inst;
+use crate::LC3;
+
+// How many instructions run between checks for incoming requests.
+const SLICE: usize = 10_000;
+
+const REGISTERS_REF: u64 = 1;
+const MEMORY_REF: u64 = 2;
+
+// Words shown in the Memory scope, starting a few words before the PC.
+const MEMORY_WINDOW: u16 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RunMode {
+    Continue,
+    Step,
+    StepOver(u16),
+    // how many calls deeper than the routine being left
+    StepOut(u32),
+}
+
+pub struct DapServer<W: Write> {
+    out: W,
+    seq: u64,
+    lc3: LC3,
+    program: Option<Program>,
+    source: Option<String>,
+    breakpoint_lines: Vec<usize>,
+    breakpoints: BTreeSet<u16>,
+    stop_on_entry: bool,
+    launched: bool,
+    configured: bool,
+    run: Option<RunMode>,
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new(out: W) -> Self {
+        DapServer {
+            out,
+            seq: 0,
+            lc3: LC3 {
+                console: Console::buffer(&[]),
+                ..LC3::default()
+            },
+            program: None,
+            source: None,
+            breakpoint_lines: Vec::new(),
+            breakpoints: BTreeSet::new(),
+            stop_on_entry: false,
+            launched: false,
+            configured: false,
+            run: None,
+        }
+    }
+
+    fn send(&mut self, mut fields: Vec<(&str, Json)>) -> io::Result<()> {
+        self.seq += 1;
+        fields.insert(0, ("seq", Json::from(self.seq)));
+        json::write_message(&mut self.out, &Json::object(fields))
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) -> io::Result<()> {
+        let mut fields = vec![
+            ("type", Json::from("response")),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("success", Json::from(result.is_ok())),
+            ("command", request.get("command").cloned().unwrap_or(Json::Null)),
+        ];
+        match result {
+            Ok(body) => fields.push(("body", body)),
+            Err(message) => fields.push(("message", Json::from(message))),
+        }
+        self.send(fields)
+    }
+
+    fn event(&mut self, name: &str, body: Json) -> io::Result<()> {
+        self.send(vec![
+            ("type", Json::from("event")),
+            ("event", Json::from(name)),
+            ("body", body),
+        ])
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> io::Result<()> {
+        self.run = None;
+        let mut body = vec![
+            ("reason", Json::from(reason)),
+            ("threadId", Json::from(1u8)),
+            ("allThreadsStopped", Json::from(true)),
+        ];
+        if let Some(text) = text {
+            body.push(("text", Json::from(text)));
+        }
+        self.event("stopped", Json::object(body))
+    }
+
+    fn flush_output(&mut self) -> io::Result<()> {
+        let output = self.lc3.console.take_output();
+        if output.is_empty() {
+            return Ok(());
+        }
+        let body = Json::object(vec![
+            ("category", Json::from("stdout")),
+            ("output", Json::from(String::from_utf8_lossy(&output).into_owned())),
+        ]);
+        self.event("output", body)
+    }
+
+    // Maps the requested source lines onto addresses, moving each one down
+    // to the next line that produced code.
+    fn resolve_breakpoints(&mut self) -> Json {
+        self.breakpoints.clear();
+        let mut resolved = Vec::new();
+        for line in &self.breakpoint_lines {
+            let hit = self.program.as_ref().and_then(|p| p.addr_of_line(*line));
+            let mut fields = vec![("verified", Json::from(hit.is_some()))];
+            match hit {
+                Some((addr, actual)) => {
+                    self.breakpoints.insert(addr);
+                    fields.push(("line", Json::from(actual)));
+                }
+                None => fields.push(("line", Json::from(*line))),
+            }
+            resolved.push(Json::object(fields));
+        }
+        Json::object(vec![("breakpoints", Json::Array(resolved))])
+    }
+
+    fn launch(&mut self, args: &Json) -> Result<Json, String> {
+        let path = args
+            .get("program")
+            .and_then(Json::as_str)
+            .ok_or("launch needs a 'program' path")?
+            .to_string();
+        let input = args.get("input").and_then(Json::as_str).unwrap_or("");
+        self.lc3.console = Console::buffer(input.as_bytes());
+        self.stop_on_entry = args.get("stopOnEntry").and_then(Json::as_bool).unwrap_or(false);
+
+        if path.ends_with(".asm") {
+            let src = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+            let program = asm::assemble_at(&src, Path::new(&path)).map_err(|errors| {
+                let lines: Vec<String> = errors.iter().map(|e| e.in_file(&path)).collect();
+                lines.join("\n")
+            })?;
+            program.load_into(&mut self.lc3);
+            self.program = Some(program);
+            self.source = Some(path);
+        } else {
+            self.lc3.load_obj_file(&path).map_err(|e| format!("{}: {}", path, e))?;
+            // label addresses from a symbol table, named explicitly or found
+            // next to the object file
+            let sym = match args.get("symbols").and_then(Json::as_str) {
+                Some(sym) => Some(sym.to_string()),
+                None => Some(Path::new(&path).with_extension("sym"))
+                    .filter(|p| p.exists())
+                    .map(|p| p.to_string_lossy().into_owned()),
+            };
+            if let Some(sym) = sym {
+                let text = std::fs::read_to_string(&sym).map_err(|e| format!("{}: {}", sym, e))?;
+                let symbols = Program::from_sym(&text).map_err(|e| format!("{}:{}", sym, e))?;
+                self.program = Some(symbols);
+            }
+        }
+        self.launched = true;
+        Ok(Json::Null)
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        if !(self.launched && self.configured) {
+            return Ok(());
+        }
+        if self.stop_on_entry {
+            self.stopped("entry", None)
+        } else if self.breakpoints.contains(&self.lc3.pc) {
+            // run_slice only looks for breakpoints after a step
+            self.stopped("breakpoint", None)
+        } else {
+            self.run = Some(RunMode::Continue);
+            Ok(())
+        }
+    }
+
+    fn stack_trace(&self) -> Json {
+        let pc = self.lc3.pc;
+        let name = match self.program.as_ref().and_then(|p| p.label_before(pc)) {
+            Some((label, 0)) => label.to_string(),
+            Some((label, off)) => format!("{}+{}", label, off),
+            None => format!("x{:04X}", pc),
+        };
+        let line = self.program.as_ref().and_then(|p| p.line_of(pc));
+        let mut frame = vec![
+            ("id", Json::from(0u8)),
+            ("name", Json::from(name)),
+            ("line", Json::from(line.unwrap_or(0))),
+            ("column", Json::from(if line.is_some() { 1u8 } else { 0 })),
+            ("instructionPointerReference", Json::from(format!("x{:04X}", pc))),
+        ];
+        if let (Some(path), Some(_)) = (&self.source, line) {
+            let name = Path::new(path).file_name().map_or(path.clone(), |n| n.to_string_lossy().into_owned());
+            frame.push((
+                "source",
+                Json::object(vec![("name", Json::from(name)), ("path", Json::from(path.as_str()))]),
+            ));
+        }
+        Json::object(vec![
+            ("stackFrames", Json::Array(vec![Json::object(frame)])),
+            ("totalFrames", Json::from(1u8)),
+        ])
+    }
+
+    fn variables(&self, reference: u64) -> Json {
+        let var = |name: String, value: String| {
+            Json::object(vec![
+                ("name", Json::from(name)),
+                ("value", Json::from(value)),
+                ("variablesReference", Json::from(0u8)),
+            ])
+        };
+        let mut vars = Vec::new();
+        if reference == REGISTERS_REF {
+            for (i, r) in self.lc3.registers.iter().enumerate() {
+                vars.push(var(format!("R{}", i), format!("x{:04X} ({})", *r as u16, r)));
+            }
+            let cond = &self.lc3.condition;
+            let cc: String = [(cond.n, 'n'), (cond.z, 'z'), (cond.p, 'p')]
+                .iter()
+                .filter(|(set, _)| *set)
+                .map(|(_, c)| *c)
+                .collect();
+            vars.push(var("PC".to_string(), format!("x{:04X}", self.lc3.pc)));
+            vars.push(var("PSR".to_string(), format!("x{:04X}", self.lc3.psr())));
+            vars.push(var("CC".to_string(), cc));
+        } else if reference == MEMORY_REF {
+            let start = self.lc3.pc.wrapping_sub(MEMORY_WINDOW / 4);
+            for i in 0..MEMORY_WINDOW {
+                let addr = start.wrapping_add(i);
+                let word = self.lc3.memory[addr as usize];
+                let label = self
+                    .program
+                    .as_ref()
+                    .and_then(|p| p.label_before(addr))
+                    .filter(|(_, off)| *off == 0)
+                    .map_or(String::new(), |(l, _)| format!(" {}", l));
+                let marker = if addr == self.lc3.pc { " <- PC" } else { "" };
+                vars.push(var(
+                    format!("x{:04X}{}", addr, label),
+                    format!("x{:04X}  {}{}", word, asm::disassemble(word), marker),
+                ));
+            }
+        }
+        Json::object(vec![("variables", Json::Array(vars))])
+    }
+
+    // Answers one request. Returns false once the client has gone.
+    fn handle(&mut self, request: &Json) -> io::Result<bool> {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("").to_string();
+        let null = Json::Null;
+        let args = request.get("arguments").unwrap_or(&null);
+        match command.as_str() {
+            "initialize" => {
+                let caps = Json::object(vec![
+                    ("supportsConfigurationDoneRequest", Json::from(true)),
+                    ("supportsTerminateRequest", Json::from(true)),
+                ]);
+                self.respond(request, Ok(caps))?;
+                self.event("initialized", Json::Null)?;
+            }
+            "launch" => {
+                let result = self.launch(args);
+                let launched = result.is_ok();
+                self.respond(request, result)?;
+                if launched {
+                    // breakpoints set before launch can only be placed now
+                    self.resolve_breakpoints();
+                    self.start()?;
+                }
+            }
+            "setBreakpoints" => {
+                self.breakpoint_lines = args
+                    .get("breakpoints")
+                    .and_then(Json::as_array)
+                    .unwrap_or(&[])
+                    .iter()
+                    .filter_map(|b| b.get("line").and_then(Json::as_u64))
+                    .map(|l| l as usize)
+                    .collect();
+                let body = self.resolve_breakpoints();
+                self.respond(request, Ok(body))?;
+            }
+            "setExceptionBreakpoints" => {
+                self.respond(request, Ok(Json::object(vec![("breakpoints", Json::Array(vec![]))])))?;
+            }
+            "configurationDone" => {
+                self.configured = true;
+                self.respond(request, Ok(Json::Null))?;
+                self.start()?;
+            }
+            "threads" => {
+                let thread = Json::object(vec![("id", Json::from(1u8)), ("name", Json::from("LC-3"))]);
+                self.respond(request, Ok(Json::object(vec![("threads", Json::Array(vec![thread]))])))?;
+            }
+            "stackTrace" => {
+                let body = self.stack_trace();
+                self.respond(request, Ok(body))?;
+            }
+            "scopes" => {
+                let scope = |name: &str, reference: u64| {
+                    Json::object(vec![
+                        ("name", Json::from(name)),
+                        ("variablesReference", Json::from(reference)),
+                        ("expensive", Json::from(false)),
+                    ])
+                };
+                let scopes = vec![scope("Registers", REGISTERS_REF), scope("Memory", MEMORY_REF)];
+                self.respond(request, Ok(Json::object(vec![("scopes", Json::Array(scopes))])))?;
+            }
+            "variables" => {
+                let reference = args.get("variablesReference").and_then(Json::as_u64).unwrap_or(0);
+                let body = self.variables(reference);
+                self.respond(request, Ok(body))?;
+            }
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                let mode = match command.as_str() {
+                    "continue" => RunMode::Continue,
+                    "stepIn" => RunMode::Step,
+                    "stepOut" => RunMode::StepOut(0),
+                    _ => match self.lc3.memory[self.lc3.pc as usize] {
+                        raw if raw >> 12 == 0b1101 => RunMode::Step,
+                        raw => match Inst::from(raw) {
+                            Inst::JSR { .. } | Inst::JSRr { .. } => RunMode::StepOver(self.lc3.pc.wrapping_add(1)),
+                            _ => RunMode::Step,
+                        },
+                    },
+                };
+                let body = match mode {
+                    RunMode::Continue => Json::object(vec![("allThreadsContinued", Json::from(true))]),
+                    _ => Json::Null,
+                };
+                self.respond(request, Ok(body))?;
+                self.run = Some(mode);
+            }
+            "pause" => {
+                self.respond(request, Ok(Json::Null))?;
+                if self.run.is_some() {
+                    self.stopped("pause", None)?;
+                }
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(Json::Null))?;
+                if command == "terminate" {
+                    self.event("terminated", Json::Null)?;
+                }
+                return Ok(false);
+            }
+            _ => self.respond(request, Err(format!("unsupported request '{}'", command)))?,
+        }
+        Ok(true)
+    }
+
+    fn run_slice(&mut self) -> io::Result<()> {
+        let mut mode = match self.run {
+            Some(mode) => mode,
+            None => return Ok(()),
+        };
+        for _ in 0..SLICE {
+            // a step that takes an interrupt runs the handler's first instruction
+            let pc = match self.lc3.peek_interrupt() {
+                Some((entry, _)) => self.lc3.memory[entry as usize],
+                None => self.lc3.pc,
+            };
+            let word = self.lc3.memory[pc as usize];
+            let stepped = self.lc3.run_step();
+            self.flush_output()?;
+            if let Err(fault) = stepped {
+                return self.stopped("exception", Some(fault.to_string()));
+            }
+            if self.lc3.halted {
+                self.run = None;
+                self.event("exited", Json::object(vec![("exitCode", Json::from(0u8))]))?;
+                return self.event("terminated", Json::Null);
+            }
+            let done = match mode {
+                RunMode::Continue => false,
+                RunMode::Step => true,
+                RunMode::StepOver(ret) => self.lc3.pc == ret,
+                // JSR and JSRR go a call deeper and RET, which is JMP R7,
+                // comes back out; traps and interrupts return with RTI
+                RunMode::StepOut(depth) => match word {
+                    0xC1C0 if depth == 0 => true,
+                    0xC1C0 => {
+                        mode = RunMode::StepOut(depth - 1);
+                        false
+                    }
+                    _ if word >> 12 == 0b0100 => {
+                        mode = RunMode::StepOut(depth + 1);
+                        false
+                    }
+                    _ => false,
+                },
+            };
+            if done {
+                return self.stopped("step", None);
+            }
+            if self.breakpoints.contains(&self.lc3.pc) {
+                return self.stopped("breakpoint", None);
+            }
+        }
+        self.run = Some(mode);
+        Ok(())
+    }
+
+    pub fn run<R: Read + Send + 'static>(mut self, input: R) -> io::Result<()> {
+        let requests: Receiver<Json> = {
+            let (tx, rx) = mpsc::channel();
+            thread::spawn(move || {
+                let mut input = BufReader::new(input);
+                while let Ok(Some(msg)) = json::read_message(&mut input) {
+                    if tx.send(msg).is_err() {
+                        break;
+                    }
+                }
+            });
+            rx
+        };
+        loop {
+            let request = if self.run.is_some() {
+                match requests.try_recv() {
+                    Ok(request) => Some(request),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            } else {
+                match requests.recv() {
+                    Ok(request) => Some(request),
+                    Err(_) => return Ok(()),
+                }
+            };
+            if let Some(request) = request {
+                if !self.handle(&request)? {
+                    return Ok(());
+                }
+            }
+            self.run_slice()?;
+        }
+    }
+}
+
+pub fn serve_stdio() -> io::Result<()> {
+    DapServer::new(io::stdout()).run(io::stdin())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::thread;
+
+    use super::DapServer;
+    use crate::json::{self, Json};
+
+    const COUNTDOWN: &str = r#"        .ORIG x3000
+        LD R1, COUNT
+LOOP    LEA R0, MSG
+        PUTS
+        ADD R1, R1, #-1
+        BRp LOOP
+        HALT
+COUNT   .FILL #2
+MSG     .STRINGZ "hi\n"
+        .END
+"#;
+
+    // One end of an in-memory pipe, fed by whole writes from the other end.
+    struct PipeReader {
+        rx: Receiver<Vec<u8>>,
+        buf: Vec<u8>,
+    }
+
+    impl Read for PipeReader {
+        fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+            if self.buf.is_empty() {
+                match self.rx.recv() {
+                    Ok(chunk) => self.buf = chunk,
+                    Err(_) => return Ok(0),
+                }
+            }
+            let n = out.len().min(self.buf.len());
+            out[..n].copy_from_slice(&self.buf[..n]);
+            self.buf.drain(..n);
+            Ok(n)
+        }
+    }
+
+    struct PipeWriter(Sender<Vec<u8>>);
+
+    impl Write for PipeWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _ = self.0.send(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Client {
+        requests: Sender<Vec<u8>>,
+        messages: PipeReader,
+        seq: u64,
+    }
+
+    impl Client {
+        fn send(&mut self, command: &str, arguments: Json) -> u64 {
+            self.seq += 1;
+            let body = Json::object(vec![
+                ("seq", Json::from(self.seq)),
+                ("type", Json::from("request")),
+                ("command", Json::from(command)),
+                ("arguments", arguments),
+            ])
+            .to_string();
+            let frame = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+            self.requests.send(frame.into_bytes()).unwrap();
+            self.seq
+        }
+
+        fn next(&mut self) -> Json {
+            let mut input = io::BufReader::new(&mut self.messages);
+            json::read_message(&mut input).unwrap().unwrap()
+        }
+
+        // Sends a request and returns its response along with any events
+        // that arrived first.
+        fn request(&mut self, command: &str, arguments: Json) -> (Json, Vec<Json>) {
+            let seq = self.send(command, arguments);
+            let mut events = Vec::new();
+            loop {
+                let msg = self.next();
+                if msg.get("request_seq").and_then(Json::as_u64) == Some(seq) {
+                    return (msg, events);
+                }
+                events.push(msg);
+            }
+        }
+
+        fn event(&mut self) -> (String, Json) {
+            let msg = self.next();
+            let name = msg.get("event").and_then(Json::as_str).unwrap().to_string();
+            (name, msg.get("body").cloned().unwrap_or(Json::Null))
+        }
+    }
+
+    fn start() -> (Client, thread::JoinHandle<()>) {
+        let (req_tx, req_rx) = mpsc::channel();
+        let (msg_tx, msg_rx) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let input = PipeReader { rx: req_rx, buf: Vec::new() };
+            DapServer::new(PipeWriter(msg_tx)).run(input).unwrap();
+        });
+        let client = Client {
+            requests: req_tx,
+            messages: PipeReader { rx: msg_rx, buf: Vec::new() },
+            seq: 0,
+        };
+        (client, server)
+    }
+
+    fn source_file(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("lc3-dap-{}-{}.asm", std::process::id(), name));
+        std::fs::write(&path, COUNTDOWN).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn variable(vars: &Json, name: &str) -> String {
+        vars.get("variables")
+            .and_then(Json::as_array)
+            .unwrap()
+            .iter()
+            .find(|v| v.get("name").and_then(Json::as_str) == Some(name))
+            .and_then(|v| v.get("value").and_then(Json::as_str))
+            .unwrap()
+            .to_string()
+    }
+
+    fn body_str<'a>(msg: &'a Json, path: &[&str]) -> Option<&'a str> {
+        path.iter().try_fold(msg, |v, key| v.get(key)).and_then(Json::as_str)
+    }
+
+    #[test]
+    fn test_dap_breakpoint_step_and_run() {
+        let path = source_file("run");
+        let (mut client, server) = start();
+
+        let (init, _) = client.request("initialize", Json::object(vec![("adapterID", Json::from("lc3"))]));
+        assert_eq!(init.get("success"), Some(&Json::Bool(true)));
+        assert_eq!(client.event().0, "initialized");
+
+        let (launch, _) = client.request("launch", Json::object(vec![("program", Json::from(path.as_str()))]));
+        assert_eq!(launch.get("success"), Some(&Json::Bool(true)));
+
+        // line 4 is PUTS; line 1 has no code and moves down to line 2
+        let lines = Json::from(vec![
+            Json::object(vec![("line", Json::from(4u8))]),
+            Json::object(vec![("line", Json::from(1u8))]),
+        ]);
+        let args = Json::object(vec![
+            ("source", Json::object(vec![("path", Json::from(path.as_str()))])),
+            ("breakpoints", lines),
+        ]);
+        let (bps, _) = client.request("setBreakpoints", args);
+        assert_eq!(
+            bps.get("body").unwrap().to_string(),
+            r#"{"breakpoints":[{"verified":true,"line":4},{"verified":true,"line":2}]}"#
+        );
+
+        // the breakpoint on the first instruction stops before it runs
+        client.request("configurationDone", Json::Null);
+        let (name, body) = client.event();
+        assert_eq!((name.as_str(), body_str(&body, &["reason"])), ("stopped", Some("breakpoint")));
+        let (trace, _) = client.request("stackTrace", Json::object(vec![("threadId", Json::from(1u8))]));
+        let frame = &trace.get("body").unwrap().get("stackFrames").unwrap().as_array().unwrap()[0];
+        assert_eq!(frame.get("line").and_then(Json::as_u64), Some(2));
+        let (regs, _) = client.request("variables", Json::object(vec![("variablesReference", Json::from(1u8))]));
+        assert_eq!(variable(regs.get("body").unwrap(), "R1"), "x0000 (0)");
+
+        client.request("continue", Json::object(vec![("threadId", Json::from(1u8))]));
+        let (name, body) = client.event();
+        assert_eq!((name.as_str(), body_str(&body, &["reason"])), ("stopped", Some("breakpoint")));
+        let (trace, _) = client.request("stackTrace", Json::object(vec![("threadId", Json::from(1u8))]));
+        let frame = &trace.get("body").unwrap().get("stackFrames").unwrap().as_array().unwrap()[0];
+        assert_eq!(frame.get("line").and_then(Json::as_u64), Some(4));
+        assert_eq!(body_str(frame, &["name"]), Some("LOOP+1"));
+        assert_eq!(body_str(frame, &["source", "path"]), Some(path.as_str()));
+
+        let (scopes, _) = client.request("scopes", Json::object(vec![("frameId", Json::from(0u8))]));
+        assert!(scopes.get("body").unwrap().to_string().contains(r#""name":"Memory","variablesReference":2"#));
+        let (regs, _) = client.request("variables", Json::object(vec![("variablesReference", Json::from(1u8))]));
+        let regs = regs.get("body").unwrap();
+        assert_eq!(variable(regs, "R1"), "x0002 (2)");
+        assert_eq!(variable(regs, "PC"), "x3002");
+        let (mem, _) = client.request("variables", Json::object(vec![("variablesReference", Json::from(2u8))]));
+        assert_eq!(variable(mem.get("body").unwrap(), "x3002"), "xF022  TRAP #34 <- PC");
+        assert_eq!(variable(mem.get("body").unwrap(), "x3001 LOOP"), "xE005  LEA r0 #5");
+
+        client.request("next", Json::object(vec![("threadId", Json::from(1u8))]));
+        let (name, body) = client.event();
+        assert_eq!((name.as_str(), body_str(&body, &["output"])), ("output", Some("hi\n")));
+        let (name, body) = client.event();
+        assert_eq!((name.as_str(), body_str(&body, &["reason"])), ("stopped", Some("step")));
+
+        let args = Json::object(vec![
+            ("source", Json::object(vec![("path", Json::from(path.as_str()))])),
+            ("breakpoints", Json::Array(vec![])),
+        ]);
+        client.request("setBreakpoints", args);
+        client.request("continue", Json::object(vec![("threadId", Json::from(1u8))]));
+        let (name, body) = client.event();
+        assert_eq!((name.as_str(), body_str(&body, &["output"])), ("output", Some("hi\n")));
+        assert_eq!(client.event().0, "exited");
+        assert_eq!(client.event().0, "terminated");
+
+        let (disconnect, _) = client.request("disconnect", Json::Null);
+        assert_eq!(disconnect.get("success"), Some(&Json::Bool(true)));
+        server.join().unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_dap_pause_and_launch_errors() {
+        let path = std::env::temp_dir().join(format!("lc3-dap-{}-loop.asm", std::process::id()));
+        std::fs::write(&path, ".ORIG x3000\nLOOP BRnzp LOOP\n.END\n").unwrap();
+        let bad = std::env::temp_dir().join(format!("lc3-dap-{}-bad.asm", std::process::id()));
+        std::fs::write(&bad, ".ORIG x3000\nBRz NOWHERE\n.END\n").unwrap();
+        let (mut client, server) = start();
+        client.request("initialize", Json::Null);
+        client.event();
+
+        let (launch, _) = client.request("launch", Json::object(vec![("program", Json::from(bad.to_str().unwrap()))]));
+        assert_eq!(launch.get("success"), Some(&Json::Bool(false)));
+        assert!(body_str(&launch, &["message"]).unwrap().ends_with("line 2: undefined label 'NOWHERE'"));
+
+        client.request("launch", Json::object(vec![("program", Json::from(path.to_str().unwrap()))]));
+        client.request("configurationDone", Json::Null);
+        let (pause, events) = client.request("pause", Json::object(vec![("threadId", Json::from(1u8))]));
+        assert!(events.is_empty());
+        assert_eq!(pause.get("success"), Some(&Json::Bool(true)));
+        let (name, body) = client.event();
+        assert_eq!((name.as_str(), body_str(&body, &["reason"])), ("stopped", Some("pause")));
+
+        let (unknown, _) = client.request("evaluate", Json::Null);
+        assert_eq!(unknown.get("success"), Some(&Json::Bool(false)));
+        client.request("disconnect", Json::Null);
+        server.join().unwrap();
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(bad).unwrap();
+    }
+
+    #[test]
+    fn test_dap_step_out_of_nested_calls() {
+        let src = ".ORIG x3000\nJSR OUTER\nADD R2, R2, #1\n.FILL xD000\nHALT\n\
+                   OUTER ST R7, SAVE\nJSR INNER\nLD R7, SAVE\nRET\nINNER ADD R1, R1, #1\nRET\nSAVE .BLKW 1\n.END\n";
+        let path = std::env::temp_dir().join(format!("lc3-dap-{}-nested.asm", std::process::id()));
+        std::fs::write(&path, src).unwrap();
+        let path = path.to_string_lossy().into_owned();
+        let (mut client, server) = start();
+        client.request("initialize", Json::Null);
+        client.event();
+        client.request("launch", Json::object(vec![("program", Json::from(path.as_str()))]));
+        let args = Json::object(vec![
+            ("source", Json::object(vec![("path", Json::from(path.as_str()))])),
+            ("breakpoints", Json::from(vec![Json::object(vec![("line", Json::from(6u8))])])),
+        ]);
+        client.request("setBreakpoints", args);
+        client.request("configurationDone", Json::Null);
+        assert_eq!(body_str(&client.event().1, &["reason"]), Some("breakpoint"));
+
+        let line = |client: &mut Client| {
+            let (trace, _) = client.request("stackTrace", Json::object(vec![("threadId", Json::from(1u8))]));
+            let frames = trace.get("body").unwrap().get("stackFrames").unwrap().as_array().unwrap();
+            frames[0].get("line").and_then(Json::as_u64)
+        };
+        // past INNER's RET to OUTER's caller
+        client.request("stepOut", Json::object(vec![("threadId", Json::from(1u8))]));
+        assert_eq!(body_str(&client.event().1, &["reason"]), Some("step"));
+        assert_eq!(line(&mut client), Some(3));
+
+        client.request("next", Json::object(vec![("threadId", Json::from(1u8))]));
+        assert_eq!(body_str(&client.event().1, &["reason"]), Some("step"));
+        assert_eq!(line(&mut client), Some(4));
+        // the reserved opcode is stepped into like anything else
+        client.request("next", Json::object(vec![("threadId", Json::from(1u8))]));
+        let (name, body) = client.event();
+        assert_eq!((name.as_str(), body_str(&body, &["reason"])), ("stopped", Some("exception")));
+
+        client.request("disconnect", Json::Null);
+        server.join().unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_dap_obj_with_symbol_table() {
+        let program = crate::asm::assemble(COUNTDOWN).unwrap();
+        let obj = std::env::temp_dir().join(format!("lc3-dap-{}-sym.obj", std::process::id()));
+        std::fs::write(&obj, program.to_obj()).unwrap();
+        std::fs::write(obj.with_extension("sym"), program.sym()).unwrap();
+        let (mut client, server) = start();
+        client.request("initialize", Json::Null);
+        client.event();
+
+        let args = Json::object(vec![
+            ("program", Json::from(obj.to_str().unwrap())),
+            ("stopOnEntry", Json::from(true)),
+        ]);
+        client.request("launch", args);
+        client.request("configurationDone", Json::Null);
+        let (name, body) = client.event();
+        assert_eq!((name.as_str(), body_str(&body, &["reason"])), ("stopped", Some("entry")));
+
+        client.request("stepIn", Json::object(vec![("threadId", Json::from(1u8))]));
+        client.event();
+        let (trace, _) = client.request("stackTrace", Json::object(vec![("threadId", Json::from(1u8))]));
+        let frame = &trace.get("body").unwrap().get("stackFrames").unwrap().as_array().unwrap()[0];
+        assert_eq!(body_str(frame, &["name"]), Some("LOOP"));
+        assert!(frame.get("source").is_none());
+
+        client.request("disconnect", Json::Null);
+        server.join().unwrap();
+        std::fs::remove_file(obj.with_extension("sym")).unwrap();
+        std::fs::remove_file(obj).unwrap();
+    }
+}
@@ -0,0 +1,376 @@
+use std::any::Any;
+use std::ops::RangeInclusive;
+
+use crate::console::Console;
+use crate::timer::{self, Timer};
+use crate::{Fault, EXCEPTION_TABLE, LC3};
+
+pub const KBSR: u16 = 0xFE00;
+pub const KBDR: u16 = 0xFE02;
+pub const DSR: u16 = 0xFE04;
+pub const DDR: u16 = 0xFE06;
+pub const MCR: u16 = 0xFFFE;
+
+// A memory mapped peripheral. Loads and stores to its addresses come here
+// instead of going to memory, with the rest of the machine to work on; the
+// device is taken off the bus while it runs, so touching its own registers
+// from in here reaches plain memory.
+pub trait Device: Any + Send {
+    fn read(&mut self, lc3: &mut LC3, addr: u16) -> Result<u16, Fault>;
+
+    fn write(&mut self, lc3: &mut LC3, addr: u16, val: u16) -> Result<(), Fault>;
+
+    // Called after every instruction.
+    fn tick(&mut self, _lc3: &mut LC3) {}
+
+    // The vector (an offset into the table at x0100) and priority of the
+    // interrupt the device is asking for, if any.
+    fn irq(&self, _lc3: &LC3) -> Option<(u8, u8)> {
+        None
+    }
+
+    // What a read of `addr` would give, for tools to look at without the
+    // side effects a read can have, or None if that can't be known.
+    fn peek(&self, _lc3: &LC3, _addr: u16) -> Option<u16> {
+        None
+    }
+}
+
+struct Slot {
+    range: RangeInclusive<u16>,
+    device: Option<Box<dyn Device>>,
+}
+
+// The devices attached to the memory bus, by address range. Where ranges
+// overlap the device attached last wins, so a built in one can be replaced.
+#[derive(Default)]
+pub struct Bus {
+    slots: Vec<Slot>,
+}
+
+impl Bus {
+    // The keyboard, display, timer and machine control register.
+    pub fn builtin() -> Bus {
+        let mut bus = Bus::default();
+        bus.attach(KBSR..=KBDR, Keyboard::default());
+        bus.attach(DSR..=DDR, Display);
+        bus.attach(timer::TMCR..=timer::TMCNT, Timer::default());
+        bus.attach(MCR..=MCR, Mcr::default());
+        bus
+    }
+
+    pub fn attach<D: Device>(&mut self, range: RangeInclusive<u16>, device: D) {
+        self.slots.push(Slot {
+            range,
+            device: Some(Box::new(device)),
+        });
+    }
+
+    // Takes off the device answering at `addr`.
+    pub fn detach(&mut self, addr: u16) -> Option<Box<dyn Device>> {
+        let i = self.find(addr)?;
+        self.slots.remove(i).device
+    }
+
+    pub fn clear(&mut self) {
+        self.slots.clear();
+    }
+
+    // The first attached device of type `T`.
+    pub fn get<T: Device>(&self) -> Option<&T> {
+        self.slots
+            .iter()
+            .filter_map(|s| s.device.as_deref())
+            .find_map(|d| (d as &dyn Any).downcast_ref())
+    }
+
+    pub fn get_mut<T: Device>(&mut self) -> Option<&mut T> {
+        self.slots
+            .iter_mut()
+            .filter_map(|s| s.device.as_deref_mut())
+            .find_map(|d| (d as &mut dyn Any).downcast_mut())
+    }
+
+    pub fn ranges(&self) -> Vec<RangeInclusive<u16>> {
+        self.slots.iter().map(|s| s.range.clone()).collect()
+    }
+
+    fn find(&self, addr: u16) -> Option<usize> {
+        self.slots
+            .iter()
+            .rposition(|s| s.device.is_some() && s.range.contains(&addr))
+    }
+}
+
+impl LC3 {
+    // Runs `f` on the device in slot `i`, with the device off the bus.
+    fn with_device<R>(&mut self, i: usize, f: impl FnOnce(&mut dyn Device, &mut LC3) -> R) -> R {
+        let mut device = self.devices.slots[i].device.take().unwrap();
+        let result = f(device.as_mut(), self);
+        self.devices.slots[i].device = Some(device);
+        result
+    }
+
+    pub(crate) fn device_read(&mut self, addr: u16) -> Option<Result<u16, Fault>> {
+        let i = self.devices.find(addr)?;
+        self.cycles += self.cost.device_wait;
+        Some(self.journaled(Some(addr), |lc3| lc3.with_device(i, |d, lc3| d.read(lc3, addr))))
+    }
+
+    // Memory or a device register, as `read` would see it but leaving
+    // everything as it was.
+    pub fn peek(&self, addr: u16) -> Option<u16> {
+        match self.devices.find(addr) {
+            Some(i) => self.devices.slots[i].device.as_ref()?.peek(self, addr),
+            None => Some(self.memory[addr as usize]),
+        }
+    }
+
+    pub(crate) fn device_write(&mut self, addr: u16, val: u16) -> Option<Result<(), Fault>> {
+        let i = self.devices.find(addr)?;
+        self.cycles += self.cost.device_wait;
+        Some(self.with_device(i, |d, lc3| d.write(lc3, addr, val)))
+    }
+
+    pub(crate) fn tick_devices(&mut self) {
+        for i in 0..self.devices.slots.len() {
+            if self.devices.slots[i].device.is_some() {
+                self.with_device(i, |d, lc3| d.tick(lc3));
+            }
+        }
+    }
+
+    // The most urgent interrupt that outranks the running program and has a
+    // handler to go to, as the address of its table entry.
+    pub(crate) fn pending_interrupt(&self) -> Option<(u16, u8)> {
+        self.devices
+            .slots
+            .iter()
+            .filter_map(|s| s.device.as_ref()?.irq(self))
+            .map(|(vector, priority)| (EXCEPTION_TABLE + vector as u16, priority))
+            .filter(|&(entry, priority)| priority > self.priority && self.memory[entry as usize] != 0)
+            .max_by_key(|&(_, priority)| priority)
+    }
+}
+
+// KBSR bit 15 says a key is waiting and bit 14 enables the interrupt at
+// x0180, priority 4. KBDR hands over the key.
+#[derive(Debug, Default)]
+pub struct Keyboard {
+    pub interrupt_enable: bool,
+}
+
+impl Device for Keyboard {
+    fn read(&mut self, lc3: &mut LC3, addr: u16) -> Result<u16, Fault> {
+        match addr {
+            KBSR => {
+                if let Console::Buffer { input, .. } = &lc3.console {
+                    if input.is_empty() && !self.interrupt_enable {
+                        // nothing will ever arrive for a polling loop
+                        return Err(Fault::EndOfInput);
+                    }
+                }
+                Ok((lc3.console.has_input() as u16) << 15 | (self.interrupt_enable as u16) << 14)
+            }
+            KBDR => Ok(lc3.console.get_char().ok_or(Fault::EndOfInput)? as u16),
+            _ => Ok(0),
+        }
+    }
+
+    fn write(&mut self, _lc3: &mut LC3, addr: u16, val: u16) -> Result<(), Fault> {
+        if addr == KBSR {
+            self.interrupt_enable = val & 0x4000 != 0;
+        }
+        Ok(())
+    }
+
+    fn irq(&self, lc3: &LC3) -> Option<(u8, u8)> {
+        (self.interrupt_enable && lc3.console.has_input()).then_some((0x80, 4))
+    }
+
+    // A terminal's next key isn't known until it is read.
+    fn peek(&self, lc3: &LC3, addr: u16) -> Option<u16> {
+        match (addr, &lc3.console) {
+            (KBSR, console) => Some((console.has_input() as u16) << 15 | (self.interrupt_enable as u16) << 14),
+            (KBDR, Console::Buffer { input, .. }) => Some(input.front().copied().unwrap_or(0) as u16),
+            (KBDR, Console::Stdio) => None,
+            _ => Some(0),
+        }
+    }
+}
+
+// Always ready; characters stored to DDR go straight to the console.
+#[derive(Debug, Default)]
+pub struct Display;
+
+impl Device for Display {
+    fn read(&mut self, lc3: &mut LC3, addr: u16) -> Result<u16, Fault> {
+        Ok(self.peek(lc3, addr).unwrap())
+    }
+
+    fn write(&mut self, lc3: &mut LC3, addr: u16, val: u16) -> Result<(), Fault> {
+        if addr == DDR {
+            lc3.console.put(&[val as u8]);
+        }
+        Ok(())
+    }
+
+    fn peek(&self, _lc3: &LC3, addr: u16) -> Option<u16> {
+        Some(if addr == DSR { 0x8000 } else { 0 })
+    }
+}
+
+// Bit 15 is the clock; clearing it halts the machine.
+#[derive(Debug, Default)]
+pub struct Mcr {
+    pub value: u16,
+}
+
+impl Device for Mcr {
+    fn read(&mut self, lc3: &mut LC3, addr: u16) -> Result<u16, Fault> {
+        Ok(self.peek(lc3, addr).unwrap())
+    }
+
+    fn write(&mut self, lc3: &mut LC3, _addr: u16, val: u16) -> Result<(), Fault> {
+        self.value = val;
+        if val & 0x8000 == 0 {
+            lc3.halted = true;
+        }
+        Ok(())
+    }
+
+    fn peek(&self, lc3: &LC3, _addr: u16) -> Option<u16> {
+        Some((!lc3.halted as u16) << 15 | self.value & 0x7FFF)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    // Eight LEDs at xFE10 and eight switches at xFE12. Flipping a switch
+    // raises an interrupt at x0190 until the switch register is read.
+    #[derive(Default)]
+    struct Gpio {
+        leds: u8,
+        switches: u8,
+        changed: bool,
+        // instructions run with at least one LED lit
+        lit: u64,
+    }
+
+    impl Gpio {
+        fn flip(&mut self, switches: u8) {
+            self.switches = switches;
+            self.changed = true;
+        }
+    }
+
+    impl Device for Gpio {
+        fn read(&mut self, _lc3: &mut LC3, addr: u16) -> Result<u16, Fault> {
+            match addr {
+                0xFE10 => Ok(self.leds as u16),
+                _ => {
+                    self.changed = false;
+                    Ok(self.switches as u16)
+                }
+            }
+        }
+
+        fn write(&mut self, _lc3: &mut LC3, addr: u16, val: u16) -> Result<(), Fault> {
+            match addr {
+                0xFE10 => self.leds = val as u8,
+                _ => return Err(Fault::AccessViolation(addr)),
+            }
+            Ok(())
+        }
+
+        fn tick(&mut self, _lc3: &mut LC3) {
+            if self.leds != 0 {
+                self.lit += 1;
+            }
+        }
+
+        fn irq(&self, _lc3: &LC3) -> Option<(u8, u8)> {
+            self.changed.then_some((0x90, 2))
+        }
+    }
+
+    // Copies the switches to the LEDs whenever they change.
+    const MIRROR: &str = r#"
+        .ORIG x3000
+LOOP    ADD R1, R1, #0
+        BRz LOOP
+        HALT
+ISR     LDI R0, SWITCHES
+        STI R0, LEDS
+        ADD R1, R1, #1
+        RTI
+LEDS     .FILL xFE10
+SWITCHES .FILL xFE12
+        .END
+    "#;
+
+    #[test]
+    fn test_gpio_device() {
+        let program = assemble(MIRROR).unwrap();
+        let mut lc3 = LC3 {
+            console: Console::buffer(&[]),
+            ..LC3::default()
+        };
+        program.load_into(&mut lc3);
+        lc3.memory[0x0190] = program.symbols["ISR"].addr;
+        lc3.devices.attach(0xFE10..=0xFE12, Gpio::default());
+
+        assert_eq!(lc3.run_for(10), crate::Stop::StepLimit);
+        assert_eq!(lc3.devices.get::<Gpio>().unwrap().lit, 0);
+        lc3.devices.get_mut::<Gpio>().unwrap().flip(0b1010_0101);
+        lc3.run().unwrap();
+
+        let gpio = lc3.devices.get::<Gpio>().unwrap();
+        assert_eq!(gpio.leds, 0b1010_0101);
+        assert!(!gpio.changed);
+        // the rest of the handler from the STI on, one more trip round the
+        // loop and HALT
+        assert_eq!(gpio.lit, 6);
+        assert_eq!(lc3.registers[1], 1);
+        assert_eq!(lc3.read(0xFE10), Ok(0b1010_0101));
+        assert_eq!(lc3.write(0xFE12, 1), Err(Fault::AccessViolation(0xFE12)));
+    }
+
+    #[test]
+    fn test_builtin_devices_can_be_replaced() {
+        let mut lc3 = LC3 {
+            console: Console::buffer(&[]),
+            ..LC3::default()
+        };
+        assert_eq!(lc3.devices.ranges().len(), 4);
+        assert!(lc3.devices.get::<Timer>().is_some());
+        lc3.write(DDR, b'a' as u16).unwrap();
+        assert_eq!(lc3.read(MCR), Ok(0x8000));
+
+        // a display that shouts
+        struct Upper;
+        impl Device for Upper {
+            fn read(&mut self, _lc3: &mut LC3, _addr: u16) -> Result<u16, Fault> {
+                Ok(0x8000)
+            }
+            fn write(&mut self, lc3: &mut LC3, _addr: u16, val: u16) -> Result<(), Fault> {
+                lc3.console.put(&[(val as u8).to_ascii_uppercase()]);
+                Ok(())
+            }
+        }
+        lc3.devices.attach(DDR..=DDR, Upper);
+        lc3.write(DDR, b'b' as u16).unwrap();
+        assert!(lc3.devices.detach(DDR).is_some());
+        lc3.write(DDR, b'c' as u16).unwrap();
+        assert_eq!(lc3.console.output(), b"aBc");
+
+        // with nothing on the bus the registers are plain memory
+        lc3.devices.clear();
+        lc3.write(MCR, 0).unwrap();
+        assert!(!lc3.halted);
+        assert_eq!(lc3.read(MCR), Ok(0));
+    }
+}
@@ -0,0 +1,294 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::device::Device;
+use crate::{Fault, LC3};
+
+// A disk controller that moves whole blocks between an image file on the
+// host and memory by itself, while the program carries on.
+//
+//   DKBR xFE10  block number
+//   DKAR xFE12  address of the block's buffer in memory
+//   DKCR xFE14  command: writing READ_BLOCK or WRITE_BLOCK starts a
+//               transfer, bit 14 enables the completion interrupt
+//   DKSR xFE16  status: bit 15 ready, bit 14 the last transfer failed,
+//               bit 0 a transfer finished; any write clears bit 0
+//
+// A transfer takes LATENCY instructions. The completion interrupt is at
+// x0182, priority 3, and asks until DKSR is written. Images are just the
+// blocks one after another, each BLOCK_WORDS big-endian words.
+pub const DKBR: u16 = 0xFE10;
+pub const DKAR: u16 = 0xFE12;
+pub const DKCR: u16 = 0xFE14;
+pub const DKSR: u16 = 0xFE16;
+
+pub const READ_BLOCK: u16 = 1;
+pub const WRITE_BLOCK: u16 = 2;
+pub const INTERRUPT_ENABLE: u16 = 0x4000;
+
+pub const BLOCK_WORDS: usize = 256;
+pub const LATENCY: u16 = 50;
+pub const VECTOR: u8 = 0x82;
+pub const PRIORITY: u8 = 3;
+
+pub struct Disk {
+    image: File,
+    pub blocks: u16,
+    pub block: u16,
+    pub buffer: u16,
+    pub control: u16,
+    pub error: bool,
+    pub done: bool,
+    // instructions left of the transfer under way
+    remaining: u16,
+}
+
+impl Disk {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Disk> {
+        let image = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = image.metadata()?.len();
+        let block_bytes = 2 * BLOCK_WORDS as u64;
+        if len % block_bytes != 0 || len / block_bytes > u16::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a disk image"));
+        }
+        Ok(Disk {
+            image,
+            blocks: (len / block_bytes) as u16,
+            block: 0,
+            buffer: 0,
+            control: 0,
+            error: false,
+            done: false,
+            remaining: 0,
+        })
+    }
+
+    pub fn busy(&self) -> bool {
+        self.remaining != 0
+    }
+
+    fn seek(&mut self) -> io::Result<()> {
+        if self.block >= self.blocks {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no such block"));
+        }
+        let offset = self.block as u64 * 2 * BLOCK_WORDS as u64;
+        self.image.seek(SeekFrom::Start(offset)).map(|_| ())
+    }
+
+    fn transfer(&mut self, lc3: &mut LC3) -> io::Result<()> {
+        self.seek()?;
+        let buffer = self.buffer;
+        let addrs = (0..BLOCK_WORDS as u16).map(|i| buffer.wrapping_add(i) as usize);
+        match self.control & 0xFF {
+            READ_BLOCK => {
+                let bytes = &mut [0; 2 * BLOCK_WORDS];
+                self.image.read_exact(bytes)?;
+                for (addr, word) in addrs.zip(bytes.chunks(2)) {
+                    lc3.memory[addr] = u16::from_be_bytes([word[0], word[1]]);
+                }
+            }
+            WRITE_BLOCK => {
+                let bytes: Vec<u8> = addrs.flat_map(|addr| lc3.memory[addr].to_be_bytes()).collect();
+                self.image.write_all(&bytes)?;
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "unknown command")),
+        }
+        Ok(())
+    }
+}
+
+impl Device for Disk {
+    fn read(&mut self, lc3: &mut LC3, addr: u16) -> Result<u16, Fault> {
+        Ok(self.peek(lc3, addr).unwrap())
+    }
+
+    // Registers other than DKSR are ignored while a transfer is under way.
+    fn write(&mut self, _lc3: &mut LC3, addr: u16, val: u16) -> Result<(), Fault> {
+        match addr {
+            DKSR => self.done = false,
+            _ if self.busy() => {}
+            DKBR => self.block = val,
+            DKAR => self.buffer = val,
+            DKCR => {
+                self.control = val;
+                if val & 0xFF != 0 {
+                    self.done = false;
+                    self.remaining = LATENCY;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, lc3: &mut LC3) {
+        if !self.busy() {
+            return;
+        }
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            self.error = self.transfer(lc3).is_err();
+            self.done = true;
+        }
+    }
+
+    fn irq(&self, _lc3: &LC3) -> Option<(u8, u8)> {
+        (self.done && self.control & INTERRUPT_ENABLE != 0).then_some((VECTOR, PRIORITY))
+    }
+
+    fn peek(&self, _lc3: &LC3, addr: u16) -> Option<u16> {
+        Some(match addr {
+            DKBR => self.block,
+            DKAR => self.buffer,
+            DKCR => self.control,
+            DKSR => (!self.busy() as u16) << 15 | (self.error as u16) << 14 | self.done as u16,
+            _ => 0,
+        })
+    }
+}
+
+// Makes an image of `blocks` empty blocks.
+pub fn create<P: AsRef<Path>>(path: P, blocks: u16) -> io::Result<()> {
+    let image = File::create(path)?;
+    image.set_len(blocks as u64 * 2 * BLOCK_WORDS as u64)
+}
+
+// Puts the controller for `path` on the bus at DKBR-DKSR.
+pub fn attach<P: AsRef<Path>>(lc3: &mut LC3, path: P) -> io::Result<()> {
+    lc3.devices.attach(DKBR..=DKSR, Disk::open(path)?);
+    Ok(())
+}
+
+// The words of every block in an image.
+pub fn blocks<P: AsRef<Path>>(path: P) -> io::Result<Vec<Vec<u16>>> {
+    let bytes = std::fs::read(path)?;
+    if bytes.len() % (2 * BLOCK_WORDS) != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a disk image"));
+    }
+    Ok(bytes
+        .chunks(2 * BLOCK_WORDS)
+        .map(|block| block.chunks(2).map(|w| u16::from_be_bytes([w[0], w[1]])).collect())
+        .collect())
+}
+
+// A hex dump of one block, eight words to a line with the offset first and
+// the words that are printable characters at the end.
+pub fn dump(block: &[u16]) -> String {
+    let mut out = String::new();
+    for (i, line) in block.chunks(8).enumerate() {
+        let words: Vec<String> = line.iter().map(|w| format!("{:04X}", w)).collect();
+        let text: String = line
+            .iter()
+            .map(|&w| match w {
+                0x20..=0x7E => w as u8 as char,
+                _ => '.',
+            })
+            .collect();
+        out += &format!("x{:02X}: {}  {}\n", i * 8, words.join(" "), text);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::console::Console;
+
+    fn image(name: &str, blocks: u16) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("lc3-disk-{}-{}", std::process::id(), name));
+        create(&path, blocks).unwrap();
+        path
+    }
+
+    fn machine(src: &str, path: &Path) -> (LC3, crate::asm::Program) {
+        let program = assemble(src).unwrap();
+        let mut lc3 = LC3 {
+            console: Console::buffer(&[]),
+            ..LC3::default()
+        };
+        program.load_into(&mut lc3);
+        attach(&mut lc3, path).unwrap();
+        (lc3, program)
+    }
+
+    // Writes the message out to block 2 and waits for the interrupt, counting
+    // while it waits, then polls while reading it back to x5000.
+    const ROUND_TRIP: &str = r#"
+        .ORIG x3000
+        AND R0, R0, #0
+        ADD R0, R0, #2
+        STI R0, DKBR_PTR
+        LEA R0, MESSAGE
+        STI R0, DKAR_PTR
+        LD R0, WRITE_IE
+        STI R0, DKCR_PTR
+        AND R1, R1, #0
+WAIT    ADD R1, R1, #1
+        LD R0, DONE
+        BRz WAIT
+        LD R0, BUFFER
+        STI R0, DKAR_PTR
+        AND R0, R0, #0
+        ADD R0, R0, #1
+        STI R0, DKCR_PTR
+POLL    LDI R0, DKSR_PTR
+        BRzp POLL
+        HALT
+ISR     ST R0, SAVED
+        STI R0, DKSR_PTR
+        ADD R0, R0, #1
+        ST R0, DONE
+        LD R0, SAVED
+        RTI
+WRITE_IE .FILL x4002
+BUFFER   .FILL x5000
+DONE     .FILL 0
+SAVED    .BLKW 1
+DKBR_PTR .FILL xFE10
+DKAR_PTR .FILL xFE12
+DKCR_PTR .FILL xFE14
+DKSR_PTR .FILL xFE16
+MESSAGE  .STRINGZ "stored on disk"
+        .END
+    "#;
+
+    #[test]
+    fn test_dma_round_trip() {
+        let path = image("round-trip.img", 4);
+        let (mut lc3, program) = machine(ROUND_TRIP, &path);
+        lc3.memory[0x0182] = program.symbols["ISR"].addr;
+        lc3.run().unwrap();
+
+        // the program ran on while the block was written
+        assert!(lc3.registers[1] as u16 > LATENCY / 4);
+        let message = program.symbols["MESSAGE"].addr as usize;
+        assert_eq!(lc3.memory[0x5000..0x5000 + BLOCK_WORDS], lc3.memory[message..message + BLOCK_WORDS]);
+        let disk = lc3.devices.get::<Disk>().unwrap();
+        assert!(disk.done && !disk.error && !disk.busy());
+        assert_eq!(disk.blocks, 4);
+
+        let blocks = blocks(&path).unwrap();
+        assert_eq!(blocks.len(), 4);
+        assert!(blocks[1].iter().all(|&w| w == 0));
+        assert_eq!(blocks[2][..6], [b's', b't', b'o', b'r', b'e', b'd'].map(u16::from));
+        let dump = dump(&blocks[2]);
+        assert_eq!(dump.lines().count(), 32);
+        assert!(dump.starts_with("x00: 0073 0074 006F 0072 0065 0064 0020 006F  stored o\n"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_bad_block() {
+        let path = image("bad-block.img", 1);
+        let src = ".ORIG x3000\nLD R0, BLOCK\nSTI R0, DKBR_PTR\nAND R0, R0, #0\nADD R0, R0, #1\nSTI R0, DKCR_PTR\n\
+                   POLL LDI R0, DKSR_PTR\nBRzp POLL\nHALT\nBLOCK .FILL 1\nDKBR_PTR .FILL xFE10\nDKCR_PTR .FILL xFE14\n\
+                   DKSR_PTR .FILL xFE16\n.END";
+        let (mut lc3, _) = machine(src, &path);
+        lc3.run().unwrap();
+        assert_eq!(lc3.registers[0] as u16, 0x8000 | 0x4000 | 1);
+        assert!(Disk::open(path.with_extension("missing")).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,393 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::LC3;
+
+// Trap vectors of the file services. Arguments go in R0-R2 and the result
+// comes back in R0, negative for an error, with the condition codes set
+// from it.
+//
+//   OPEN  R0 = path, R1 = mode              -> descriptor
+//   READ  R0 = fd, R1 = buffer, R2 = count  -> words read, 0 at end of file
+//   WRITE R0 = fd, R1 = buffer, R2 = count  -> words written
+//   CLOSE R0 = fd                           -> 0
+//   SEEK  R0 = fd, R1 = offset, R2 = whence -> new position
+//
+// Paths are null-terminated strings, one character per word, relative to the
+// sandbox. Data moves one byte per word.
+pub const OPEN: u8 = 0x30;
+pub const READ: u8 = 0x31;
+pub const WRITE: u8 = 0x32;
+pub const CLOSE: u8 = 0x33;
+pub const SEEK: u8 = 0x34;
+
+// OPEN modes
+pub const MODE_READ: i16 = 0;
+pub const MODE_WRITE: i16 = 1;
+pub const MODE_APPEND: i16 = 2;
+pub const MODE_UPDATE: i16 = 3;
+
+// Error results
+pub const E_BAD_FD: i16 = -1;
+pub const E_NOT_FOUND: i16 = -2;
+pub const E_DENIED: i16 = -3;
+pub const E_IO: i16 = -4;
+pub const E_TOO_MANY: i16 = -5;
+pub const E_INVALID: i16 = -6;
+
+const MAX_FILES: usize = 16;
+const MAX_PATH: usize = 256;
+
+// The open files of one machine, confined to `root`.
+struct Sandbox {
+    root: PathBuf,
+    files: Vec<Option<File>>,
+}
+
+impl Sandbox {
+    // Only plain relative names are allowed, and symlinks may not lead
+    // out of the sandbox either. A name that doesn't resolve may be created,
+    // unless it is a dangling symlink, which creating would follow.
+    fn resolve(&self, name: &str) -> Result<PathBuf, i16> {
+        let rel = Path::new(name);
+        if name.is_empty() || rel.components().any(|c| !matches!(c, Component::Normal(_))) {
+            return Err(E_DENIED);
+        }
+        let path = self.root.join(rel);
+        let full = match path.canonicalize() {
+            Ok(full) => full,
+            Err(_) if path.symlink_metadata().is_ok() => return Err(E_DENIED),
+            Err(_) => {
+                let parent = path.parent().unwrap().canonicalize().map_err(|_| E_NOT_FOUND)?;
+                parent.join(path.file_name().unwrap())
+            }
+        };
+        if full.starts_with(&self.root) {
+            Ok(full)
+        } else {
+            Err(E_DENIED)
+        }
+    }
+
+    fn open(&mut self, name: &str, mode: i16) -> Result<i16, i16> {
+        let mut options = OpenOptions::new();
+        match mode {
+            MODE_READ => options.read(true),
+            MODE_WRITE => options.write(true).create(true).truncate(true),
+            MODE_APPEND => options.append(true).create(true),
+            MODE_UPDATE => options.read(true).write(true),
+            _ => return Err(E_INVALID),
+        };
+        let path = self.resolve(name)?;
+        let slot = match self.files.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None if self.files.len() < MAX_FILES => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return Err(E_TOO_MANY),
+        };
+        self.files[slot] = Some(options.open(path).map_err(error_code)?);
+        Ok(slot as i16)
+    }
+
+    fn file(&mut self, fd: i16) -> Result<&mut File, i16> {
+        self.files.get_mut(fd as usize).and_then(Option::as_mut).ok_or(E_BAD_FD)
+    }
+}
+
+// One service: its result, or the error code to report instead.
+type Call = fn(&mut LC3, &mut Sandbox) -> Result<i16, i16>;
+
+fn error_code(e: io::Error) -> i16 {
+    match e.kind() {
+        io::ErrorKind::NotFound => E_NOT_FOUND,
+        io::ErrorKind::PermissionDenied => E_DENIED,
+        _ => E_IO,
+    }
+}
+
+// Memory is read and written as the program itself would, so a buffer it
+// may not touch is E_INVALID.
+fn string_at(lc3: &mut LC3, addr: u16) -> Result<String, i16> {
+    let mut name = String::new();
+    for i in 0..MAX_PATH as u16 {
+        match lc3.read(addr.wrapping_add(i)).map_err(|_| E_INVALID)? {
+            0 => break,
+            w => name.push(w as u8 as char),
+        }
+    }
+    Ok(name)
+}
+
+fn service(lc3: &mut LC3, sandbox: &Mutex<Sandbox>, call: Call) -> Result<(), crate::Fault> {
+    let mut sandbox = sandbox.lock().unwrap();
+    let result = call(lc3, &mut sandbox).unwrap_or_else(|e| e);
+    lc3.registers[0] = result;
+    lc3.set_condition(result);
+    Ok(())
+}
+
+fn open(lc3: &mut LC3, sandbox: &mut Sandbox) -> Result<i16, i16> {
+    let name = string_at(lc3, lc3.registers[0] as u16)?;
+    sandbox.open(&name, lc3.registers[1])
+}
+
+fn read(lc3: &mut LC3, sandbox: &mut Sandbox) -> Result<i16, i16> {
+    let (buffer, count) = (lc3.registers[1] as u16, lc3.registers[2]);
+    if count < 0 {
+        return Err(E_INVALID);
+    }
+    // nothing is taken from the file for a buffer that can't hold it
+    for i in 0..count as u16 {
+        lc3.check_access(buffer.wrapping_add(i)).map_err(|_| E_INVALID)?;
+    }
+    let mut bytes = vec![0; count as usize];
+    let n = sandbox.file(lc3.registers[0])?.read(&mut bytes).map_err(error_code)?;
+    for (i, b) in bytes[..n].iter().enumerate() {
+        lc3.write(buffer.wrapping_add(i as u16), *b as u16).map_err(|_| E_INVALID)?;
+    }
+    Ok(n as i16)
+}
+
+fn write(lc3: &mut LC3, sandbox: &mut Sandbox) -> Result<i16, i16> {
+    let (buffer, count) = (lc3.registers[1] as u16, lc3.registers[2]);
+    if count < 0 {
+        return Err(E_INVALID);
+    }
+    let bytes = (0..count as u16)
+        .map(|i| lc3.read(buffer.wrapping_add(i)).map(|w| w as u8))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| E_INVALID)?;
+    sandbox.file(lc3.registers[0])?.write_all(&bytes).map_err(error_code)?;
+    Ok(count)
+}
+
+fn close(lc3: &mut LC3, sandbox: &mut Sandbox) -> Result<i16, i16> {
+    let fd = lc3.registers[0];
+    sandbox.file(fd)?;
+    sandbox.files[fd as usize] = None;
+    Ok(0)
+}
+
+fn seek(lc3: &mut LC3, sandbox: &mut Sandbox) -> Result<i16, i16> {
+    let offset = lc3.registers[1] as i64;
+    let from = match lc3.registers[2] {
+        0 if offset >= 0 => SeekFrom::Start(offset as u64),
+        1 => SeekFrom::Current(offset),
+        2 => SeekFrom::End(offset),
+        _ => return Err(E_INVALID),
+    };
+    let pos = sandbox.file(lc3.registers[0])?.seek(from).map_err(|_| E_INVALID)?;
+    if pos > i16::MAX as u64 {
+        return Err(E_INVALID);
+    }
+    Ok(pos as i16)
+}
+
+// Registers the file services on `lc3`, with every path relative to `root`.
+// Nothing is registered by default.
+pub fn install<P: AsRef<Path>>(lc3: &mut LC3, root: P) -> io::Result<()> {
+    let root = root.as_ref().canonicalize()?;
+    if !root.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the sandbox must be a directory"));
+    }
+    let sandbox = Arc::new(Mutex::new(Sandbox { root, files: Vec::new() }));
+    let calls: [(u8, Call); 5] = [(OPEN, open), (READ, read), (WRITE, write), (CLOSE, close), (SEEK, seek)];
+    for (vect, call) in calls.iter().copied() {
+        let sandbox = Arc::clone(&sandbox);
+        lc3.traps.register(vect, move |lc3: &mut LC3| service(lc3, &sandbox, call));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::console::Console;
+    use crate::Fault;
+
+    fn sandbox(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lc3-files-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn machine(src: &str, root: &Path) -> LC3 {
+        let mut lc3 = LC3 {
+            console: Console::buffer(&[]),
+            ..LC3::default()
+        };
+        assemble(src).unwrap().load_into(&mut lc3);
+        install(&mut lc3, root).unwrap();
+        lc3
+    }
+
+    // Copies in.txt to out.txt upper cased, then reads back the last two
+    // characters written.
+    const COPY: &str = r#"
+        .ORIG x3000
+        LEA R0, IN_NAME
+        AND R1, R1, #0
+        TRAP x30
+        BRn FAIL
+        ST R0, IN_FD
+        LEA R0, OUT_NAME
+        ADD R1, R1, #3
+        TRAP x30
+        BRn FAIL
+        ST R0, OUT_FD
+LOOP    LD R0, IN_FD
+        LEA R1, BUF
+        AND R2, R2, #0
+        ADD R2, R2, #1
+        TRAP x31
+        BRnz DONE
+        LD R3, BUF
+        LD R4, CASE
+        ADD R3, R3, R4
+        ST R3, BUF
+        LD R0, OUT_FD
+        TRAP x32
+        BR LOOP
+DONE    LD R0, OUT_FD
+        AND R1, R1, #0
+        ADD R1, R1, #-2
+        ADD R2, R1, #4
+        TRAP x34
+        LD R0, OUT_FD
+        LEA R1, BUF
+        ADD R2, R2, #0
+        TRAP x31
+        ST R0, COUNT
+        LD R0, OUT_FD
+        TRAP x33
+        LD R0, IN_FD
+        TRAP x33
+        LD R0, IN_FD
+        TRAP x33
+        ST R0, RESULT
+FAIL    HALT
+IN_NAME  .STRINGZ "in.txt"
+OUT_NAME .STRINGZ "out.txt"
+IN_FD    .BLKW 1
+OUT_FD   .BLKW 1
+CASE     .FILL #-32
+COUNT    .BLKW 1
+RESULT   .BLKW 1
+BUF      .BLKW 2
+        .END
+"#;
+
+    #[test]
+    fn test_copy_file_in_sandbox() {
+        let dir = sandbox("copy");
+        std::fs::write(dir.join("in.txt"), "abc").unwrap();
+        std::fs::write(dir.join("out.txt"), "").unwrap();
+        let program = assemble(COPY).unwrap();
+        let mut lc3 = machine(COPY, &dir);
+        lc3.run().unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("out.txt")).unwrap(), "ABC");
+        let at = |name: &str| lc3.memory[program.symbols[name].addr as usize];
+        assert_eq!(at("COUNT"), 2);
+        let buf = program.symbols["BUF"].addr as usize;
+        assert_eq!(&lc3.memory[buf..buf + 2], &[b'B' as u16, b'C' as u16]);
+        assert_eq!(at("RESULT") as i16, E_BAD_FD);
+        assert!(lc3.condition.n);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sandbox_rules() {
+        let dir = sandbox("rules");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/data"), "x").unwrap();
+        let mut lc3 = machine(".ORIG x3000\nHALT\n.END", &dir);
+        let mut open = |name: &str, mode: i16| {
+            for (i, c) in name.bytes().chain(Some(0)).enumerate() {
+                lc3.memory[0x4000 + i] = c as u16;
+            }
+            lc3.registers[0] = 0x4000;
+            lc3.registers[1] = mode;
+            lc3.run_instruction(crate::opcodes::Inst::TRAP { trap_vect: OPEN as i16 }).unwrap();
+            lc3.registers[0]
+        };
+        assert_eq!(open("sub/data", MODE_READ), 0);
+        assert_eq!(open("missing", MODE_READ), E_NOT_FOUND);
+        assert_eq!(open("nodir/new", MODE_WRITE), E_NOT_FOUND);
+        assert_eq!(open("../escape", MODE_WRITE), E_DENIED);
+        assert_eq!(open("sub/../sub/data", MODE_READ), E_DENIED);
+        assert_eq!(open(&dir.join("sub/data").to_string_lossy(), MODE_READ), E_DENIED);
+        assert_eq!(open("", MODE_READ), E_DENIED);
+        assert_eq!(open("sub/data", 9), E_INVALID);
+        // a dangling link isn't followed to create its target
+        #[cfg(unix)]
+        {
+            let outside = dir.parent().unwrap().join(format!("lc3-files-pwned-{}", std::process::id()));
+            std::os::unix::fs::symlink(&outside, dir.join("dangling")).unwrap();
+            assert_eq!(open("dangling", MODE_WRITE), E_DENIED);
+            assert_eq!(open("dangling", MODE_APPEND), E_DENIED);
+            assert!(!outside.exists());
+        }
+        for fd in 1..MAX_FILES as i16 {
+            assert_eq!(open("sub/data", MODE_READ), fd);
+        }
+        assert_eq!(open("sub/data", MODE_READ), E_TOO_MANY);
+        assert!(!dir.parent().unwrap().join("escape").exists());
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(std::env::temp_dir(), dir.join("link")).unwrap();
+            let mut lc3 = machine(".ORIG x3000\nLEA R0, NAME\nAND R1, R1, #0\nADD R1, R1, #1\nTRAP x30\nHALT\nNAME .STRINGZ \"link/x\"\n.END", &dir);
+            lc3.run().unwrap();
+            assert_eq!(lc3.registers[0], E_DENIED);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_buffers_are_checked() {
+        let dir = sandbox("buffers");
+        std::fs::write(dir.join("data"), "ab").unwrap();
+        let mut lc3 = machine(".ORIG x3000\nHALT\n.END", &dir);
+        // an OS has claimed the access violation exception
+        lc3.memory[0x0102] = 0x1000;
+        for (i, c) in b"data\0".iter().enumerate() {
+            lc3.memory[0x4000 + i] = *c as u16;
+        }
+        let mut call = |vect: u8, args: [u16; 3]| {
+            for (r, arg) in args.iter().enumerate() {
+                lc3.registers[r] = *arg as i16;
+            }
+            lc3.run_instruction(crate::opcodes::Inst::TRAP { trap_vect: vect as i16 }).unwrap();
+            lc3.registers[0]
+        };
+        assert_eq!(call(OPEN, [0x0100, 0, 0]), E_INVALID);
+        assert_eq!(call(OPEN, [0x4000, MODE_UPDATE as u16, 0]), 0);
+        assert_eq!(call(READ, [0, 0x0100, 2]), E_INVALID);
+        assert_eq!(call(READ, [0, 0x2FFF, 2]), E_INVALID);
+        assert_eq!(call(WRITE, [0, 0x0000, 2]), E_INVALID);
+        // nothing was taken from the file or put in it
+        assert_eq!(call(READ, [0, 0x5000, 2]), 2);
+        assert_eq!(std::fs::read_to_string(dir.join("data")).unwrap(), "ab");
+        assert_eq!(lc3.memory[0x0100..0x0102], [0, 0]);
+        assert_eq!(lc3.memory[0x2FFF..0x3001], [0, 0xF025]);
+        assert_eq!(lc3.memory[0x5000..0x5002], [b'a' as u16, b'b' as u16]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_off_by_default() {
+        let mut lc3 = LC3 {
+            console: Console::buffer(&[]),
+            ..LC3::default()
+        };
+        assemble(".ORIG x3000\nTRAP x30\nHALT\n.END").unwrap().load_into(&mut lc3);
+        assert_eq!(lc3.run(), Err(Fault::UnknownTrap(OPEN)));
+        assert!(install(&mut lc3, "/definitely/not/here").is_err());
+    }
+}
@@ -0,0 +1,613 @@
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::console::Console;
+use crate::opcodes::Inst;
+use crate::{Fault, LC3};
+
+// Registers are numbered r0-r7, pc, psr. Addresses in memory packets are LC-3
+// word addresses, and every word travels as two little-endian bytes.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.lc3.core">
+    <reg name="r0" bitsize="16" type="int16" regnum="0"/>
+    <reg name="r1" bitsize="16" type="int16"/>
+    <reg name="r2" bitsize="16" type="int16"/>
+    <reg name="r3" bitsize="16" type="int16"/>
+    <reg name="r4" bitsize="16" type="int16"/>
+    <reg name="r5" bitsize="16" type="int16"/>
+    <reg name="r6" bitsize="16" type="data_ptr"/>
+    <reg name="r7" bitsize="16" type="code_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="psr" bitsize="16" type="uint16"/>
+  </feature>
+</target>
+"#;
+
+const REGISTERS: usize = 10;
+
+// The largest packet we take or send, as advertised to the client. A memory
+// read's reply is two hex digits a byte.
+const PACKET_SIZE: usize = 0x1000;
+
+// How many instructions a continue runs between checks for a break from the
+// client.
+const POLL_INTERVAL: u64 = 1024;
+
+pub trait Transport: Read + Write {
+    // Checks without blocking whether the client has sent a break (0x03).
+    fn interrupted(&mut self) -> bool {
+        false
+    }
+}
+
+impl Transport for TcpStream {
+    fn interrupted(&mut self) -> bool {
+        let mut buf = [0; 1];
+        if self.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let got = matches!(self.peek(&mut buf), Ok(1) if buf[0] == 0x03);
+        if got {
+            let _ = self.read(&mut buf);
+        }
+        let _ = self.set_nonblocking(false);
+        got
+    }
+}
+
+pub struct Stdio;
+
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::stdin().lock().read(buf)
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stdout().lock().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().lock().flush()
+    }
+}
+
+impl Transport for Stdio {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Watchpoint {
+    kind: WatchKind,
+    start: u16,
+    len: u16,
+}
+
+impl Watchpoint {
+    fn covers(&self, addr: u16) -> bool {
+        addr.wrapping_sub(self.start) < self.len.max(1)
+    }
+}
+
+// The memory words an instruction is about to read and write, worked out from
+// the machine state before it executes.
+fn data_accesses(lc3: &LC3, inst: &Inst) -> (Vec<u16>, Vec<u16>) {
+    let reg = |r: i16| lc3.registers[r as usize] as u16;
+    let rel = |off: i16| lc3.pc.wrapping_add(1).wrapping_add(off as u16);
+    match *inst {
+        Inst::LD { pc_offset, .. } => (vec![rel(pc_offset)], vec![]),
+        Inst::LDI { pc_offset, .. } => {
+            let ptr = rel(pc_offset);
+            (vec![ptr, lc3.memory[ptr as usize]], vec![])
+        }
+        Inst::LDR { base_r, offset, .. } => (vec![reg(base_r).wrapping_add(offset as u16)], vec![]),
+        Inst::ST { pc_offset, .. } => (vec![], vec![rel(pc_offset)]),
+        Inst::STI { pc_offset, .. } => {
+            let ptr = rel(pc_offset);
+            (vec![ptr], vec![lc3.memory[ptr as usize]])
+        }
+        Inst::STR { base_r, offset, .. } => (vec![], vec![reg(base_r).wrapping_add(offset as u16)]),
+        Inst::RTI => {
+            let sp = reg(6);
+            (vec![sp, sp.wrapping_add(1)], vec![])
+        }
+        _ => (vec![], vec![]),
+    }
+}
+
+fn signal(fault: &Fault) -> u8 {
+    match fault {
+        Fault::IllegalOpcode(_) | Fault::PrivilegeViolation => 4, // SIGILL
+        Fault::AccessViolation(_) => 11,                          // SIGSEGV
+        Fault::UnknownTrap(_) => 31,                              // SIGSYS
+        Fault::Trap(..) => 5,                                     // SIGTRAP
+        Fault::EndOfInput => 1,                                   // SIGHUP
+        Fault::Diverged(_) => 6,                                  // SIGABRT
+    }
+}
+
+fn hex_word(val: u16) -> String {
+    format!("{:02x}{:02x}", val & 0xFF, val >> 8)
+}
+
+fn parse_hex(s: &str) -> Option<u16> {
+    u16::from_str_radix(s, 16).ok()
+}
+
+fn parse_hex_word(s: &str) -> Option<u16> {
+    if s.len() != 4 {
+        return None;
+    }
+    Some(parse_hex(&s[..2])? | parse_hex(&s[2..])? << 8)
+}
+
+pub struct GdbServer<T: Transport> {
+    pub lc3: LC3,
+    stream: T,
+    breakpoints: BTreeSet<u16>,
+    watchpoints: Vec<Watchpoint>,
+    no_ack: bool,
+}
+
+impl<T: Transport> GdbServer<T> {
+    pub fn new(lc3: LC3, stream: T) -> Self {
+        GdbServer {
+            lc3,
+            stream,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            no_ack: false,
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut buf = [0; 1];
+        match self.stream.read(&mut buf)? {
+            0 => Ok(None),
+            _ => Ok(Some(buf[0])),
+        }
+    }
+
+    // Reads the next packet body, skipping acks and stray interrupts.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => break,
+                Some(_) => continue,
+            }
+        }
+        let mut body = Vec::new();
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'#') => break,
+                Some(b) => body.push(b),
+            }
+        }
+        let mut checksum = [0; 2];
+        self.stream.read_exact(&mut checksum)?;
+        let expected = std::str::from_utf8(&checksum).ok().and_then(parse_hex);
+        let actual = body.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+        if !self.no_ack {
+            let ack: &[u8] = if expected == Some(actual as u16) { b"+" } else { b"-" };
+            self.stream.write_all(ack)?;
+        }
+        Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+    }
+
+    fn send(&mut self, body: &str) -> io::Result<()> {
+        let checksum = body.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+        write!(self.stream, "${}#{:02x}", body, checksum)?;
+        self.stream.flush()?;
+        if self.no_ack {
+            return Ok(());
+        }
+        // one retransmit on a nack is plenty on a reliable stream
+        match self.read_byte()? {
+            Some(b'-') => {
+                write!(self.stream, "${}#{:02x}", body, checksum)?;
+                self.stream.flush()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn register(&self, n: usize) -> Option<u16> {
+        match n {
+            0..=7 => Some(self.lc3.registers[n] as u16),
+            8 => Some(self.lc3.pc),
+            9 => Some(self.lc3.psr()),
+            _ => None,
+        }
+    }
+
+    fn set_register(&mut self, n: usize, val: u16) -> bool {
+        match n {
+            0..=7 => self.lc3.registers[n] = val as i16,
+            8 => self.lc3.pc = val,
+            9 => self.lc3.set_psr(val),
+            _ => return false,
+        }
+        true
+    }
+
+    fn forward_output(&mut self) -> io::Result<()> {
+        let output = self.lc3.console.take_output();
+        if output.is_empty() {
+            return Ok(());
+        }
+        let hex: String = output.iter().map(|b| format!("{:02x}", b)).collect();
+        self.send(&format!("O{}", hex))
+    }
+
+    // Runs until something worth reporting happens and returns the stop reply.
+    fn resume(&mut self, step: bool) -> io::Result<String> {
+        let mut since_poll = 0;
+        loop {
+            if self.lc3.halted {
+                return Ok("W00".to_string());
+            }
+            let raw = self.lc3.memory[self.lc3.pc as usize];
+            let (reads, writes) = match raw >> 12 {
+                0b1101 => (vec![], vec![]),
+                _ => data_accesses(&self.lc3, &Inst::from(raw)),
+            };
+            let stepped = self.lc3.run_step();
+            self.forward_output()?;
+            if let Err(fault) = stepped {
+                return Ok(format!("S{:02x}", signal(&fault)));
+            }
+            if self.lc3.halted {
+                return Ok("W00".to_string());
+            }
+            for w in &self.watchpoints {
+                let hit = match w.kind {
+                    WatchKind::Write => writes.iter().find(|a| w.covers(**a)),
+                    WatchKind::Read => reads.iter().find(|a| w.covers(**a)),
+                    WatchKind::Access => reads.iter().chain(writes.iter()).find(|a| w.covers(**a)),
+                };
+                if let Some(addr) = hit {
+                    let kind = match w.kind {
+                        WatchKind::Write => "watch",
+                        WatchKind::Read => "rwatch",
+                        WatchKind::Access => "awatch",
+                    };
+                    return Ok(format!("T05{}:{:x};", kind, addr));
+                }
+            }
+            if step {
+                return Ok("S05".to_string());
+            }
+            if self.breakpoints.contains(&self.lc3.pc) {
+                return Ok("T05swbreak:;".to_string());
+            }
+            since_poll += 1;
+            if since_poll == POLL_INTERVAL {
+                since_poll = 0;
+                if self.stream.interrupted() {
+                    return Ok("S02".to_string());
+                }
+            }
+        }
+    }
+
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (addr, len) = args.split_once(',')?;
+        let (addr, len) = (parse_hex(addr)?, usize::from_str_radix(len, 16).ok()?);
+        if len > PACKET_SIZE / 2 {
+            return None;
+        }
+        let words = len.div_ceil(2);
+        Some(
+            (0..words)
+                .map(|i| hex_word(self.lc3.memory[addr.wrapping_add(i as u16) as usize]))
+                .collect::<String>()[..len * 2]
+                .to_string(),
+        )
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<()> {
+        let (range, data) = args.split_once(':')?;
+        let (addr, _) = range.split_once(',')?;
+        let addr = parse_hex(addr)?;
+        if !data.len().is_multiple_of(4) {
+            return None;
+        }
+        for (i, chunk) in data.as_bytes().chunks(4).enumerate() {
+            let word = parse_hex_word(std::str::from_utf8(chunk).ok()?)?;
+            self.lc3.memory[addr.wrapping_add(i as u16) as usize] = word;
+        }
+        Some(())
+    }
+
+    fn set_point(&mut self, insert: bool, args: &str) -> Option<()> {
+        let mut parts = args.split(',');
+        let kind = parts.next()?;
+        let addr = parse_hex(parts.next()?)?;
+        let len = parse_hex(parts.next()?)?;
+        let watch = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                return Some(());
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return None,
+        };
+        let point = Watchpoint {
+            kind: watch,
+            start: addr,
+            // gdb sends lengths in bytes
+            len: len.div_ceil(2),
+        };
+        if insert {
+            self.watchpoints.push(point);
+        } else {
+            self.watchpoints.retain(|w| *w != point);
+        }
+        Some(())
+    }
+
+    fn read_features(&self, args: &str) -> Option<String> {
+        let (offset, len) = args.strip_prefix("target.xml:")?.split_once(',')?;
+        let offset = usize::from_str_radix(offset, 16).ok()?.min(TARGET_XML.len());
+        let len = usize::from_str_radix(len, 16).ok()?;
+        let end = (offset + len).min(TARGET_XML.len());
+        let marker = if end == TARGET_XML.len() { 'l' } else { 'm' };
+        Some(format!("{}{}", marker, &TARGET_XML[offset..end]))
+    }
+
+    // Answers one packet. Returns None once the session should end.
+    fn handle(&mut self, packet: &str) -> io::Result<Option<String>> {
+        let (cmd, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match cmd {
+            "?" => {
+                if self.lc3.halted {
+                    "W00".to_string()
+                } else {
+                    "S05".to_string()
+                }
+            }
+            "g" => (0..REGISTERS).filter_map(|n| self.register(n)).map(hex_word).collect(),
+            "G" => {
+                let ok = args.len() == REGISTERS * 4
+                    && (0..REGISTERS).all(|n| {
+                        parse_hex_word(&args[n * 4..n * 4 + 4]).is_some_and(|v| self.set_register(n, v))
+                    });
+                if ok { "OK" } else { "E01" }.to_string()
+            }
+            "p" => usize::from_str_radix(args, 16)
+                .ok()
+                .and_then(|n| self.register(n))
+                .map_or_else(|| "E01".to_string(), hex_word),
+            "P" => {
+                let ok = args
+                    .split_once('=')
+                    .and_then(|(n, v)| Some((usize::from_str_radix(n, 16).ok()?, parse_hex_word(v)?)))
+                    .is_some_and(|(n, v)| self.set_register(n, v));
+                if ok { "OK" } else { "E01" }.to_string()
+            }
+            "m" => self.read_memory(args).unwrap_or_else(|| "E01".to_string()),
+            "M" => self.write_memory(args).map_or("E01", |_| "OK").to_string(),
+            "c" | "s" => {
+                if let Some(addr) = parse_hex(args) {
+                    self.lc3.pc = addr;
+                }
+                self.resume(cmd == "s")?
+            }
+            "Z" => self.set_point(true, args).map_or("", |_| "OK").to_string(),
+            "z" => self.set_point(false, args).map_or("", |_| "OK").to_string(),
+            "H" => "OK".to_string(),
+            "k" => return Ok(None),
+            "D" => {
+                self.send("OK")?;
+                return Ok(None);
+            }
+            "q" if args.starts_with("Supported") => {
+                format!("PacketSize={:x};qXfer:features:read+;swbreak+;QStartNoAckMode+", PACKET_SIZE)
+            }
+            "q" if args.starts_with("Xfer:features:read:") => self
+                .read_features(&args["Xfer:features:read:".len()..])
+                .unwrap_or_else(|| "E00".to_string()),
+            "q" if args == "Attached" => "1".to_string(),
+            "q" if args == "C" => "QC1".to_string(),
+            "q" if args == "fThreadInfo" => "m1".to_string(),
+            "q" if args == "sThreadInfo" => "l".to_string(),
+            "Q" if args == "StartNoAckMode" => {
+                self.send("OK")?;
+                self.no_ack = true;
+                return Ok(Some(String::new()));
+            }
+            _ => String::new(),
+        };
+        self.send(&reply)?;
+        Ok(Some(reply))
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            if self.handle(&packet)?.is_none() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Waits for one debugger on 127.0.0.1:`port` and serves it until it detaches.
+pub fn serve_tcp(lc3: LC3, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("waiting for gdb on {}", listener.local_addr()?);
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    GdbServer::new(lc3, stream).run()
+}
+
+// Speaks the protocol over stdin/stdout, so console output has to travel to
+// the debugger in O packets.
+pub fn serve_stdio(mut lc3: LC3) -> io::Result<()> {
+    lc3.console = Console::buffer(&[]);
+    GdbServer::new(lc3, Stdio).run()
+}
+
+#[allow(clippy::unusual_byte_groupings)]
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use super::{GdbServer, TARGET_XML};
+    use crate::console::Console;
+    use crate::LC3;
+
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn read_packet(&mut self) -> String {
+            let mut byte = [0; 1];
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            let mut body = Vec::new();
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                body.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            let sum = body.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+            assert_eq!(std::str::from_utf8(&checksum).unwrap(), format!("{:02x}", sum));
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(body).unwrap()
+        }
+
+        fn request(&mut self, body: &str) -> String {
+            let sum = body.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+            write!(self.stream, "${}#{:02x}", body, sum).unwrap();
+            let mut ack = [0; 1];
+            self.stream.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+            self.read_packet()
+        }
+    }
+
+    fn start(lc3: LC3) -> (Client, thread::JoinHandle<LC3>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut server = GdbServer::new(lc3, stream);
+            server.run().unwrap();
+            server.lc3
+        });
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_nodelay(true).unwrap();
+        (Client { stream }, server)
+    }
+
+    fn program() -> LC3 {
+        let mut lc3 = LC3 {
+            console: Console::buffer(&[]),
+            ..LC3::default()
+        };
+        let code = [
+            0b0101_000_000_1_00000, // clears R0
+            0b0001_000_000_1_00101, // R0 <- 5
+            0b0011_000_000000011,   // stores R0 to x3006
+            0b0001_000_000_1_11111, // R0 <- R0 - 1
+            0b0010_001_000000001,   // loads x3006 to R1
+            0b1111_0000_00100101,   // HALT
+        ];
+        lc3.memory[0x3000..0x3006].copy_from_slice(&code);
+        lc3
+    }
+
+    #[test]
+    fn test_gdb_session() {
+        let (mut client, server) = start(program());
+
+        let supported = client.request("qSupported:swbreak+");
+        assert!(supported.starts_with("PacketSize=1000;") && supported.contains("qXfer:features:read+"));
+        let xml = client.request("qXfer:features:read:target.xml:0,fff");
+        assert_eq!(xml, format!("l{}", TARGET_XML));
+        assert_eq!(client.request("?"), "S05");
+
+        // r0-r7 are zero, pc is x3000 and the psr says user mode
+        let regs = client.request("g");
+        assert_eq!(regs.len(), 40);
+        assert_eq!(&regs[32..], "00300080");
+
+        assert_eq!(client.request("Z0,3003,2"), "OK");
+        assert_eq!(client.request("c"), "T05swbreak:;");
+        assert_eq!(client.request("p8"), "0330");
+        assert_eq!(client.request("p0"), "0500");
+        assert_eq!(client.request("m3006,2"), "0500");
+        // no more than fits in a packet
+        assert_eq!(client.request("m0,ffffffffffffffff"), "E01");
+        assert_eq!(client.request("m0,801"), "E01");
+        assert_eq!(client.request("mfc00,800").len(), 0x1000);
+
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.request("p0"), "0400");
+        assert_eq!(client.request("P0=0900"), "OK");
+        assert_eq!(client.request("M3006,2:2a00"), "OK");
+
+        assert_eq!(client.request("z0,3003,2"), "OK");
+        assert_eq!(client.request("Z3,3006,2"), "OK");
+        assert_eq!(client.request("c"), "T05rwatch:3006;");
+        assert_eq!(client.request("p1"), "2a00");
+
+        assert_eq!(client.request("c"), "W00");
+        client.stream.write_all(b"$k#6b").unwrap();
+        drop(client);
+
+        let lc3 = server.join().unwrap();
+        assert!(lc3.halted);
+        assert_eq!(lc3.registers[0], 9);
+    }
+
+    #[test]
+    fn test_gdb_write_watchpoint_and_output() {
+        let mut lc3 = program();
+        lc3.memory[0x3005] = 0b1111_0000_00100001; // OUT
+        lc3.memory[0x3007] = 0b1111_0000_00100101; // HALT, after the stored 5 runs as a no-op BR
+        let (mut client, server) = start(lc3);
+
+        assert_eq!(client.request("Z2,3006,2"), "OK");
+        assert_eq!(client.request("c"), "T05watch:3006;");
+        assert_eq!(client.request("p8"), "0330");
+        assert_eq!(client.request("z2,3006,2"), "OK");
+        assert_eq!(client.request("P0=4200"), "OK");
+        // R0 is decremented, then OUT prints 'A' through an O packet before the stop reply
+        assert_eq!(client.request("c"), "O41");
+        assert_eq!(client.read_packet(), "W00");
+        assert_eq!(client.request("D"), "OK");
+        drop(client);
+        server.join().unwrap();
+    }
+}
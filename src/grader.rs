@@ -0,0 +1,729 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::console::Console;
+use crate::json::Json;
+use crate::opcodes::Inst;
+use crate::utils::{parse_number, unescape};
+use crate::{Fault, LC3};
+
+// How many instructions run between wall-clock checks.
+const SLICE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Reg(u8),
+    Pc,
+    Mem(u16),
+    Output,
+}
+
+impl Target {
+    fn parse(s: &str) -> Option<Target> {
+        let upper = s.to_ascii_uppercase();
+        match upper.as_str() {
+            "PC" => Some(Target::Pc),
+            "OUTPUT" => Some(Target::Output),
+            _ => match upper.strip_prefix('R').map(str::parse::<u8>) {
+                Some(Ok(r)) if r < 8 => Some(Target::Reg(r)),
+                Some(_) => None,
+                None => parse_number(s)
+                    .filter(|&a| (0..=0xFFFF).contains(&a))
+                    .map(|a| Target::Mem(a as u16)),
+            },
+        }
+    }
+
+    fn read(&self, lc3: &LC3) -> Value {
+        match *self {
+            Target::Reg(r) => Value::Word(lc3.registers[r as usize] as u16),
+            Target::Pc => Value::Word(lc3.pc),
+            Target::Mem(addr) => Value::Word(lc3.memory[addr as usize]),
+            Target::Output => Value::Text(lc3.console.output().to_vec()),
+        }
+    }
+
+    fn write(&self, lc3: &mut LC3, val: u16) {
+        match *self {
+            Target::Reg(r) => lc3.registers[r as usize] = val as i16,
+            Target::Pc => lc3.pc = val,
+            Target::Mem(addr) => lc3.memory[addr as usize] = val,
+            Target::Output => {}
+        }
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Reg(r) => write!(f, "R{}", r),
+            Target::Pc => write!(f, "PC"),
+            Target::Mem(addr) => write!(f, "x{:04X}", addr),
+            Target::Output => write!(f, "output"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Word(u16),
+    Text(Vec<u8>),
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Word(w) => write!(f, "x{:04X}", w),
+            Value::Text(t) => write!(f, "\"{}\"", String::from_utf8_lossy(t).escape_debug()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub max_instructions: u64,
+    pub timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_instructions: 1_000_000,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestCase {
+    pub name: String,
+    pub input: Vec<u8>,
+    pub setup: Vec<(Target, u16)>,
+    pub checks: Vec<(Target, Value)>,
+    pub points: u32,
+    pub limits: Limits,
+    pub trace_len: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpecError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for SpecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for SpecError {}
+
+// A test spec is a line-oriented list of directives:
+//
+//     limit 100000            ; defaults before the first test apply to all
+//     test negative
+//     set x3100 #-10
+//     input "a"
+//     expect x3102 x0001
+//     expect output "hi\n"
+//     points 2
+//     trace 32                ; instructions kept for failure reports
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TestSpec {
+    pub tests: Vec<TestCase>,
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_str = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' if !escaped => in_str = !in_str,
+            ';' if !in_str => return &line[..i],
+            _ => {}
+        }
+        escaped = in_str && c == '\\' && !escaped;
+    }
+    line
+}
+
+impl TestSpec {
+    pub fn parse(src: &str) -> Result<TestSpec, SpecError> {
+        let mut spec = TestSpec::default();
+        let mut defaults = Limits::default();
+        let mut default_trace = 16;
+
+        for (idx, raw) in src.lines().enumerate() {
+            let err = |message: String| SpecError { line: idx + 1, message };
+            let line = strip_comment(raw).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (directive, rest) = match line.find(char::is_whitespace) {
+                Some(i) => (line[..i].to_ascii_lowercase(), line[i..].trim()),
+                None => (line.to_ascii_lowercase(), ""),
+            };
+            let args: Vec<&str> = rest.split_whitespace().collect();
+            let number = |s: &str| parse_number(s).ok_or_else(|| err(format!("invalid number '{}'", s)));
+            // negative words are two's complement
+            let word = |s: &str| match number(s)? {
+                n @ -0x8000..=0xFFFF => Ok(n as u16),
+                n => Err(err(format!("{} does not fit in 16 bits", n))),
+            };
+            let (limits, trace_len) = match spec.tests.last_mut() {
+                Some(test) => (&mut test.limits, &mut test.trace_len),
+                None => (&mut defaults, &mut default_trace),
+            };
+
+            match directive.as_str() {
+                "test" => {
+                    if rest.is_empty() {
+                        return Err(err("test needs a name".to_string()));
+                    }
+                    spec.tests.push(TestCase {
+                        name: rest.to_string(),
+                        input: Vec::new(),
+                        setup: Vec::new(),
+                        checks: Vec::new(),
+                        points: 1,
+                        limits: defaults,
+                        trace_len: default_trace,
+                    });
+                }
+                "limit" => {
+                    limits.max_instructions = rest
+                        .parse()
+                        .map_err(|_| err(format!("invalid instruction limit '{}'", rest)))?;
+                }
+                "trace" => {
+                    *trace_len = rest
+                        .parse()
+                        .map_err(|_| err(format!("invalid trace length '{}'", rest)))?;
+                }
+                "timeout" => {
+                    let ms = rest
+                        .parse()
+                        .map_err(|_| err(format!("invalid timeout '{}'", rest)))?;
+                    limits.timeout = Duration::from_millis(ms);
+                }
+                "input" | "set" | "expect" | "points" => {
+                    let test = spec
+                        .tests
+                        .last_mut()
+                        .ok_or_else(|| err(format!("'{}' outside of a test", directive)))?;
+                    match directive.as_str() {
+                        "input" => {
+                            test.input = unescape(rest).ok_or_else(|| err(format!("invalid string {}", rest)))?;
+                        }
+                        "points" => match number(rest)? {
+                            n if n >= 0 => test.points = n as u32,
+                            n => return Err(err(format!("points cannot be negative, got {}", n))),
+                        },
+                        _ => {
+                            if args.len() < 2 {
+                                return Err(err(format!("'{}' needs a target and a value", directive)));
+                            }
+                            let target = Target::parse(args[0])
+                                .ok_or_else(|| err(format!("invalid target '{}'", args[0])))?;
+                            let value = rest[args[0].len()..].trim();
+                            if directive == "set" {
+                                if target == Target::Output {
+                                    return Err(err("output cannot be set".to_string()));
+                                }
+                                test.setup.push((target, word(value)?));
+                            } else if target == Target::Output {
+                                let text = unescape(value).ok_or_else(|| err(format!("invalid string {}", value)))?;
+                                test.checks.push((target, Value::Text(text)));
+                            } else {
+                                test.checks.push((target, Value::Word(word(value)?)));
+                            }
+                        }
+                    }
+                }
+                _ => return Err(err(format!("unknown directive '{}'", directive))),
+            }
+        }
+        Ok(spec)
+    }
+
+    pub fn total_points(&self) -> u32 {
+        self.tests.iter().map(|t| t.points).sum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Halted,
+    StepLimit,
+    Timeout,
+    Fault(Fault),
+    Panic(String),
+    LoadError(String),
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Halted => write!(f, "halted"),
+            Outcome::StepLimit => write!(f, "instruction limit reached"),
+            Outcome::Timeout => write!(f, "wall-clock limit reached"),
+            Outcome::Fault(fault) => write!(f, "{}", fault),
+            Outcome::Panic(msg) => write!(f, "panicked: {}", msg),
+            Outcome::LoadError(msg) => write!(f, "could not load program: {}", msg),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckResult {
+    pub target: Target,
+    pub expected: Value,
+    pub actual: Value,
+}
+
+impl CheckResult {
+    pub fn passed(&self) -> bool {
+        self.expected == self.actual
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    pub pc: u16,
+    pub word: u16,
+    pub registers: [i16; 8],
+}
+
+impl Display for TraceEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let inst = match self.word >> 12 {
+            0b1101 => "(reserved)".to_string(),
+            _ => Inst::from(self.word).to_string(),
+        };
+        write!(f, "x{:04X}  x{:04X}  {:<18}", self.pc, self.word, inst)?;
+        for (i, r) in self.registers.iter().enumerate() {
+            write!(f, " R{}=x{:04X}", i, *r as u16)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestResult {
+    pub name: String,
+    pub outcome: Outcome,
+    pub checks: Vec<CheckResult>,
+    pub instructions: u64,
+    pub output: Vec<u8>,
+    pub points: u32,
+    pub duration: Duration,
+    // The last instructions executed, oldest first, with registers after each
+    pub trace: Vec<TraceEntry>,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.outcome == Outcome::Halted && self.checks.iter().all(CheckResult::passed)
+    }
+
+    pub fn status(&self) -> &'static str {
+        match self.outcome {
+            _ if self.passed() => "pass",
+            Outcome::Halted => "fail",
+            Outcome::StepLimit => "step-limit",
+            Outcome::Timeout => "timeout",
+            Outcome::Fault(_) => "fault",
+            Outcome::Panic(_) => "panic",
+            Outcome::LoadError(_) => "load-error",
+        }
+    }
+
+    pub fn earned(&self) -> u32 {
+        if self.passed() {
+            self.points
+        } else {
+            0
+        }
+    }
+}
+
+fn run_limited(lc3: &mut LC3, test: &TestCase, trace: &mut VecDeque<TraceEntry>) -> Outcome {
+    let start = Instant::now();
+    while !lc3.halted {
+        if lc3.executed >= test.limits.max_instructions {
+            return Outcome::StepLimit;
+        }
+        if lc3.executed.is_multiple_of(SLICE) && start.elapsed() > test.limits.timeout {
+            return Outcome::Timeout;
+        }
+        // a step that takes an interrupt runs the handler's first instruction
+        let pc = match lc3.peek_interrupt() {
+            Some((entry, _)) => lc3.memory[entry as usize],
+            None => lc3.pc,
+        };
+        let word = lc3.memory[pc as usize];
+        let stepped = lc3.run_step();
+        if test.trace_len > 0 {
+            if trace.len() == test.trace_len {
+                trace.pop_front();
+            }
+            trace.push_back(TraceEntry {
+                pc,
+                word,
+                registers: lc3.registers,
+            });
+        }
+        if let Err(fault) = stepped {
+            return Outcome::Fault(fault);
+        }
+    }
+    Outcome::Halted
+}
+
+fn execute(image: &[u8], test: &TestCase) -> TestResult {
+    let start = Instant::now();
+    let mut lc3 = LC3 {
+        console: Console::buffer(&test.input),
+        ..LC3::default()
+    };
+    let mut trace = VecDeque::new();
+    let outcome = match lc3.load_obj(image) {
+        Ok(_) => {
+            for (target, val) in &test.setup {
+                target.write(&mut lc3, *val);
+            }
+            run_limited(&mut lc3, test, &mut trace)
+        }
+        Err(e) => Outcome::LoadError(e.to_string()),
+    };
+    let checks = test
+        .checks
+        .iter()
+        .map(|(target, expected)| CheckResult {
+            target: *target,
+            expected: expected.clone(),
+            actual: target.read(&lc3),
+        })
+        .collect();
+    TestResult {
+        name: test.name.clone(),
+        outcome,
+        checks,
+        instructions: lc3.executed,
+        output: lc3.console.take_output(),
+        points: test.points,
+        duration: start.elapsed(),
+        trace: trace.into(),
+    }
+}
+
+// Runs one test case against an object image on a fresh machine. Panics are
+// caught so that a misbehaving run only ever affects its own result.
+pub fn run_test(image: &[u8], test: &TestCase) -> TestResult {
+    isolate(test, || execute(image, test))
+}
+
+fn isolate(test: &TestCase, run: impl FnOnce() -> TestResult) -> TestResult {
+    panic::catch_unwind(AssertUnwindSafe(run)).unwrap_or_else(|payload| {
+        let msg = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        TestResult {
+            name: test.name.clone(),
+            outcome: Outcome::Panic(msg),
+            checks: Vec::new(),
+            instructions: 0,
+            output: Vec::new(),
+            points: test.points,
+            duration: Duration::default(),
+            trace: Vec::new(),
+        }
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Submission {
+    pub name: String,
+    pub image: Vec<u8>,
+}
+
+// Every .obj file in `dir` is one submission, named after its file stem.
+pub fn load_submissions<P: AsRef<Path>>(dir: P) -> io::Result<Vec<Submission>> {
+    let mut submissions = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "obj") {
+            submissions.push(Submission {
+                name: path.file_stem().unwrap().to_string_lossy().into_owned(),
+                image: std::fs::read(&path)?,
+            });
+        }
+    }
+    submissions.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(submissions)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubmissionResult {
+    pub name: String,
+    pub results: Vec<TestResult>,
+}
+
+impl SubmissionResult {
+    pub fn score(&self) -> u32 {
+        self.results.iter().map(TestResult::earned).sum()
+    }
+}
+
+pub fn run_batch(submissions: &[Submission], spec: &TestSpec, threads: usize) -> Vec<SubmissionResult> {
+    let per_sub = spec.tests.len();
+    let jobs = submissions.len() * per_sub;
+    let next = AtomicUsize::new(0);
+    let slots: Vec<Mutex<Option<TestResult>>> = (0..jobs).map(|_| Mutex::new(None)).collect();
+
+    std::thread::scope(|s| {
+        for _ in 0..threads.max(1).min(jobs.max(1)) {
+            s.spawn(|| loop {
+                let job = next.fetch_add(1, Ordering::Relaxed);
+                if job >= jobs {
+                    break;
+                }
+                let result = run_test(&submissions[job / per_sub].image, &spec.tests[job % per_sub]);
+                *slots[job].lock().unwrap() = Some(result);
+            });
+        }
+    });
+
+    let mut results = slots.into_iter().map(|slot| slot.into_inner().unwrap().unwrap());
+    submissions
+        .iter()
+        .map(|sub| SubmissionResult {
+            name: sub.name.clone(),
+            results: results.by_ref().take(per_sub).collect(),
+        })
+        .collect()
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+pub fn write_csv<W: Write>(out: &mut W, spec: &TestSpec, results: &[SubmissionResult]) -> io::Result<()> {
+    let mut header = vec!["submission".to_string()];
+    header.extend(spec.tests.iter().map(|t| csv_field(&t.name)));
+    header.push("score".to_string());
+    header.push("total".to_string());
+    writeln!(out, "{}", header.join(","))?;
+
+    for sub in results {
+        let mut row = vec![csv_field(&sub.name)];
+        row.extend(sub.results.iter().map(|r| r.status().to_string()));
+        row.push(sub.score().to_string());
+        row.push(spec.total_points().to_string());
+        writeln!(out, "{}", row.join(","))?;
+    }
+    Ok(())
+}
+
+pub fn gradebook_json(spec: &TestSpec, results: &[SubmissionResult]) -> Json {
+    let tests = spec
+        .tests
+        .iter()
+        .map(|t| Json::object(vec![("name", Json::from(t.name.as_str())), ("points", Json::from(t.points))]))
+        .collect::<Vec<_>>();
+    let submissions = results
+        .iter()
+        .map(|sub| {
+            let runs = sub
+                .results
+                .iter()
+                .map(|r| {
+                    Json::object(vec![
+                        ("test", Json::from(r.name.as_str())),
+                        ("status", Json::from(r.status())),
+                        ("points", Json::from(r.earned())),
+                        ("instructions", Json::from(r.instructions)),
+                        ("message", Json::from(r.outcome.to_string())),
+                    ])
+                })
+                .collect::<Vec<_>>();
+            Json::object(vec![
+                ("name", Json::from(sub.name.as_str())),
+                ("score", Json::from(sub.score())),
+                ("results", Json::from(runs)),
+            ])
+        })
+        .collect::<Vec<_>>();
+    Json::object(vec![
+        ("total", Json::from(spec.total_points())),
+        ("tests", Json::from(tests)),
+        ("submissions", Json::from(submissions)),
+    ])
+}
+
+pub fn write_json<W: Write>(out: &mut W, spec: &TestSpec, results: &[SubmissionResult]) -> io::Result<()> {
+    writeln!(out, "{}", gradebook_json(spec, results))
+}
+
+#[allow(clippy::unusual_byte_groupings)]
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::time::Duration;
+
+    use super::{isolate, run_batch, run_limited, run_test, write_csv, Outcome, Submission, Target, TestSpec, Value};
+    use crate::console::Console;
+    use crate::device::Keyboard;
+    use crate::{Fault, LC3};
+
+    const SPEC: &str = r#"
+        limit 5000 ; applies to every test
+        test doubles R0
+        set R0 #21
+        expect R0 #42
+        expect output "*"
+        points 2
+
+        test keeps x3100
+        set x3100 xBEEF
+        expect x3100 xBEEF
+    "#;
+
+    fn obj(origin: u16, words: &[u16]) -> Vec<u8> {
+        std::iter::once(origin)
+            .chain(words.iter().copied())
+            .flat_map(u16::to_be_bytes)
+            .collect()
+    }
+
+    fn doubler() -> Vec<u8> {
+        obj(
+            0x3000,
+            &[
+                0b0001_000_000_0_00_000, // R0 <- R0 + R0
+                0b0001_100_000_1_00000,  // saves R0 in R4
+                0b0010_000_000000011,    // loads '*' to R0
+                0b1111_0000_00100001,    // OUT
+                0b0001_000_100_1_00000,  // restores R0
+                0b1111_0000_00100101,    // HALT
+                b'*' as u16,
+            ],
+        )
+    }
+
+    #[test]
+    fn test_parse_spec() {
+        let spec = TestSpec::parse(SPEC).unwrap();
+        assert_eq!(spec.tests.len(), 2);
+        assert_eq!(spec.tests[0].name, "doubles R0");
+        assert_eq!(spec.tests[0].setup, vec![(Target::Reg(0), 21)]);
+        assert_eq!(spec.tests[0].checks[1], (Target::Output, Value::Text(b"*".to_vec())));
+        assert_eq!(spec.tests[0].points, 2);
+        assert_eq!(spec.tests[1].limits.max_instructions, 5000);
+        assert_eq!(spec.total_points(), 3);
+
+        let err = TestSpec::parse("test a\nexpect R9 #1").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert!(TestSpec::parse("set R0 #1").is_err());
+
+        let spec = TestSpec::parse("test a\nset R0 #-1\nexpect x3000 xFFFF").unwrap();
+        assert_eq!(spec.tests[0].setup, vec![(Target::Reg(0), 0xFFFF)]);
+        let err = TestSpec::parse("test a\n\nset R0 70000").unwrap_err();
+        assert_eq!((err.line, err.message.as_str()), (3, "70000 does not fit in 16 bits"));
+        assert_eq!(TestSpec::parse("test a\nexpect R0 x-8001").unwrap_err().line, 2);
+        let err = TestSpec::parse("test a\npoints -1").unwrap_err();
+        assert_eq!((err.line, err.message.as_str()), (2, "points cannot be negative, got -1"));
+    }
+
+    #[test]
+    fn test_run_test_pass_and_fail() {
+        let mut spec = TestSpec::parse(SPEC).unwrap();
+        let result = run_test(&doubler(), &spec.tests[0]);
+        assert_eq!(result.outcome, Outcome::Halted);
+        assert!(result.passed(), "{:?}", result);
+        assert_eq!(result.output, b"*");
+
+        spec.tests[0].checks[0].1 = Value::Word(41);
+        let result = run_test(&doubler(), &spec.tests[0]);
+        assert!(!result.passed());
+        assert_eq!(result.status(), "fail");
+        assert_eq!(result.checks[0].actual, Value::Word(42));
+    }
+
+    #[test]
+    fn test_trace_follows_interrupts() {
+        // the handler takes the key and returns
+        let mut lc3 = LC3 {
+            console: Console::buffer(b"k"),
+            ..LC3::default()
+        };
+        lc3.load_obj(&obj(0x3000, &[0b0001_001_001_1_00001, 0b1111_0000_00100101])).unwrap();
+        lc3.load_obj(&obj(0x1000, &[0b1010_000_000000001, 0b1000_000000000000, 0xFE02])).unwrap();
+        lc3.pc = 0x3000;
+        lc3.memory[0x0180] = 0x1000;
+        lc3.devices.get_mut::<Keyboard>().unwrap().interrupt_enable = true;
+
+        let mut test = TestSpec::parse(SPEC).unwrap().tests.remove(1);
+        test.trace_len = 8;
+        let mut trace = VecDeque::new();
+        assert_eq!(run_limited(&mut lc3, &test, &mut trace), Outcome::Halted);
+        let ran: Vec<(u16, u16)> = trace.iter().map(|t| (t.pc, t.word)).collect();
+        assert_eq!(ran, [(0x1000, 0xA001), (0x1001, 0x8000), (0x3000, 0x1261), (0x3001, 0xF025)]);
+        assert_eq!(trace[0].registers[0], b'k' as i16);
+    }
+
+    #[test]
+    fn test_panics_are_caught() {
+        let spec = TestSpec::parse(SPEC).unwrap();
+        let result = isolate(&spec.tests[0], || panic!("decoder bug"));
+        assert_eq!(result.outcome, Outcome::Panic("decoder bug".to_string()));
+        assert_eq!((result.status(), result.earned(), result.points), ("panic", 0, 2));
+        // and the next run is unaffected
+        assert!(run_test(&doubler(), &spec.tests[0]).passed());
+    }
+
+    #[test]
+    fn test_batch_isolates_runs() {
+        let mut spec = TestSpec::parse(SPEC).unwrap();
+        spec.tests[1].limits.timeout = Duration::from_millis(50);
+        spec.tests[1].limits.max_instructions = u64::MAX;
+
+        let submissions = vec![
+            Submission { name: "good".to_string(), image: doubler() },
+            Submission { name: "loops".to_string(), image: obj(0x3000, &[0b0000_111_111111111]) },
+            Submission { name: "traps".to_string(), image: obj(0x3000, &[0b1111_0000_01000000]) },
+            Submission { name: "rti".to_string(), image: obj(0x3000, &[0b1000_000000000000]) },
+            Submission { name: "empty".to_string(), image: Vec::new() },
+        ];
+        let results = run_batch(&submissions, &spec, 4);
+
+        assert_eq!(results[0].score(), 3);
+        assert_eq!(results[1].results[0].outcome, Outcome::StepLimit);
+        assert_eq!(results[1].results[1].outcome, Outcome::Timeout);
+        assert_eq!(results[2].results[0].outcome, Outcome::Fault(Fault::UnknownTrap(0x40)));
+        assert_eq!(results[3].results[0].outcome, Outcome::Fault(Fault::PrivilegeViolation));
+        assert_eq!(results[4].results[0].status(), "load-error");
+
+        let mut csv = Vec::new();
+        write_csv(&mut csv, &spec, &results).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("submission,doubles R0,keeps x3100,score,total"));
+        assert_eq!(lines.next(), Some("good,pass,pass,3,3"));
+        assert_eq!(lines.next(), Some("loops,step-limit,timeout,0,3"));
+        assert_eq!(lines.next(), Some("traps,fault,fault,0,3"));
+    }
+}
@@ -1,22 +1,127 @@
+pub mod aot;
+pub mod asm;
+pub mod builder;
+pub mod cache;
+pub mod console;
+pub mod dap;
+pub mod device;
+pub mod disk;
+pub mod files;
+pub mod gdb;
+pub mod grader;
+mod json;
+pub mod link;
+pub mod lsp;
+pub mod micro;
+pub mod microcode;
 pub mod opcodes;
+pub mod os;
+pub mod pipeline;
+mod preprocess;
+pub mod replay;
+pub mod report;
+pub mod serial;
+pub mod timer;
+pub mod timing;
+pub mod traps;
 mod utils;
+pub mod vcd;
+pub mod video;
 
-use std::io::{BufRead, Read, Write};
+// Code translated by `aot` names the crate as its users would, and the
+// tests compile some in.
+#[cfg(test)]
+extern crate self as lc3_tools;
+
+use std::convert::TryInto;
+use std::fmt::Display;
+use std::path::Path;
 
+use console::Console;
 use opcodes::Inst;
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    IllegalOpcode(u16),
+    PrivilegeViolation,
+    AccessViolation(u16),
+    UnknownTrap(u8),
+    // a Rust trap handler gave up, saying why
+    Trap(u8, String),
+    EndOfInput,
+    // a replayed run stopped following its log
+    Diverged(String),
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fault::IllegalOpcode(raw) => write!(f, "illegal opcode in x{:04X}", raw),
+            Fault::PrivilegeViolation => write!(f, "privilege mode violation"),
+            Fault::AccessViolation(addr) => write!(f, "access control violation at x{:04X}", addr),
+            Fault::UnknownTrap(vect) => write!(f, "unknown trap x{:02X}", vect),
+            Fault::Trap(vect, message) => write!(f, "trap x{:02X} failed: {}", vect, message),
+            Fault::EndOfInput => write!(f, "console input exhausted"),
+            Fault::Diverged(message) => write!(f, "replay diverged: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Fault {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    Halted,
+    StepLimit,
+    Fault(Fault),
+}
+
 pub struct LC3 {
-    pub memory: [u16; 65536],
+    pub memory: Box<[u16; 65536]>,
     pub registers: [i16; 8],
     pub pc: u16,
     pub supervisor: bool,
     pub priority: u8,
     pub condition: utils::Condition,
     pub halted: bool,
+    pub executed: u64,
+    // clock cycles, as `cost` has them
+    pub cycles: u64,
+    pub cost: timing::CostModel,
+    pub console: Console,
+    // R6 for whichever of the two stacks is not in use
+    pub saved_ssp: u16,
+    pub saved_usp: u16,
+    pub traps: traps::Traps,
+    // set by any store to video memory, for renderers to clear
+    pub video_dirty: bool,
+    pub devices: device::Bus,
+    // set while recording a run or replaying one
+    pub journal: Option<replay::Journal>,
+    // set to collect every fetch and data access, for `cache` to take
+    pub accesses: Option<Vec<cache::Access>>,
 }
 
+const EXCEPTION_TABLE: u16 = 0x0100;
+
 impl LC3 {
-    pub fn run_instruction(&mut self, inst: Inst) {
+    // A machine with the bundled OS loaded and booted, sitting in user mode
+    // at x3000 with nothing loaded there yet. Traps go to the OS rather than
+    // the built in Rust services.
+    pub fn with_os() -> LC3 {
+        let mut lc3 = LC3::default();
+        lc3.traps.clear();
+        os::image().load_into(&mut lc3);
+        lc3.pc = os::BOOT;
+        lc3.supervisor = true;
+        while lc3.supervisor && !lc3.halted {
+            lc3.run_step().expect("the bundled OS failed to boot");
+        }
+        lc3.executed = 0;
+        lc3
+    }
+
+    pub fn run_instruction(&mut self, inst: Inst) -> Result<(), Fault> {
         macro_rules! reg {
             [$v:expr] => {
                 self.registers[$v as usize]
@@ -25,13 +130,14 @@ impl LC3 {
 
         macro_rules! mem {
             [r, $v:expr] => {
-                self.memory[$v as usize] as i16
+                self.read($v as u16)? as i16
             };
-            [w, $v:expr] => {
-                self.memory[$v as usize]
+            [w, $v:expr, $val:expr] => {
+                self.write($v as u16, $val as u16)?
             }
         }
 
+        self.cycles += self.cost.instruction(&inst);
         match inst {
             Inst::ADD { dr, sr1, sr2 } => {
                 reg![dr] = reg![sr1].wrapping_add(reg![sr2]);
@@ -52,25 +158,27 @@ impl LC3 {
             Inst::BR { cond, pc_offset } => {
                 if self.condition.is_satisfied_by(&cond) {
                     self.pc = self.pc.wrapping_add(pc_offset as u16);
+                    self.cycles += self.cost.branch_taken;
                 }
             }
             Inst::JMP { base_r } => {
-                self.pc = self.pc.wrapping_add(reg![base_r] as u16);
+                self.pc = reg![base_r] as u16;
             }
             Inst::JSR { pc_offset } => {
                 reg![7] = self.pc as i16;
                 self.pc = self.pc.wrapping_add(pc_offset as u16);
             }
             Inst::JSRr { base_r } => {
+                let target = reg![base_r] as u16;
                 reg![7] = self.pc as i16;
-                self.pc = reg![base_r] as u16;
+                self.pc = target;
             }
             Inst::LD { dr, pc_offset } => {
                 reg![dr] = mem![r, self.pc.wrapping_add(pc_offset as u16)];
                 self.set_condition(reg![dr]);
             }
             Inst::LDI { dr, pc_offset } => {
-                let mem_loc = self.pc.wrapping_add(pc_offset as u16);
+                let mem_loc = mem![r, self.pc.wrapping_add(pc_offset as u16)];
                 reg![dr] = mem![r, mem_loc];
                 self.set_condition(reg![dr]);
             }
@@ -87,85 +195,223 @@ impl LC3 {
                 self.set_condition(reg![dr]);
             }
             Inst::RTI => {
-                unimplemented!();
+                if !self.supervisor {
+                    return Err(Fault::PrivilegeViolation);
+                }
+                let sp = reg![6] as u16;
+                let pc = mem![r, sp] as u16;
+                let psr = mem![r, sp.wrapping_add(1)] as u16;
+                self.pc = pc;
+                reg![6] = sp.wrapping_add(2) as i16;
+                self.set_psr(psr);
+                if !self.supervisor {
+                    self.saved_ssp = reg![6] as u16;
+                    reg![6] = self.saved_usp as i16;
+                }
             }
             Inst::ST { sr, pc_offset } => {
-                mem![w, self.pc.wrapping_add(pc_offset as u16)] = reg![sr] as u16;
+                mem![w, self.pc.wrapping_add(pc_offset as u16), reg![sr]];
             }
             Inst::STI { sr, pc_offset } => {
-                mem![w, mem![r, self.pc.wrapping_add(pc_offset as u16)]] = reg![sr] as u16;
+                let mem_loc = mem![r, self.pc.wrapping_add(pc_offset as u16)];
+                mem![w, mem_loc, reg![sr]];
             }
             Inst::STR { sr, base_r, offset } => {
-                mem![w, reg![base_r].wrapping_add(offset)] = reg![sr] as u16;
+                mem![w, reg![base_r].wrapping_add(offset), reg![sr]];
             }
-            Inst::TRAP { trap_vect } => match trap_vect {
-                // GETC
-                0x20 => {
-                    let mut c = [0; 1];
-                    std::io::stdin().lock().read_exact(&mut c).unwrap();
-                    reg![0] = c[0] as i16;
-                },
-                // OUT
-                0x21 => {
-                    let mut c = [0; 1];
-                    c[0] = reg![0] as u8;
-                    std::io::stdout().lock().write_all(&c).unwrap();
-                    std::io::stdout().flush().unwrap();
-                },
-                // PUTS
-                0x22 => {
-                    let mut buf = Vec::new();
-                    let mut spot = reg![0] as u16;
-                    while mem![r, spot] != 0x0000 {
-                        buf.push(mem![r, spot] as u8);
-                        spot += 1;
-                    }
-                    std::io::stdout().lock().write_all(&buf).unwrap();
-                },
-                // IN
-                0x23 => {
-                    print!("Input one character: ");
-                    std::io::stdout().flush().unwrap();
-                    let mut buf = String::new();
-                    std::io::stdin().lock().read_line(&mut buf).unwrap();
-                    reg![0] = buf.bytes().next().unwrap() as i16;
-                },
-                // PUTSP
-                0x24 => {
-                    let mut buf = Vec::new();
-                    let mut spot = reg![0] as u16;
-                    while mem![r, spot] != 0x0000 {
-                        buf.push(mem![r, spot] as u8);
-                        if mem![r, spot] >> 8 != 0x0000 {
-                            buf.push((mem![r, spot] >> 8) as u8);
-                        }
-                        spot += 1;
-                    }
-                    std::io::stdout().lock().write_all(&buf).unwrap();
-                },
-                // HALT
-                0x25 => {
-                    self.halted = true;
-                    println!("LC3 Halted");
+            Inst::TRAP { trap_vect } => {
+                let vect = trap_vect as u8;
+                match self.native_trap(vect) {
+                    Some(result) => result?,
+                    None if self.memory[vect as usize] != 0 => self.interrupt(vect as u16, None),
+                    None => return Err(Fault::UnknownTrap(vect)),
                 }
-                _ => unimplemented!(),
-            },
+            }
         };
+        Ok(())
+    }
+
+    pub fn run_step(&mut self) -> Result<(), Fault> {
+        if let Some((vector, priority)) = self.next_interrupt() {
+            self.cycles += self.cost.interrupt;
+            self.interrupt(vector, Some(priority));
+        }
+        let result = self.fetch_and_run();
+        self.end_step(result)
+    }
+
+    fn fetch_and_run(&mut self) -> Result<(), Fault> {
+        let raw = self.fetch()?;
+        // 1101 is reserved and has no decoding
+        if raw >> 12 == 0b1101 {
+            return Err(Fault::IllegalOpcode(raw));
+        }
+        self.run_instruction(Inst::from(raw))
     }
 
-    pub fn run_step(&mut self) {
-        let inst = Inst::from(self.memory[self.pc as usize]);
+    // Reads the instruction at the PC and moves past it.
+    fn fetch(&mut self) -> Result<u16, Fault> {
+        self.cycles += self.cost.fetch + self.cost.memory;
+        self.check_access(self.pc)?;
+        self.observe(cache::Kind::Fetch, self.pc);
+        let raw = self.memory[self.pc as usize];
         self.pc = self.pc.wrapping_add(1);
-        self.run_instruction(inst);
+        self.executed += 1;
+        Ok(raw)
+    }
+
+    fn end_step(&mut self, result: Result<(), Fault>) -> Result<(), Fault> {
+        self.tick_devices();
+        match result {
+            Err(fault) => self.exception(fault),
+            ok => ok,
+        }
+    }
+
+    // Hands a fault to the OS if it has installed a handler for it.
+    fn exception(&mut self, fault: Fault) -> Result<(), Fault> {
+        let vector = match fault {
+            Fault::PrivilegeViolation => 0,
+            Fault::IllegalOpcode(_) => 1,
+            Fault::AccessViolation(_) => 2,
+            _ => return Err(fault),
+        };
+        if self.memory[(EXCEPTION_TABLE + vector) as usize] == 0 {
+            return Err(fault);
+        }
+        self.cycles += self.cost.exception;
+        self.interrupt(EXCEPTION_TABLE + vector, None);
+        Ok(())
+    }
+
+    // Switches to the supervisor stack, pushes the PSR and PC for RTI and
+    // jumps through `vector`. Traps and exceptions keep the current priority.
+    fn interrupt(&mut self, vector: u16, priority: Option<u8>) {
+        let psr = self.psr();
+        // two pushes and a read of the table
+        self.cycles += self.cost.entry + 3 * self.cost.memory;
+        if !self.supervisor {
+            self.cycles += self.cost.stack_switch;
+            self.saved_usp = self.registers[6] as u16;
+            self.registers[6] = self.saved_ssp as i16;
+        }
+        self.supervisor = true;
+        if let Some(priority) = priority {
+            self.priority = priority;
+        }
+        let sp = (self.registers[6] as u16).wrapping_sub(2);
+        self.memory[sp.wrapping_add(1) as usize] = psr;
+        self.memory[sp as usize] = self.pc;
+        self.registers[6] = sp as i16;
+        self.pc = self.memory[vector as usize];
+        self.observe(cache::Kind::Write, sp.wrapping_add(1));
+        self.observe(cache::Kind::Write, sp);
+        self.observe(cache::Kind::Read, vector);
+    }
+
+    fn observe(&mut self, kind: cache::Kind, addr: u16) {
+        if let Some(accesses) = &mut self.accesses {
+            accesses.push(cache::Access { kind, addr });
+        }
+    }
+
+    // User mode may not touch the OS or the device registers, though this is
+    // only enforced once an OS has installed a handler for it.
+    fn check_access(&self, addr: u16) -> Result<(), Fault> {
+        let protected = !(0x3000..0xFE00).contains(&addr);
+        if protected && !self.supervisor && self.memory[(EXCEPTION_TABLE + 2) as usize] != 0 {
+            return Err(Fault::AccessViolation(addr));
+        }
+        Ok(())
     }
 
-    pub fn run(&mut self) {
+    // A data read, going to whichever device is attached at `addr`.
+    pub fn read(&mut self, addr: u16) -> Result<u16, Fault> {
+        self.cycles += self.cost.memory;
+        self.check_access(addr)?;
+        self.observe(cache::Kind::Read, addr);
+        match self.device_read(addr) {
+            Some(result) => result,
+            None => Ok(self.memory[addr as usize]),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u16) -> Result<(), Fault> {
+        self.cycles += self.cost.memory;
+        self.check_access(addr)?;
+        self.observe(cache::Kind::Write, addr);
+        if let Some(result) = self.device_write(addr, val) {
+            return result;
+        }
+        self.memory[addr as usize] = val;
+        if (video::START..video::END).contains(&addr) {
+            self.video_dirty = true;
+        }
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> Result<(), Fault> {
         while !self.halted {
-            self.run_step();
+            self.run_step()?;
+        }
+        Ok(())
+    }
+
+    pub fn run_for(&mut self, max_steps: u64) -> Stop {
+        for _ in 0..max_steps {
+            if self.halted {
+                return Stop::Halted;
+            }
+            if let Err(fault) = self.run_step() {
+                return Stop::Fault(fault);
+            }
+        }
+        if self.halted {
+            Stop::Halted
+        } else {
+            Stop::StepLimit
+        }
+    }
+
+    // Loads an object file (big-endian words, the first being the origin)
+    // and points the PC at its origin.
+    pub fn load_obj(&mut self, bytes: &[u8]) -> std::io::Result<u16> {
+        let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string());
+        if bytes.len() < 2 || !bytes.len().is_multiple_of(2) {
+            return Err(invalid("object file must contain an even number of bytes"));
+        }
+        let mut words = bytes.chunks(2).map(|w| u16::from_be_bytes([w[0], w[1]]));
+        let origin = words.next().unwrap();
+        let words: Vec<u16> = words.collect();
+        if origin as usize + words.len() > self.memory.len() {
+            return Err(invalid("object file does not fit in memory"));
         }
+        self.memory[origin as usize..origin as usize + words.len()].copy_from_slice(&words);
+        self.pc = origin;
+        Ok(origin)
+    }
+
+    pub fn load_obj_file<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<u16> {
+        self.load_obj(&std::fs::read(path)?)
     }
 
-    fn set_condition(&mut self, val: i16) {
+    pub fn psr(&self) -> u16 {
+        ((!self.supervisor as u16) << 15)
+            | ((self.priority as u16 & 0b111) << 8)
+            | ((self.condition.n as u16) << 2)
+            | ((self.condition.z as u16) << 1)
+            | self.condition.p as u16
+    }
+
+    pub fn set_psr(&mut self, psr: u16) {
+        self.supervisor = psr >> 15 == 0;
+        self.priority = ((psr >> 8) & 0b111) as u8;
+        self.condition.n = (psr >> 2) & 1 == 1;
+        self.condition.z = (psr >> 1) & 1 == 1;
+        self.condition.p = psr & 1 == 1;
+    }
+
+    pub fn set_condition(&mut self, val: i16) {
         self.condition.n = val < 0;
         self.condition.z = val == 0;
         self.condition.p = val > 0;
@@ -175,13 +421,25 @@ impl LC3 {
 impl Default for LC3 {
     fn default() -> Self {
         LC3 {
-            memory: [0; 65536],
+            // built on the heap, a 128K array is too big to pass around by value
+            memory: vec![0; 65536].into_boxed_slice().try_into().unwrap(),
             registers: [0; 8],
             pc: 0x3000,
             supervisor: false,
             priority: 0,
             condition: utils::Condition::default(),
             halted: false,
+            executed: 0,
+            cycles: 0,
+            cost: timing::CostModel::default(),
+            console: Console::default(),
+            saved_ssp: 0x3000,
+            saved_usp: 0xFE00,
+            traps: traps::Traps::builtin(),
+            video_dirty: false,
+            devices: device::Bus::builtin(),
+            journal: None,
+            accesses: None,
         }
     }
 }
@@ -189,7 +447,7 @@ impl Default for LC3 {
 #[allow(clippy::unusual_byte_groupings)]
 #[cfg(test)]
 mod tests {
-    use crate::{opcodes::Inst, utils::Condition, LC3};
+    use crate::{lc3, opcodes::Inst, LC3};
 
     const LAB1PART1: [u16; 19] = [
         0b0010_000_011111111,   // loads X to R0
@@ -246,7 +504,7 @@ mod tests {
         let mut lc3 = load_lc3(LC3::default(), &LAB1PART1, 0x3000);
         lc3.memory[0x3100] = 12;
         lc3.memory[0x3101] = 10;
-        lc3.run();
+        lc3.run().unwrap();
         assert_eq!(lc3.memory[0x3102], 0xFFFF);
     }
 
@@ -255,7 +513,7 @@ mod tests {
         let mut lc3 = load_lc3(LC3::default(), &LAB1PART1, 0x3000);
         lc3.memory[0x3100] = !1 + 1;
         lc3.memory[0x3101] = !1 + 1;
-        lc3.run();
+        lc3.run().unwrap();
         assert_eq!(lc3.memory[0x3102], 0x0000);
     }
 
@@ -264,7 +522,7 @@ mod tests {
         let mut lc3 = load_lc3(LC3::default(), &LAB1PART1, 0x3000);
         lc3.memory[0x3100] = !10 + 1;
         lc3.memory[0x3101] = 10;
-        lc3.run();
+        lc3.run().unwrap();
         assert_eq!(lc3.memory[0x3102], 0x0001);
     }
 
@@ -272,7 +530,7 @@ mod tests {
     fn test_run_ee306_lab_1_part_2_tc_1() {
         let mut lc3 = load_lc3(LC3::default(), &LAB1PART2, 0x3000);
         lc3.memory[0x3100] = 0xFFFF;
-        lc3.run();
+        lc3.run().unwrap();
         assert_eq!(lc3.memory[0x3101], 0);
     }
 
@@ -280,7 +538,7 @@ mod tests {
     fn test_run_ee306_lab_1_part_2_tc_2() {
         let mut lc3 = load_lc3(LC3::default(), &LAB1PART2, 0x3000);
         lc3.memory[0x3100] = 0xF0FF;
-        lc3.run();
+        lc3.run().unwrap();
         for i in 0x3000..lc3.pc as usize {
             println!(
                 "{:04x}: {:08b} {}",
@@ -297,10 +555,52 @@ mod tests {
     fn test_run_ee306_lab_1_part_2_tc_3() {
         let mut lc3 = load_lc3(LC3::default(), &LAB1PART2, 0x3000);
         lc3.memory[0x3100] = 0x0000;
-        lc3.run();
+        lc3.run().unwrap();
         assert_eq!(lc3.memory[0x3101], 16);
     }
 
+    #[test]
+    fn test_jmp_goes_to_the_register() {
+        // JMP R2
+        let mut lc3 = load_lc3(LC3::default(), &[0b1100_000_010_000000], 0x3000);
+        lc3.registers[2] = 0x3050;
+        lc3.run_step().unwrap();
+        assert_eq!(lc3.pc, 0x3050);
+    }
+
+    #[test]
+    fn test_ldi_loads_through_the_pointer() {
+        // LDI R1, #1
+        let mut lc3 = load_lc3(LC3::default(), &[0b1010_001_000000001], 0x3000);
+        lc3.memory[0x3002] = 0x3100;
+        lc3.memory[0x3100] = 42;
+        lc3.run_step().unwrap();
+        assert_eq!(lc3.registers[1], 42);
+        assert!(lc3.condition.p);
+    }
+
+    #[test]
+    fn test_subroutine_calls() {
+        let program = lc3! {
+            .ORIG 0x3000;
+            JSR double;
+            LEA R5, double;
+            JSRR R5;
+            ST R0, result;
+            HALT;
+        double: ADD R0, R0, R0;
+            ADD R0, R0, #1;
+            RET;
+        result: .BLKW 1;
+        }
+        .unwrap();
+        let mut lc3 = LC3::default();
+        program.load_into(&mut lc3);
+        lc3.run().unwrap();
+        assert_eq!(lc3.memory[program.symbols["result"].addr as usize], 3);
+        assert_eq!(lc3.registers[7], 0x3003);
+    }
+
     fn load_lc3(mut vm: LC3, code: &[u16], start: usize) -> LC3 {
         vm.memory[start..(code.len() + start)].clone_from_slice(code);
         vm
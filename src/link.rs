@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use crate::asm::{Program, RelocKind, Section};
+
+// One relocatable object going into a link. `origin` moves the whole module
+// so that its entry lands there; None leaves it where it was assembled.
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub name: String,
+    pub program: Program,
+    pub origin: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkError {
+    pub module: String,
+    pub message: String,
+}
+
+impl Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.module, self.message)
+    }
+}
+
+// Places each module, checks nothing overlaps and patches every reference to
+// an .EXTERNAL name. Every label is visible to the other modules; a name
+// defined twice is only a problem if something refers to it. The entry point
+// is the first module's.
+pub fn link(modules: &[Module]) -> Result<Program, Vec<LinkError>> {
+    let mut errors = Vec::new();
+    let mut fail = |module: &Module, message: String| {
+        errors.push(LinkError {
+            module: module.name.clone(),
+            message,
+        })
+    };
+
+    let mut placed = Vec::new();
+    for module in modules {
+        let program = &module.program;
+        let delta = module.origin.map_or(0, |o| o as i32 - program.entry() as i32);
+        let mut moved = program.clone();
+        let mut fits = true;
+        for s in &mut moved.sections {
+            let origin = s.origin as i32 + delta;
+            if origin < 0 || origin as usize + s.words.len() > 0x10000 {
+                fail(module, format!("section at x{:04X} does not fit in memory when moved", s.origin));
+                fits = false;
+                break;
+            }
+            s.origin = origin as u16;
+        }
+        if !fits {
+            continue;
+        }
+        for symbol in moved.symbols.values_mut() {
+            symbol.addr = (symbol.addr as i32 + delta) as u16;
+        }
+        for r in &mut moved.relocations {
+            r.addr = (r.addr as i32 + delta) as u16;
+            if r.symbol.is_none() {
+                r.addend += delta;
+                let word = (r.addend & 0xFFFF) as u16;
+                set_word(&mut moved.sections, r.addr, |_| word);
+            }
+        }
+        placed.push((module, moved));
+    }
+
+    let mut ranges: Vec<(u16, usize, &str)> = placed
+        .iter()
+        .flat_map(|(m, p)| p.sections.iter().map(move |s| (s.origin, s.origin as usize + s.words.len(), &*m.name)))
+        .collect();
+    ranges.sort();
+    for pair in ranges.windows(2) {
+        let ((_, end, first), (start, _, second)) = (pair[0], pair[1]);
+        if (start as usize) < end {
+            let module = placed.iter().find(|(m, _)| m.name == second).unwrap().0;
+            fail(module, format!("x{:04X} overlaps {}", start, first));
+        }
+    }
+
+    let mut defined: BTreeMap<String, Vec<(&str, u16)>> = BTreeMap::new();
+    for &(module, ref program) in &placed {
+        for (name, symbol) in &program.symbols {
+            defined.entry(name.clone()).or_default().push((&module.name, symbol.addr));
+        }
+    }
+
+    for (module, program) in &mut placed {
+        let relocations = std::mem::take(&mut program.relocations);
+        for r in relocations {
+            let name = match &r.symbol {
+                Some(name) => name,
+                None => continue,
+            };
+            let addr = match defined.get(name).map(Vec::as_slice) {
+                Some([(_, addr)]) => *addr,
+                Some([(a, _), (b, _), ..]) => {
+                    fail(module, format!("'{}' is defined in both {} and {}", name, a, b));
+                    continue;
+                }
+                _ => {
+                    fail(module, format!("undefined external '{}'", name));
+                    continue;
+                }
+            };
+            let target = addr as i32 + r.addend;
+            let bits = match r.kind {
+                RelocKind::PcOffset9 => 9,
+                RelocKind::PcOffset11 => 11,
+                RelocKind::Fill => {
+                    set_word(&mut program.sections, r.addr, |_| (target & 0xFFFF) as u16);
+                    continue;
+                }
+            };
+            let offset = target - (r.addr as i32 + 1);
+            if offset < -(1 << (bits - 1)) || offset >= 1 << (bits - 1) {
+                let message = format!(
+                    "'{}' is {} words away, which does not fit in a {}-bit offset",
+                    name, offset, bits
+                );
+                fail(module, message);
+                continue;
+            }
+            let mask = (1 << bits) - 1;
+            set_word(&mut program.sections, r.addr, |w| (w & !mask) | (offset as u16 & mask));
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let mut linked = Program::default();
+    for (_, program) in placed {
+        linked.sections.extend(program.sections);
+        for (name, symbol) in program.symbols {
+            linked.symbols.entry(name).or_insert(symbol);
+        }
+    }
+    Ok(linked)
+}
+
+fn set_word(sections: &mut [Section], addr: u16, f: impl FnOnce(u16) -> u16) {
+    if let Some(s) = sections.iter_mut().find(|s| s.contains(addr)) {
+        let at = (addr - s.origin) as usize;
+        s.words[at] = f(s.words[at]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::console::Console;
+    use crate::LC3;
+
+    const MAIN: &str = r#"
+        .ORIG x3000
+        .EXTERNAL PRINT, GREETING
+        LEA R0, GREETING
+        JSR PRINT
+        LD R0, PTR
+        PUTS
+        HALT
+PTR     .FILL GREETING+3
+        .END
+"#;
+
+    const LIB: &str = r#"
+        .ORIG x5000
+PRINT   ST R7, SAVE
+        PUTS
+        LD R7, SAVE
+        RET
+SAVE    .BLKW 1
+TABLE   .FILL GREETING
+GREETING .STRINGZ "hello\n"
+        .END
+"#;
+
+    fn module(name: &str, src: &str, origin: Option<u16>) -> Module {
+        Module {
+            name: name.to_string(),
+            program: Program::from_rel(&assemble(src).unwrap().to_rel()).unwrap(),
+            origin,
+        }
+    }
+
+    #[test]
+    fn test_link_and_run() {
+        let main = module("main", MAIN, None);
+        assert_eq!(main.program.relocations.len(), 3);
+        let program = link(&[main, module("lib", LIB, Some(0x3010))]).unwrap();
+        assert_eq!(program.entry(), 0x3000);
+        assert_eq!(program.symbols["GREETING"].addr, 0x3016);
+        assert_eq!(program.sections[1].words[5], 0x3016);
+        assert_eq!(program.sections[0].words[5], 0x3019);
+
+        let mut lc3 = LC3 {
+            console: Console::buffer(&[]),
+            ..LC3::default()
+        };
+        program.load_into(&mut lc3);
+        lc3.run().unwrap();
+        assert_eq!(lc3.console.output(), b"hello\nlo\n");
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let errors = link(&[module("main", MAIN, None)]).unwrap_err();
+        assert_eq!(errors[0].to_string(), "main: undefined external 'GREETING'");
+
+        let far = link(&[module("main", MAIN, None), module("lib", LIB, Some(0x4000))]).unwrap_err();
+        assert_eq!(
+            far[0].message,
+            "'GREETING' is 4101 words away, which does not fit in a 9-bit offset"
+        );
+
+        let overlap = link(&[module("main", MAIN, None), module("lib", LIB, Some(0x3004))]).unwrap_err();
+        assert_eq!(overlap[0].to_string(), "lib: x3004 overlaps main");
+
+        let twice = link(&[
+            module("main", MAIN, None),
+            module("a", LIB, Some(0x3100)),
+            module("b", LIB, Some(0x3200)),
+        ])
+        .unwrap_err();
+        assert_eq!(twice[0].message, "'GREETING' is defined in both a and b");
+    }
+}
@@ -0,0 +1,544 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+use crate::asm::{self, Diagnostic, Program, Token, DIRECTIVES, OPCODES, TRAP_ALIASES};
+use crate::json::{self, Json};
+
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_REQUEST: i32 = -32600;
+const INVALID_PARAMS: i32 = -32602;
+
+// CompletionItemKind values from the LSP spec.
+const KIND_FUNCTION: u8 = 3;
+const KIND_VARIABLE: u8 = 6;
+const KIND_KEYWORD: u8 = 14;
+
+// Encoded words shown on hover before the rest are summarised.
+const HOVER_WORDS: usize = 8;
+
+const BRANCHES: &[&str] = &["BRn", "BRz", "BRp", "BRnz", "BRnp", "BRzp", "BRnzp"];
+
+// LSP columns count UTF-16 units while tokens carry byte offsets.
+fn utf16_col(line: &str, byte: usize) -> usize {
+    line[..byte].encode_utf16().count()
+}
+
+fn byte_col(line: &str, col: usize) -> usize {
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= col {
+            return i;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+fn position(line: usize, character: usize) -> Json {
+    Json::object(vec![("line", Json::from(line)), ("character", Json::from(character))])
+}
+
+// A range on 1-based source line `line` covering bytes `start..end`.
+fn range(text: &str, line: usize, start: usize, end: usize) -> Json {
+    let src = text.lines().nth(line - 1).unwrap_or("");
+    let (start, end) = (start.min(src.len()), end.min(src.len()));
+    Json::object(vec![
+        ("start", position(line - 1, utf16_col(src, start))),
+        ("end", position(line - 1, utf16_col(src, end))),
+    ])
+}
+
+fn location(uri: &str, text: &str, line: usize, tok: &Token) -> Json {
+    Json::object(vec![
+        ("uri", Json::from(uri)),
+        ("range", range(text, line, tok.start, tok.end)),
+    ])
+}
+
+// The token under a zero-based LSP position, with its 1-based line.
+fn token_at<'a>(text: &'a str, params: &Json) -> Option<(usize, Token<'a>)> {
+    let pos = params.get("position")?;
+    let line = pos.get("line")?.as_u64()? as usize;
+    let character = pos.get("character")?.as_u64()? as usize;
+    let src = text.lines().nth(line)?;
+    let byte = byte_col(src, character);
+    asm::tokenize(src)
+        .into_iter()
+        .find(|t| t.start <= byte && byte <= t.end)
+        .map(|t| (line + 1, t))
+}
+
+// The local path behind a file:// URI, so includes resolve next to it.
+fn path_of(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut path = Vec::new();
+    let mut i = 0;
+    while i < encoded.len() {
+        let escaped = encoded.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match (encoded[i], escaped.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(byte)) => {
+                path.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                path.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Some(PathBuf::from(String::from_utf8_lossy(&path).into_owned()))
+}
+
+fn analyze(uri: &str, text: &str) -> (Program, Vec<Diagnostic>) {
+    asm::analyze_at(text, path_of(uri).as_deref())
+}
+
+fn completion(label: &str, kind: u8, detail: String) -> Json {
+    Json::object(vec![
+        ("label", Json::from(label)),
+        ("kind", Json::from(kind)),
+        ("detail", Json::from(detail)),
+    ])
+}
+
+pub struct LspServer<W: Write> {
+    out: W,
+    documents: BTreeMap<String, String>,
+    shutdown: bool,
+}
+
+impl<W: Write> LspServer<W> {
+    pub fn new(out: W) -> Self {
+        LspServer {
+            out,
+            documents: BTreeMap::new(),
+            shutdown: false,
+        }
+    }
+
+    fn send(&mut self, mut fields: Vec<(&str, Json)>) -> io::Result<()> {
+        fields.insert(0, ("jsonrpc", Json::from("2.0")));
+        json::write_message(&mut self.out, &Json::object(fields))
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str) -> io::Result<()> {
+        let text = self.documents.get(uri).map_or("", String::as_str);
+        let diagnostics = match text {
+            "" => Vec::new(),
+            _ => analyze(uri, text)
+                .1
+                .iter()
+                .map(|d| {
+                    // problems in an included file are shown on the .INCLUDE
+                    let (range, message) = match &d.file {
+                        None => (range(text, d.line, d.start, d.end), d.message.clone()),
+                        Some(file) => (range(text, d.site, 0, usize::MAX), d.in_file(file)),
+                    };
+                    Json::object(vec![
+                        ("range", range),
+                        ("severity", Json::from(1u8)),
+                        ("source", Json::from("lc3")),
+                        ("message", Json::from(message)),
+                    ])
+                })
+                .collect(),
+        };
+        let params = Json::object(vec![("uri", Json::from(uri)), ("diagnostics", Json::Array(diagnostics))]);
+        self.send(vec![
+            ("method", Json::from("textDocument/publishDiagnostics")),
+            ("params", params),
+        ])
+    }
+
+    fn definition(&self, uri: &str, text: &str, params: &Json) -> Json {
+        let (program, _) = analyze(uri, text);
+        let found = token_at(text, params).and_then(|(_, tok)| {
+            let symbol = program.symbols.get(tok.text)?;
+            let line = text.lines().nth(symbol.line - 1)?;
+            let label = asm::parse_line(line).label?;
+            Some(location(uri, text, symbol.line, &label))
+        });
+        found.unwrap_or(Json::Null)
+    }
+
+    fn references(&self, uri: &str, text: &str, params: &Json) -> Json {
+        let (program, _) = analyze(uri, text);
+        let name = match token_at(text, params) {
+            Some((_, tok)) if program.symbols.contains_key(tok.text) => tok.text,
+            _ => return Json::Array(vec![]),
+        };
+        let declaration = params
+            .get("context")
+            .and_then(|c| c.get("includeDeclaration"))
+            .and_then(Json::as_bool)
+            .unwrap_or(true);
+        let mut found = Vec::new();
+        for (idx, line) in text.lines().enumerate() {
+            let stmt = asm::parse_line(line);
+            let label = stmt.label.filter(|_| declaration);
+            for tok in label.iter().chain(stmt.operands.iter()) {
+                if tok.text == name {
+                    found.push(location(uri, text, idx + 1, tok));
+                }
+            }
+        }
+        Json::Array(found)
+    }
+
+    fn hover(&self, uri: &str, text: &str, params: &Json) -> Json {
+        let (program, diagnostics) = analyze(uri, text);
+        let (line, tok) = match token_at(text, params) {
+            Some(found) => found,
+            None => return Json::Null,
+        };
+        let mut parts = Vec::new();
+        if let Some(symbol) = program.symbols.get(tok.text) {
+            parts.push(format!("`{}` = x{:04X} (line {})", tok.text, symbol.addr, symbol.line));
+        }
+        let words = words_of_line(&program, line);
+        if !words.is_empty() && !diagnostics.iter().any(|d| d.site == line) {
+            let mut listing: Vec<String> = words
+                .iter()
+                .take(HOVER_WORDS)
+                .map(|(addr, word)| format!("x{:04X}  x{:04X}  {}", addr, word, asm::disassemble(*word)))
+                .collect();
+            if words.len() > HOVER_WORDS {
+                listing.push(format!("... {} more words", words.len() - HOVER_WORDS));
+            }
+            parts.push(format!("```\n{}\n```", listing.join("\n")));
+        }
+        if parts.is_empty() {
+            return Json::Null;
+        }
+        Json::object(vec![
+            (
+                "contents",
+                Json::object(vec![("kind", Json::from("markdown")), ("value", Json::from(parts.join("\n\n")))]),
+            ),
+            ("range", range(text, line, tok.start, tok.end)),
+        ])
+    }
+
+    fn completion(&self, uri: &str, text: &str) -> Json {
+        let mut items = Vec::new();
+        for op in OPCODES.iter().filter(|op| **op != "BR").chain(BRANCHES) {
+            items.push(completion(op, KIND_KEYWORD, "opcode".to_string()));
+        }
+        for (alias, vect) in TRAP_ALIASES {
+            items.push(completion(alias, KIND_FUNCTION, format!("TRAP x{:02X}", vect)));
+        }
+        for directive in DIRECTIVES {
+            items.push(completion(directive, KIND_KEYWORD, "pseudo-op".to_string()));
+        }
+        for (name, symbol) in &analyze(uri, text).0.symbols {
+            items.push(completion(name, KIND_VARIABLE, format!("x{:04X}", symbol.addr)));
+        }
+        Json::Array(items)
+    }
+
+    fn request(&mut self, method: &str, params: &Json) -> Result<Json, (i32, String)> {
+        if self.shutdown {
+            return Err((INVALID_REQUEST, "server is shutting down".to_string()));
+        }
+        if method == "initialize" {
+            let capabilities = Json::object(vec![
+                ("textDocumentSync", Json::from(1u8)),
+                ("definitionProvider", Json::from(true)),
+                ("referencesProvider", Json::from(true)),
+                ("hoverProvider", Json::from(true)),
+                (
+                    "completionProvider",
+                    Json::object(vec![("triggerCharacters", Json::from(vec!["."]))]),
+                ),
+            ]);
+            let info = Json::object(vec![("name", Json::from("lc3_lsp"))]);
+            return Ok(Json::object(vec![("capabilities", capabilities), ("serverInfo", info)]));
+        }
+        if method == "shutdown" {
+            self.shutdown = true;
+            return Ok(Json::Null);
+        }
+
+        let uri = params
+            .get("textDocument")
+            .and_then(|d| d.get("uri"))
+            .and_then(Json::as_str)
+            .ok_or((INVALID_PARAMS, "missing textDocument".to_string()))?;
+        let text = self
+            .documents
+            .get(uri)
+            .ok_or_else(|| (INVALID_PARAMS, format!("{} is not open", uri)))?;
+        match method {
+            "textDocument/definition" => Ok(self.definition(uri, text, params)),
+            "textDocument/references" => Ok(self.references(uri, text, params)),
+            "textDocument/hover" => Ok(self.hover(uri, text, params)),
+            "textDocument/completion" => Ok(self.completion(uri, text)),
+            _ => Err((METHOD_NOT_FOUND, format!("unsupported method '{}'", method))),
+        }
+    }
+
+    fn notification(&mut self, method: &str, params: &Json) -> io::Result<()> {
+        let doc = params.get("textDocument");
+        let uri = doc.and_then(|d| d.get("uri")).and_then(Json::as_str).map(str::to_string);
+        match (method, uri) {
+            ("textDocument/didOpen", Some(uri)) => {
+                let text = doc.and_then(|d| d.get("text")).and_then(Json::as_str).unwrap_or("");
+                self.documents.insert(uri.clone(), text.to_string());
+                self.publish_diagnostics(&uri)
+            }
+            ("textDocument/didChange", Some(uri)) => {
+                // full sync, so the last change holds the whole document
+                let changes = params.get("contentChanges").and_then(Json::as_array).unwrap_or(&[]);
+                if let Some(text) = changes.last().and_then(|c| c.get("text")).and_then(Json::as_str) {
+                    self.documents.insert(uri.clone(), text.to_string());
+                }
+                self.publish_diagnostics(&uri)
+            }
+            ("textDocument/didClose", Some(uri)) => {
+                self.documents.remove(&uri);
+                self.publish_diagnostics(&uri)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Handles one message. Returns false once the client has asked to exit.
+    fn handle(&mut self, msg: &Json) -> io::Result<bool> {
+        let method = msg.get("method").and_then(Json::as_str).unwrap_or("");
+        let null = Json::Null;
+        let params = msg.get("params").unwrap_or(&null);
+        if method == "exit" {
+            return Ok(false);
+        }
+        match msg.get("id") {
+            Some(id) => {
+                let id = id.clone();
+                let reply = match self.request(method, params) {
+                    Ok(result) => ("result", result),
+                    Err((code, message)) => (
+                        "error",
+                        Json::object(vec![("code", Json::from(code)), ("message", Json::from(message))]),
+                    ),
+                };
+                self.send(vec![("id", id), reply])?;
+            }
+            None => self.notification(method, params)?,
+        }
+        Ok(true)
+    }
+
+    pub fn run<R: BufRead>(mut self, mut input: R) -> io::Result<()> {
+        while let Some(msg) = json::read_message(&mut input)? {
+            if !self.handle(&msg)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Every address and word produced by 1-based source line `line`.
+fn words_of_line(program: &Program, line: usize) -> Vec<(u16, u16)> {
+    program
+        .sections
+        .iter()
+        .flat_map(|s| {
+            s.lines
+                .iter()
+                .zip(&s.words)
+                .enumerate()
+                .filter(move |(_, (l, _))| **l == line)
+                .map(move |(i, (_, w))| (s.origin + i as u16, *w))
+        })
+        .collect()
+}
+
+pub fn serve_stdio() -> io::Result<()> {
+    let stdin = io::stdin();
+    let input = stdin.lock();
+    LspServer::new(io::stdout()).run(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::LspServer;
+    use crate::json::{self, Json};
+
+    const URI: &str = "file:///countdown.asm";
+
+    const SRC: &str = "        .ORIG x3000
+        LD R1, COUNT
+LOOP    LEA R0, MSG
+        PUTS
+        ADD R1, R1, #-1
+        BRp LOOP
+        HALT
+COUNT   .FILL #3
+MSG     .STRINGZ \"hi\"
+        .END
+";
+
+    fn request(id: u64, method: &str, params: Json) -> Json {
+        Json::object(vec![
+            ("jsonrpc", Json::from("2.0")),
+            ("id", Json::from(id)),
+            ("method", Json::from(method)),
+            ("params", params),
+        ])
+    }
+
+    fn notification(method: &str, params: Json) -> Json {
+        Json::object(vec![
+            ("jsonrpc", Json::from("2.0")),
+            ("method", Json::from(method)),
+            ("params", params),
+        ])
+    }
+
+    fn at(line: u64, character: u64) -> Json {
+        Json::object(vec![
+            ("textDocument", Json::object(vec![("uri", Json::from(URI))])),
+            (
+                "position",
+                Json::object(vec![("line", Json::from(line)), ("character", Json::from(character))]),
+            ),
+        ])
+    }
+
+    fn open(text: &str) -> Json {
+        let doc = Json::object(vec![
+            ("uri", Json::from(URI)),
+            ("languageId", Json::from("lc3")),
+            ("version", Json::from(1u8)),
+            ("text", Json::from(text)),
+        ]);
+        notification("textDocument/didOpen", Json::object(vec![("textDocument", doc)]))
+    }
+
+    // Feeds the messages through a server and returns everything it sent.
+    fn transcript(messages: &[Json]) -> Vec<Json> {
+        let mut input = Vec::new();
+        for msg in messages {
+            json::write_message(&mut input, msg).unwrap();
+        }
+        let mut output = Vec::new();
+        LspServer::new(&mut output).run(Cursor::new(input)).unwrap();
+        let mut replies = Vec::new();
+        let mut reader = Cursor::new(output);
+        while let Some(msg) = json::read_message(&mut reader).unwrap() {
+            replies.push(msg);
+        }
+        replies
+    }
+
+    fn result(replies: &[Json], id: u64) -> String {
+        replies
+            .iter()
+            .find(|r| r.get("id").and_then(Json::as_u64) == Some(id))
+            .and_then(|r| r.get("result"))
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_lsp_navigation_transcript() {
+        let mut refs = at(5, 13);
+        if let Json::Object(fields) = &mut refs {
+            fields.push((
+                "context".to_string(),
+                Json::object(vec![("includeDeclaration", Json::from(true))]),
+            ));
+        }
+        let replies = transcript(&[
+            request(1, "initialize", Json::object(vec![("capabilities", Json::object::<&str>(vec![]))])),
+            notification("initialized", Json::object::<&str>(vec![])),
+            open(SRC),
+            request(2, "textDocument/definition", at(5, 14)),
+            request(3, "textDocument/references", refs),
+            request(4, "textDocument/hover", at(2, 9)),
+            request(5, "textDocument/completion", at(3, 9)),
+            request(6, "shutdown", Json::Null),
+            notification("exit", Json::Null),
+        ]);
+
+        assert!(result(&replies, 1).contains(r#""hoverProvider":true"#));
+        assert_eq!(
+            replies[1].to_string(),
+            r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///countdown.asm","diagnostics":[]}}"#
+        );
+        assert_eq!(
+            result(&replies, 2),
+            r#"{"uri":"file:///countdown.asm","range":{"start":{"line":2,"character":0},"end":{"line":2,"character":4}}}"#
+        );
+        let lines: Vec<u64> = Json::parse(&result(&replies, 3))
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|l| l.get("range").unwrap().get("start").unwrap().get("line").unwrap().as_u64().unwrap())
+            .collect();
+        assert_eq!(lines, vec![2, 5]);
+        assert_eq!(
+            result(&replies, 4),
+            r#"{"contents":{"kind":"markdown","value":"```\nx3001  xE005  LEA r0 #5\n```"},"range":{"start":{"line":2,"character":8},"end":{"line":2,"character":11}}}"#
+        );
+        let completion = result(&replies, 5);
+        assert!(completion.contains(r#"{"label":"BRnzp","kind":14,"detail":"opcode"}"#));
+        assert!(completion.contains(r#"{"label":"PUTS","kind":3,"detail":"TRAP x22"}"#));
+        assert!(completion.contains(r#"{"label":".STRINGZ","kind":14,"detail":"pseudo-op"}"#));
+        assert!(completion.contains(r#"{"label":"COUNT","kind":6,"detail":"x3006"}"#));
+        assert_eq!(result(&replies, 6), "null");
+        assert_eq!(replies.len(), 7);
+    }
+
+    #[test]
+    fn test_lsp_diagnostics_and_hover_on_labels() {
+        let broken = ".ORIG x3000\nADD R1, R2\nBRz NOWHERE\nLD R0, FAR\n.BLKW x200\nFAR .FILL x1234\n.END\n";
+        let change = Json::object(vec![
+            ("textDocument", Json::object(vec![("uri", Json::from(URI)), ("version", Json::from(2u8))])),
+            ("contentChanges", Json::from(vec![Json::object(vec![("text", Json::from(SRC))])])),
+        ]);
+        let replies = transcript(&[
+            open(broken),
+            request(1, "textDocument/hover", at(5, 1)),
+            request(2, "textDocument/typeDefinition", at(5, 1)),
+            notification("textDocument/didChange", change),
+            notification(
+                "textDocument/didClose",
+                Json::object(vec![("textDocument", Json::object(vec![("uri", Json::from(URI))]))]),
+            ),
+            request(3, "textDocument/hover", at(5, 1)),
+        ]);
+
+        let diagnostics = replies[0].get("params").unwrap().get("diagnostics").unwrap().as_array().unwrap();
+        let messages: Vec<&str> = diagnostics
+            .iter()
+            .map(|d| d.get("message").and_then(Json::as_str).unwrap())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "ADD expects 3 operand(s), found 2",
+                "undefined label 'NOWHERE'",
+                "'FAR' is 512 words away, which does not fit in a 9-bit offset",
+            ]
+        );
+        assert_eq!(
+            diagnostics[1].get("range").unwrap().to_string(),
+            r#"{"start":{"line":2,"character":4},"end":{"line":2,"character":11}}"#
+        );
+
+        // labels still resolve even though the file does not assemble
+        assert_eq!(
+            replies[1].get("result").unwrap().get("contents").unwrap().get("value").and_then(Json::as_str),
+            Some("`FAR` = x3203 (line 6)\n\n```\nx3203  x1234  ADD r1 r0 #-12\n```")
+        );
+        assert_eq!(replies[2].get("error").unwrap().get("code").and_then(Json::as_f64), Some(-32601.0));
+        assert_eq!(replies[3].to_string().matches("diagnostics\":[]").count(), 1);
+        assert_eq!(replies[4].to_string().matches("diagnostics\":[]").count(), 1);
+        assert_eq!(replies[5].get("error").unwrap().get("code").and_then(Json::as_f64), Some(-32602.0));
+    }
+}
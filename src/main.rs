@@ -1,18 +1,593 @@
-use lc3_tools::{LC3, opcodes::Inst};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::process::exit;
 
+use lc3_tools::grader::{self, TestSpec};
+use lc3_tools::{aot, asm, cache, dap, disk, files, gdb, link, micro, pipeline, replay, report, serial, timing, vcd, video, Stop, LC3};
 
-fn main() {
+const USAGE: &str = "usage: lc3_vm <program.obj|program.asm> [--sandbox DIR] [--disk IMAGE]
+                [--serial [listen:]tcp:HOST:PORT|unix:PATH] [--frame FILE] [--ansi]
+                [--record LOG | --replay LOG] [--micro] [--cycles FILE]
+                [--microcode FILE] [--vcd FILE [--signals NAME,...]]
+                [--profile] [--cost NAME=CYCLES,...]
+                [--pipeline [--pipeline-config NAME=VALUE,...] [--diagram FILE]]
+                [--cache [--cache-config NAME=VALUE,...]]
+       lc3_vm test <program.obj> <spec> [--junit FILE] [--json FILE]
+       lc3_vm batch <submissions-dir> <spec> [--threads N] [--csv FILE] [--json FILE]
+       lc3_vm gdb <program.obj|program.asm> [--port N | --stdio]
+       lc3_vm dap
+       lc3_vm asm <program.asm> [-o FILE] [--sym] [--lst] [--xref] [--rel]
+       lc3_vm link <module.rel[@ADDR]>... [-o FILE] [--sym]
+       lc3_vm disasm <program.obj> [--sym FILE]
+       lc3_vm disk create <image> <blocks>
+       lc3_vm disk show <image> [BLOCK]
+       lc3_vm microcode [FILE]
+       lc3_vm translate <program.obj|program.asm> [-o FILE] [--entry ADDR]...
+
+Running a program:
+  --sandbox DIR            give it the file trap services, in DIR
+  --disk IMAGE             attach the disk controller to IMAGE
+  --serial ADDR            link the serial port to a socket, waiting for the
+                           other end with listen:
+  --frame FILE             save the video memory once it stops
+  --ansi                   redraw the video memory in the terminal as it changes
+  --record LOG             log every input the run gets
+  --replay LOG             run it again from such a log, with no input needed,
+                           checking it goes the same way
+  --micro                  run it a clock cycle at a time through the datapath
+  --cycles FILE            write out every one of those cycles
+  --microcode FILE         run it on the datapath with the control store in FILE
+  --vcd FILE               write a waveform of the run
+  --signals NAME,...       only these signals in the waveform
+  --profile                report the cycles taken in each subroutine, as counted
+                           by the datapath or the cost model
+  --cost NAME=CYCLES,...   adjust the cost model
+  --pipeline               time the run on a five stage pipeline as well and
+                           report its stalls
+  --pipeline-config NAME=VALUE,...
+                           adjust the pipeline
+  --diagram FILE           write out what is in each stage every cycle
+  --cache                  pass every memory access through a model of caches
+                           and report how they did
+  --cache-config NAME=VALUE,...
+                           adjust the caches";
+
+fn fail(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    exit(1);
+}
+
+fn assemble_file(path: &str) -> (String, asm::Program) {
+    let src = std::fs::read_to_string(path).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+    match asm::assemble_at(&src, std::path::Path::new(path)) {
+        Ok(program) => (src, program),
+        Err(errors) => {
+            let lines: Vec<String> = errors.iter().map(|e| e.in_file(path)).collect();
+            fail(&lines.join("\n"))
+        }
+    }
+}
+
+fn load(path: &str) -> LC3 {
     let mut vm = LC3::default();
-    vm.memory[0x300A] = 'h' as u16;
-    vm.memory[0x300B] = 'i' as u16;
-    vm.memory[0x300C] = '\n' as u16;
-    vm.memory[0x300D] = '\0' as u16;
-    
-    vm.memory[0x3000] = 0b1110_000_000001001; // LEA R0 <- 0x300A
-    vm.memory[0x3001] = 0b1111_0000_00100010; // PUTS
-    vm.memory[0x3002] = 0b1111_0000_00100011; // IN
-    vm.memory[0x3003] = 0b1111_0000_00100001; // OUT
-    vm.memory[0x3004] = 0b1111_0000_00100101; // HALT
-
-    vm.run();
+    if path.ends_with(".asm") {
+        assemble_file(path).1.load_into(&mut vm);
+    } else if let Err(e) = vm.load_obj_file(path) {
+        fail(&format!("could not load {}: {}", path, e));
+    }
+    vm
+}
+
+// Label addresses by name, from the source or a symbol table next to the
+// object file.
+fn labels(path: &str) -> BTreeMap<u16, String> {
+    let program = if path.ends_with(".asm") {
+        assemble_file(path).1
+    } else {
+        let sym = std::path::Path::new(path).with_extension("sym");
+        match std::fs::read_to_string(&sym) {
+            Ok(text) => asm::Program::from_sym(&text).unwrap_or_else(|e| fail(&format!("{}:{}", sym.display(), e))),
+            Err(_) => asm::Program::default(),
+        }
+    };
+    program.symbols.into_iter().map(|(name, symbol)| (symbol.addr, name)).collect()
+}
+
+// What running a program was asked for besides, from the flags in USAGE.
+#[derive(Default)]
+struct RunOptions<'a> {
+    sandbox: Option<&'a str>,
+    disk: Option<&'a str>,
+    serial: Option<&'a str>,
+    frame: Option<&'a str>,
+    ansi: bool,
+    record: Option<&'a str>,
+    replay: Option<&'a str>,
+    datapath: Option<micro::Datapath>,
+    waveform: Option<&'a str>,
+    signals: Option<Vec<vcd::Signal>>,
+    profile: bool,
+    costs: Vec<&'a str>,
+    pipeline: Option<pipeline::Pipeline>,
+    caches: Option<cache::Config>,
+}
+
+impl<'a> RunOptions<'a> {
+    fn parse(args: &'a [String]) -> RunOptions<'a> {
+        let mut options = RunOptions::default();
+        let mut rest = args.iter();
+        let create = |path: &str| File::create(path).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+        while let Some(flag) = rest.next() {
+            let mut value = || rest.next().map(String::as_str).unwrap_or_else(|| fail(USAGE));
+            match flag.as_str() {
+                "--sandbox" => options.sandbox = Some(value()),
+                "--disk" => options.disk = Some(value()),
+                "--serial" => options.serial = Some(value()),
+                "--frame" => options.frame = Some(value()),
+                "--ansi" => options.ansi = true,
+                "--record" => options.record = Some(value()),
+                "--replay" => options.replay = Some(value()),
+                "--micro" => {
+                    options.datapath();
+                }
+                "--cycles" => {
+                    let file = create(value());
+                    options.datapath().trace = Some(Box::new(std::io::BufWriter::new(file)));
+                }
+                "--microcode" => options.datapath().store = load_microcode(value()),
+                "--vcd" => options.waveform = Some(value()),
+                "--signals" => {
+                    let parse = |name| vcd::Signal::parse(name).unwrap_or_else(|| fail(&format!("no signal called {}", name)));
+                    options.signals = Some(value().split(',').map(parse).collect());
+                }
+                "--profile" => options.profile = true,
+                "--cost" => options.costs.push(value()),
+                "--pipeline" => {
+                    options.pipeline();
+                }
+                "--pipeline-config" => options.pipeline().config.parse(value()).unwrap_or_else(|e| fail(&e)),
+                "--diagram" => {
+                    let file = create(value());
+                    options.pipeline().diagram = Some(Box::new(std::io::BufWriter::new(file)));
+                }
+                "--cache" => {
+                    options.caches.get_or_insert_with(cache::Config::default);
+                }
+                "--cache-config" => {
+                    let config = options.caches.get_or_insert_with(cache::Config::default);
+                    config.parse(value()).unwrap_or_else(|e| fail(&e));
+                }
+                _ => fail(USAGE),
+            }
+        }
+        if options.record.is_some() && options.replay.is_some() {
+            fail(USAGE);
+        }
+        options
+    }
+
+    fn datapath(&mut self) -> &mut micro::Datapath {
+        self.datapath.get_or_insert_with(micro::Datapath::default)
+    }
+
+    fn pipeline(&mut self) -> &mut pipeline::Pipeline {
+        self.pipeline.get_or_insert_with(pipeline::Pipeline::default)
+    }
+
+    // The devices, services and costs the machine runs with, and its
+    // inputs when recording or replaying.
+    fn set_up(&self, vm: &mut LC3) {
+        if let Some(dir) = self.sandbox {
+            files::install(vm, dir).unwrap_or_else(|e| fail(&format!("{}: {}", dir, e)));
+        }
+        if let Some(image) = self.disk {
+            disk::attach(vm, image).unwrap_or_else(|e| fail(&format!("{}: {}", image, e)));
+        }
+        if let Some(addr) = self.serial {
+            let port = match addr.strip_prefix("listen:") {
+                Some(addr) => serial::Serial::listen(addr),
+                None => serial::Serial::connect(addr),
+            };
+            let port = port.unwrap_or_else(|e| fail(&format!("{}: {}", addr, e)));
+            serial::attach(vm, serial::BASE, port);
+        }
+        for costs in &self.costs {
+            vm.cost.parse(costs).unwrap_or_else(|e| fail(&e));
+        }
+        if self.record.is_some() {
+            vm.start_recording();
+        }
+        if let Some(path) = self.replay {
+            let text = std::fs::read_to_string(path).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+            let log = replay::Log::from_text(&text).unwrap_or_else(|e| fail(&format!("{}:{}", path, e)));
+            vm.start_replay(log).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+        }
+    }
+}
+
+fn run(args: &[String]) {
+    let mut vm = load(&args[0]);
+    let options = RunOptions::parse(&args[1..]);
+    options.set_up(&mut vm);
+    let RunOptions { frame, ansi, record, mut datapath, waveform, signals, profile, mut pipeline, caches, .. } = options;
+    let mut caches = caches.map(|config| cache::Caches::new(config).unwrap_or_else(|e| fail(&e)));
+    let mut profile = profile.then(|| timing::Profile::new(&vm, labels(&args[0])));
+    let mut waveform = waveform.map(|path| {
+        let file = std::fs::File::create(path).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+        let signals = signals.unwrap_or_else(|| vcd::Signal::all(&vm, datapath.is_some()));
+        let out = std::io::BufWriter::new(file);
+        let dump = vcd::Vcd::new(out, signals, datapath.is_some()).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+        (path, dump)
+    });
+
+    let mut step = |vm: &mut LC3| match (&mut waveform, &mut datapath) {
+        (Some((_, dump)), Some(datapath)) => dump.step_cycles(datapath, vm),
+        (Some((_, dump)), None) => dump.step(vm),
+        (None, Some(datapath)) => datapath.step(vm),
+        (None, None) => vm.run_step(),
+    };
+    let mut step = |vm: &mut LC3| match &mut pipeline {
+        Some(pipeline) => pipeline.step(vm, &mut step),
+        None => step(vm),
+    };
+    let mut step = |vm: &mut LC3| match &mut caches {
+        Some(caches) => caches.step(vm, &mut step),
+        None => step(vm),
+    };
+    let stop = loop {
+        let mut fault = None;
+        for _ in 0..10_000 {
+            if vm.halted {
+                break;
+            }
+            let result = match &mut profile {
+                Some(profile) => profile.step(&mut vm, &mut step),
+                None => step(&mut vm),
+            };
+            if let Err(e) = result {
+                fault = Some(e);
+                break;
+            }
+        }
+        match fault {
+            Some(fault) => break Stop::Fault(fault),
+            None if vm.halted => break Stop::Halted,
+            None => {}
+        }
+        if ansi && vm.video_dirty {
+            vm.video_dirty = false;
+            print!("\x1b[H{}", video::Frame::capture(&vm).to_ansi());
+        }
+    };
+    if ansi {
+        print!("\x1b[H{}", video::Frame::capture(&vm).to_ansi());
+    }
+    if let Some(path) = record {
+        match vm.finish_recording() {
+            Some(log) => write(path.as_ref(), log.to_text().as_bytes()),
+            None => fail(&format!("{}: nothing was recorded", path)),
+        }
+    }
+    if let Err(e) = vm.finish_replay() {
+        fail(&e.to_string());
+    }
+    if let Some(path) = frame {
+        video::Frame::capture(&vm).save(path).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+    }
+    if let Some((path, dump)) = waveform {
+        dump.finish().unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+    }
+    // dropped here so the trace is flushed before any exit
+    if let Some(datapath) = datapath.take() {
+        eprintln!("{} instructions in {} cycles", vm.executed, datapath.cycles);
+    }
+    if let Some(profile) = profile {
+        eprint!("{}", profile.report(&vm));
+    }
+    if let Some(mut pipeline) = pipeline {
+        pipeline.finish().unwrap_or_else(|e| fail(&format!("diagram: {}", e)));
+        eprint!("{}", pipeline.report());
+    }
+    if let Some(caches) = caches {
+        eprint!("{}", caches.report(10));
+    }
+    match stop {
+        Stop::Fault(fault) => fail(&format!("LC3 stopped at x{:04X}: {}", vm.pc, fault)),
+        _ => println!("LC3 Halted"),
+    }
+}
+
+fn debug(args: &[String]) {
+    let vm = load(args.first().unwrap_or_else(|| fail(USAGE)));
+    let served = match args.get(1).map(String::as_str) {
+        None => gdb::serve_tcp(vm, 1234),
+        Some("--stdio") => gdb::serve_stdio(vm),
+        Some("--port") => {
+            let port = args.get(2).and_then(|p| p.parse().ok());
+            gdb::serve_tcp(vm, port.unwrap_or_else(|| fail("--port expects a number")))
+        }
+        _ => fail(USAGE),
+    };
+    if let Err(e) = served {
+        fail(&format!("gdb server: {}", e));
+    }
+}
+
+fn assemble(args: &[String]) {
+    let path = args.first().unwrap_or_else(|| fail(USAGE));
+    let stem = std::path::Path::new(path).with_extension("");
+    let mut obj = stem.with_extension("obj");
+    let mut artifacts = Vec::new();
+    let mut rel = false;
+    let mut rest = args[1..].iter();
+    while let Some(flag) = rest.next() {
+        match flag.as_str() {
+            "-o" => obj = rest.next().unwrap_or_else(|| fail(USAGE)).into(),
+            "--sym" | "--lst" | "--xref" => artifacts.push(&flag[2..]),
+            "--rel" => rel = true,
+            _ => fail(USAGE),
+        }
+    }
+
+    let (src, program) = assemble_file(path);
+    if rel {
+        if !args.iter().any(|a| a == "-o") {
+            obj = stem.with_extension("rel");
+        }
+        write(&obj, program.to_rel().as_bytes());
+    } else if !program.externals.is_empty() {
+        let names: Vec<&str> = program.externals.iter().map(String::as_str).collect();
+        fail(&format!(
+            "{}: uses .EXTERNAL {}; assemble with --rel and link it",
+            path,
+            names.join(", ")
+        ));
+    } else {
+        write(&obj, &program.to_obj());
+    }
+    for kind in artifacts {
+        let contents = match kind {
+            "sym" => program.sym(),
+            "lst" => program.listing(&src),
+            _ => program.cross_reference(&src),
+        };
+        write(&obj.with_extension(kind), contents.as_bytes());
+    }
+}
+
+fn write(path: &std::path::Path, contents: &[u8]) {
+    std::fs::write(path, contents).unwrap_or_else(|e| fail(&format!("{}: {}", path.display(), e)))
+}
+
+// Writes the program out as a Rust module, translating the code reachable
+// from its origin or from the entry points given.
+fn translate(args: &[String]) {
+    let vm = load(args.first().unwrap_or_else(|| fail(USAGE)));
+    let mut out = None;
+    let mut entries = Vec::new();
+    let mut rest = args[1..].iter();
+    while let Some(flag) = rest.next() {
+        let value = rest.next().unwrap_or_else(|| fail(USAGE));
+        match flag.as_str() {
+            "-o" => out = Some(value),
+            "--entry" => {
+                let addr = u16::from_str_radix(value.trim_start_matches(['x', 'X']), 16)
+                    .unwrap_or_else(|_| fail(&format!("{}: bad entry address", value)));
+                entries.push(addr);
+            }
+            _ => fail(USAGE),
+        }
+    }
+    if entries.is_empty() {
+        entries.push(vm.pc);
+    }
+    let source = aot::translate(&vm.memory, &entries);
+    match out {
+        Some(path) => write(path.as_ref(), source.as_bytes()),
+        None => print!("{}", source),
+    }
+}
+
+// Each module is `file.rel`, or `file.rel@x4000` to place its entry there.
+fn link(args: &[String]) {
+    let mut out = std::path::PathBuf::from("a.obj");
+    let mut sym = false;
+    let mut modules = Vec::new();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "-o" => out = rest.next().unwrap_or_else(|| fail(USAGE)).into(),
+            "--sym" => sym = true,
+            _ => {
+                let (path, origin) = match arg.rsplit_once('@') {
+                    Some((path, addr)) => {
+                        let origin = u16::from_str_radix(addr.trim_start_matches(['x', 'X']), 16)
+                            .unwrap_or_else(|_| fail(&format!("{}: bad load address", arg)));
+                        (path, Some(origin))
+                    }
+                    None => (arg.as_str(), None),
+                };
+                let text = std::fs::read_to_string(path).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+                let program = asm::Program::from_rel(&text).unwrap_or_else(|e| fail(&format!("{}:{}", path, e)));
+                modules.push(link::Module {
+                    name: path.to_string(),
+                    program,
+                    origin,
+                });
+            }
+        }
+    }
+    if modules.is_empty() {
+        fail(USAGE);
+    }
+    let program = link::link(&modules).unwrap_or_else(|errors| {
+        let lines: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        fail(&lines.join("\n"))
+    });
+    write(&out, &program.to_obj());
+    if sym {
+        write(&out.with_extension("sym"), program.sym().as_bytes());
+    }
+}
+
+fn disassemble(args: &[String]) {
+    let path = args.first().unwrap_or_else(|| fail(USAGE));
+    let obj = std::fs::read(path).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+    let symbols = match &args[1..] {
+        [] => asm::Program::default(),
+        [flag, sym] if flag == "--sym" => {
+            let text = std::fs::read_to_string(sym).unwrap_or_else(|e| fail(&format!("{}: {}", sym, e)));
+            asm::Program::from_sym(&text).unwrap_or_else(|e| fail(&format!("{}:{}", sym, e)))
+        }
+        _ => fail(USAGE),
+    };
+    print!("{}", asm::disassemble_obj(&obj, &symbols));
+}
+
+// `show` lists which blocks are in use, or dumps one of them.
+fn disk_image(args: &[String]) {
+    let number = |s: &str| s.parse().unwrap_or_else(|_| fail(&format!("{}: expected a number", s)));
+    match args {
+        [cmd, image, blocks] if cmd == "create" => {
+            disk::create(image, number(blocks)).unwrap_or_else(|e| fail(&format!("{}: {}", image, e)))
+        }
+        [cmd, image, rest @ ..] if cmd == "show" && rest.len() <= 1 => {
+            let blocks = disk::blocks(image).unwrap_or_else(|e| fail(&format!("{}: {}", image, e)));
+            match rest.first() {
+                Some(n) => {
+                    let block = blocks.get(number(n) as usize).unwrap_or_else(|| fail(&format!("{}: no block {}", image, n)));
+                    print!("{}", disk::dump(block));
+                }
+                None => {
+                    let used: Vec<String> = (0..blocks.len())
+                        .filter(|&i| blocks[i].iter().any(|&w| w != 0))
+                        .map(|i| i.to_string())
+                        .collect();
+                    println!("{} blocks of {} words", blocks.len(), disk::BLOCK_WORDS);
+                    println!("in use: {}", if used.is_empty() { "none".to_string() } else { used.join(" ") });
+                }
+            }
+        }
+        _ => fail(USAGE),
+    }
+}
+
+fn load_microcode(path: &str) -> micro::ControlStore {
+    let text = std::fs::read_to_string(path).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+    micro::ControlStore::from_text(&text).unwrap_or_else(|errors| {
+        let lines: Vec<String> = errors.iter().map(|e| format!("{}:{}", path, e)).collect();
+        fail(&lines.join("\n"))
+    })
+}
+
+// Prints the built in control store, as a starting point for changes, or
+// checks the one in a file.
+fn microcode(args: &[String]) {
+    match args {
+        [] => print!("{}", micro::ControlStore::lc3().to_text()),
+        [path] => {
+            load_microcode(path);
+            println!("{}: ok", path);
+        }
+        _ => fail(USAGE),
+    }
+}
+
+fn load_spec(path: &str) -> TestSpec {
+    let src = std::fs::read_to_string(path).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+    TestSpec::parse(&src).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)))
+}
+
+fn test(args: &[String]) {
+    if args.len() < 2 {
+        fail(USAGE);
+    }
+    let mut junit = None;
+    let mut json = None;
+    let mut rest = args[2..].iter();
+    while let Some(flag) = rest.next() {
+        let value = rest.next().unwrap_or_else(|| fail(USAGE));
+        match flag.as_str() {
+            "--junit" => junit = Some(value),
+            "--json" => json = Some(value),
+            _ => fail(USAGE),
+        }
+    }
+
+    let image = std::fs::read(&args[0]).unwrap_or_else(|e| fail(&format!("{}: {}", args[0], e)));
+    let spec = load_spec(&args[1]);
+    let results: Vec<_> = spec.tests.iter().map(|t| grader::run_test(&image, t)).collect();
+    for result in &results {
+        println!("{:<10} {} ({})", result.status(), result.name, result.outcome);
+    }
+
+    let suite = std::path::Path::new(&args[0])
+        .file_stem()
+        .map_or_else(|| args[0].clone(), |s| s.to_string_lossy().into_owned());
+    if let Some(path) = junit {
+        std::fs::write(path, report::junit_xml(&suite, &results))
+            .unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+    }
+    if let Some(path) = json {
+        std::fs::write(path, format!("{}\n", report::json_report(&suite, &results)))
+            .unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+    }
+    if !results.iter().all(|r| r.passed()) {
+        exit(1);
+    }
+}
+
+fn batch(args: &[String]) {
+    if args.len() < 2 {
+        fail(USAGE);
+    }
+    let mut threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut csv = None;
+    let mut json = None;
+    let mut rest = args[2..].iter();
+    while let Some(flag) = rest.next() {
+        let value = rest.next().unwrap_or_else(|| fail(USAGE));
+        match flag.as_str() {
+            "--threads" => threads = value.parse().unwrap_or_else(|_| fail("--threads expects a number")),
+            "--csv" => csv = Some(value),
+            "--json" => json = Some(value),
+            _ => fail(USAGE),
+        }
+    }
+
+    let spec = load_spec(&args[1]);
+    let submissions = grader::load_submissions(&args[0]).unwrap_or_else(|e| fail(&format!("{}: {}", args[0], e)));
+    let results = grader::run_batch(&submissions, &spec, threads);
+
+    let written = match (csv, json) {
+        (None, None) => grader::write_csv(&mut std::io::stdout().lock(), &spec, &results),
+        (csv, json) => csv
+            .map_or(Ok(()), |path| grader::write_csv(&mut File::create(path)?, &spec, &results))
+            .and_then(|_| json.map_or(Ok(()), |path| grader::write_json(&mut File::create(path)?, &spec, &results))),
+    };
+    if let Err(e) = written {
+        fail(&format!("could not write gradebook: {}", e));
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("test") => test(&args[1..]),
+        Some("batch") => batch(&args[1..]),
+        Some("gdb") => debug(&args[1..]),
+        Some("asm") => assemble(&args[1..]),
+        Some("disasm") => disassemble(&args[1..]),
+        Some("link") => link(&args[1..]),
+        Some("disk") => disk_image(&args[1..]),
+        Some("microcode") => microcode(&args[1..]),
+        Some("translate") => translate(&args[1..]),
+        Some("dap") => {
+            if let Err(e) = dap::serve_stdio() {
+                fail(&format!("dap server: {}", e));
+            }
+        }
+        Some(_) => run(&args),
+        None => fail(USAGE),
+    }
 }
@@ -0,0 +1,1141 @@
+use std::fmt::Display;
+use std::io::Write;
+
+use crate::utils::sext;
+use crate::{Fault, Stop, EXCEPTION_TABLE, LC3};
+
+// The LC-3 run one clock cycle at a time through the state machine in
+// Patt & Patel's appendix C: a control store of microinstructions, a
+// microsequencer choosing the next state, and a datapath of registers on a
+// shared bus. It runs the same images as `LC3::run_step` and ends every
+// instruction in the same architectural state.
+//
+// Where this machine differs from the book the microcode follows the
+// machine: the PC is incremented in state 35 rather than 18, so a fetch that
+// faults leaves it on the instruction; TRAPs, exceptions and interrupts all
+// go through the supervisor stack, and TRAPs with a Rust handler run it from
+// state 15. The engine itself stands in for hardware the book leaves out:
+// it decides when to take an interrupt, sends faulting memory accesses to
+// state 60, and stops with the fault when the OS has no handler for it.
+
+// Cycles a memory access takes, the last asserting R.
+pub const MEMORY_LATENCY: u16 = 5;
+
+pub const FETCH: u8 = 18;
+pub const TRAP: u8 = 15;
+pub const INTERRUPT: u8 = 49;
+pub const ACCESS_VIOLATION: u8 = 60;
+
+// How the microsequencer picks the next state. All but Unconditional OR a
+// bit into J when their condition holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Cond {
+    #[default]
+    Unconditional,
+    // bit 1, memory ready
+    Ready,
+    // bit 2, the branch is taken
+    Branch,
+    // bit 0, IR[11]
+    AddrMode,
+    // bit 3, user mode
+    Privilege,
+    // bit 4, an interrupt is waiting
+    Interrupt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PcMux {
+    #[default]
+    PcPlus1,
+    Bus,
+    Adder,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DrMux {
+    #[default]
+    Ir11_9,
+    R7,
+    Sp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Sr1Mux {
+    #[default]
+    Ir11_9,
+    Ir8_6,
+    Sp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Addr1Mux {
+    #[default]
+    Pc,
+    BaseR,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Addr2Mux {
+    #[default]
+    Zero,
+    Offset6,
+    PcOffset9,
+    PcOffset11,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SpMux {
+    #[default]
+    SpPlus1,
+    SpMinus1,
+    SavedSsp,
+    SavedUsp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MarMux {
+    #[default]
+    Zext7_0,
+    Adder,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TableMux {
+    #[default]
+    X00,
+    X01,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VectorMux {
+    // the interrupt being taken
+    #[default]
+    Intv,
+    // IR[7:0], for TRAP
+    Trap,
+    Privilege,
+    IllegalOpcode,
+    AccessViolation,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PsrMux {
+    // each field from its own source
+    #[default]
+    Individual,
+    Bus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Aluk {
+    #[default]
+    Add,
+    And,
+    Not,
+    PassA,
+}
+
+// The control signals of one microinstruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Signals {
+    pub ld_mar: bool,
+    pub ld_mdr: bool,
+    pub ld_ir: bool,
+    pub ld_ben: bool,
+    pub ld_reg: bool,
+    pub ld_cc: bool,
+    pub ld_pc: bool,
+    pub ld_priv: bool,
+    pub ld_priority: bool,
+    pub ld_saved_ssp: bool,
+    pub ld_saved_usp: bool,
+    pub ld_vector: bool,
+    pub gate_pc: bool,
+    pub gate_mdr: bool,
+    pub gate_alu: bool,
+    pub gate_marmux: bool,
+    pub gate_vector: bool,
+    pub gate_pc1: bool,
+    pub gate_psr: bool,
+    pub gate_sp: bool,
+    pub pcmux: PcMux,
+    pub drmux: DrMux,
+    pub sr1mux: Sr1Mux,
+    pub addr1mux: Addr1Mux,
+    pub addr2mux: Addr2Mux,
+    pub spmux: SpMux,
+    pub marmux: MarMux,
+    pub tablemux: TableMux,
+    pub vectormux: VectorMux,
+    pub psrmux: PsrMux,
+    pub aluk: Aluk,
+    pub mio_en: bool,
+    // set for a write
+    pub r_w: bool,
+    // the mode LD.Priv sets when not loading from the bus, true for user
+    pub set_priv: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Microinstruction {
+    pub ird: bool,
+    pub cond: Cond,
+    pub j: u8,
+    pub signals: Signals,
+}
+
+fn mi(j: u8, cond: Cond, set: impl FnOnce(&mut Signals)) -> Microinstruction {
+    let mut signals = Signals::default();
+    set(&mut signals);
+    Microinstruction {
+        ird: false,
+        cond,
+        j,
+        signals,
+    }
+}
+
+// A memory access that waits for R and then moves to `j | 2`.
+fn memory(j: u8, set: impl FnOnce(&mut Signals)) -> Microinstruction {
+    mi(j, Cond::Ready, |s| {
+        s.mio_en = true;
+        set(s)
+    })
+}
+
+// MDR <- PSR, PSR[15] <- 0 and the vector table entry for an exception,
+// TRAP or interrupt, then on to 45 to switch stacks or 37 if already there.
+fn enter_supervisor(table: TableMux, vector: VectorMux) -> Microinstruction {
+    mi(37, Cond::Privilege, |s| {
+        s.ld_vector = true;
+        s.tablemux = table;
+        s.vectormux = vector;
+        s.gate_psr = true;
+        s.ld_mdr = true;
+        s.ld_priv = true;
+        s.ld_priority = vector == VectorMux::Intv;
+    })
+}
+
+// The 64 states' microinstructions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlStore {
+    pub states: Vec<Option<Microinstruction>>,
+}
+
+impl ControlStore {
+    // The microcode for this machine.
+    pub fn lc3() -> ControlStore {
+        use Cond::*;
+        let mut states = vec![None; 64];
+        let mut set = |state: usize, mi: Microinstruction| states[state] = Some(mi);
+
+        // fetch and decode
+        set(18, mi(33, Interrupt, |s| {
+            s.gate_pc = true;
+            s.ld_mar = true;
+        }));
+        set(33, memory(33, |s| s.ld_mdr = true));
+        set(35, mi(32, Unconditional, |s| {
+            s.gate_mdr = true;
+            s.ld_ir = true;
+            s.ld_pc = true;
+            s.pcmux = PcMux::PcPlus1;
+        }));
+        set(32, Microinstruction {
+            ird: true,
+            signals: Signals {
+                ld_ben: true,
+                ..Signals::default()
+            },
+            ..Microinstruction::default()
+        });
+
+        // operates
+        for (state, aluk) in [(1, Aluk::Add), (5, Aluk::And), (9, Aluk::Not)] {
+            set(state, mi(18, Unconditional, |s| {
+                s.sr1mux = Sr1Mux::Ir8_6;
+                s.aluk = aluk;
+                s.gate_alu = true;
+                s.ld_reg = true;
+                s.ld_cc = true;
+            }));
+        }
+        set(14, mi(18, Unconditional, |s| {
+            s.addr2mux = Addr2Mux::PcOffset9;
+            s.marmux = MarMux::Adder;
+            s.gate_marmux = true;
+            s.ld_reg = true;
+            s.ld_cc = true;
+        }));
+
+        // loads and stores work out the address, then share the rest
+        let address = |j: u8, base: bool| {
+            mi(j, Unconditional, move |s| {
+                if base {
+                    s.sr1mux = Sr1Mux::Ir8_6;
+                    s.addr1mux = Addr1Mux::BaseR;
+                    s.addr2mux = Addr2Mux::Offset6;
+                } else {
+                    s.addr2mux = Addr2Mux::PcOffset9;
+                }
+                s.marmux = MarMux::Adder;
+                s.gate_marmux = true;
+                s.ld_mar = true;
+            })
+        };
+        set(2, address(25, false));
+        set(6, address(25, true));
+        set(10, address(24, false));
+        set(3, address(23, false));
+        set(7, address(23, true));
+        set(11, address(29, false));
+        set(24, memory(24, |s| s.ld_mdr = true));
+        set(26, mi(25, Unconditional, |s| {
+            s.gate_mdr = true;
+            s.ld_mar = true;
+        }));
+        set(25, memory(25, |s| s.ld_mdr = true));
+        set(27, mi(18, Unconditional, |s| {
+            s.gate_mdr = true;
+            s.ld_reg = true;
+            s.ld_cc = true;
+        }));
+        set(29, memory(29, |s| s.ld_mdr = true));
+        set(31, mi(23, Unconditional, |s| {
+            s.gate_mdr = true;
+            s.ld_mar = true;
+        }));
+        set(23, mi(16, Unconditional, |s| {
+            s.aluk = Aluk::PassA;
+            s.gate_alu = true;
+            s.ld_mdr = true;
+        }));
+        set(16, memory(16, |s| s.r_w = true));
+
+        // control
+        set(0, mi(18, Branch, |_| {}));
+        set(22, mi(18, Unconditional, |s| {
+            s.addr2mux = Addr2Mux::PcOffset9;
+            s.pcmux = PcMux::Adder;
+            s.ld_pc = true;
+        }));
+        set(12, mi(18, Unconditional, |s| {
+            s.sr1mux = Sr1Mux::Ir8_6;
+            s.addr1mux = Addr1Mux::BaseR;
+            s.pcmux = PcMux::Adder;
+            s.ld_pc = true;
+        }));
+        set(4, mi(20, AddrMode, |_| {}));
+        set(20, mi(18, Unconditional, |s| {
+            s.gate_pc = true;
+            s.drmux = DrMux::R7;
+            s.ld_reg = true;
+            s.sr1mux = Sr1Mux::Ir8_6;
+            s.addr1mux = Addr1Mux::BaseR;
+            s.pcmux = PcMux::Adder;
+            s.ld_pc = true;
+        }));
+        set(21, mi(18, Unconditional, |s| {
+            s.gate_pc = true;
+            s.drmux = DrMux::R7;
+            s.ld_reg = true;
+            s.addr2mux = Addr2Mux::PcOffset11;
+            s.pcmux = PcMux::Adder;
+            s.ld_pc = true;
+        }));
+
+        // into supervisor mode: push the PSR and PC, then jump through the
+        // vector table
+        set(15, enter_supervisor(TableMux::X00, VectorMux::Trap));
+        set(13, enter_supervisor(TableMux::X01, VectorMux::IllegalOpcode));
+        set(44, enter_supervisor(TableMux::X01, VectorMux::Privilege));
+        set(60, enter_supervisor(TableMux::X01, VectorMux::AccessViolation));
+        set(49, enter_supervisor(TableMux::X01, VectorMux::Intv));
+        set(45, mi(37, Unconditional, |s| {
+            s.ld_saved_usp = true;
+            s.spmux = SpMux::SavedSsp;
+            s.gate_sp = true;
+            s.drmux = DrMux::Sp;
+            s.ld_reg = true;
+        }));
+        let push = |j: u8| {
+            mi(j, Unconditional, |s| {
+                s.spmux = SpMux::SpMinus1;
+                s.gate_sp = true;
+                s.ld_mar = true;
+                s.drmux = DrMux::Sp;
+                s.ld_reg = true;
+            })
+        };
+        set(37, push(41));
+        set(41, memory(41, |s| s.r_w = true));
+        set(43, mi(47, Unconditional, |s| {
+            s.gate_pc = true;
+            s.ld_mdr = true;
+        }));
+        set(47, push(48));
+        set(48, memory(48, |s| s.r_w = true));
+        set(50, mi(52, Unconditional, |s| {
+            s.gate_vector = true;
+            s.ld_mar = true;
+        }));
+        set(52, memory(52, |s| s.ld_mdr = true));
+        set(54, mi(18, Unconditional, |s| {
+            s.gate_mdr = true;
+            s.pcmux = PcMux::Bus;
+            s.ld_pc = true;
+        }));
+
+        // RTI pops the PC and PSR, going back to the user stack if need be
+        set(8, mi(36, Privilege, |s| {
+            s.sr1mux = Sr1Mux::Sp;
+            s.aluk = Aluk::PassA;
+            s.gate_alu = true;
+            s.ld_mar = true;
+        }));
+        set(36, memory(36, |s| s.ld_mdr = true));
+        set(38, mi(39, Unconditional, |s| {
+            s.gate_mdr = true;
+            s.pcmux = PcMux::Bus;
+            s.ld_pc = true;
+        }));
+        set(39, mi(40, Unconditional, |s| {
+            s.spmux = SpMux::SpPlus1;
+            s.gate_sp = true;
+            s.ld_mar = true;
+            s.drmux = DrMux::Sp;
+            s.ld_reg = true;
+        }));
+        set(40, memory(40, |s| s.ld_mdr = true));
+        set(42, mi(34, Unconditional, |s| {
+            s.gate_mdr = true;
+            s.psrmux = PsrMux::Bus;
+            s.ld_priv = true;
+            s.ld_priority = true;
+            s.ld_cc = true;
+        }));
+        set(34, mi(51, Privilege, |s| {
+            s.spmux = SpMux::SpPlus1;
+            s.gate_sp = true;
+            s.drmux = DrMux::Sp;
+            s.ld_reg = true;
+        }));
+        set(59, mi(18, Unconditional, |s| {
+            s.ld_saved_ssp = true;
+            s.spmux = SpMux::SavedUsp;
+            s.gate_sp = true;
+            s.drmux = DrMux::Sp;
+            s.ld_reg = true;
+        }));
+        set(51, mi(18, Unconditional, |_| {}));
+
+        ControlStore { states }
+    }
+
+    pub fn get(&self, state: u8) -> Option<&Microinstruction> {
+        self.states.get(state as usize)?.as_ref()
+    }
+}
+
+// Where a register transfer went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg {
+    Mar,
+    Mdr,
+    Ir,
+    Ben,
+    R(u8),
+    Cc,
+    Pc,
+    Priv,
+    Priority,
+    SavedSsp,
+    SavedUsp,
+    Vector,
+    Memory(u16),
+}
+
+impl Display for Reg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reg::Mar => write!(f, "MAR"),
+            Reg::Mdr => write!(f, "MDR"),
+            Reg::Ir => write!(f, "IR"),
+            Reg::Ben => write!(f, "BEN"),
+            Reg::R(n) => write!(f, "R{}", n),
+            Reg::Cc => write!(f, "NZP"),
+            Reg::Pc => write!(f, "PC"),
+            Reg::Priv => write!(f, "PSR[15]"),
+            Reg::Priority => write!(f, "PSR[10:8]"),
+            Reg::SavedSsp => write!(f, "Saved.SSP"),
+            Reg::SavedUsp => write!(f, "Saved.USP"),
+            Reg::Vector => write!(f, "Vector"),
+            Reg::Memory(addr) => write!(f, "M[x{:04X}]", addr),
+        }
+    }
+}
+
+// What happened in one clock cycle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cycle {
+    pub state: u8,
+    pub bus: Option<u16>,
+    // memory was ready this cycle
+    pub ready: bool,
+    pub transfers: Vec<(Reg, u16)>,
+    pub next: u8,
+}
+
+impl Display for Cycle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:>2}", self.state)?;
+        match self.bus {
+            Some(bus) => write!(f, "  bus x{:04X}", bus)?,
+            None => write!(f, "  bus -----")?,
+        }
+        for (reg, value) in &self.transfers {
+            write!(f, "  {}<-x{:04X}", reg, value)?;
+        }
+        write!(f, "  -> {}", self.next)
+    }
+}
+
+// The registers the programmer never sees, and the state the machine is in.
+// Everything architectural stays in the LC3 it runs.
+pub struct Datapath {
+    pub store: ControlStore,
+    pub memory_latency: u16,
+    pub state: u8,
+    pub mar: u16,
+    pub mdr: u16,
+    pub ir: u16,
+    pub ben: bool,
+    pub vector: u16,
+    pub cycles: u64,
+    // gets a line for every cycle
+    pub trace: Option<Box<dyn Write>>,
+    // cycles spent so far on the memory access under way
+    wait: u16,
+    // the interrupt chosen in state 18, as its table entry and priority
+    interrupt: Option<(u16, u8)>,
+    // the fault that sent the machine to state 60
+    fault: Option<Fault>,
+    // state 18 has taken an interrupt, and next time fetches regardless
+    taken: bool,
+    // the machine has left state 18 for an instruction, and has loaded IR
+    started: bool,
+    decoded: bool,
+    // pushing onto the supervisor stack and reading the vector table
+    entering: bool,
+}
+
+impl Default for Datapath {
+    fn default() -> Self {
+        Datapath::new(ControlStore::lc3())
+    }
+}
+
+impl Datapath {
+    pub fn new(store: ControlStore) -> Datapath {
+        Datapath {
+            store,
+            memory_latency: MEMORY_LATENCY,
+            state: FETCH,
+            mar: 0,
+            mdr: 0,
+            ir: 0,
+            ben: false,
+            vector: 0,
+            cycles: 0,
+            trace: None,
+            wait: 0,
+            interrupt: None,
+            fault: None,
+            taken: false,
+            started: false,
+            decoded: false,
+            entering: false,
+        }
+    }
+
+    // Runs one instruction, with any interrupt taken first, as far as
+    // state 18 again.
+    pub fn step(&mut self, lc3: &mut LC3) -> Result<(), Fault> {
+        self.trace_step(lc3).map(|_| ())
+    }
+
+    pub fn trace_step(&mut self, lc3: &mut LC3) -> Result<Vec<Cycle>, Fault> {
+        let mut cycles = Vec::new();
+        self.step_with(lc3, |_, _, cycle| cycles.push(cycle))?;
+        Ok(cycles)
+    }
+
+    // Steps, handing `each` every cycle along with the machine as it was
+    // left by that cycle.
+    pub fn step_with(&mut self, lc3: &mut LC3, mut each: impl FnMut(&Datapath, &LC3, Cycle)) -> Result<(), Fault> {
+        let mut started = false;
+        loop {
+            let cycle = self.cycle(lc3)?;
+            started |= cycle.state == FETCH && cycle.next != INTERRUPT;
+            let done = started && cycle.next == FETCH;
+            each(self, lc3, cycle);
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    pub fn run(&mut self, lc3: &mut LC3) -> Result<(), Fault> {
+        while !lc3.halted {
+            self.step(lc3)?;
+        }
+        Ok(())
+    }
+
+    pub fn run_for(&mut self, lc3: &mut LC3, max_steps: u64) -> Stop {
+        for _ in 0..max_steps {
+            if lc3.halted {
+                return Stop::Halted;
+            }
+            if let Err(fault) = self.step(lc3) {
+                return Stop::Fault(fault);
+            }
+        }
+        if lc3.halted {
+            Stop::Halted
+        } else {
+            Stop::StepLimit
+        }
+    }
+
+    // Gives up on the instruction, ticking the devices as run_step would.
+    fn stop(&mut self, lc3: &mut LC3, fault: Fault) -> Fault {
+        self.state = FETCH;
+        self.wait = 0;
+        if self.started {
+            lc3.tick_devices();
+        }
+        self.boundary();
+        fault
+    }
+
+    fn boundary(&mut self) {
+        self.taken = false;
+        self.started = false;
+        self.decoded = false;
+        self.entering = false;
+    }
+
+    // The fault an exception state stands for, if the OS has no handler.
+    fn unhandled(&mut self, lc3: &LC3, vectormux: VectorMux) -> Option<Fault> {
+        let (vector, fault) = match vectormux {
+            VectorMux::Privilege => (0, Fault::PrivilegeViolation),
+            VectorMux::IllegalOpcode => (1, Fault::IllegalOpcode(self.ir)),
+            VectorMux::AccessViolation => (2, self.fault.take().unwrap_or(Fault::AccessViolation(self.mar))),
+            _ => return None,
+        };
+        (lc3.memory[(EXCEPTION_TABLE + vector) as usize] == 0).then_some(fault)
+    }
+
+    pub fn cycle(&mut self, lc3: &mut LC3) -> Result<Cycle, Fault> {
+        let state = self.state;
+        let mi = *self
+            .store
+            .get(state)
+            .unwrap_or_else(|| panic!("state {} has no microinstruction", state));
+        let s = mi.signals;
+
+        // the parts of the machine the microcode leaves to hardware
+        if state == FETCH {
+            self.interrupt = if self.taken { None } else { lc3.next_interrupt() };
+        }
+        if state == TRAP && self.wait == 0 {
+            let vect = self.ir as u8;
+            if let Some(result) = lc3.native_trap(vect) {
+                result.map_err(|fault| self.stop(lc3, fault))?;
+                return Ok(self.finish(lc3, state, None, false, Vec::new(), FETCH));
+            }
+            if lc3.memory[vect as usize] == 0 {
+                return Err(self.stop(lc3, Fault::UnknownTrap(vect)));
+            }
+        }
+        if s.ld_vector {
+            if let Some(fault) = self.unhandled(lc3, s.vectormux) {
+                return Err(self.stop(lc3, fault));
+            }
+        }
+
+        // everything is worked out from the registers as they were at the
+        // start of the cycle
+        let ir = self.ir;
+        let pc = lc3.pc;
+        let reg = |n: u16| lc3.registers[(n & 7) as usize] as u16;
+        let sr1 = match s.sr1mux {
+            Sr1Mux::Ir11_9 => reg(ir >> 9),
+            Sr1Mux::Ir8_6 => reg(ir >> 6),
+            Sr1Mux::Sp => reg(6),
+        };
+        let sr2 = if ir & 0x20 != 0 {
+            sext(ir as i16 & 0x1F, 5) as u16
+        } else {
+            reg(ir)
+        };
+        let alu = match s.aluk {
+            Aluk::Add => sr1.wrapping_add(sr2),
+            Aluk::And => sr1 & sr2,
+            Aluk::Not => !sr1,
+            Aluk::PassA => sr1,
+        };
+        let addr1 = match s.addr1mux {
+            Addr1Mux::Pc => pc,
+            Addr1Mux::BaseR => sr1,
+        };
+        let addr2 = match s.addr2mux {
+            Addr2Mux::Zero => 0,
+            Addr2Mux::Offset6 => sext(ir as i16 & 0x3F, 6) as u16,
+            Addr2Mux::PcOffset9 => sext(ir as i16 & 0x1FF, 9) as u16,
+            Addr2Mux::PcOffset11 => sext(ir as i16 & 0x7FF, 11) as u16,
+        };
+        let adder = addr1.wrapping_add(addr2);
+        let marmux = match s.marmux {
+            MarMux::Zext7_0 => ir & 0xFF,
+            MarMux::Adder => adder,
+        };
+        let sp = reg(6);
+        let spmux = match s.spmux {
+            SpMux::SpPlus1 => sp.wrapping_add(1),
+            SpMux::SpMinus1 => sp.wrapping_sub(1),
+            SpMux::SavedSsp => lc3.saved_ssp,
+            SpMux::SavedUsp => lc3.saved_usp,
+        };
+        let gates = [
+            (s.gate_pc, pc),
+            (s.gate_mdr, self.mdr),
+            (s.gate_alu, alu),
+            (s.gate_marmux, marmux),
+            (s.gate_vector, self.vector),
+            (s.gate_pc1, pc.wrapping_sub(1)),
+            (s.gate_psr, lc3.psr()),
+            (s.gate_sp, spmux),
+        ];
+        let bus = gates.iter().find(|(on, _)| *on).map(|&(_, value)| value);
+        let bus_value = bus.unwrap_or(0);
+        let user = !lc3.supervisor;
+
+        // memory answers on the last cycle of its latency
+        let mut ready = false;
+        let mut read = None;
+        let mut transfers = Vec::new();
+        if s.mio_en {
+            self.wait += 1;
+            if self.wait >= self.memory_latency {
+                self.wait = 0;
+                ready = true;
+                match self.access(lc3, s.r_w) {
+                    Ok(value) => {
+                        read = value;
+                        if s.r_w {
+                            transfers.push((Reg::Memory(self.mar), self.mdr));
+                        }
+                    }
+                    Err(fault @ Fault::AccessViolation(_)) => {
+                        self.fault = Some(fault);
+                        return Ok(self.finish(lc3, state, bus, true, transfers, ACCESS_VIOLATION));
+                    }
+                    Err(fault) => return Err(self.stop(lc3, fault)),
+                }
+            }
+        }
+
+        // then every register that is loading takes its new value at once
+        let mut load = |reg: Reg, value: u16| transfers.push((reg, value));
+        if s.ld_mar {
+            load(Reg::Mar, bus_value);
+        }
+        if s.ld_mdr {
+            match (s.mio_en, read) {
+                (false, _) => load(Reg::Mdr, bus_value),
+                (true, Some(value)) => load(Reg::Mdr, value),
+                (true, None) => {}
+            }
+        }
+        if s.ld_ir {
+            load(Reg::Ir, bus_value);
+        }
+        if s.ld_ben {
+            let c = lc3.condition;
+            let ben = (ir >> 11 & 1 == 1 && c.n) || (ir >> 10 & 1 == 1 && c.z) || (ir >> 9 & 1 == 1 && c.p);
+            load(Reg::Ben, ben as u16);
+        }
+        if s.ld_reg {
+            let dr = match s.drmux {
+                DrMux::Ir11_9 => ir >> 9 & 7,
+                DrMux::R7 => 7,
+                DrMux::Sp => 6,
+            };
+            load(Reg::R(dr as u8), bus_value);
+        }
+        if s.ld_cc {
+            let nzp = match s.psrmux {
+                PsrMux::Individual => match bus_value as i16 {
+                    v if v < 0 => 0b100,
+                    0 => 0b010,
+                    _ => 0b001,
+                },
+                PsrMux::Bus => bus_value & 0b111,
+            };
+            load(Reg::Cc, nzp);
+        }
+        if s.ld_pc {
+            let next = match s.pcmux {
+                PcMux::PcPlus1 => pc.wrapping_add(1),
+                PcMux::Bus => bus_value,
+                PcMux::Adder => adder,
+            };
+            load(Reg::Pc, next);
+        }
+        if s.ld_priv {
+            let user = match s.psrmux {
+                PsrMux::Individual => s.set_priv,
+                PsrMux::Bus => bus_value >> 15 == 1,
+            };
+            load(Reg::Priv, user as u16);
+        }
+        if s.ld_priority {
+            let priority = match s.psrmux {
+                PsrMux::Individual => self.interrupt.map_or(0, |(_, p)| p as u16),
+                PsrMux::Bus => bus_value >> 8 & 0b111,
+            };
+            load(Reg::Priority, priority);
+        }
+        if s.ld_saved_ssp {
+            load(Reg::SavedSsp, sp);
+        }
+        if s.ld_saved_usp {
+            load(Reg::SavedUsp, sp);
+        }
+        if s.ld_vector {
+            let vector = match s.vectormux {
+                VectorMux::Intv => self.interrupt.map_or(0, |(entry, _)| entry & 0xFF),
+                VectorMux::Trap => ir & 0xFF,
+                VectorMux::Privilege => 0,
+                VectorMux::IllegalOpcode => 1,
+                VectorMux::AccessViolation => 2,
+            };
+            let table = match s.tablemux {
+                TableMux::X00 => 0x0000,
+                TableMux::X01 => EXCEPTION_TABLE,
+            };
+            load(Reg::Vector, table | vector);
+        }
+
+        // the microsequencer
+        let next = if mi.ird {
+            (ir >> 12) as u8
+        } else {
+            let bit = |on: bool, n: u8| (on as u8) << n;
+            mi.j | match mi.cond {
+                Cond::Unconditional => 0,
+                Cond::Ready => bit(ready, 1),
+                Cond::Branch => bit(self.ben, 2),
+                Cond::AddrMode => bit(ir >> 11 & 1 == 1, 0),
+                Cond::Privilege => bit(user, 3),
+                Cond::Interrupt => bit(self.interrupt.is_some(), 4),
+            }
+        };
+        Ok(self.finish(lc3, state, bus, ready, transfers, next))
+    }
+
+    // Instruction fetches, the pushes onto the supervisor stack and reads of
+    // the vector table go straight to memory as they do in run_step; loads
+    // and stores go through the devices.
+    fn access(&mut self, lc3: &mut LC3, write: bool) -> Result<Option<u16>, Fault> {
+        let addr = self.mar;
+        if self.entering || (self.started && !self.decoded) {
+            if !self.entering {
+                lc3.check_access(addr)?;
+            }
+            if write {
+                lc3.memory[addr as usize] = self.mdr;
+                return Ok(None);
+            }
+            return Ok(Some(lc3.memory[addr as usize]));
+        }
+        // the datapath counts its own cycles, not what the cost model charges
+        let cycles = lc3.cycles;
+        let result = if write {
+            lc3.write(addr, self.mdr).map(|_| None)
+        } else {
+            lc3.read(addr).map(Some)
+        };
+        lc3.cycles = cycles;
+        result
+    }
+
+    // Commits the cycle's register transfers and moves to `next`.
+    fn finish(&mut self, lc3: &mut LC3, state: u8, bus: Option<u16>, ready: bool, transfers: Vec<(Reg, u16)>, next: u8) -> Cycle {
+        for &(reg, value) in &transfers {
+            match reg {
+                Reg::Mar => self.mar = value,
+                Reg::Mdr => self.mdr = value,
+                Reg::Ir => {
+                    self.ir = value;
+                    lc3.executed += 1;
+                    self.decoded = true;
+                }
+                Reg::Ben => self.ben = value != 0,
+                Reg::R(n) => lc3.registers[n as usize] = value as i16,
+                Reg::Cc => {
+                    lc3.condition.n = value & 0b100 != 0;
+                    lc3.condition.z = value & 0b010 != 0;
+                    lc3.condition.p = value & 0b001 != 0;
+                }
+                Reg::Pc => lc3.pc = value,
+                Reg::Priv => lc3.supervisor = value == 0,
+                Reg::Priority => lc3.priority = value as u8,
+                Reg::SavedSsp => lc3.saved_ssp = value,
+                Reg::SavedUsp => lc3.saved_usp = value,
+                Reg::Vector => {
+                    self.vector = value;
+                    self.entering = true;
+                }
+                Reg::Memory(_) => {}
+            }
+        }
+        self.cycles += 1;
+        lc3.cycles += 1;
+        self.state = next;
+        if state == FETCH {
+            self.taken |= next == INTERRUPT;
+            self.started |= next != INTERRUPT;
+        }
+        if next == FETCH {
+            self.entering = false;
+            if self.started {
+                lc3.tick_devices();
+                self.boundary();
+            }
+        }
+        let cycle = Cycle {
+            state,
+            bus,
+            ready,
+            transfers,
+            next,
+        };
+        if let Some(trace) = &mut self.trace {
+            // a trace that can no longer be written is given up on
+            if writeln!(trace, "{}", cycle).is_err() {
+                self.trace = None;
+            }
+        }
+        cycle
+    }
+}
+
+#[allow(clippy::unusual_byte_groupings)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::console::Console;
+    use crate::device::Keyboard;
+
+    // Everything a program can see of the machine.
+    fn state(lc3: &LC3) -> (Vec<i16>, u16, u16, u16, u16, u64, bool) {
+        let (ssp, usp) = if lc3.supervisor { (0, lc3.saved_usp) } else { (lc3.saved_ssp, 0) };
+        (lc3.registers.to_vec(), lc3.pc, lc3.psr(), ssp, usp, lc3.executed, lc3.halted)
+    }
+
+    // Runs the same machine both ways, comparing after every instruction.
+    fn differential(make: impl Fn() -> LC3, max_steps: usize) -> Result<LC3, Fault> {
+        let (mut reference, mut micro) = (make(), make());
+        let mut datapath = Datapath::default();
+        for step in 0..max_steps {
+            if reference.halted {
+                break;
+            }
+            let expected = reference.run_step();
+            let actual = datapath.step(&mut micro);
+            assert_eq!(actual, expected, "step {} at x{:04X}", step, reference.pc);
+            assert_eq!(state(&micro), state(&reference), "step {}", step);
+            assert!(micro.memory[..] == reference.memory[..], "step {}", step);
+            assert_eq!(micro.console.output(), reference.console.output());
+            expected?;
+        }
+        Ok(micro)
+    }
+
+    fn machine(src: &str, input: &[u8]) -> LC3 {
+        let mut lc3 = LC3 {
+            console: Console::buffer(input),
+            ..LC3::default()
+        };
+        assemble(src).unwrap().load_into(&mut lc3);
+        lc3
+    }
+
+    fn with_os(src: &str, input: &[u8]) -> LC3 {
+        let mut lc3 = LC3::with_os();
+        lc3.console = Console::buffer(input);
+        assemble(src).unwrap().load_into(&mut lc3);
+        lc3
+    }
+
+    // Touches every opcode and addressing mode.
+    const EVERYTHING: &str = r#"
+        .ORIG x3000
+        LEA R0, DATA
+        LDR R1, R0, #1
+        LD R2, DATA
+        LDI R3, POINTER
+        ADD R4, R1, R2
+        ADD R4, R4, #-16
+        AND R5, R4, R3
+        AND R5, R5, #7
+        NOT R6, R5
+        STR R6, R0, #2
+        ST R4, DATA
+        STI R1, POINTER
+        JSR SUB
+        LEA R7, SUB
+        JSRR R7
+        BRnzp SKIP
+        ADD R0, R0, #1
+SKIP    ADD R1, R1, #0
+        BRz SKIP
+        BRp NEXT
+        ADD R2, R2, #1
+NEXT    LD R0, CHAR
+        OUT
+        LEA R0, TEXT
+        PUTS
+        GETC
+        HALT
+SUB     ADD R1, R1, #-1
+        RET
+DATA    .FILL #12
+        .FILL #-30
+        .BLKW 1
+POINTER .FILL DATA
+CHAR    .FILL x41
+TEXT    .STRINGZ "bc"
+        .END
+    "#;
+
+    #[test]
+    fn test_matches_run_step() {
+        differential(|| machine(EVERYTHING, b"z"), 1000).unwrap();
+        differential(|| with_os(EVERYTHING, b"z"), 5000).unwrap();
+
+        let mut lc3 = machine(EVERYTHING, b"z");
+        let mut datapath = Datapath::default();
+        datapath.run(&mut lc3).unwrap();
+        assert_eq!(lc3.console.output(), b"Abc");
+        assert!(datapath.cycles > 5 * lc3.executed);
+    }
+
+    #[test]
+    fn test_exceptions_and_interrupts_match() {
+        // privilege, illegal opcode and access violations, through the OS
+        let cases = [
+            ("RTI", "privilege mode violation"),
+            (".FILL xD000", "illegal opcode"),
+            ("LDI R0, OS", "access control violation"),
+            ("LD R0, OS\nJMP R0", "access control violation"),
+        ];
+        for (body, message) in cases {
+            let src = format!(".ORIG x3000\n{}\nHALT\nOS .FILL x0200\n.END", body);
+            let lc3 = differential(|| with_os(&src, b""), 5000).unwrap();
+            assert!(lc3.halted && lc3.console.output().starts_with(format!("\n{}\n", message).as_bytes()));
+            let _ = differential(|| machine(&src, b""), 100);
+        }
+
+        // keyboard interrupts taken by the OS
+        let src = ".ORIG x3000\nLOOP ADD R1, R1, #1\nADD R2, R1, #-15\nBRn LOOP\nHALT\n.END";
+        let make = || {
+            let mut lc3 = with_os(src, b"k");
+            lc3.devices.get_mut::<Keyboard>().unwrap().interrupt_enable = true;
+            lc3
+        };
+        assert!(differential(make, 5000).unwrap().halted);
+
+        // the timer preempting a user program with its own handler
+        let src = r#"
+            .ORIG x3000
+            LD R0, INTERVAL
+            STI R0, TMIR
+            LD R0, CONTROL
+            STI R0, TMCR
+LOOP        ADD R1, R1, #1
+            LD R2, TICKS
+            ADD R2, R2, #-3
+            BRn LOOP
+            HALT
+ISR         LD R0, TICKS
+            ADD R0, R0, #1
+            ST R0, TICKS
+            STI R0, TMSR
+            RTI
+INTERVAL    .FILL #20
+CONTROL     .FILL xE381
+TICKS       .FILL 0
+TMCR        .FILL xFE08
+TMSR        .FILL xFE0A
+TMIR        .FILL xFE0C
+            .END
+        "#;
+        let make = || {
+            let mut lc3 = machine(src, b"");
+            lc3.memory[0x0181] = 0x3009;
+            lc3
+        };
+        let lc3 = differential(make, 1000).unwrap();
+        assert_eq!(lc3.memory[0x3010], 3);
+    }
+
+    #[test]
+    fn test_random_programs_match() {
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let mut random = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as u16
+        };
+        for _ in 0..200 {
+            let words: Vec<u16> = (0..64).map(|_| random()).collect();
+            let make = || {
+                let mut lc3 = LC3 {
+                    console: Console::buffer(b"xyz"),
+                    ..LC3::default()
+                };
+                lc3.memory[0x3000..0x3040].copy_from_slice(&words);
+                lc3
+            };
+            let _ = differential(make, 200);
+        }
+    }
+
+    #[test]
+    fn test_cycle_trace() {
+        let mut lc3 = machine(".ORIG x3000\nLDR R1, R0, #5\nHALT\n.END", b"");
+        lc3.registers[0] = 0x3000;
+        lc3.memory[0x3005] = 0xBEEF;
+        let mut datapath = Datapath {
+            memory_latency: 2,
+            ..Datapath::default()
+        };
+        let trace = datapath.trace_step(&mut lc3).unwrap();
+        let states: Vec<u8> = trace.iter().map(|c| c.state).collect();
+        assert_eq!(states, [18, 33, 33, 35, 32, 6, 25, 25, 27]);
+        assert_eq!(trace[0].to_string(), "18  bus x3000  MAR<-x3000  -> 33");
+        assert!(!trace[1].ready && trace[2].ready);
+        assert_eq!(trace[3].transfers, [(Reg::Ir, 0b0110_001_000_000101), (Reg::Pc, 0x3001)]);
+        assert_eq!(trace[5].to_string(), " 6  bus x3005  MAR<-x3005  -> 25");
+        assert_eq!(trace[8].transfers, [(Reg::R(1), 0xBEEF), (Reg::Cc, 0b100)]);
+        assert_eq!(datapath.cycles, 9);
+    }
+}
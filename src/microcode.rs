@@ -0,0 +1,481 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use crate::micro::*;
+
+// Control stores as text, so a course can hand out the microcode for the
+// datapath in `micro` to be changed. Each line gives one state's
+// microinstruction, listing only the fields that differ from their default:
+//
+//     ; fetch
+//     18: J=33 COND=INT GATE.PC LD.MAR
+//     33: J=33 COND=R MIO.EN LD.MDR
+//     32: IRD LD.BEN
+//
+//   J=n            the next state, 0 to 63, before COND ORs in its bit
+//   COND=c         UNCOND, R (memory ready), BEN, IR11, PSR15 or INT
+//   IRD            go to the state numbered by the opcode instead
+//   LD.x           MAR MDR IR BEN REG CC PC PRIV PRIORITY SAVED.SSP
+//                  SAVED.USP VECTOR
+//   GATE.x         PC MDR ALU MARMUX VECTOR PC-1 PSR SP
+//   MIO.EN         access memory at MAR, R.W=WR to write MDR there
+//   SET.PRIV       LD.PRIV without PSRMUX=BUS enters user mode
+//   xMUX=v, ALUK=v the input a mux or the ALU takes, as named in `inputs!`
+//
+// Some states keep a meaning of their own whatever microcode they hold: 18
+// is where every instruction starts and interrupts are noticed, 15 runs
+// TRAPs that have a Rust service, and memory accesses that are refused go
+// to 60.
+const FLAGS: [&str; 23] = [
+    "LD.MAR",
+    "LD.MDR",
+    "LD.IR",
+    "LD.BEN",
+    "LD.REG",
+    "LD.CC",
+    "LD.PC",
+    "LD.PRIV",
+    "LD.PRIORITY",
+    "LD.SAVED.SSP",
+    "LD.SAVED.USP",
+    "LD.VECTOR",
+    "GATE.PC",
+    "GATE.MDR",
+    "GATE.ALU",
+    "GATE.MARMUX",
+    "GATE.VECTOR",
+    "GATE.PC-1",
+    "GATE.PSR",
+    "GATE.SP",
+    "MIO.EN",
+    "R.W=WR",
+    "SET.PRIV",
+];
+
+// In the same order as FLAGS.
+fn flags(s: &mut Signals) -> [&mut bool; 23] {
+    [
+        &mut s.ld_mar,
+        &mut s.ld_mdr,
+        &mut s.ld_ir,
+        &mut s.ld_ben,
+        &mut s.ld_reg,
+        &mut s.ld_cc,
+        &mut s.ld_pc,
+        &mut s.ld_priv,
+        &mut s.ld_priority,
+        &mut s.ld_saved_ssp,
+        &mut s.ld_saved_usp,
+        &mut s.ld_vector,
+        &mut s.gate_pc,
+        &mut s.gate_mdr,
+        &mut s.gate_alu,
+        &mut s.gate_marmux,
+        &mut s.gate_vector,
+        &mut s.gate_pc1,
+        &mut s.gate_psr,
+        &mut s.gate_sp,
+        &mut s.mio_en,
+        &mut s.r_w,
+        &mut s.set_priv,
+    ]
+}
+
+const CONDS: [(&str, Cond); 6] = [
+    ("UNCOND", Cond::Unconditional),
+    ("R", Cond::Ready),
+    ("BEN", Cond::Branch),
+    ("IR11", Cond::AddrMode),
+    ("PSR15", Cond::Privilege),
+    ("INT", Cond::Interrupt),
+];
+
+// The inputs of each mux, the first being its default.
+trait Mux: Copy + PartialEq + Default + 'static {
+    const INPUTS: &'static [(&'static str, Self)];
+
+    fn name(self) -> &'static str {
+        Self::INPUTS.iter().find(|(_, m)| *m == self).unwrap().0
+    }
+}
+
+macro_rules! inputs {
+    ($($ty:ty => [$($name:literal => $input:expr),*];)*) => {
+        $(impl Mux for $ty {
+            const INPUTS: &'static [(&'static str, Self)] = &[$(($name, $input)),*];
+        })*
+    };
+}
+
+inputs! {
+    PcMux => ["PC+1" => PcMux::PcPlus1, "BUS" => PcMux::Bus, "ADDER" => PcMux::Adder];
+    DrMux => ["11.9" => DrMux::Ir11_9, "R7" => DrMux::R7, "SP" => DrMux::Sp];
+    Sr1Mux => ["11.9" => Sr1Mux::Ir11_9, "8.6" => Sr1Mux::Ir8_6, "SP" => Sr1Mux::Sp];
+    Addr1Mux => ["PC" => Addr1Mux::Pc, "BASER" => Addr1Mux::BaseR];
+    Addr2Mux => [
+        "ZERO" => Addr2Mux::Zero,
+        "OFFSET6" => Addr2Mux::Offset6,
+        "PCOFFSET9" => Addr2Mux::PcOffset9,
+        "PCOFFSET11" => Addr2Mux::PcOffset11
+    ];
+    SpMux => [
+        "SP+1" => SpMux::SpPlus1,
+        "SP-1" => SpMux::SpMinus1,
+        "SAVED.SSP" => SpMux::SavedSsp,
+        "SAVED.USP" => SpMux::SavedUsp
+    ];
+    MarMux => ["7.0" => MarMux::Zext7_0, "ADDER" => MarMux::Adder];
+    TableMux => ["x00" => TableMux::X00, "x01" => TableMux::X01];
+    VectorMux => [
+        "INTV" => VectorMux::Intv,
+        "TRAP" => VectorMux::Trap,
+        "PRIV" => VectorMux::Privilege,
+        "OPC" => VectorMux::IllegalOpcode,
+        "ACV" => VectorMux::AccessViolation
+    ];
+    PsrMux => ["INDIVIDUAL" => PsrMux::Individual, "BUS" => PsrMux::Bus];
+    Aluk => ["ADD" => Aluk::Add, "AND" => Aluk::And, "NOT" => Aluk::Not, "PASSA" => Aluk::PassA];
+}
+
+fn choose<M: Mux>(mux: &str, input: &str, field: &mut M) -> Result<(), String> {
+    match M::INPUTS.iter().find(|(name, _)| name.eq_ignore_ascii_case(input)) {
+        Some(&(_, m)) => {
+            *field = m;
+            Ok(())
+        }
+        None => {
+            let names: Vec<&str> = M::INPUTS.iter().map(|(name, _)| *name).collect();
+            Err(format!("{} has no input {} (it takes {})", mux, input, names.join(", ")))
+        }
+    }
+}
+
+macro_rules! muxes {
+    ($($name:literal => $field:ident),*) => {
+        // Sets the mux called `name`, if there is one.
+        fn set_mux(s: &mut Signals, name: &str, input: &str) -> Option<Result<(), String>> {
+            match name {
+                $($name => Some(choose($name, input, &mut s.$field)),)*
+                _ => None,
+            }
+        }
+
+        // The muxes not on their default input, and the input they are on.
+        fn set_muxes(s: &Signals) -> Vec<(&'static str, &'static str)> {
+            let mut set = Vec::new();
+            $(if s.$field != Default::default() {
+                set.push(($name, s.$field.name()));
+            })*
+            set
+        }
+    };
+}
+
+muxes!(
+    "PCMUX" => pcmux,
+    "DRMUX" => drmux,
+    "SR1MUX" => sr1mux,
+    "ADDR1MUX" => addr1mux,
+    "ADDR2MUX" => addr2mux,
+    "SPMUX" => spmux,
+    "MARMUX" => marmux,
+    "TABLEMUX" => tablemux,
+    "VECTORMUX" => vectormux,
+    "PSRMUX" => psrmux,
+    "ALUK" => aluk
+);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MicrocodeError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for MicrocodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for MicrocodeError {}
+
+fn parse_line(text: &str) -> Result<(u8, Microinstruction), String> {
+    let (state, fields) = text.split_once(':').ok_or("expected a state number and a colon")?;
+    let state = match state.trim().parse() {
+        Ok(n @ 0..=63) => n,
+        _ => return Err(format!("{} is not a state, they run from 0 to 63", state.trim())),
+    };
+    let mut mi = Microinstruction::default();
+    let mut seen = Vec::new();
+    for field in fields.split_whitespace() {
+        let field = field.to_ascii_uppercase();
+        let (name, value) = field.split_once('=').unwrap_or((&field, ""));
+        if seen.contains(&name.to_string()) {
+            return Err(format!("{} is given twice", name));
+        }
+        seen.push(name.to_string());
+        if let Some(i) = FLAGS.iter().position(|&flag| flag == field) {
+            *flags(&mut mi.signals)[i] = true;
+            continue;
+        }
+        if let Some(result) = set_mux(&mut mi.signals, name, value) {
+            result?;
+            continue;
+        }
+        match name {
+            "IRD" if value.is_empty() => mi.ird = true,
+            "J" => match value.parse() {
+                Ok(j @ 0..=63) => mi.j = j,
+                _ => return Err(format!("J={} is not a state, they run from 0 to 63", value)),
+            },
+            "COND" => match CONDS.iter().find(|(c, _)| *c == value) {
+                Some(&(_, cond)) => mi.cond = cond,
+                None => return Err(format!("COND has no setting {} (it takes UNCOND, R, BEN, IR11, PSR15 or INT)", value)),
+            },
+            "R.W" if value == "RD" => {}
+            _ => return Err(format!("unknown signal {}", field)),
+        }
+    }
+    if mi.ird && (seen.iter().any(|f| f == "J" || f == "COND")) {
+        return Err("IRD takes the next state from the opcode, so J and COND would be ignored".to_string());
+    }
+    Ok((state, mi))
+}
+
+// The states `mi` can go to next.
+fn successors(mi: &Microinstruction) -> Vec<u8> {
+    if mi.ird {
+        return (0..16).collect();
+    }
+    let bit = match mi.cond {
+        Cond::Unconditional => return vec![mi.j],
+        Cond::Ready => 1 << 1,
+        Cond::Branch => 1 << 2,
+        Cond::AddrMode => 1,
+        Cond::Privilege => 1 << 3,
+        Cond::Interrupt => 1 << 4,
+    };
+    if mi.j & bit != 0 {
+        vec![mi.j]
+    } else {
+        vec![mi.j, mi.j | bit]
+    }
+}
+
+// What is wrong with one state's microinstruction on its own.
+fn check(state: u8, mi: &Microinstruction, store: &ControlStore) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut s = mi.signals;
+    let gates: Vec<&str> = flags(&mut s)
+        .iter()
+        .zip(FLAGS)
+        .filter(|(on, name)| ***on && name.starts_with("GATE."))
+        .map(|(_, name)| name)
+        .collect();
+    if gates.len() > 1 {
+        problems.push(format!("bus conflict, {} drive the bus at once", gates.join(" and ")));
+    }
+    let s = mi.signals;
+    let from_bus = [
+        ("LD.MAR", s.ld_mar),
+        ("LD.MDR", s.ld_mdr && !s.mio_en),
+        ("LD.IR", s.ld_ir),
+        ("LD.REG", s.ld_reg),
+        ("LD.CC", s.ld_cc),
+        ("LD.PC", s.ld_pc && s.pcmux == PcMux::Bus),
+        ("LD.PRIV", s.ld_priv && s.psrmux == PsrMux::Bus),
+        ("LD.PRIORITY", s.ld_priority && s.psrmux == PsrMux::Bus),
+    ];
+    if gates.is_empty() {
+        for (name, _) in from_bus.iter().filter(|(_, on)| *on) {
+            problems.push(format!("{} loads from the bus, which nothing drives", name));
+        }
+    }
+    match (s.mio_en, mi.cond == Cond::Ready) {
+        (true, false) => problems.push("MIO.EN needs COND=R to wait for memory".to_string()),
+        (false, true) => problems.push("COND=R waits for memory, but MIO.EN is not set".to_string()),
+        (true, true) if mi.j != state => problems.push(format!("J={} leaves before memory is ready, it should be J={}", mi.j, state)),
+        _ => {}
+    }
+    if s.mio_en && !s.r_w && !s.ld_mdr {
+        problems.push("memory is read, but LD.MDR is not set to keep it".to_string());
+    }
+    if s.mio_en && s.r_w && s.ld_mdr {
+        problems.push("LD.MDR while writing MDR to memory".to_string());
+    }
+    for next in successors(mi) {
+        if store.get(next).is_none() {
+            let how = if mi.ird {
+                format!("IRD can go to state {} (opcode {:04b})", next, next)
+            } else {
+                format!("it can go to state {}", next)
+            };
+            problems.push(format!("{}, which has no microinstruction", how));
+        }
+    }
+    problems
+}
+
+impl ControlStore {
+    pub fn from_text(text: &str) -> Result<ControlStore, Vec<MicrocodeError>> {
+        let mut store = ControlStore { states: vec![None; 64] };
+        let mut lines = HashMap::new();
+        let mut errors = Vec::new();
+        let mut fail = |line: usize, message: String| errors.push(MicrocodeError { line, message });
+        for (idx, line) in text.lines().enumerate() {
+            let line_no = idx + 1;
+            let code = line.split(';').next().unwrap().trim();
+            if code.is_empty() {
+                continue;
+            }
+            match parse_line(code) {
+                Ok((state, mi)) => match lines.get(&state) {
+                    Some(first) => fail(line_no, format!("state {} is already given on line {}", state, first)),
+                    None => {
+                        lines.insert(state, line_no);
+                        store.states[state as usize] = Some(mi);
+                    }
+                },
+                Err(message) => fail(line_no, message),
+            }
+        }
+        let mut states: Vec<(u8, usize)> = lines.iter().map(|(&s, &l)| (s, l)).collect();
+        states.sort_by_key(|&(_, line)| line);
+        for (state, line) in states {
+            for problem in check(state, store.get(state).unwrap(), &store) {
+                fail(line, format!("state {}: {}", state, problem));
+            }
+        }
+        let last = text.lines().count().max(1);
+        if store.get(FETCH).is_none() {
+            fail(last, format!("no microinstruction for state {}, where every instruction starts", FETCH));
+        }
+        let accesses = store.states.iter().flatten().any(|mi| mi.signals.mio_en);
+        if accesses && store.get(ACCESS_VIOLATION).is_none() {
+            fail(
+                last,
+                format!("no microinstruction for state {}, where refused memory accesses go", ACCESS_VIOLATION),
+            );
+        }
+        if errors.is_empty() {
+            Ok(store)
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        for (state, mi) in self.states.iter().enumerate() {
+            let mi = match mi {
+                Some(mi) => mi,
+                None => continue,
+            };
+            let mut fields = Vec::new();
+            if mi.ird {
+                fields.push("IRD".to_string());
+            } else {
+                fields.push(format!("J={}", mi.j));
+                if mi.cond != Cond::Unconditional {
+                    fields.push(format!("COND={}", CONDS.iter().find(|(_, c)| *c == mi.cond).unwrap().0));
+                }
+            }
+            let mut s = mi.signals;
+            for (on, name) in flags(&mut s).iter().zip(FLAGS) {
+                if **on {
+                    fields.push(name.to_string());
+                }
+            }
+            for (mux, input) in set_muxes(&mi.signals) {
+                fields.push(format!("{}={}", mux, input));
+            }
+            out += &format!("{}: {}\n", state, fields.join(" "));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::console::Console;
+    use crate::LC3;
+
+    #[test]
+    fn test_text_round_trip() {
+        let text = ControlStore::lc3().to_text();
+        assert!(text.contains("18: J=33 COND=INT LD.MAR GATE.PC\n"));
+        assert!(text.contains("32: IRD LD.BEN\n"));
+        assert!(text.contains("6: J=25 LD.MAR GATE.MARMUX SR1MUX=8.6 ADDR1MUX=BASER ADDR2MUX=OFFSET6 MARMUX=ADDER\n"));
+        assert_eq!(ControlStore::from_text(&text), Ok(ControlStore::lc3()));
+
+        let commented = "; fetch\n\n18: j=33 cond=INT gate.pc  LD.MAR ; MAR <- PC\n";
+        let store = ControlStore::from_text(&(commented.to_string() + &text.replacen("18:", "; 18:", 1))).unwrap();
+        assert_eq!(store, ControlStore::lc3());
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let errors = |text: &str| -> Vec<String> {
+            let text = ControlStore::lc3().to_text() + text;
+            let base = ControlStore::lc3().to_text().lines().count();
+            let errors = ControlStore::from_text(&text).unwrap_err();
+            errors.iter().map(|e| format!("{}: {}", e.line - base, e.message)).collect()
+        };
+        assert_eq!(
+            errors("13 J=18\n64: J=18\n13: J=64\n13: J=18 LD.FOO\n13: J=18 PCMUX=PC+2\n13: J=18 COND=N\n"),
+            [
+                "1: expected a state number and a colon",
+                "2: 64 is not a state, they run from 0 to 63",
+                "3: J=64 is not a state, they run from 0 to 63",
+                "4: unknown signal LD.FOO",
+                "5: PCMUX has no input PC+2 (it takes PC+1, BUS, ADDER)",
+                "6: COND has no setting N (it takes UNCOND, R, BEN, IR11, PSR15 or INT)",
+            ]
+        );
+        let errors: Vec<String> = ControlStore::from_text(
+            "18: J=33 COND=INT GATE.PC GATE.ALU LD.MAR\n33: J=33 MIO.EN LD.MDR\n35: J=32 LD.IR\n32: IRD J=1\n18: J=0\n49: J=51 COND=R MIO.EN LD.MDR\n",
+        )
+        .unwrap_err()
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+        assert_eq!(
+            errors,
+            [
+                "line 4: IRD takes the next state from the opcode, so J and COND would be ignored",
+                "line 5: state 18 is already given on line 1",
+                "line 1: state 18: bus conflict, GATE.PC and GATE.ALU drive the bus at once",
+                "line 2: state 33: MIO.EN needs COND=R to wait for memory",
+                "line 3: state 35: LD.IR loads from the bus, which nothing drives",
+                "line 3: state 35: it can go to state 32, which has no microinstruction",
+                "line 6: state 49: J=51 leaves before memory is ready, it should be J=49",
+                "line 6: state 49: it can go to state 51, which has no microinstruction",
+                "line 6: no microinstruction for state 60, where refused memory accesses go",
+            ]
+        );
+    }
+
+    // Opcode 1101 as LDRI, loading through a pointer at BaseR+offset6 by
+    // sharing LDR's address calculation with LDI's two reads.
+    #[test]
+    fn test_new_instruction() {
+        let text = ControlStore::lc3().to_text().replace(
+            "13: J=37 COND=PSR15",
+            "13: J=24 LD.MAR GATE.MARMUX SR1MUX=8.6 ADDR1MUX=BASER ADDR2MUX=OFFSET6 MARMUX=ADDER\n; 13: J=37 COND=PSR15",
+        );
+        let mut datapath = Datapath::new(ControlStore::from_text(&text).unwrap());
+
+        let src = ".ORIG x3000\nLEA R1, TABLE\n.FILL xD441 ; LDRI R2, R1, #1\nHALT\nTABLE .BLKW 1\n.FILL VALUE\nVALUE .FILL #-7\n.END";
+        let mut lc3 = LC3 {
+            console: Console::buffer(&[]),
+            ..LC3::default()
+        };
+        assemble(src).unwrap().load_into(&mut lc3);
+        datapath.run(&mut lc3).unwrap();
+        assert_eq!(lc3.registers[2], -7);
+        assert!(lc3.condition.n);
+        assert_eq!(lc3.executed, 3);
+    }
+}
@@ -125,6 +125,35 @@ impl From<u16> for Inst {
     }
 }
 
+impl Inst {
+    pub fn encode(&self) -> u16 {
+        let reg = |r: i16, shift: u16| ((r as u16) & 0b111) << shift;
+        let field = |v: i16, width: u16| (v as u16) & ((1 << width) - 1);
+        match *self {
+            Inst::ADD { dr, sr1, sr2 } => 0b0001 << 12 | reg(dr, 9) | reg(sr1, 6) | reg(sr2, 0),
+            Inst::ADDi { dr, sr, imm } => 0b0001 << 12 | reg(dr, 9) | reg(sr, 6) | 1 << 5 | field(imm, 5),
+            Inst::AND { dr, sr1, sr2 } => 0b0101 << 12 | reg(dr, 9) | reg(sr1, 6) | reg(sr2, 0),
+            Inst::ANDi { dr, sr, imm } => 0b0101 << 12 | reg(dr, 9) | reg(sr, 6) | 1 << 5 | field(imm, 5),
+            Inst::BR { ref cond, pc_offset } => {
+                (cond.n as u16) << 11 | (cond.z as u16) << 10 | (cond.p as u16) << 9 | field(pc_offset, 9)
+            }
+            Inst::JMP { base_r } => 0b1100 << 12 | reg(base_r, 6),
+            Inst::JSR { pc_offset } => 0b0100 << 12 | 1 << 11 | field(pc_offset, 11),
+            Inst::JSRr { base_r } => 0b0100 << 12 | reg(base_r, 6),
+            Inst::LD { dr, pc_offset } => 0b0010 << 12 | reg(dr, 9) | field(pc_offset, 9),
+            Inst::LDI { dr, pc_offset } => 0b1010 << 12 | reg(dr, 9) | field(pc_offset, 9),
+            Inst::LDR { dr, base_r, offset } => 0b0110 << 12 | reg(dr, 9) | reg(base_r, 6) | field(offset, 6),
+            Inst::LEA { dr, pc_offset } => 0b1110 << 12 | reg(dr, 9) | field(pc_offset, 9),
+            Inst::NOT { dr, sr } => 0b1001 << 12 | reg(dr, 9) | reg(sr, 6) | 0b111111,
+            Inst::RTI => 0b1000 << 12,
+            Inst::ST { sr, pc_offset } => 0b0011 << 12 | reg(sr, 9) | field(pc_offset, 9),
+            Inst::STI { sr, pc_offset } => 0b1011 << 12 | reg(sr, 9) | field(pc_offset, 9),
+            Inst::STR { sr, base_r, offset } => 0b0111 << 12 | reg(sr, 9) | reg(base_r, 6) | field(offset, 6),
+            Inst::TRAP { trap_vect } => 0b1111 << 12 | field(trap_vect, 8),
+        }
+    }
+}
+
 impl Display for Inst {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -155,6 +184,7 @@ impl Display for Inst {
     }
 }
 
+#[allow(clippy::unusual_byte_groupings)]
 #[cfg(test)]
 mod tests {
     use super::{Condition, Inst};
@@ -340,4 +370,31 @@ mod tests {
         let trap = Inst::TRAP { trap_vect: 0x33 };
         assert_eq!(Inst::from(trap_raw), trap);
     }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let raws = [
+            0b0001_000_001_0_00_010,
+            0b0001_111_011_1_10001,
+            0b0101_011_000_0_00_111,
+            0b0101_110_000_1_11011,
+            0b0000_110_010010010,
+            0b1100_000_111_000000,
+            0b0100_1_01001001011,
+            0b0100_0_00_011_000000,
+            0b0010_101_111000111,
+            0b1010_001_011000110,
+            0b0110_110_001_000000,
+            0b1110_000_111111111,
+            0b1001_000_111_1_11111,
+            0b1000_000000000000,
+            0b0011_000_111111111,
+            0b1011_010_111111110,
+            0b0111_010_001_111111,
+            0b1111_0000_00110011,
+        ];
+        for raw in raws.iter() {
+            assert_eq!(Inst::from(*raw).encode(), *raw, "{:016b}", raw);
+        }
+    }
 }
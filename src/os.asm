@@ -0,0 +1,215 @@
+; The operating system bundled with lc3_tools. It fills in the trap vector
+; table, the exception and interrupt vectors, and boots into the user
+; program at x3000. Every routine runs in supervisor mode on the supervisor
+; stack and returns with RTI.
+
+KBSR    .EQU xFE00
+KBDR    .EQU xFE02
+DSR     .EQU xFE04
+DDR     .EQU xFE06
+MCR     .EQU xFFFE
+TMSR    .EQU xFE0A
+
+; Waits for the display, then writes the character in \reg to it. \tmp is
+; clobbered.
+        .MACRO PUTC reg tmp
+@wait   LDI \tmp, DSR_PTR
+        BRzp @wait
+        STI \reg, DDR_PTR
+        .ENDM
+
+; Waits for a key and reads it into \reg.
+        .MACRO GETKEY reg
+@wait   LDI \reg, KBSR_PTR
+        BRzp @wait
+        LDI \reg, KBDR_PTR
+        .ENDM
+
+        .ORIG x0200
+; Starts out in supervisor mode. Builds the frame RTI needs to drop into
+; user mode, with the supervisor stack just below the user program.
+BOOT    LD R6, SSP
+        LD R0, USER_PSR
+        STR R0, R6, #-1
+        LD R0, USER_PC
+        STR R0, R6, #-2
+        ADD R6, R6, #-2
+        AND R0, R0, #0
+        RTI
+
+SSP         .FILL x3000
+USER_PSR    .FILL x8002
+USER_PC     .FILL x3000
+KBSR_PTR    .FILL KBSR
+KBDR_PTR    .FILL KBDR
+DSR_PTR     .FILL DSR
+DDR_PTR     .FILL DDR
+MCR_PTR     .FILL MCR
+TMSR_PTR    .FILL TMSR
+
+; Scratch space for the routines below. Trap routines can be interrupted,
+; so the interrupt handlers keep their own.
+SAVE_R0     .BLKW 1
+SAVE_R1     .BLKW 1
+SAVE_R2     .BLKW 1
+SAVE_R3     .BLKW 1
+SAVE_R4     .BLKW 1
+SAVE_R5     .BLKW 1
+INT_R0      .BLKW 1
+
+TRAP_GETC
+        GETKEY R0
+        RTI
+
+TRAP_OUT
+        ST R1, SAVE_R1
+        PUTC R0, R1
+        LD R1, SAVE_R1
+        RTI
+
+TRAP_PUTS
+        ST R0, SAVE_R0
+        ST R1, SAVE_R1
+        ST R2, SAVE_R2
+PUTS_NEXT
+        LDR R1, R0, #0
+        BRz PUTS_DONE
+        PUTC R1, R2
+        ADD R0, R0, #1
+        BR PUTS_NEXT
+PUTS_DONE
+        LD R0, SAVE_R0
+        LD R1, SAVE_R1
+        LD R2, SAVE_R2
+        RTI
+
+TRAP_IN
+        ST R1, SAVE_R1
+        ST R2, SAVE_R2
+        LEA R1, PROMPT
+IN_PROMPT
+        LDR R0, R1, #0
+        BRz IN_READ
+        PUTC R0, R2
+        ADD R1, R1, #1
+        BR IN_PROMPT
+IN_READ GETKEY R0
+        PUTC R0, R2
+        AND R1, R1, #0
+        ADD R1, R1, #10
+        PUTC R1, R2
+        LD R1, SAVE_R1
+        LD R2, SAVE_R2
+        RTI
+
+; Two characters per word, low byte first. A zero high byte ends the string
+; early.
+TRAP_PUTSP
+        ST R0, SAVE_R0
+        ST R1, SAVE_R1
+        ST R2, SAVE_R2
+        ST R3, SAVE_R3
+        ST R4, SAVE_R4
+        ST R5, SAVE_R5
+PUTSP_NEXT
+        LDR R1, R0, #0
+        BRz PUTSP_DONE
+        LD R4, LOW_BYTE
+        AND R5, R1, R4
+        PUTC R5, R2
+        ; shift the high byte down a bit at a time
+        AND R5, R5, #0
+        AND R3, R3, #0
+        ADD R3, R3, #1
+        LD R4, BIT_8
+PUTSP_BIT
+        AND R2, R1, R4
+        BRz PUTSP_ZERO
+        ADD R5, R5, R3
+PUTSP_ZERO
+        ADD R3, R3, R3
+        ADD R4, R4, R4
+        BRnp PUTSP_BIT
+        ADD R5, R5, #0
+        BRz PUTSP_DONE
+        PUTC R5, R2
+        ADD R0, R0, #1
+        BR PUTSP_NEXT
+PUTSP_DONE
+        LD R0, SAVE_R0
+        LD R1, SAVE_R1
+        LD R2, SAVE_R2
+        LD R3, SAVE_R3
+        LD R4, SAVE_R4
+        LD R5, SAVE_R5
+        RTI
+
+LOW_BYTE    .FILL x00FF
+BIT_8       .FILL x0100
+
+; Clearing the top bit of the MCR stops the clock.
+TRAP_HALT
+        LEA R0, HALT_MSG
+        PUTS
+        LDI R1, MCR_PTR
+        LD R2, CLOCK_OFF
+        AND R1, R1, R2
+        STI R1, MCR_PTR
+        BR TRAP_HALT
+
+CLOCK_OFF   .FILL x7FFF
+
+; Exceptions report what went wrong and halt.
+EX_PRIVILEGE
+        LEA R0, PRIVILEGE_MSG
+        PUTS
+        HALT
+EX_ILLEGAL
+        LEA R0, ILLEGAL_MSG
+        PUTS
+        HALT
+EX_ACCESS
+        LEA R0, ACCESS_MSG
+        PUTS
+        HALT
+
+; Acknowledges the timer and carries on.
+INT_TIMER
+        ST R0, INT_R0
+        STI R0, TMSR_PTR
+        LD R0, INT_R0
+        RTI
+
+; Nobody is waiting for the key, so throw it away rather than interrupt again.
+INT_KEYBOARD
+        ST R0, INT_R0
+        LDI R0, KBDR_PTR
+        LD R0, INT_R0
+        RTI
+
+PROMPT          .STRINGZ "Input a character> "
+HALT_MSG        .STRINGZ "\n--- halting the LC-3 ---\n"
+PRIVILEGE_MSG   .STRINGZ "\nprivilege mode violation\n"
+ILLEGAL_MSG     .STRINGZ "\nillegal opcode\n"
+ACCESS_MSG      .STRINGZ "\naccess control violation\n"
+        .END
+
+        .ORIG x0020
+        .FILL TRAP_GETC
+        .FILL TRAP_OUT
+        .FILL TRAP_PUTS
+        .FILL TRAP_IN
+        .FILL TRAP_PUTSP
+        .FILL TRAP_HALT
+        .END
+
+        .ORIG x0100
+        .FILL EX_PRIVILEGE
+        .FILL EX_ILLEGAL
+        .FILL EX_ACCESS
+        .END
+
+        .ORIG x0180
+        .FILL INT_KEYBOARD
+        .FILL INT_TIMER
+        .END
@@ -0,0 +1,127 @@
+use std::sync::OnceLock;
+
+use crate::asm::{self, Program};
+
+// The assembly source of the bundled operating system.
+pub const SOURCE: &str = include_str!("os.asm");
+
+// Where the OS starts running, in supervisor mode.
+pub const BOOT: u16 = 0x0200;
+
+// The bundled OS, assembled on first use.
+pub fn image() -> &'static Program {
+    static IMAGE: OnceLock<Program> = OnceLock::new();
+    IMAGE.get_or_init(|| match asm::assemble(SOURCE) {
+        Ok(program) => program,
+        Err(errors) => panic!("the bundled OS does not assemble: {}", errors[0]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::assemble;
+    use crate::console::Console;
+    use crate::device::Keyboard;
+    use crate::{Fault, LC3};
+
+    const HALTING: &str = "\n--- halting the LC-3 ---\n";
+
+    fn boot(src: &str, input: &[u8]) -> LC3 {
+        let mut lc3 = LC3::with_os();
+        lc3.console = Console::buffer(input);
+        assemble(src).unwrap().load_into(&mut lc3);
+        lc3
+    }
+
+    fn output(lc3: &LC3) -> String {
+        String::from_utf8_lossy(lc3.console.output()).into_owned()
+    }
+
+    #[test]
+    fn test_boots_into_user_mode() {
+        let lc3 = LC3::with_os();
+        assert_eq!(lc3.pc, 0x3000);
+        assert!(!lc3.supervisor);
+        assert_eq!(lc3.registers[6] as u16, 0xFE00);
+        assert_eq!(lc3.executed, 0);
+        assert_eq!(lc3.memory[0x25], super::image().symbols["TRAP_HALT"].addr);
+    }
+
+    #[test]
+    fn test_traps_run_through_the_os() {
+        let src = r#"
+            .ORIG x3000
+            GETC
+            OUT
+            LEA R0, MSG
+            PUTS
+            IN
+            ADD R3, R0, #0
+            LEA R0, PACKED
+            PUTSP
+            HALT
+            ADD R4, R4, #1
+MSG         .STRINGZ "!\n"
+PACKED      .FILL x6261
+            .FILL x0063
+            .END
+        "#;
+        let mut lc3 = boot(src, b"xy");
+        lc3.run().unwrap();
+        assert_eq!(output(&lc3), format!("x!\nInput a character> y\nabc{}", HALTING));
+        assert_eq!(lc3.registers[3], b'y' as i16);
+        assert_eq!(lc3.registers[4], 0);
+        assert!(lc3.halted);
+
+        let mut lc3 = boot(".ORIG x3000\nGETC\n.END", b"");
+        assert_eq!(lc3.run(), Err(Fault::EndOfInput));
+    }
+
+    #[test]
+    fn test_exceptions_halt_with_a_message() {
+        let cases = [
+            ("RTI", "privilege mode violation"),
+            (".FILL xD000", "illegal opcode"),
+            ("AND R1, R1, #0\nLDR R0, R1, #0", "access control violation"),
+            ("LD R1, KBSR\nSTR R1, R1, #0\nKBSR .FILL xFE00", "access control violation"),
+        ];
+        for (body, message) in cases.iter() {
+            let mut lc3 = boot(&format!(".ORIG x3000\n{}\n.END", body), b"");
+            lc3.run().unwrap();
+            assert_eq!(output(&lc3), format!("\n{}\n{}", message, HALTING));
+        }
+    }
+
+    #[test]
+    fn test_keyboard_interrupt() {
+        let src = r#"
+            .ORIG x3000
+LOOP        LD R0, KEY
+            BRz LOOP
+            OUT
+            HALT
+KEY         .FILL 0
+ISR         ST R0, SAVED
+            LDI R0, KBDR
+            ST R0, KEY
+            LD R0, SAVED
+            RTI
+SAVED       .BLKW 1
+KBDR        .FILL xFE02
+            .END
+        "#;
+        let program = assemble(src).unwrap();
+        let mut lc3 = boot(src, b"k");
+        lc3.memory[0x0180] = program.symbols["ISR"].addr;
+        lc3.devices.get_mut::<Keyboard>().unwrap().interrupt_enable = true;
+        lc3.run().unwrap();
+        assert_eq!(output(&lc3), format!("k{}", HALTING));
+
+        // the default handler swallows the key
+        let mut lc3 = boot(".ORIG x3000\nAND R0, R0, #0\nHALT\n.END", b"k");
+        lc3.devices.get_mut::<Keyboard>().unwrap().interrupt_enable = true;
+        lc3.run().unwrap();
+        assert_eq!(output(&lc3), HALTING);
+        assert!(!lc3.console.has_input());
+    }
+}
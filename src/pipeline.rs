@@ -0,0 +1,622 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{self, Display};
+use std::io::{self, Write};
+
+use crate::opcodes::Inst;
+use crate::{Fault, LC3};
+
+// A timing model of the LC-3 on a classic five stage pipeline, IF ID EX MEM
+// WB, run alongside the functional core: `LC3::run_step`, or an engine that
+// does the same, executes each instruction and the pipeline works out when
+// it would have gone through each stage and what held it up.
+//
+// Registers are read in ID and written in WB, early enough in the cycle for
+// ID to read what WB writes. With forwarding, results go from the end of EX,
+// or of MEM for loads, straight into EX. Conditional branches are predicted
+// in ID, where their target is known, and resolved in EX; JSR and BRnzp
+// redirect the fetch from ID, JMP and JSRR from EX, and TRAP, RTI and the
+// entries to interrupt and exception handlers once MEM is done. MEM takes a
+// cycle for each memory access, so LDI, STI, RTI and entries hold it for
+// more than one, and with a single memory port a fetch waits for MEM.
+//
+// Instructions fetched down the wrong path are never executed by the core,
+// so they don't appear in the diagram; the cycles spent on them count as
+// control stalls.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Predictor {
+    NotTaken,
+    // a table of saturating counters indexed by the low bits of the PC
+    TwoBit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub forwarding: bool,
+    pub predictor: Predictor,
+    // separate instruction and data memories, so fetches never wait on MEM
+    pub split_memory: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            forwarding: true,
+            predictor: Predictor::NotTaken,
+            split_memory: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match (name, value) {
+            ("forwarding", "on") => self.forwarding = true,
+            ("forwarding", "off") => self.forwarding = false,
+            ("predictor", "not-taken") => self.predictor = Predictor::NotTaken,
+            ("predictor", "2-bit") => self.predictor = Predictor::TwoBit,
+            ("memory", "unified") => self.split_memory = false,
+            ("memory", "split") => self.split_memory = true,
+            ("forwarding", _) => return Err("forwarding: expected on or off".to_string()),
+            ("predictor", _) => return Err("predictor: expected not-taken or 2-bit".to_string()),
+            ("memory", _) => return Err("memory: expected unified or split".to_string()),
+            _ => return Err(format!("no pipeline setting called {}", name)),
+        }
+        Ok(())
+    }
+
+    // Settings like `forwarding=off,predictor=2-bit`, as the config displays.
+    pub fn parse(&mut self, settings: &str) -> Result<(), String> {
+        for setting in settings.split(',') {
+            let (name, value) = setting.split_once('=').ok_or_else(|| format!("expected a setting and =, got {}", setting))?;
+            self.set(name, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let forwarding = if self.forwarding { "on" } else { "off" };
+        let predictor = match self.predictor {
+            Predictor::NotTaken => "not-taken",
+            Predictor::TwoBit => "2-bit",
+        };
+        let memory = if self.split_memory { "split" } else { "unified" };
+        write!(f, "forwarding={},predictor={},memory={}", forwarding, predictor, memory)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub cycles: u64,
+    pub instructions: u64,
+    // interrupt and exception entries, each going down the pipeline too
+    pub entries: u64,
+    // cycles an instruction was held in a stage, beyond waiting on the one
+    // ahead of it, by each kind of hazard
+    pub data_stalls: u64,
+    pub structural_stalls: u64,
+    pub control_stalls: u64,
+    // redirects of the fetch, each flushing what was fetched after it
+    pub flushes: u64,
+    // conditional ones
+    pub branches: u64,
+    pub mispredicted: u64,
+}
+
+// The cycles an instruction entered each stage in.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stages {
+    pub fetch: u64,
+    pub decode: u64,
+    pub execute: u64,
+    pub memory: u64,
+    pub writeback: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Redirect {
+    None,
+    Decode,
+    Execute,
+    Memory,
+}
+
+// What the pipeline needs to know of an instruction. Registers are bits
+// 0-7 of the masks and the condition codes bit 8.
+#[derive(Debug, Clone, Copy)]
+struct Op {
+    label: Label,
+    reads: u16,
+    writes: u16,
+    // the result comes out of MEM rather than EX
+    load: bool,
+    accesses: u64,
+    redirect: Redirect,
+}
+
+const CC: u16 = 1 << 8;
+
+fn r(reg: i16) -> u16 {
+    1 << reg
+}
+
+impl Op {
+    fn new(label: Label) -> Op {
+        Op {
+            label,
+            reads: 0,
+            writes: 0,
+            load: false,
+            accesses: 0,
+            redirect: Redirect::None,
+        }
+    }
+
+    // Pushing the PSR and PC and reading the vector table.
+    fn entry() -> Op {
+        Op {
+            reads: r(6),
+            writes: r(6),
+            load: true,
+            accesses: 3,
+            redirect: Redirect::Memory,
+            ..Op::new(Label::Entry)
+        }
+    }
+
+    // A TRAP with a Rust handler is counted as one access.
+    fn decode(pc: u16, inst: &Inst, native: bool) -> Op {
+        let op = Op::new(Label::At(pc));
+        let load = |reads, dr, accesses| Op {
+            reads,
+            writes: r(dr) | CC,
+            load: true,
+            accesses,
+            ..op
+        };
+        let store = |reads, accesses| Op { reads, accesses, ..op };
+        match *inst {
+            Inst::ADD { dr, sr1, sr2 } | Inst::AND { dr, sr1, sr2 } => Op {
+                reads: r(sr1) | r(sr2),
+                writes: r(dr) | CC,
+                ..op
+            },
+            Inst::ADDi { dr, sr, .. } | Inst::ANDi { dr, sr, .. } | Inst::NOT { dr, sr } => Op {
+                reads: r(sr),
+                writes: r(dr) | CC,
+                ..op
+            },
+            Inst::LEA { dr, .. } => Op { writes: r(dr) | CC, ..op },
+            // its redirect is down to the prediction
+            Inst::BR { .. } => Op { reads: CC, ..op },
+            Inst::JMP { base_r } => Op {
+                reads: r(base_r),
+                redirect: Redirect::Execute,
+                ..op
+            },
+            Inst::JSR { .. } => Op {
+                writes: r(7),
+                redirect: Redirect::Decode,
+                ..op
+            },
+            Inst::JSRr { base_r } => Op {
+                reads: r(base_r),
+                writes: r(7),
+                redirect: Redirect::Execute,
+                ..op
+            },
+            Inst::LD { dr, .. } => load(0, dr, 1),
+            Inst::LDI { dr, .. } => load(0, dr, 2),
+            Inst::LDR { dr, base_r, .. } => load(r(base_r), dr, 1),
+            Inst::ST { sr, .. } => store(r(sr), 1),
+            Inst::STI { sr, .. } => store(r(sr), 2),
+            Inst::STR { sr, base_r, .. } => store(r(sr) | r(base_r), 1),
+            Inst::TRAP { .. } => Op {
+                reads: r(6),
+                writes: 0xFF | CC,
+                load: true,
+                accesses: if native { 1 } else { 3 },
+                redirect: Redirect::Memory,
+                ..op
+            },
+            Inst::RTI => Op {
+                reads: r(6),
+                writes: r(6) | CC,
+                load: true,
+                accesses: 2,
+                redirect: Redirect::Memory,
+                ..op
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum Label {
+    #[default]
+    Empty,
+    At(u16),
+    Entry,
+}
+
+impl Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Label::Empty => f.pad("-"),
+            Label::At(pc) => f.pad(&format!("x{:04X}", pc)),
+            Label::Entry => f.pad("entry"),
+        }
+    }
+}
+
+const COUNTERS: usize = 256;
+
+pub struct Pipeline {
+    pub config: Config,
+    pub stats: Stats,
+    // where the per cycle diagram goes, if anywhere
+    pub diagram: Option<Box<dyn Write>>,
+    // the stages of the last instruction in, all 0 before the first
+    last: Stages,
+    // when each register, then the condition codes, can be had by EX with
+    // forwarding, and by ID without
+    ready: [(u64, u64); 9],
+    // the earliest the next fetch can be after a redirect
+    redirect: u64,
+    // the cycles MEM is accessing memory in, as [start, end)
+    busy: VecDeque<(u64, u64)>,
+    counters: Vec<u8>,
+    // what each stage holds in the cycles not yet in the diagram
+    rows: BTreeMap<u64, [Label; 5]>,
+    written: u64,
+    error: Option<io::Error>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline::new(Config::default())
+    }
+}
+
+impl Pipeline {
+    pub fn new(config: Config) -> Pipeline {
+        Pipeline {
+            config,
+            stats: Stats::default(),
+            diagram: None,
+            last: Stages::default(),
+            ready: [(0, 0); 9],
+            redirect: 0,
+            busy: VecDeque::new(),
+            // weakly not taken
+            counters: vec![1; COUNTERS],
+            rows: BTreeMap::new(),
+            written: 1,
+            error: None,
+        }
+    }
+
+    // Takes a step with `run` and times what it did: an interrupt entry if
+    // it took one, then the instruction, then an exception entry if the
+    // instruction faulted into the OS.
+    pub fn step(&mut self, lc3: &mut LC3, run: impl FnOnce(&mut LC3) -> Result<(), Fault>) -> Result<(), Fault> {
+        let interrupt = lc3.peek_interrupt();
+        let pc = interrupt.map_or(lc3.pc, |(entry, _)| lc3.memory[entry as usize]);
+        let raw = lc3.memory[pc as usize];
+        let supervisor = interrupt.is_some() || lc3.supervisor;
+        let native = raw >> 12 == 0b1111 && lc3.traps.contains(raw as u8);
+        run(lc3)?;
+
+        if interrupt.is_some() {
+            self.issue(Op::entry());
+        }
+        // 1101 is reserved, and only a TRAP goes from user to supervisor
+        // mode on its own
+        if raw >> 12 == 0b1101 || (!supervisor && lc3.supervisor && raw >> 12 != 0b1111) {
+            self.issue(Op::new(Label::At(pc)));
+            self.issue(Op::entry());
+            return Ok(());
+        }
+        let inst = Inst::from(raw);
+        let mut op = Op::decode(pc, &inst, native);
+        if let Inst::BR { cond, .. } = inst {
+            op.redirect = match (cond.n, cond.z, cond.p) {
+                (false, false, false) => Redirect::None,
+                (true, true, true) => Redirect::Decode,
+                _ => self.predict(pc, lc3.pc != pc.wrapping_add(1)),
+            };
+        }
+        self.issue(op);
+        Ok(())
+    }
+
+    pub fn run(&mut self, lc3: &mut LC3) -> Result<(), Fault> {
+        while !lc3.halted {
+            self.step(lc3, LC3::run_step)?;
+        }
+        Ok(())
+    }
+
+    // Where a conditional branch sends the fetch, learning its outcome.
+    fn predict(&mut self, pc: u16, taken: bool) -> Redirect {
+        let predicted = match self.config.predictor {
+            Predictor::NotTaken => false,
+            Predictor::TwoBit => {
+                let counter = &mut self.counters[pc as usize % COUNTERS];
+                let predicted = *counter >= 2;
+                *counter = if taken { (*counter + 1).min(3) } else { counter.saturating_sub(1) };
+                predicted
+            }
+        };
+        self.stats.branches += 1;
+        match (predicted, taken) {
+            (true, true) => Redirect::Decode,
+            (false, false) => Redirect::None,
+            _ => {
+                self.stats.mispredicted += 1;
+                Redirect::Execute
+            }
+        }
+    }
+
+    // Each stage is entered once the instruction ahead has left it and,
+    // past that, whatever hazard there is has cleared.
+    fn issue(&mut self, op: Op) -> Stages {
+        let last = self.last;
+        let stats = &mut self.stats;
+
+        let base = (last.fetch + 1).max(last.decode);
+        let mut fetch = base.max(self.redirect);
+        stats.control_stalls += fetch - base;
+        let redirected = fetch;
+        if !self.config.split_memory {
+            while self.busy.iter().any(|&(start, end)| (start..end).contains(&fetch)) {
+                fetch += 1;
+            }
+        }
+        stats.structural_stalls += fetch - redirected;
+
+        let regs = |ready: &[(u64, u64); 9], pick: fn(&(u64, u64)) -> u64| {
+            (0..9).filter(|i| op.reads & 1 << i != 0).map(|i| pick(&ready[i])).max().unwrap_or(0)
+        };
+        let base = (fetch + 1).max(last.execute);
+        let decode = if self.config.forwarding { base } else { base.max(regs(&self.ready, |r| r.1)) };
+        stats.data_stalls += decode - base;
+
+        let base = (decode + 1).max(last.memory);
+        let execute = if self.config.forwarding { base.max(regs(&self.ready, |r| r.0)) } else { base };
+        stats.data_stalls += execute - base;
+
+        let memory = (execute + 1).max(last.writeback);
+        stats.structural_stalls += memory - (execute + 1);
+        let writeback = memory + op.accesses.max(1);
+
+        let stages = Stages {
+            fetch,
+            decode,
+            execute,
+            memory,
+            writeback,
+        };
+        for (i, ready) in self.ready.iter_mut().enumerate() {
+            if op.writes & 1 << i != 0 {
+                *ready = (if op.load { writeback } else { memory }, writeback);
+            }
+        }
+        if op.accesses > 0 {
+            self.busy.push_back((memory, memory + op.accesses));
+        }
+        while self.busy.front().is_some_and(|&(_, end)| end <= fetch) {
+            self.busy.pop_front();
+        }
+        self.redirect = match op.redirect {
+            Redirect::None => 0,
+            Redirect::Decode => decode + 1,
+            Redirect::Execute => execute + 1,
+            Redirect::Memory => writeback,
+        };
+        stats.flushes += (op.redirect != Redirect::None) as u64;
+        match op.label {
+            Label::Entry => stats.entries += 1,
+            _ => stats.instructions += 1,
+        }
+        stats.cycles = writeback;
+        self.last = stages;
+        if self.diagram.is_some() {
+            self.draw(op.label, stages);
+        }
+        stages
+    }
+
+    // Records what the instruction occupies, then writes out the cycles no
+    // later instruction can be in, which is those before this one's fetch.
+    fn draw(&mut self, label: Label, s: Stages) {
+        let spans = [(s.fetch, s.decode), (s.decode, s.execute), (s.execute, s.memory), (s.memory, s.writeback), (s.writeback, s.writeback + 1)];
+        for (stage, &(start, end)) in spans.iter().enumerate() {
+            for cycle in start..end {
+                self.rows.entry(cycle).or_default()[stage] = label;
+            }
+        }
+        self.write_rows(s.fetch);
+    }
+
+    fn write_rows(&mut self, before: u64) {
+        let out = match &mut self.diagram {
+            Some(out) if self.error.is_none() => out,
+            _ => return,
+        };
+        let mut result = Ok(());
+        if self.written == 1 && before > 1 {
+            result = writeln!(out, "{:>6}  {:<7}{:<7}{:<7}{:<7}WB", "cycle", "IF", "ID", "EX", "MEM");
+        }
+        while self.written < before && result.is_ok() {
+            let row = self.rows.remove(&self.written).unwrap_or_default();
+            result = writeln!(out, "{:>6}  {:<7}{:<7}{:<7}{:<7}{}", self.written, row[0], row[1], row[2], row[3], row[4]);
+            self.written += 1;
+        }
+        if let Err(e) = result {
+            self.error = Some(e);
+        }
+    }
+
+    // Writes out the rest of the diagram.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.write_rows(self.stats.cycles + 1);
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        match &mut self.diagram {
+            Some(out) => out.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let s = &self.stats;
+        let cpi = s.cycles as f64 / s.instructions.max(1) as f64;
+        let mut out = format!("{} cycles, {} instructions, {:.2} cycles per instruction\n", s.cycles, s.instructions, cpi);
+        out += &format!("pipeline {}\n", self.config);
+        out += &format!(
+            "stalls: {} data, {} structural, {} control\n",
+            s.data_stalls, s.structural_stalls, s.control_stalls
+        );
+        out += &format!("{} flushes, {} interrupt and exception entries\n", s.flushes, s.entries);
+        if s.branches > 0 {
+            let right = 100.0 * (s.branches - s.mispredicted) as f64 / s.branches as f64;
+            out += &format!("{} branches, {} mispredicted, {:.1}% predicted right\n", s.branches, s.mispredicted, right);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::console::Console;
+    use crate::device::Keyboard;
+
+    fn machine(src: &str) -> LC3 {
+        let mut lc3 = LC3 {
+            console: Console::buffer(&[]),
+            ..LC3::default()
+        };
+        assemble(src).unwrap().load_into(&mut lc3);
+        lc3
+    }
+
+    fn timed(src: &str, config: Config) -> Pipeline {
+        let mut lc3 = machine(src);
+        let mut pipeline = Pipeline::new(config);
+        pipeline.run(&mut lc3).unwrap();
+        pipeline
+    }
+
+    #[test]
+    fn test_data_hazards() {
+        let no_forwarding = Config {
+            forwarding: false,
+            ..Config::default()
+        };
+        // HALT is a TRAP, the last instruction in either way
+        let independent = ".ORIG x3000\nADD R1, R1, #1\nADD R2, R2, #1\nADD R3, R3, #1\nHALT\n.END";
+        let stats = timed(independent, Config::default()).stats;
+        assert_eq!((stats.cycles, stats.instructions, stats.data_stalls), (4 + 4, 4, 0));
+
+        // an ALU result is forwarded in time, and otherwise read in WB
+        let dependent = ".ORIG x3000\nADD R1, R1, #1\nADD R2, R1, #1\nHALT\n.END";
+        assert_eq!(timed(dependent, Config::default()).stats.data_stalls, 0);
+        assert_eq!(timed(dependent, no_forwarding.clone()).stats.data_stalls, 2);
+
+        // a load's isn't there until the end of MEM
+        let load_use = ".ORIG x3000\nLD R1, X\nADD R2, R1, #1\nHALT\nX .FILL 5\n.END";
+        assert_eq!(timed(load_use, Config::default()).stats.data_stalls, 1);
+        assert_eq!(timed(load_use, no_forwarding).stats.data_stalls, 2);
+
+        // LDI holds MEM for two cycles, and the fetch behind it waits for
+        // the memory port unless memory is split
+        let indirect = ".ORIG x3000\nLDI R1, P\nADD R2, R2, #1\nADD R3, R3, #1\nADD R4, R4, #1\nHALT\nP .FILL x3000\n.END";
+        let unified = timed(indirect, Config::default()).stats;
+        let mut split = Config::default();
+        split.parse("memory=split").unwrap();
+        let split = timed(indirect, split).stats;
+        assert_eq!((split.structural_stalls, split.cycles), (1, 5 + 4 + 1));
+        assert!(unified.structural_stalls > split.structural_stalls);
+    }
+
+    #[test]
+    fn test_branch_prediction() {
+        // BRp taken nine times and then falling through
+        let src = ".ORIG x3000\nAND R1, R1, #0\nADD R1, R1, #10\nLOOP ADD R1, R1, #-1\nBRp LOOP\nHALT\n.END";
+        let not_taken = timed(src, Config::default());
+        assert_eq!((not_taken.stats.branches, not_taken.stats.mispredicted), (10, 9));
+        let mut config = Config::default();
+        config.parse("predictor=2-bit").unwrap();
+        let two_bit = timed(src, config);
+        // wrong on the first time round and on leaving
+        assert_eq!((two_bit.stats.branches, two_bit.stats.mispredicted), (10, 2));
+        assert!(two_bit.stats.cycles < not_taken.stats.cycles);
+        assert!(two_bit.stats.control_stalls < not_taken.stats.control_stalls);
+        assert!(two_bit.report().contains("10 branches, 2 mispredicted, 80.0% predicted right"));
+
+        assert_eq!(config_error("predictor=always"), "predictor: expected not-taken or 2-bit");
+        assert_eq!(config_error("depth=7"), "no pipeline setting called depth");
+        assert_eq!(two_bit.config.to_string(), "forwarding=on,predictor=2-bit,memory=unified");
+    }
+
+    fn config_error(settings: &str) -> String {
+        Config::default().parse(settings).unwrap_err()
+    }
+
+    #[test]
+    fn test_diagram_and_interrupts() {
+        let src = ".ORIG x3000\nLD R1, X\nADD R2, R1, #1\nHALT\nX .FILL 5\n.END";
+        let mut lc3 = machine(src);
+        let mut pipeline = Pipeline::new(Config::default());
+        let path = std::env::temp_dir().join(format!("lc3-pipeline-{}.txt", std::process::id()));
+        pipeline.diagram = Some(Box::new(std::fs::File::create(&path).unwrap()));
+        pipeline.run(&mut lc3).unwrap();
+        pipeline.finish().unwrap();
+        let diagram = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let expected = " cycle  IF     ID     EX     MEM    WB
+     1  x3000  -      -      -      -
+     2  x3001  x3000  -      -      -
+     3  x3002  x3001  x3000  -      -
+     4  x3002  x3001  -      x3000  -
+     5  -      x3002  x3001  -      x3000
+     6  -      -      x3002  x3001  -
+     7  -      -      -      x3002  x3001
+     8  -      -      -      -      x3002
+";
+        assert_eq!(diagram, expected);
+
+        // keyboard interrupts taken by the OS, with the core's results the
+        // same as without the pipeline
+        let make = || {
+            let mut lc3 = LC3::with_os();
+            lc3.console = Console::buffer(b"kk");
+            let src = ".ORIG x3000\nLOOP ADD R1, R1, #1\nADD R2, R1, #-15\nBRn LOOP\nHALT\n.END";
+            assemble(src).unwrap().load_into(&mut lc3);
+            lc3.devices.get_mut::<Keyboard>().unwrap().interrupt_enable = true;
+            lc3
+        };
+        let (mut plain, mut lc3) = (make(), make());
+        plain.run().unwrap();
+        let mut pipeline = Pipeline::new(Config::default());
+        pipeline.run(&mut lc3).unwrap();
+        assert_eq!((lc3.registers, lc3.executed, lc3.memory), (plain.registers, plain.executed, plain.memory));
+        assert_eq!(pipeline.stats.instructions, lc3.executed);
+        assert_eq!(pipeline.stats.entries, 2);
+
+        // RTI in user mode, which the OS reports before halting
+        let mut lc3 = LC3::with_os();
+        lc3.console = Console::buffer(&[]);
+        assemble(".ORIG x3000\nRTI\n.END").unwrap().load_into(&mut lc3);
+        let mut pipeline = Pipeline::new(Config::default());
+        pipeline.run(&mut lc3).unwrap();
+        assert_eq!((pipeline.stats.entries, pipeline.stats.instructions), (1, lc3.executed));
+    }
+}
@@ -0,0 +1,570 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::asm::{is_label, is_mnemonic, parse_register, tokenize, Diagnostic, Token};
+use crate::utils::{parse_number, unescape};
+
+// How deeply includes and macro expansions may nest.
+const MAX_DEPTH: usize = 32;
+
+// One line of assembler input after preprocessing, remembering where it
+// came from.
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub text: String,
+    // The line of the main source that produced this one. For included or
+    // expanded text, that is the .INCLUDE line or the macro call.
+    pub site: usize,
+    // Where the text was written: None for the main source.
+    pub file: Option<String>,
+    pub line: usize,
+    // The length of the original line, set once substitution has moved
+    // columns so that token positions no longer match it.
+    pub width: Option<usize>,
+    // Which macro expansions this line went through, for error messages.
+    pub context: Option<String>,
+}
+
+impl Line {
+    pub fn diagnostic(&self, start: usize, end: usize, message: String) -> Diagnostic {
+        let (start, end) = self.width.map_or((start, end), |w| (0, w));
+        let message = match &self.context {
+            Some(context) => format!("{} ({})", message, context),
+            None => message,
+        };
+        Diagnostic {
+            file: self.file.clone(),
+            line: self.line,
+            site: self.site,
+            start,
+            end,
+            message,
+        }
+    }
+
+    fn location(&self) -> String {
+        match &self.file {
+            Some(file) => format!("{}:{}", file, self.line),
+            None => format!("line {}", self.line),
+        }
+    }
+}
+
+pub enum EvalError {
+    Undefined(String),
+    Invalid(String),
+}
+
+struct Expr<'a, F: Fn(&str) -> Option<i32>> {
+    src: &'a str,
+    pos: usize,
+    lookup: F,
+    symbolic: bool,
+}
+
+impl<F: Fn(&str) -> Option<i32>> Expr<'_, F> {
+    fn peek(&self) -> Option<u8> {
+        self.src.as_bytes().get(self.pos).copied()
+    }
+
+    fn invalid(&self) -> EvalError {
+        EvalError::Invalid(format!("expected a number, found '{}'", self.src))
+    }
+
+    fn sum(&mut self) -> Result<i32, EvalError> {
+        let mut value = self.product()?;
+        while let Some(op @ (b'+' | b'-')) = self.peek() {
+            self.pos += 1;
+            let rhs = self.product()?;
+            value = if op == b'+' { value.wrapping_add(rhs) } else { value.wrapping_sub(rhs) };
+        }
+        Ok(value)
+    }
+
+    fn product(&mut self) -> Result<i32, EvalError> {
+        let mut value = self.unary()?;
+        while let Some(op @ (b'*' | b'/')) = self.peek() {
+            self.pos += 1;
+            let rhs = self.unary()?;
+            value = match op {
+                b'*' => value.wrapping_mul(rhs),
+                _ if rhs == 0 => return Err(EvalError::Invalid(format!("division by zero in '{}'", self.src))),
+                _ => value.wrapping_div(rhs),
+            };
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<i32, EvalError> {
+        match self.peek() {
+            Some(b'-') => {
+                self.pos += 1;
+                Ok(self.unary()?.wrapping_neg())
+            }
+            Some(b'(') => {
+                self.pos += 1;
+                let value = self.sum()?;
+                if self.peek() != Some(b')') {
+                    return Err(self.invalid());
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            _ => self.atom(),
+        }
+    }
+
+    fn atom(&mut self) -> Result<i32, EvalError> {
+        let start = self.pos;
+        if self.peek() == Some(b'#') {
+            self.pos += 1;
+            if self.peek() == Some(b'-') {
+                self.pos += 1;
+            }
+        }
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == b'_') {
+            self.pos += 1;
+        }
+        let word = &self.src[start..self.pos];
+        if let Some(n) = parse_number(word) {
+            return Ok(n);
+        }
+        if !is_label(word) || parse_register(word).is_some() {
+            return Err(self.invalid());
+        }
+        self.symbolic = true;
+        (self.lookup)(word).ok_or_else(|| EvalError::Undefined(word.to_string()))
+    }
+}
+
+// Evaluates an operand such as `LABEL+3` or `#-1*4`, returning its value and
+// whether it referred to any labels.
+pub fn eval<F: Fn(&str) -> Option<i32>>(src: &str, lookup: F) -> Result<(i32, bool), EvalError> {
+    let mut expr = Expr {
+        src,
+        pos: 0,
+        lookup,
+        symbolic: false,
+    };
+    let value = expr.sum()?;
+    if expr.pos != src.len() {
+        return Err(expr.invalid());
+    }
+    Ok((value, expr.symbolic))
+}
+
+// Rewrites the identifiers in each non-string token, leaving the rest of the
+// line (spacing, comments) as it was. Returns None if nothing changed.
+fn rewrite<F: Fn(&str) -> Option<String>>(text: &str, tokens: &[Token], f: F) -> Option<String> {
+    let mut out = String::new();
+    let mut pos = 0;
+    let mut changed = false;
+    for tok in tokens.iter().filter(|t| !t.text.starts_with('"')) {
+        out += &text[pos..tok.start];
+        let bytes = tok.text.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            let c = bytes[i];
+            if !(c.is_ascii_alphabetic() || c == b'_' || c == b'@' || c == b'\\') {
+                out.push(c as char);
+                i += 1;
+                continue;
+            }
+            let mut j = i + 1;
+            while j < bytes.len() && (bytes[j].is_ascii_alphanumeric() || bytes[j] == b'_') {
+                j += 1;
+            }
+            let word = &tok.text[i..j];
+            match f(word) {
+                Some(replacement) => {
+                    out += &replacement;
+                    changed = true;
+                }
+                None => out += word,
+            }
+            i = j;
+        }
+        pos = tok.end;
+    }
+    out += &text[pos..];
+    if changed {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Line>,
+}
+
+struct Cond {
+    value: bool,
+    in_else: bool,
+    at: usize,
+}
+
+struct Preprocessor {
+    main: Option<PathBuf>,
+    // value, and whether it was fixed with .EQU
+    constants: BTreeMap<String, (i32, bool)>,
+    macros: BTreeMap<String, Macro>,
+    expansions: usize,
+    out: Vec<Line>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Preprocessor {
+    fn error(&mut self, line: &Line, tok: Option<&Token>, message: String) {
+        let (start, end) = tok.map_or((0, line.text.len()), |t| (t.start, t.end));
+        self.diagnostics.push(line.diagnostic(start, end, message));
+    }
+
+    fn constant(&self, expr: &str) -> Result<i32, String> {
+        match eval(expr, |name| self.constants.get(name).map(|c| c.0)) {
+            Ok((value, _)) => Ok(value),
+            Err(EvalError::Undefined(name)) => Err(format!("'{}' is not a constant defined above", name)),
+            Err(EvalError::Invalid(message)) => Err(message),
+        }
+    }
+
+    fn define(&mut self, line: &Line, label: Option<&Token>, op: &Token, operands: &[Token]) {
+        let directive = op.text.to_ascii_uppercase();
+        let name = match label {
+            Some(name) if is_label(name.text) => name,
+            _ => return self.error(line, Some(op), format!("{} needs a name, as in 'NAME {} 10'", directive, directive)),
+        };
+        if operands.len() != 1 {
+            let message = format!("{} expects 1 operand(s), found {}", directive, operands.len());
+            return self.error(line, Some(op), message);
+        }
+        let value = match self.constant(operands[0].text) {
+            Ok(value) => value,
+            Err(message) => return self.error(line, Some(&operands[0]), message),
+        };
+        let fixed = directive == ".EQU";
+        if let Some((_, was_fixed)) = self.constants.get(name.text) {
+            if fixed || *was_fixed {
+                return self.error(line, Some(name), format!("constant '{}' is already defined", name.text));
+            }
+        }
+        self.constants.insert(name.text.to_string(), (value, fixed));
+    }
+
+    fn include(&mut self, line: &Line, op: &Token, operands: &[Token], depth: usize) {
+        let name = match operands {
+            [tok] => match unescape(tok.text) {
+                Some(name) => String::from_utf8_lossy(&name).into_owned(),
+                None => return self.error(line, Some(tok), format!("expected a quoted file name, found {}", tok.text)),
+            },
+            _ => {
+                let message = format!(".INCLUDE expects 1 operand(s), found {}", operands.len());
+                return self.error(line, Some(op), message);
+            }
+        };
+        if depth >= MAX_DEPTH {
+            return self.error(line, Some(op), format!("includes nest too deeply, does '{}' include itself?", name));
+        }
+        let dir = match &line.file {
+            Some(file) => Path::new(file).parent().map(Path::to_path_buf),
+            None => self.main.as_ref().and_then(|m| m.parent()).map(Path::to_path_buf),
+        };
+        let path = dir.unwrap_or_default().join(&name);
+        let src = match std::fs::read_to_string(&path) {
+            Ok(src) => src,
+            Err(e) => return self.error(line, Some(&operands[0]), format!("cannot include '{}': {}", name, e)),
+        };
+        let file = path.to_string_lossy().into_owned();
+        let lines = src
+            .lines()
+            .enumerate()
+            .map(|(i, text)| Line {
+                text: text.to_string(),
+                site: line.site,
+                file: Some(file.clone()),
+                line: i + 1,
+                width: None,
+                context: line.context.clone(),
+            })
+            .collect();
+        self.process(lines, depth + 1);
+    }
+
+    fn expand(&mut self, line: &Line, name: &Token, args: &[Token], depth: usize) {
+        let mac = &self.macros[name.text];
+        let (params, body) = (mac.params.clone(), mac.body.clone());
+        if args.len() != params.len() {
+            let message = format!("macro '{}' expects {} argument(s), found {}", name.text, params.len(), args.len());
+            return self.error(line, Some(name), message);
+        }
+        if depth >= MAX_DEPTH {
+            let message = format!("macro expansion nests too deeply, does '{}' call itself?", name.text);
+            return self.error(line, Some(name), message);
+        }
+        self.expansions += 1;
+        let n = self.expansions;
+        let context = match &line.context {
+            Some(outer) => format!("in macro '{}' expanded on {}, {}", name.text, line.location(), outer),
+            None => format!("in macro '{}' expanded on {}", name.text, line.location()),
+        };
+        let substitute = |word: &str| {
+            if let Some(param) = word.strip_prefix('\\') {
+                let i = params.iter().position(|p| p == param)?;
+                return Some(args[i].text.to_string());
+            }
+            let local = word.strip_prefix('@')?;
+            Some(format!("_{}{}_{}", name.text, n, local))
+        };
+        let lines: Vec<Line> = body
+            .iter()
+            .map(|body| {
+                let rewritten = rewrite(&body.text, &tokenize(&body.text), substitute);
+                Line {
+                    width: rewritten.as_ref().map_or(body.width, |_| Some(body.text.len())),
+                    text: rewritten.unwrap_or_else(|| body.text.clone()),
+                    site: line.site,
+                    file: body.file.clone(),
+                    line: body.line,
+                    context: Some(context.clone()),
+                }
+            })
+            .collect();
+        self.process(lines, depth + 1);
+    }
+
+    fn process(&mut self, lines: Vec<Line>, depth: usize) {
+        let mut conds: Vec<Cond> = Vec::new();
+        let mut defining: Option<(String, Vec<String>, usize, Vec<Line>)> = None;
+        for (idx, line) in lines.iter().enumerate() {
+            let tokens = tokenize(&line.text);
+            let labelled = tokens
+                .first()
+                .is_some_and(|t| !is_mnemonic(t.text) && !self.macros.contains_key(t.text));
+            let label = tokens.first().filter(|_| labelled);
+            let rest = &tokens[labelled as usize..];
+            let (op, operands) = match rest.split_first() {
+                Some((op, operands)) => (Some(op), operands),
+                None => (None, rest),
+            };
+            let directive = op.map_or(String::new(), |t| t.text.to_ascii_uppercase());
+
+            if let Some((_, _, _, body)) = &mut defining {
+                match directive.as_str() {
+                    ".ENDM" => {
+                        let (name, params, _, body) = defining.take().unwrap();
+                        self.macros.insert(name, Macro { params, body });
+                    }
+                    ".MACRO" => self.error(line, op, "macros cannot be defined inside another macro".to_string()),
+                    _ => body.push(line.clone()),
+                }
+                continue;
+            }
+
+            let active = conds.iter().all(|c| c.value != c.in_else);
+            match directive.as_str() {
+                ".IF" => {
+                    let value = active
+                        && match operands {
+                            [expr] => match self.constant(expr.text) {
+                                Ok(value) => value != 0,
+                                Err(message) => {
+                                    self.error(line, Some(expr), message);
+                                    false
+                                }
+                            },
+                            _ => {
+                                let message = format!(".IF expects 1 operand(s), found {}", operands.len());
+                                self.error(line, op, message);
+                                false
+                            }
+                        };
+                    conds.push(Cond {
+                        value,
+                        in_else: false,
+                        at: idx,
+                    });
+                    continue;
+                }
+                ".ELSE" => {
+                    match conds.last_mut() {
+                        Some(cond) if !cond.in_else => cond.in_else = true,
+                        Some(_) => self.error(line, op, ".ELSE already seen for this .IF".to_string()),
+                        None => self.error(line, op, ".ELSE without .IF".to_string()),
+                    }
+                    continue;
+                }
+                ".ENDIF" => {
+                    if conds.pop().is_none() {
+                        self.error(line, op, ".ENDIF without .IF".to_string());
+                    }
+                    continue;
+                }
+                _ if !active => continue,
+                _ => {}
+            }
+
+            if let Some(label) = label.filter(|_| {
+                matches!(directive.as_str(), ".INCLUDE" | ".MACRO") || op.is_some_and(|t| self.macros.contains_key(t.text))
+            }) {
+                // keep the label on its own line so it binds to what follows
+                self.out.push(Line {
+                    text: line.text[..label.end].to_string(),
+                    ..line.clone()
+                });
+            }
+            match (directive.as_str(), op) {
+                (".MACRO", Some(op)) => match operands.split_first() {
+                    Some((name, params)) if is_label(name.text) => {
+                        let params = params.iter().map(|p| p.text.trim_start_matches('\\').to_string()).collect();
+                        defining = Some((name.text.to_string(), params, idx, Vec::new()));
+                    }
+                    Some((name, _)) => self.error(line, Some(name), format!("'{}' is not a valid macro name", name.text)),
+                    None => self.error(line, Some(op), ".MACRO needs a name".to_string()),
+                },
+                (".ENDM", _) => self.error(line, op, ".ENDM without .MACRO".to_string()),
+                (".INCLUDE", Some(op)) => self.include(line, op, operands, depth),
+                (".EQU", Some(op)) | (".SET", Some(op)) => self.define(line, label, op, operands),
+                (_, Some(op)) if self.macros.contains_key(op.text) => self.expand(line, op, operands, depth),
+                _ => {
+                    let constants = &self.constants;
+                    let rewritten = rewrite(&line.text, operands, |word| {
+                        constants.get(word).map(|(value, _)| value.to_string())
+                    });
+                    self.out.push(match rewritten {
+                        Some(text) => Line {
+                            width: Some(line.width.unwrap_or(line.text.len())),
+                            text,
+                            ..line.clone()
+                        },
+                        None => line.clone(),
+                    });
+                }
+            }
+        }
+        if let Some((name, _, at, _)) = defining {
+            self.error(&lines[at], None, format!("missing .ENDM for macro '{}'", name));
+        }
+        for cond in conds {
+            self.error(&lines[cond.at], None, "missing .ENDIF for this .IF".to_string());
+        }
+    }
+}
+
+// Expands includes, macros, constants and conditionals, leaving plain
+// assembly for the two passes. Includes are found relative to the file that
+// names them, or to `path` for the main source.
+pub fn preprocess(src: &str, path: Option<&Path>) -> (Vec<Line>, Vec<Diagnostic>) {
+    let mut pp = Preprocessor {
+        main: path.map(Path::to_path_buf),
+        constants: BTreeMap::new(),
+        macros: BTreeMap::new(),
+        expansions: 0,
+        out: Vec::new(),
+        diagnostics: Vec::new(),
+    };
+    let lines = src
+        .lines()
+        .enumerate()
+        .map(|(i, text)| Line {
+            text: text.to_string(),
+            site: i + 1,
+            file: None,
+            line: i + 1,
+            width: None,
+            context: None,
+        })
+        .collect();
+    pp.process(lines, 0);
+    (pp.out, pp.diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{eval, preprocess, EvalError};
+
+    fn texts(src: &str) -> Vec<String> {
+        let (lines, diagnostics) = preprocess(src, None);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        lines.into_iter().map(|l| l.text.trim().to_string()).filter(|t| !t.is_empty()).collect()
+    }
+
+    #[test]
+    fn test_eval() {
+        let lookup = |name: &str| if name == "TABLE" { Some(0x3010) } else { None };
+        assert!(matches!(eval("#-1*4", lookup), Ok((-4, false))));
+        assert!(matches!(eval("TABLE+3", lookup), Ok((0x3013, true))));
+        assert!(matches!(eval("-(x10-2)/2", lookup), Ok((-7, false))));
+        assert!(matches!(eval("2-#-3", lookup), Ok((5, false))));
+        assert!(matches!(eval("NOPE+1", lookup), Err(EvalError::Undefined(ref n)) if n == "NOPE"));
+        assert!(matches!(eval("R1", lookup), Err(EvalError::Invalid(_))));
+        assert!(matches!(eval("1/0", lookup), Err(EvalError::Invalid(_))));
+        assert!(matches!(eval("(1", lookup), Err(EvalError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_constants_and_conditionals() {
+        let src = "
+SIZE .EQU 4
+DEBUG .SET 0
+.IF SIZE-4
+  ADD R0, R0, #1
+.ELSE
+  .IF DEBUG
+    TRAP x21
+  .ELSE
+    AND R0, R0, #SIZE*2 ; stays a comment
+  .ENDIF
+.ENDIF
+DEBUG .SET 1
+.IF DEBUG
+  .BLKW SIZE
+.ENDIF";
+        assert_eq!(texts(src), vec!["AND R0, R0, #4*2 ; stays a comment", ".BLKW 4"]);
+    }
+
+    #[test]
+    fn test_macros_and_local_labels() {
+        let src = "
+.MACRO PUSH reg
+  ADD R6, R6, #-1
+  STR \\reg, R6, #0
+.ENDM
+.MACRO SPIN n
+@loop ADD \\n, \\n, #-1
+  BRp @loop
+.ENDM
+TOP PUSH R1
+    SPIN R2
+    SPIN R3";
+        assert_eq!(
+            texts(src),
+            vec![
+                "TOP",
+                "ADD R6, R6, #-1",
+                "STR R1, R6, #0",
+                "_SPIN2_loop ADD R2, R2, #-1",
+                "BRp _SPIN2_loop",
+                "_SPIN3_loop ADD R3, R3, #-1",
+                "BRp _SPIN3_loop",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_preprocess_errors() {
+        let (_, diagnostics) = preprocess(".MACRO M a\n.ENDM\nM\n.IF X\n.ELSE\n.ELSE\n.ENDIF\n.ENDIF\nX .EQU 1\nX .SET 2\n.IF 1", None);
+        let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "line 3: macro 'M' expects 1 argument(s), found 0",
+                "line 4: 'X' is not a constant defined above",
+                "line 6: .ELSE already seen for this .IF",
+                "line 8: .ENDIF without .IF",
+                "line 10: constant 'X' is already defined",
+                "line 11: missing .ENDIF for this .IF",
+            ]
+        );
+    }
+}
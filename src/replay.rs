@@ -0,0 +1,354 @@
+use std::fmt::Display;
+
+use crate::{Fault, LC3};
+
+// A log of everything that came into a run from outside: what device
+// registers read as, the characters the console traps got, and where
+// interrupts arrived, each keyed by the number of instructions executed.
+// Replaying it feeds the same run back without a terminal or devices.
+//
+// Writes and DMA still go to the devices on replay, so a run using the disk
+// or file traps needs the same files. Anything else that differs shows up
+// in the final state, which is checked at the end.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Log {
+    // of the memory and PC when recording started
+    pub image: u64,
+    pub events: Vec<Event>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    // a device register, or the console when there is no address; None when
+    // the input ran out
+    Input { at: u64, addr: Option<u16>, value: Option<u16> },
+    Interrupt { at: u64, entry: u16, priority: u8 },
+    // the machine's state when recording stopped
+    End { at: u64, state: u64 },
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::Input { at, addr, value } => {
+                match addr {
+                    Some(addr) => write!(f, "READ {} x{:04X}", at, addr)?,
+                    None => write!(f, "GETC {}", at)?,
+                }
+                match value {
+                    Some(value) => write!(f, " x{:04X}", value),
+                    None => write!(f, " EOF"),
+                }
+            }
+            Event::Interrupt { at, entry, priority } => write!(f, "INTERRUPT {} x{:04X} {}", at, entry, priority),
+            Event::End { at, state } => write!(f, "END {} {:016X}", at, state),
+        }
+    }
+}
+
+fn hex(s: &str) -> Option<u16> {
+    u16::from_str_radix(s.strip_prefix('x')?, 16).ok()
+}
+
+impl Log {
+    pub fn to_text(&self) -> String {
+        let mut out = format!("LC3LOG 1\nIMAGE {:016X}\n", self.image);
+        for event in &self.events {
+            out += &format!("{}\n", event);
+        }
+        out
+    }
+
+    pub fn from_text(text: &str) -> Result<Log, String> {
+        let mut lines = text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty());
+        let fail = |idx: usize, message: &str| format!("{}: {}", idx + 1, message);
+        if lines.next().map(|(_, l)| l.split_whitespace().collect::<Vec<_>>()) != Some(vec!["LC3LOG", "1"]) {
+            return Err(fail(0, "not an LC-3 replay log"));
+        }
+        let image = match lines.next() {
+            Some((idx, line)) => match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                ["IMAGE", hash] => u64::from_str_radix(hash, 16).map_err(|_| fail(idx, "expected an image hash"))?,
+                _ => return Err(fail(idx, "expected an image hash")),
+            },
+            None => return Err(fail(1, "expected an image hash")),
+        };
+        let mut events = Vec::new();
+        for (idx, line) in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let at = fields
+                .get(1)
+                .and_then(|at| at.parse().ok())
+                .ok_or_else(|| fail(idx, "expected an instruction count"))?;
+            let value = |s: &str| match s {
+                "EOF" => Ok(None),
+                s => hex(s).map(Some).ok_or_else(|| fail(idx, "expected a value")),
+            };
+            events.push(match fields.as_slice() {
+                ["READ", _, addr, v] => Event::Input {
+                    at,
+                    addr: Some(hex(addr).ok_or_else(|| fail(idx, "expected an address"))?),
+                    value: value(v)?,
+                },
+                ["GETC", _, v] => Event::Input {
+                    at,
+                    addr: None,
+                    value: value(v)?,
+                },
+                ["INTERRUPT", _, entry, priority] => Event::Interrupt {
+                    at,
+                    entry: hex(entry).ok_or_else(|| fail(idx, "expected a vector"))?,
+                    priority: priority.parse().map_err(|_| fail(idx, "expected a priority"))?,
+                },
+                ["END", _, state] => Event::End {
+                    at,
+                    state: u64::from_str_radix(state, 16).map_err(|_| fail(idx, "expected a state hash"))?,
+                },
+                _ => return Err(fail(idx, "unrecognised event")),
+            });
+        }
+        Ok(Log { image, events })
+    }
+}
+
+pub enum Journal {
+    Recording(Log),
+    Replaying { log: Log, next: usize },
+}
+
+// FNV-1a, which is plenty to tell two runs apart.
+fn fnv(words: impl Iterator<Item = u16>) -> u64 {
+    words.fold(0xcbf2_9ce4_8422_2325, |hash, w| {
+        w.to_le_bytes()
+            .iter()
+            .fold(hash, |hash, &b| (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+    })
+}
+
+impl LC3 {
+    fn image_hash(&self) -> u64 {
+        fnv(self.memory.iter().copied().chain([self.pc]))
+    }
+
+    fn state_hash(&self) -> u64 {
+        let registers = self.registers.iter().map(|&r| r as u16);
+        let rest = [self.pc, self.psr(), self.halted as u16, self.saved_ssp, self.saved_usp];
+        fnv(self.memory.iter().copied().chain(registers).chain(rest))
+    }
+
+    // Starts logging, once the program is loaded and the devices attached.
+    pub fn start_recording(&mut self) {
+        self.journal = Some(Journal::Recording(Log {
+            image: self.image_hash(),
+            events: Vec::new(),
+        }));
+    }
+
+    pub fn finish_recording(&mut self) -> Option<Log> {
+        let end = Event::End {
+            at: self.executed,
+            state: self.state_hash(),
+        };
+        match self.journal.take()? {
+            Journal::Recording(mut log) => {
+                log.events.push(end);
+                Some(log)
+            }
+            journal => {
+                self.journal = Some(journal);
+                None
+            }
+        }
+    }
+
+    // Fails straight away if this is not the program the log was made with.
+    pub fn start_replay(&mut self, log: Log) -> Result<(), Fault> {
+        if log.image != self.image_hash() {
+            return Err(Fault::Diverged("the program is not the one that was recorded".to_string()));
+        }
+        self.journal = Some(Journal::Replaying { log, next: 0 });
+        Ok(())
+    }
+
+    // Checks the run ended where and how the recording did.
+    pub fn finish_replay(&mut self) -> Result<(), Fault> {
+        let (log, next) = match self.journal.take() {
+            Some(Journal::Replaying { log, next }) => (log, next),
+            _ => return Ok(()),
+        };
+        match log.events.get(next) {
+            Some(&Event::End { at, state }) if at == self.executed && state == self.state_hash() => Ok(()),
+            Some(&Event::End { at, .. }) if at == self.executed => Err(self.diverged("the final state differs")),
+            Some(&Event::End { at, .. }) => Err(self.diverged(&format!("the recording stopped after {}", at))),
+            Some(event) => Err(self.diverged(&format!("stopped early, before {}", event))),
+            None => Err(self.diverged("ran past the end of the log")),
+        }
+    }
+
+    fn diverged(&self, what: &str) -> Fault {
+        Fault::Diverged(format!("after {} instructions, {}", self.executed, what))
+    }
+
+    // Takes an input from the log when replaying, and otherwise gets it live,
+    // logging it if recording.
+    pub(crate) fn journaled<F>(&mut self, addr: Option<u16>, live: F) -> Result<u16, Fault>
+    where
+        F: FnOnce(&mut LC3) -> Result<u16, Fault>,
+    {
+        let at = self.executed;
+        if let Some(Journal::Replaying { log, next }) = &mut self.journal {
+            return match log.events.get(*next) {
+                Some(&Event::Input { at: a, addr: r, value }) if a == at && r == addr => {
+                    *next += 1;
+                    value.ok_or(Fault::EndOfInput)
+                }
+                event => {
+                    let expected = event.map_or("the end of the log".to_string(), ToString::to_string);
+                    let source = addr.map_or("the console".to_string(), |a| format!("x{:04X}", a));
+                    Err(self.diverged(&format!("read {} where the log has {}", source, expected)))
+                }
+            };
+        }
+        let result = live(self);
+        if let Some(Journal::Recording(log)) = &mut self.journal {
+            let value = match &result {
+                Ok(value) => Some(*value),
+                Err(Fault::EndOfInput) => None,
+                Err(_) => return result,
+            };
+            log.events.push(Event::Input { at, addr, value });
+        }
+        result
+    }
+
+    // The interrupt to take before the next instruction: the one the log
+    // says when replaying, and otherwise whatever the devices ask for.
+    pub(crate) fn next_interrupt(&mut self) -> Option<(u16, u8)> {
+        let at = self.executed;
+        let interrupt = self.peek_interrupt();
+        match (&mut self.journal, interrupt) {
+            (Some(Journal::Replaying { next, .. }), Some(_)) => *next += 1,
+            (Some(Journal::Recording(log)), Some((entry, priority))) => log.events.push(Event::Interrupt { at, entry, priority }),
+            _ => {}
+        }
+        interrupt
+    }
+
+    // The same, without taking it.
+    pub(crate) fn peek_interrupt(&self) -> Option<(u16, u8)> {
+        match &self.journal {
+            Some(Journal::Replaying { log, next }) => match log.events.get(*next) {
+                Some(&Event::Interrupt { at, entry, priority }) if at == self.executed => Some((entry, priority)),
+                _ => None,
+            },
+            _ => self.pending_interrupt(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::console::Console;
+
+    // Echoes keys upper-cased until a newline, counting in the foreground
+    // until the keyboard interrupt says there is one, and reading the timer
+    // as it goes.
+    const ECHO: &str = r#"
+        .ORIG x3000
+        LD R0, IE
+        STI R0, KBSR
+LOOP    ADD R1, R1, #1
+        LDI R3, TMCNT
+        ADD R2, R2, #0
+        BRz LOOP
+        AND R2, R2, #0
+        GETC
+        LD R5, IE
+        STI R5, KBSR
+        ADD R4, R0, #-10
+        BRz DONE
+        ADD R0, R0, #-16
+        ADD R0, R0, #-16
+        OUT
+        BR LOOP
+DONE    HALT
+ISR     ADD R2, R2, #1
+        AND R5, R5, #0
+        STI R5, KBSR
+        RTI
+IE      .FILL x4000
+KBSR    .FILL xFE00
+TMCNT   .FILL xFE0E
+        .END
+    "#;
+
+    fn machine(src: &str, input: &[u8]) -> LC3 {
+        let program = assemble(src).unwrap();
+        let mut lc3 = LC3 {
+            console: Console::buffer(input),
+            ..LC3::default()
+        };
+        program.load_into(&mut lc3);
+        if let Some(isr) = program.symbols.get("ISR") {
+            lc3.memory[0x0180] = isr.addr;
+        }
+        lc3
+    }
+
+    fn record(input: &[u8]) -> (LC3, Log) {
+        let mut lc3 = machine(ECHO, input);
+        lc3.start_recording();
+        lc3.run().unwrap();
+        let log = lc3.finish_recording().unwrap();
+        (lc3, log)
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let (recorded, log) = record(b"abc\n");
+        assert_eq!(recorded.console.output(), b"ABC");
+        assert!(log.events.iter().any(|e| matches!(e, Event::Input { addr: Some(0xFE0E), value: Some(0), .. })));
+        assert!(log.events.iter().any(|e| matches!(e, Event::Interrupt { entry: 0x0180, priority: 4, .. })));
+        assert!(log.events.iter().any(|e| matches!(e, Event::Input { addr: None, value: Some(10), .. })));
+        assert_eq!(Log::from_text(&log.to_text()), Ok(log.clone()));
+
+        // no input at all this time, it all comes from the log
+        let mut lc3 = machine(ECHO, b"");
+        lc3.start_replay(log).unwrap();
+        lc3.run().unwrap();
+        lc3.finish_replay().unwrap();
+        assert_eq!(lc3.console.output(), b"ABC");
+        assert_eq!((lc3.registers, lc3.executed), (recorded.registers, recorded.executed));
+    }
+
+    #[test]
+    fn test_divergence() {
+        let (_, log) = record(b"ab\n");
+
+        let mut other = machine(&ECHO.replace("#-10", "#-9"), b"");
+        let fault = other.start_replay(log.clone()).unwrap_err();
+        assert_eq!(fault.to_string(), "replay diverged: the program is not the one that was recorded");
+
+        // the same program, changed once it is running
+        let mut lc3 = machine(ECHO, b"");
+        lc3.start_replay(log.clone()).unwrap();
+        lc3.run_for(30);
+        lc3.memory[0x3003] = 0x1261; // ADD R1, R1, #1 instead of reading TMCNT
+        let fault = lc3.run().unwrap_err();
+        assert!(matches!(fault, Fault::Diverged(_)), "{}", fault);
+
+        let mut lc3 = machine(ECHO, b"");
+        let mut truncated = log.clone();
+        truncated.events.pop();
+        lc3.start_replay(truncated).unwrap();
+        lc3.run().unwrap();
+        assert_eq!(
+            lc3.finish_replay().unwrap_err().to_string(),
+            format!("replay diverged: after {} instructions, ran past the end of the log", lc3.executed)
+        );
+
+        assert_eq!(Log::from_text("LC3LOG 1\nIMAGE 0\nREAD\n"), Err("3: expected an instruction count".to_string()));
+        assert_eq!(Log::from_text("LC3LOG 1\nIMAGE 0\nJUMP 3 x\n"), Err("3: unrecognised event".to_string()));
+    }
+}
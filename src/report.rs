@@ -0,0 +1,235 @@
+use std::fmt::Write;
+
+use crate::grader::{Outcome, TestResult};
+use crate::json::Json;
+
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            // XML 1.0 cannot carry most control characters, even escaped
+            '\t' | '\n' | '\r' => out.push(c),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\x{:02x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// Human readable explanation of why a test did not pass, followed by the
+// tail of its execution trace.
+fn failure_details(result: &TestResult) -> String {
+    let mut details = String::new();
+    let _ = writeln!(details, "stop reason: {}", result.outcome);
+    let _ = writeln!(details, "instructions executed: {}", result.instructions);
+    for check in result.checks.iter().filter(|c| !c.passed()) {
+        let _ = writeln!(details, "{}: expected {}, got {}", check.target, check.expected, check.actual);
+    }
+    if !result.trace.is_empty() {
+        let _ = writeln!(details, "last {} instructions:", result.trace.len());
+        for entry in &result.trace {
+            let _ = writeln!(details, "  {}", entry);
+        }
+    }
+    details
+}
+
+fn failure_message(result: &TestResult) -> String {
+    match result.checks.iter().find(|c| !c.passed()) {
+        Some(check) if result.outcome == Outcome::Halted => {
+            format!("{}: expected {}, got {}", check.target, check.expected, check.actual)
+        }
+        _ => result.outcome.to_string(),
+    }
+}
+
+pub fn junit_xml(suite: &str, results: &[TestResult]) -> String {
+    let failures = results
+        .iter()
+        .filter(|r| !r.passed() && r.outcome == Outcome::Halted)
+        .count();
+    let errors = results
+        .iter()
+        .filter(|r| r.outcome != Outcome::Halted)
+        .count();
+    let time: f64 = results.iter().map(|r| r.duration.as_secs_f64()).sum();
+
+    let mut xml = String::new();
+    let _ = writeln!(xml, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    let _ = writeln!(
+        xml,
+        r#"<testsuites tests="{}" failures="{}" errors="{}" time="{:.6}">"#,
+        results.len(),
+        failures,
+        errors,
+        time
+    );
+    let _ = writeln!(
+        xml,
+        r#"  <testsuite name="{}" tests="{}" failures="{}" errors="{}" time="{:.6}">"#,
+        xml_escape(suite),
+        results.len(),
+        failures,
+        errors,
+        time
+    );
+    for result in results {
+        let _ = write!(
+            xml,
+            r#"    <testcase name="{}" classname="{}" time="{:.6}""#,
+            xml_escape(&result.name),
+            xml_escape(suite),
+            result.duration.as_secs_f64()
+        );
+        if result.passed() && result.output.is_empty() {
+            let _ = writeln!(xml, "/>");
+            continue;
+        }
+        let _ = writeln!(xml, ">");
+        if !result.passed() {
+            let tag = if result.outcome == Outcome::Halted { "failure" } else { "error" };
+            let _ = writeln!(
+                xml,
+                r#"      <{} message="{}" type="{}">{}</{}>"#,
+                tag,
+                xml_escape(&failure_message(result)),
+                result.status(),
+                xml_escape(&failure_details(result)),
+                tag
+            );
+        }
+        if !result.output.is_empty() {
+            let _ = writeln!(
+                xml,
+                "      <system-out>{}</system-out>",
+                xml_escape(&String::from_utf8_lossy(&result.output))
+            );
+        }
+        let _ = writeln!(xml, "    </testcase>");
+    }
+    let _ = writeln!(xml, "  </testsuite>");
+    let _ = writeln!(xml, "</testsuites>");
+    xml
+}
+
+fn result_json(result: &TestResult) -> Json {
+    let checks = result
+        .checks
+        .iter()
+        .map(|c| {
+            Json::object(vec![
+                ("target", Json::from(c.target.to_string())),
+                ("expected", Json::from(c.expected.to_string())),
+                ("actual", Json::from(c.actual.to_string())),
+                ("passed", Json::from(c.passed())),
+            ])
+        })
+        .collect::<Vec<_>>();
+    let trace = if result.passed() {
+        Vec::new()
+    } else {
+        result
+            .trace
+            .iter()
+            .map(|t| {
+                let registers = t.registers.iter().map(|r| *r as u16).collect::<Vec<_>>();
+                Json::object(vec![
+                    ("pc", Json::from(t.pc)),
+                    ("word", Json::from(t.word)),
+                    ("text", Json::from(t.to_string())),
+                    ("registers", Json::from(registers)),
+                ])
+            })
+            .collect()
+    };
+    Json::object(vec![
+        ("name", Json::from(result.name.as_str())),
+        ("status", Json::from(result.status())),
+        ("stop_reason", Json::from(result.outcome.to_string())),
+        ("points", Json::from(result.earned())),
+        ("max_points", Json::from(result.points)),
+        ("instructions", Json::from(result.instructions)),
+        ("time", Json::from(result.duration.as_secs_f64())),
+        ("checks", Json::from(checks)),
+        ("output", Json::from(String::from_utf8_lossy(&result.output).into_owned())),
+        ("trace", Json::from(trace)),
+    ])
+}
+
+pub fn json_report(suite: &str, results: &[TestResult]) -> Json {
+    Json::object(vec![
+        ("suite", Json::from(suite)),
+        ("tests", Json::from(results.len())),
+        ("passed", Json::from(results.iter().filter(|r| r.passed()).count())),
+        ("results", Json::from(results.iter().map(result_json).collect::<Vec<_>>())),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{json_report, junit_xml, xml_escape};
+    use crate::grader::{CheckResult, Outcome, Target, TestResult, TraceEntry, Value};
+    use crate::Fault;
+
+    fn result(name: &str, outcome: Outcome, actual: u16) -> TestResult {
+        TestResult {
+            name: name.to_string(),
+            outcome,
+            checks: vec![CheckResult {
+                target: Target::Reg(0),
+                expected: Value::Word(4),
+                actual: Value::Word(actual),
+            }],
+            instructions: 2,
+            output: b"<ok>".to_vec(),
+            points: 1,
+            duration: Duration::from_millis(1),
+            trace: vec![TraceEntry {
+                pc: 0x3000,
+                word: 0x1020,
+                registers: [actual as i16, 0, 0, 0, 0, 0, 0, 0],
+            }],
+        }
+    }
+
+    #[test]
+    fn test_xml_escape() {
+        assert_eq!(xml_escape("a<b & \"c\"\u{1}"), "a&lt;b &amp; &quot;c&quot;\\x01");
+    }
+
+    #[test]
+    fn test_junit_xml() {
+        let results = vec![
+            result("passes", Outcome::Halted, 4),
+            result("fails", Outcome::Halted, 3),
+            result("faults", Outcome::Fault(Fault::UnknownTrap(0x40)), 4),
+        ];
+        let xml = junit_xml("lab1", &results);
+        assert!(xml.contains(r#"<testsuite name="lab1" tests="3" failures="1" errors="1""#));
+        assert!(xml.contains(r#"<failure message="R0: expected x0004, got x0003" type="fail">"#));
+        assert!(xml.contains(r#"<error message="unknown trap x40" type="fault">"#));
+        assert!(xml.contains("x3000  x1020  ADD r0 r0 #0"));
+        assert!(xml.contains("<system-out>&lt;ok&gt;</system-out>"));
+    }
+
+    #[test]
+    fn test_json_report() {
+        let results = vec![result("passes", Outcome::Halted, 4), result("fails", Outcome::Halted, 3)];
+        let json = json_report("lab1", &results).to_string();
+        assert!(json.starts_with(r#"{"suite":"lab1","tests":2,"passed":1,"#));
+        assert!(json.contains(r#""checks":[{"target":"R0","expected":"x0004","actual":"x0003","passed":false}]"#));
+        assert!(json.contains(r#""stop_reason":"halted""#));
+        // passing tests carry no trace
+        assert!(json.contains(r#""output":"<ok>","trace":[]}"#));
+        assert!(json.contains(r#""trace":[{"pc":12288,"word":4128,"#));
+    }
+}
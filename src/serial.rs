@@ -0,0 +1,456 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+use crate::device::Device;
+use crate::{Fault, Stop, LC3};
+
+// A UART passing whole words, laid out like the console registers, at BASE
+// unless attached elsewhere.
+//
+//   SRSR base+0  receive status: bit 15 a word is waiting, bit 14 enables
+//                the receive interrupt
+//   SRDR base+2  receive data, taking the word
+//   STSR base+4  transmit status: bit 15 ready for another word
+//   STDR base+6  transmit data
+//
+// The receive interrupt is at x0183, priority 4. A port is linked either to
+// another port in the same process, or to a socket carrying each word as two
+// big-endian bytes.
+pub const BASE: u16 = 0xFE18;
+pub const SRSR: u16 = 0;
+pub const SRDR: u16 = 2;
+pub const STSR: u16 = 4;
+pub const STDR: u16 = 6;
+
+pub const VECTOR: u8 = 0x83;
+pub const PRIORITY: u8 = 4;
+// words in flight before the sender has to wait
+pub const CAPACITY: usize = 16;
+// instructions between looks at a socket
+const POLL_EVERY: u16 = 64;
+
+type Queue = Arc<Mutex<VecDeque<u16>>>;
+
+trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+enum Link {
+    Local {
+        rx: Queue,
+        tx: Queue,
+    },
+    Socket {
+        stream: Box<dyn Stream>,
+        rx: VecDeque<u16>,
+        // the first byte of a word still on its way
+        partial: Option<u8>,
+        tx: Vec<u8>,
+    },
+}
+
+pub struct Serial {
+    base: u16,
+    link: Link,
+    pub interrupt_enable: bool,
+    since_poll: u16,
+}
+
+impl Serial {
+    fn new(link: Link) -> Serial {
+        Serial {
+            base: BASE,
+            link,
+            interrupt_enable: false,
+            since_poll: 0,
+        }
+    }
+
+    // Two ports wired to each other.
+    pub fn pair() -> (Serial, Serial) {
+        let (a, b) = (Queue::default(), Queue::default());
+        let left = Serial::new(Link::Local {
+            rx: a.clone(),
+            tx: b.clone(),
+        });
+        let right = Serial::new(Link::Local { rx: b, tx: a });
+        (left, right)
+    }
+
+    fn socket<S: Stream + 'static>(stream: S) -> Serial {
+        Serial::new(Link::Socket {
+            stream: Box::new(stream),
+            rx: VecDeque::new(),
+            partial: None,
+            tx: Vec::new(),
+        })
+    }
+
+    // Connects to `tcp:HOST:PORT` or, on Unix, `unix:PATH`.
+    pub fn connect(addr: &str) -> io::Result<Serial> {
+        match addr.split_once(':') {
+            Some(("tcp", addr)) => {
+                let stream = TcpStream::connect(addr)?;
+                stream.set_nonblocking(true)?;
+                stream.set_nodelay(true)?;
+                Ok(Serial::socket(stream))
+            }
+            #[cfg(unix)]
+            Some(("unix", path)) => {
+                let stream = std::os::unix::net::UnixStream::connect(path)?;
+                stream.set_nonblocking(true)?;
+                Ok(Serial::socket(stream))
+            }
+            _ => Err(bad_address(addr)),
+        }
+    }
+
+    // Waits for one connection on an address `connect` accepts.
+    pub fn listen(addr: &str) -> io::Result<Serial> {
+        match addr.split_once(':') {
+            Some(("tcp", addr)) => Serial::accept_tcp(&TcpListener::bind(addr)?),
+            #[cfg(unix)]
+            Some(("unix", path)) => {
+                let (stream, _) = std::os::unix::net::UnixListener::bind(path)?.accept()?;
+                stream.set_nonblocking(true)?;
+                Ok(Serial::socket(stream))
+            }
+            _ => Err(bad_address(addr)),
+        }
+    }
+
+    // For a listener already bound, say to port 0 to let the OS choose.
+    pub fn accept_tcp(listener: &TcpListener) -> io::Result<Serial> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(Serial::socket(stream))
+    }
+
+    // Moves whatever the socket has ready in either direction.
+    fn poll(&mut self) {
+        self.since_poll = 0;
+        if let Link::Socket { stream, rx, partial, tx } = &mut self.link {
+            if !tx.is_empty() {
+                match stream.write(tx) {
+                    Ok(n) => drop(tx.drain(..n)),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    // the other end has gone, so the words go nowhere
+                    Err(_) => tx.clear(),
+                }
+            }
+            let mut buf = [0; 256];
+            while let Ok(n @ 1..) = stream.read(&mut buf) {
+                for &byte in &buf[..n] {
+                    match partial.take() {
+                        Some(high) => rx.push_back(u16::from_be_bytes([high, byte])),
+                        None => *partial = Some(byte),
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn has_input(&self) -> bool {
+        match &self.link {
+            Link::Local { rx, .. } => !rx.lock().unwrap().is_empty(),
+            Link::Socket { rx, .. } => !rx.is_empty(),
+        }
+    }
+
+    pub fn ready(&self) -> bool {
+        match &self.link {
+            Link::Local { tx, .. } => tx.lock().unwrap().len() < CAPACITY,
+            Link::Socket { tx, .. } => tx.len() < 2 * CAPACITY,
+        }
+    }
+
+    pub fn receive(&mut self) -> Option<u16> {
+        match &mut self.link {
+            Link::Local { rx, .. } => rx.lock().unwrap().pop_front(),
+            Link::Socket { rx, .. } => rx.pop_front(),
+        }
+    }
+
+    // Words sent while the port is not ready are dropped, like a real UART
+    // overrunning.
+    pub fn send(&mut self, word: u16) {
+        if !self.ready() {
+            return;
+        }
+        match &mut self.link {
+            Link::Local { tx, .. } => tx.lock().unwrap().push_back(word),
+            Link::Socket { tx, .. } => tx.extend(word.to_be_bytes()),
+        }
+        self.poll();
+    }
+}
+
+fn bad_address(addr: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{}: expected tcp:HOST:PORT or unix:PATH", addr))
+}
+
+impl Device for Serial {
+    fn read(&mut self, _lc3: &mut LC3, addr: u16) -> Result<u16, Fault> {
+        Ok(match addr.wrapping_sub(self.base) {
+            SRSR => {
+                self.poll();
+                (self.has_input() as u16) << 15 | (self.interrupt_enable as u16) << 14
+            }
+            SRDR => {
+                self.poll();
+                self.receive().unwrap_or(0)
+            }
+            STSR => (self.ready() as u16) << 15,
+            _ => 0,
+        })
+    }
+
+    fn write(&mut self, _lc3: &mut LC3, addr: u16, val: u16) -> Result<(), Fault> {
+        match addr.wrapping_sub(self.base) {
+            SRSR => self.interrupt_enable = val & 0x4000 != 0,
+            STDR => self.send(val),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, _lc3: &mut LC3) {
+        self.since_poll += 1;
+        if self.since_poll == POLL_EVERY {
+            self.poll();
+        }
+    }
+
+    fn irq(&self, _lc3: &LC3) -> Option<(u8, u8)> {
+        (self.interrupt_enable && self.has_input()).then_some((VECTOR, PRIORITY))
+    }
+
+    // As of the last poll, and without taking anything off the link.
+    fn peek(&self, _lc3: &LC3, addr: u16) -> Option<u16> {
+        match addr.wrapping_sub(self.base) {
+            SRSR => Some((self.has_input() as u16) << 15 | (self.interrupt_enable as u16) << 14),
+            SRDR => None,
+            STSR => Some((self.ready() as u16) << 15),
+            _ => Some(0),
+        }
+    }
+}
+
+// Puts `serial` on the bus with its registers from `base`.
+pub fn attach(lc3: &mut LC3, base: u16, mut serial: Serial) {
+    serial.base = base;
+    lc3.devices.attach(base..=base + STDR, serial);
+}
+
+// How a network shares out turns between its machines. Either way the
+// order is fixed, so a run can be repeated exactly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Schedule {
+    // one instruction each, in order
+    LockStep,
+    // this many instructions each, in order
+    RoundRobin(u64),
+}
+
+// Machines in one process, talking over linked serial ports.
+pub struct Network {
+    pub machines: Vec<LC3>,
+    pub schedule: Schedule,
+}
+
+impl Network {
+    pub fn new(machines: Vec<LC3>, schedule: Schedule) -> Network {
+        Network { machines, schedule }
+    }
+
+    // Links ports at BASE on machines `a` and `b`.
+    pub fn connect(&mut self, a: usize, b: usize) {
+        self.connect_at(a, BASE, b, BASE);
+    }
+
+    pub fn connect_at(&mut self, a: usize, a_base: u16, b: usize, b_base: u16) {
+        let (left, right) = Serial::pair();
+        attach(&mut self.machines[a], a_base, left);
+        attach(&mut self.machines[b], b_base, right);
+    }
+
+    // Runs until every machine halts or faults, or each has had `max_steps`
+    // instructions. Machines still going at the end say StepLimit.
+    pub fn run(&mut self, max_steps: u64) -> Vec<Stop> {
+        let quantum = match self.schedule {
+            Schedule::LockStep => 1,
+            Schedule::RoundRobin(quantum) => quantum.max(1),
+        };
+        let mut stops: Vec<Option<Stop>> = vec![None; self.machines.len()];
+        let mut steps = 0;
+        while steps < max_steps && stops.iter().any(Option::is_none) {
+            let turn = quantum.min(max_steps - steps);
+            for (lc3, stop) in self.machines.iter_mut().zip(&mut stops) {
+                if stop.is_none() {
+                    match lc3.run_for(turn) {
+                        Stop::StepLimit => {}
+                        done => *stop = Some(done),
+                    }
+                }
+            }
+            steps += turn;
+        }
+        stops.into_iter().map(|s| s.unwrap_or(Stop::StepLimit)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::console::Console;
+
+    // Sends 1 and waits for replies, sending back one more each time, until
+    // it gets LIMIT.
+    const PING: &str = r#"
+        .ORIG x3000
+        AND R0, R0, #0
+        ADD R0, R0, #1
+        JSR SEND
+LOOP    JSR RECEIVE
+        ADD R1, R1, #1
+        LD R2, LIMIT
+        ADD R2, R0, R2
+        BRz DONE
+        ADD R0, R0, #1
+        JSR SEND
+        BR LOOP
+DONE    HALT
+SEND    LDI R3, STSR
+        BRzp SEND
+        STI R0, STDR
+        RET
+RECEIVE LDI R3, SRSR
+        BRzp RECEIVE
+        LDI R0, SRDR
+        RET
+LIMIT   .FILL #-10
+SRSR    .FILL xFE18
+SRDR    .FILL xFE1A
+STSR    .FILL xFE1C
+STDR    .FILL xFE1E
+        .END
+    "#;
+
+    // Echoes every word back one higher, stopping after it sends 10.
+    const PONG: &str = r#"
+        .ORIG x3000
+LOOP    LDI R3, SRSR
+        BRzp LOOP
+        LDI R0, SRDR
+        ADD R1, R1, #1
+        ADD R0, R0, #1
+WAIT    LDI R3, STSR
+        BRzp WAIT
+        STI R0, STDR
+        ADD R0, R0, #-10
+        BRn LOOP
+        HALT
+SRSR    .FILL xFE18
+SRDR    .FILL xFE1A
+STSR    .FILL xFE1C
+STDR    .FILL xFE1E
+        .END
+    "#;
+
+    fn machine(src: &str) -> LC3 {
+        let mut lc3 = LC3 {
+            console: Console::buffer(&[]),
+            ..LC3::default()
+        };
+        assemble(src).unwrap().load_into(&mut lc3);
+        lc3
+    }
+
+    fn ping_pong(schedule: Schedule) -> Network {
+        let mut network = Network::new(vec![machine(PING), machine(PONG)], schedule);
+        network.connect(0, 1);
+        assert_eq!(network.run(100_000), vec![Stop::Halted, Stop::Halted]);
+        network
+    }
+
+    #[test]
+    fn test_ping_pong_in_process() {
+        for schedule in [Schedule::LockStep, Schedule::RoundRobin(7)] {
+            let network = ping_pong(schedule);
+            let (ping, pong) = (&network.machines[0], &network.machines[1]);
+            // five round trips, each side receiving five words
+            assert_eq!((ping.registers[0], ping.registers[1]), (10, 5));
+            assert_eq!((pong.registers[0], pong.registers[1]), (0, 5));
+
+            let again = ping_pong(schedule);
+            let executed = |n: &Network| n.machines.iter().map(|m| m.executed).collect::<Vec<_>>();
+            assert_eq!(executed(&network), executed(&again));
+        }
+    }
+
+    #[test]
+    fn test_receive_interrupt_and_overrun() {
+        let (mut left, mut right) = Serial::pair();
+        for word in 0..CAPACITY as u16 + 3 {
+            left.send(word);
+        }
+        assert!(!left.ready());
+        assert_eq!(right.receive(), Some(0));
+        assert!(left.ready());
+
+        let src = ".ORIG x3000\nLOOP BR LOOP\nISR LDI R0, SRDR\nHALT\nSRDR .FILL xFE22\n.END";
+        let mut lc3 = machine(src);
+        lc3.memory[0x0183] = 0x3001;
+        right.interrupt_enable = true;
+        attach(&mut lc3, 0xFE20, right);
+        lc3.run().unwrap();
+        assert_eq!(lc3.registers[0], 1);
+        assert_eq!(lc3.priority, PRIORITY);
+    }
+
+    // Runs the two ends on their own threads, since each waits on the other.
+    fn over_sockets<F>(accept: F, addr: String)
+    where
+        F: FnOnce() -> Serial + Send + 'static,
+    {
+        let pong = std::thread::spawn(move || {
+            let mut lc3 = machine(PONG);
+            attach(&mut lc3, BASE, accept());
+            lc3.run().unwrap();
+            lc3.registers[1]
+        });
+        let serial = loop {
+            match Serial::connect(&addr) {
+                Ok(serial) => break serial,
+                Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        };
+        let mut ping = machine(PING);
+        attach(&mut ping, BASE, serial);
+        ping.run().unwrap();
+        assert_eq!(ping.registers[0], 10);
+        assert_eq!(pong.join().unwrap(), 5);
+    }
+
+    #[test]
+    fn test_ping_pong_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("tcp:{}", listener.local_addr().unwrap());
+        over_sockets(move || Serial::accept_tcp(&listener).unwrap(), addr);
+        assert!(Serial::connect("udp:127.0.0.1:1").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_ping_pong_over_unix_socket() {
+        let path = std::env::temp_dir().join(format!("lc3-serial-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let addr = format!("unix:{}", path.display());
+        let listen = addr.clone();
+        over_sockets(move || Serial::listen(&listen).unwrap(), addr);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
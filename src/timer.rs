@@ -0,0 +1,211 @@
+use crate::device::Device;
+use crate::{Fault, LC3};
+
+// An interval timer counting executed instructions, so runs involving it are
+// exactly reproducible.
+//
+//   TMCR  xFE08  control: bit 15 enable, bit 14 interrupt enable, bit 13
+//                periodic, bit 12 count clock cycles rather than
+//                instructions, bits 10:8 priority, bits 7:0 interrupt vector
+//   TMSR  xFE0A  status: bit 15 set when the interval expires, cleared by
+//                any write
+//   TMIR  xFE0C  interval, in instructions or cycles
+//   TMCNT xFE0E  instructions or cycles left until it expires, read only
+//
+// Writing TMIR or enabling the timer restarts the count. A one-shot timer
+// disables itself when it expires; a periodic one starts over.
+pub const TMCR: u16 = 0xFE08;
+pub const TMSR: u16 = 0xFE0A;
+pub const TMIR: u16 = 0xFE0C;
+pub const TMCNT: u16 = 0xFE0E;
+
+pub const ENABLE: u16 = 0x8000;
+pub const INTERRUPT_ENABLE: u16 = 0x4000;
+pub const PERIODIC: u16 = 0x2000;
+pub const CYCLES: u16 = 0x1000;
+pub const DEFAULT_VECTOR: u8 = 0x81;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Timer {
+    pub control: u16,
+    pub expired: bool,
+    pub interval: u16,
+    pub remaining: u16,
+    // `LC3::cycles` when it last ticked
+    pub last: u64,
+}
+
+impl Timer {
+    pub fn enabled(&self) -> bool {
+        self.control & ENABLE != 0
+    }
+
+    pub fn vector(&self) -> u8 {
+        self.control as u8
+    }
+
+    pub fn priority(&self) -> u8 {
+        ((self.control >> 8) & 0b111) as u8
+    }
+}
+
+impl Device for Timer {
+    fn read(&mut self, lc3: &mut LC3, addr: u16) -> Result<u16, Fault> {
+        Ok(self.peek(lc3, addr).unwrap())
+    }
+
+    fn write(&mut self, _lc3: &mut LC3, addr: u16, val: u16) -> Result<(), Fault> {
+        match addr {
+            TMCR => {
+                let starting = val & ENABLE != 0 && !self.enabled();
+                self.control = val;
+                if starting {
+                    self.remaining = self.interval;
+                }
+            }
+            TMSR => self.expired = false,
+            TMIR => {
+                self.interval = val;
+                self.remaining = val;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Counts one instruction, or the cycles it took. A periodic timer
+    // counting cycles carries over whatever the interval was overrun by.
+    fn tick(&mut self, lc3: &mut LC3) {
+        let elapsed = if self.control & CYCLES != 0 { lc3.cycles - self.last } else { 1 };
+        self.last = lc3.cycles;
+        if !self.enabled() || self.interval == 0 {
+            return;
+        }
+        if elapsed < self.remaining as u64 {
+            self.remaining -= elapsed as u16;
+            return;
+        }
+        self.expired = true;
+        if self.control & PERIODIC != 0 {
+            let over = (elapsed - self.remaining as u64) % self.interval as u64;
+            self.remaining = self.interval - over as u16;
+        } else {
+            self.remaining = 0;
+            self.control &= !ENABLE;
+        }
+    }
+
+    fn irq(&self, _lc3: &LC3) -> Option<(u8, u8)> {
+        if self.expired && self.control & INTERRUPT_ENABLE != 0 {
+            Some((self.vector(), self.priority()))
+        } else {
+            None
+        }
+    }
+
+    fn peek(&self, _lc3: &LC3, addr: u16) -> Option<u16> {
+        Some(match addr {
+            TMCR => self.control,
+            TMSR => (self.expired as u16) << 15,
+            TMIR => self.interval,
+            TMCNT => self.remaining,
+            _ => 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::console::Console;
+
+    // Counts loop iterations until the timer handler has run three times.
+    const PREEMPT: &str = r#"
+        .ORIG x3000
+        LD R0, INTERVAL
+        STI R0, TMIR_PTR
+        LD R0, CONTROL
+        STI R0, TMCR_PTR
+        AND R1, R1, #0
+LOOP    ADD R1, R1, #1
+        LD R2, TICKS
+        ADD R2, R2, #-3
+        BRn LOOP
+        AND R0, R0, #0
+        STI R0, TMCR_PTR
+        HALT
+ISR     ST R0, SAVED
+        LD R0, TICKS
+        ADD R0, R0, #1
+        ST R0, TICKS
+        STI R0, TMSR_PTR
+        LD R0, SAVED
+        RTI
+INTERVAL .FILL #20
+CONTROL  .FILL xE581
+TICKS    .FILL 0
+SAVED    .BLKW 1
+TMCR_PTR .FILL xFE08
+TMSR_PTR .FILL xFE0A
+TMIR_PTR .FILL xFE0C
+        .END
+    "#;
+
+    fn preempt() -> LC3 {
+        let program = assemble(PREEMPT).unwrap();
+        let mut lc3 = LC3 {
+            console: Console::buffer(&[]),
+            ..LC3::default()
+        };
+        program.load_into(&mut lc3);
+        lc3.memory[0x0181] = program.symbols["ISR"].addr;
+        lc3.run().unwrap();
+        lc3
+    }
+
+    #[test]
+    fn test_periodic_interrupts_are_deterministic() {
+        let first = preempt();
+        let ticks = assemble(PREEMPT).unwrap().symbols["TICKS"].addr;
+        assert_eq!(first.memory[ticks as usize], 3);
+        assert!(!first.devices.get::<Timer>().unwrap().enabled());
+        assert_eq!(first.priority, 0);
+        let second = preempt();
+        assert_eq!((first.registers, first.executed), (second.registers, second.executed));
+        assert_eq!(first.executed, 77);
+    }
+
+    #[test]
+    fn test_one_shot_polling() {
+        let src = r#"
+            .ORIG x3000
+            AND R0, R0, #0
+            ADD R0, R0, #5
+            STI R0, TMIR_PTR
+            LD R0, CONTROL
+            STI R0, TMCR_PTR
+            AND R1, R1, #0
+WAIT        ADD R1, R1, #1
+            LDI R0, TMSR_PTR
+            BRzp WAIT
+            HALT
+CONTROL     .FILL x8000
+TMCR_PTR    .FILL xFE08
+TMSR_PTR    .FILL xFE0A
+TMIR_PTR    .FILL xFE0C
+            .END
+        "#;
+        let mut lc3 = LC3 {
+            console: Console::buffer(&[]),
+            ..LC3::default()
+        };
+        assemble(src).unwrap().load_into(&mut lc3);
+        lc3.run().unwrap();
+        assert_eq!(lc3.registers[1], 2);
+        let timer = lc3.devices.get::<Timer>().unwrap();
+        assert!(timer.expired);
+        assert!(!timer.enabled());
+        assert_eq!(lc3.read(TMCNT), Ok(0));
+    }
+}
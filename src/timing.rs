@@ -0,0 +1,384 @@
+use std::collections::BTreeMap;
+
+use crate::micro::MEMORY_LATENCY;
+use crate::opcodes::Inst;
+use crate::{Fault, LC3};
+
+// What things cost in clock cycles, for `LC3::cycles` to count as the
+// machine runs. Memory accesses are counted separately from the cycles an
+// instruction spends in the datapath, so `memory` and `device_wait` can be
+// changed on their own.
+//
+// The defaults follow the state machine in `micro`, so a program that
+// doesn't fault or use Rust trap services takes as many cycles here as it
+// does there. Native services cost one cycle plus the memory they touch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostModel {
+    // each read or write of memory, and the extra for a device register
+    pub memory: u64,
+    pub device_wait: u64,
+    // besides reading the instruction
+    pub fetch: u64,
+    // ADD, AND, NOT and LEA
+    pub operate: u64,
+    pub branch: u64,
+    pub branch_taken: u64,
+    // JMP and RET
+    pub jump: u64,
+    // JSR and JSRR
+    pub call: u64,
+    // LD, LDR, ST and STR, then LDI and STI
+    pub load_store: u64,
+    pub indirect: u64,
+    pub trap: u64,
+    pub rti: u64,
+    // pushing the PSR and PC and going through the vector table, besides
+    // the memory accesses, with the extra for leaving the user stack
+    pub entry: u64,
+    pub stack_switch: u64,
+    // before the entry, for an interrupt or an exception
+    pub interrupt: u64,
+    pub exception: u64,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        CostModel {
+            memory: MEMORY_LATENCY as u64,
+            device_wait: 0,
+            fetch: 3,
+            operate: 1,
+            branch: 1,
+            branch_taken: 1,
+            jump: 1,
+            call: 2,
+            load_store: 2,
+            indirect: 3,
+            trap: 1,
+            rti: 6,
+            entry: 5,
+            stack_switch: 1,
+            interrupt: 2,
+            exception: 1,
+        }
+    }
+}
+
+impl CostModel {
+    // The cycles of `inst` outside memory, but for a taken branch's extra.
+    pub fn instruction(&self, inst: &Inst) -> u64 {
+        match inst {
+            Inst::ADD { .. } | Inst::ADDi { .. } | Inst::AND { .. } | Inst::ANDi { .. } => self.operate,
+            Inst::NOT { .. } | Inst::LEA { .. } => self.operate,
+            Inst::BR { .. } => self.branch,
+            Inst::JMP { .. } => self.jump,
+            Inst::JSR { .. } | Inst::JSRr { .. } => self.call,
+            Inst::LD { .. } | Inst::LDR { .. } | Inst::ST { .. } | Inst::STR { .. } => self.load_store,
+            Inst::LDI { .. } | Inst::STI { .. } => self.indirect,
+            Inst::TRAP { .. } => self.trap,
+            Inst::RTI => self.rti,
+        }
+    }
+
+    // Sets one cost by the name of its field.
+    pub fn set(&mut self, name: &str, cycles: u64) -> Result<(), String> {
+        let field = match name {
+            "memory" => &mut self.memory,
+            "device_wait" => &mut self.device_wait,
+            "fetch" => &mut self.fetch,
+            "operate" => &mut self.operate,
+            "branch" => &mut self.branch,
+            "branch_taken" => &mut self.branch_taken,
+            "jump" => &mut self.jump,
+            "call" => &mut self.call,
+            "load_store" => &mut self.load_store,
+            "indirect" => &mut self.indirect,
+            "trap" => &mut self.trap,
+            "rti" => &mut self.rti,
+            "entry" => &mut self.entry,
+            "stack_switch" => &mut self.stack_switch,
+            "interrupt" => &mut self.interrupt,
+            "exception" => &mut self.exception,
+            _ => return Err(format!("no cost called {}", name)),
+        };
+        *field = cycles;
+        Ok(())
+    }
+
+    // Settings like `memory=10,device_wait=20`.
+    pub fn parse(&mut self, settings: &str) -> Result<(), String> {
+        for setting in settings.split(',') {
+            let (name, cycles) = setting.split_once('=').ok_or_else(|| format!("expected a cost and =, got {}", setting))?;
+            let cycles = cycles.parse().map_err(|_| format!("{}: expected a number of cycles", name))?;
+            self.set(name, cycles)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Routine {
+    pub calls: u64,
+    // from the call to the return, and in the routine itself
+    pub cycles: u64,
+    pub own: u64,
+}
+
+// Where the cycles of a run went, by subroutine. A JSR or JSRR enters one and
+// a RET leaves it; time in interrupt handlers and the OS counts against
+// whatever they interrupted or was calling.
+pub struct Profile {
+    names: BTreeMap<u16, String>,
+    pub routines: BTreeMap<u16, Routine>,
+    // the subroutines entered and not yet left, with the cycle count then
+    stack: Vec<(u16, u64)>,
+    start: (u64, u64),
+}
+
+impl Profile {
+    // Starts at the PC as the outermost routine, naming routines from
+    // `names` where it can.
+    pub fn new(lc3: &LC3, names: BTreeMap<u16, String>) -> Profile {
+        let mut routines = BTreeMap::new();
+        routines.insert(
+            lc3.pc,
+            Routine {
+                calls: 1,
+                ..Routine::default()
+            },
+        );
+        Profile {
+            names,
+            routines,
+            stack: vec![(lc3.pc, lc3.cycles)],
+            start: (lc3.cycles, lc3.executed),
+        }
+    }
+
+    // Takes a step with `run`, which might be `LC3::run_step` or another
+    // engine's.
+    pub fn step(&mut self, lc3: &mut LC3, run: impl FnOnce(&mut LC3) -> Result<(), Fault>) -> Result<(), Fault> {
+        let (pc, priority, before) = (lc3.pc, lc3.priority, lc3.cycles);
+        let result = run(lc3);
+        let &(current, _) = self.stack.last().unwrap();
+        self.routines.entry(current).or_default().own += lc3.cycles - before;
+        // a step that took an interrupt ran the handler's first instruction,
+        // and the reserved opcode has no decoding when the OS catches it
+        let raw = lc3.memory[pc as usize];
+        if result.is_err() || lc3.priority > priority || raw >> 12 == 0b1101 {
+            return result;
+        }
+        match Inst::from(raw) {
+            Inst::JSR { .. } | Inst::JSRr { .. } if lc3.registers[7] as u16 == pc.wrapping_add(1) => {
+                self.routines.entry(lc3.pc).or_default().calls += 1;
+                self.stack.push((lc3.pc, lc3.cycles));
+            }
+            Inst::JMP { base_r: 7 } if self.stack.len() > 1 => {
+                let (entry, start) = self.stack.pop().unwrap();
+                // a recursive routine counts from its outermost call
+                if self.stack.iter().all(|&(e, _)| e != entry) {
+                    self.routines.get_mut(&entry).unwrap().cycles += lc3.cycles - start;
+                }
+            }
+            _ => {}
+        }
+        result
+    }
+
+    pub fn name(&self, addr: u16) -> String {
+        match self.names.get(&addr) {
+            Some(name) => name.clone(),
+            None => format!("x{:04X}", addr),
+        }
+    }
+
+    // The routines by address, counting those not yet returned from up to
+    // now.
+    pub fn totals(&self, lc3: &LC3) -> BTreeMap<u16, Routine> {
+        let mut routines = self.routines.clone();
+        for (i, &(entry, start)) in self.stack.iter().enumerate() {
+            if self.stack[..i].iter().all(|&(e, _)| e != entry) {
+                routines.get_mut(&entry).unwrap().cycles += lc3.cycles - start;
+            }
+        }
+        routines
+    }
+
+    // Total cycles and CPI, then the routines that took longest first.
+    pub fn report(&self, lc3: &LC3) -> String {
+        let cycles = lc3.cycles - self.start.0;
+        let instructions = lc3.executed - self.start.1;
+        let cpi = cycles as f64 / instructions.max(1) as f64;
+        let mut out = format!("{} cycles, {} instructions, {:.2} cycles per instruction\n", cycles, instructions, cpi);
+        out += &format!("{:<20} {:>8} {:>12} {:>7} {:>12}\n", "subroutine", "calls", "cycles", "share", "self");
+        let mut routines: Vec<(u16, Routine)> = self.totals(lc3).into_iter().collect();
+        routines.sort_by_key(|(addr, r)| (std::cmp::Reverse(r.cycles), *addr));
+        for (addr, r) in routines {
+            let share = 100.0 * r.cycles as f64 / cycles.max(1) as f64;
+            out += &format!("{:<20} {:>8} {:>12} {:>6.1}% {:>12}\n", self.name(addr), r.calls, r.cycles, share, r.own);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::console::Console;
+    use crate::device::Keyboard;
+    use crate::micro::{ControlStore, Datapath};
+    use crate::timer::{self, Timer};
+
+    // The cycles a run takes on each engine.
+    fn both(make: impl Fn() -> LC3) -> (u64, u64) {
+        let (mut lc3, mut micro) = (make(), make());
+        let start = lc3.cycles;
+        lc3.run().unwrap();
+        Datapath::default().run(&mut micro).unwrap();
+        assert_eq!((lc3.executed, lc3.registers), (micro.executed, micro.registers));
+        (lc3.cycles - start, micro.cycles - start)
+    }
+
+    const CALLS: &str = r#"
+        .ORIG x3000
+        LEA R0, TEXT
+        PUTS
+        LD R1, COUNT
+LOOP    JSR TWICE
+        ADD R1, R1, #-1
+        BRp LOOP
+        LDI R2, PTR
+        STI R2, PTR
+        GETC
+        HALT
+TWICE   ST R7, SAVE
+        JSR ONCE
+        JSR ONCE
+        LD R7, SAVE
+        RET
+ONCE    ADD R3, R3, #1
+        RET
+COUNT   .FILL #3
+SAVE    .BLKW 1
+PTR     .FILL COUNT
+TEXT    .STRINGZ "ab"
+        .END
+    "#;
+
+    #[test]
+    fn test_defaults_follow_the_state_machine() {
+        let os = |input: &'static [u8]| {
+            move || {
+                let mut lc3 = LC3::with_os();
+                lc3.console = Console::buffer(input);
+                assemble(CALLS).unwrap().load_into(&mut lc3);
+                lc3
+            }
+        };
+        let (model, micro) = both(os(b"k"));
+        assert_eq!(model, micro);
+
+        // keyboard interrupts, and RTI back to user mode
+        let keyboard = || {
+            let mut lc3 = LC3::with_os();
+            lc3.console = Console::buffer(b"kk");
+            let src = ".ORIG x3000\nLOOP ADD R1, R1, #1\nADD R2, R1, #-15\nBRn LOOP\nHALT\n.END";
+            assemble(src).unwrap().load_into(&mut lc3);
+            lc3.devices.get_mut::<Keyboard>().unwrap().interrupt_enable = true;
+            lc3
+        };
+        let (model, micro) = both(keyboard);
+        assert_eq!(model, micro);
+
+        // slower memory costs the same on both
+        let slow = || {
+            let mut lc3 = os(b"k")();
+            lc3.cost.memory = 7;
+            lc3
+        };
+        let (mut lc3, mut micro) = (slow(), slow());
+        let start = lc3.cycles;
+        lc3.run().unwrap();
+        let mut datapath = Datapath::new(ControlStore::lc3());
+        datapath.memory_latency = 7;
+        datapath.run(&mut micro).unwrap();
+        assert_eq!(lc3.cycles - start, datapath.cycles);
+        assert!(datapath.cycles > model);
+    }
+
+    #[test]
+    fn test_profile() {
+        let mut lc3 = LC3 {
+            console: Console::buffer(b"k"),
+            ..LC3::default()
+        };
+        lc3.cost.parse("device_wait=10,memory=1").unwrap();
+        assert_eq!(lc3.cost.parse("memory=x"), Err("memory: expected a number of cycles".to_string()));
+        assert_eq!(lc3.cost.parse("clock=3"), Err("no cost called clock".to_string()));
+        let program = assemble(CALLS).unwrap();
+        program.load_into(&mut lc3);
+        let names = program.symbols.iter().map(|(name, s)| (s.addr, name.clone())).collect();
+        let mut profile = Profile::new(&lc3, names);
+        while !lc3.halted {
+            profile.step(&mut lc3, LC3::run_step).unwrap();
+        }
+
+        let totals = profile.totals(&lc3);
+        let once = &totals[&program.symbols["ONCE"].addr];
+        let twice = &totals[&program.symbols["TWICE"].addr];
+        let main = &totals[&0x3000];
+        assert_eq!((main.calls, twice.calls, once.calls), (1, 3, 6));
+        // ADD and RET, each fetched from memory taking one cycle
+        assert_eq!(once.cycles, 6 * (4 + 1 + 4 + 1));
+        assert_eq!(once.own, once.cycles);
+        assert_eq!(twice.cycles, twice.own + once.cycles);
+        assert_eq!(main.cycles, lc3.cycles);
+        assert_eq!(main.own + twice.cycles, lc3.cycles);
+
+        let report = profile.report(&lc3);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], format!("{} cycles, {} instructions, {:.2} cycles per instruction", lc3.cycles, lc3.executed, lc3.cycles as f64 / lc3.executed as f64));
+        assert!(lines[2].starts_with("x3000                       1"));
+        assert!(lines[3].starts_with("TWICE                       3"));
+        assert!(lines[4].starts_with("ONCE                        6           60"));
+    }
+
+    #[test]
+    fn test_profile_reserved_opcode() {
+        let mut lc3 = LC3::with_os();
+        lc3.console = Console::buffer(&[]);
+        let program = assemble(".ORIG x3000\nJSR BAD\nHALT\nBAD .FILL xD000\n.END").unwrap();
+        program.load_into(&mut lc3);
+        let mut profile = Profile::new(&lc3, BTreeMap::new());
+        while !lc3.halted {
+            profile.step(&mut lc3, LC3::run_step).unwrap();
+        }
+        assert!(lc3.console.output().starts_with(b"\nillegal opcode\n"));
+        assert_eq!(profile.totals(&lc3)[&program.symbols["BAD"].addr].calls, 1);
+    }
+
+    #[test]
+    fn test_timer_counting_cycles() {
+        // the same number of cycles is fewer instructions with slower memory
+        let run = |memory| {
+            let mut lc3 = LC3 {
+                console: Console::buffer(&[]),
+                ..LC3::default()
+            };
+            lc3.cost.memory = memory;
+            let src = ".ORIG x3000\nLD R0, INTERVAL\nSTI R0, TMIR\nLD R0, CONTROL\nSTI R0, TMCR\n\
+                       WAIT ADD R1, R1, #1\nLDI R0, TMSR\nBRzp WAIT\nHALT\n\
+                       INTERVAL .FILL #200\nCONTROL .FILL x9000\nTMCR .FILL xFE08\nTMSR .FILL xFE0A\nTMIR .FILL xFE0C\n.END";
+            assemble(src).unwrap().load_into(&mut lc3);
+            lc3.run().unwrap();
+            assert!(lc3.devices.get::<Timer>().unwrap().expired);
+            lc3.registers[1]
+        };
+        assert_eq!(timer::CYCLES, 0x1000);
+        let (fast, slow) = (run(1), run(5));
+        assert!(fast > slow, "{} {}", fast, slow);
+        // ADD, LDI and a taken BR are 5 + 9 + 6 cycles with single cycle memory
+        assert_eq!(fast, 200 / 20 + 1);
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+
+use crate::{Fault, LC3};
+
+// A trap service written in Rust. It runs in place of the TRAP instruction
+// with the whole machine to work on; the PC already points past the TRAP.
+pub type TrapHandler = Box<dyn FnMut(&mut LC3) -> Result<(), Fault> + Send>;
+
+// The Rust trap services, by vector. A TRAP with no handler here goes
+// through the vector table in memory, as long as an OS has filled it in.
+#[derive(Default)]
+pub struct Traps {
+    handlers: BTreeMap<u8, TrapHandler>,
+}
+
+impl Traps {
+    // GETC, OUT, PUTS, IN, PUTSP and HALT working straight on the console.
+    pub fn builtin() -> Traps {
+        let mut traps = Traps::default();
+        traps.register(0x20, getc);
+        traps.register(0x21, out);
+        traps.register(0x22, puts);
+        traps.register(0x23, input);
+        traps.register(0x24, putsp);
+        traps.register(0x25, halt);
+        traps
+    }
+
+    // Adds or replaces the service for `vect`.
+    pub fn register<F>(&mut self, vect: u8, handler: F)
+    where
+        F: FnMut(&mut LC3) -> Result<(), Fault> + Send + 'static,
+    {
+        self.handlers.insert(vect, Box::new(handler));
+    }
+
+    pub fn remove(&mut self, vect: u8) -> bool {
+        self.handlers.remove(&vect).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.handlers.clear();
+    }
+
+    pub fn contains(&self, vect: u8) -> bool {
+        self.handlers.contains_key(&vect)
+    }
+
+    pub fn vectors(&self) -> Vec<u8> {
+        self.handlers.keys().copied().collect()
+    }
+}
+
+impl LC3 {
+    // Runs the Rust service for `vect`, if there is one. The handler is
+    // taken out while it runs so it can be handed the machine; one that
+    // registers a replacement for itself keeps the replacement.
+    pub(crate) fn native_trap(&mut self, vect: u8) -> Option<Result<(), Fault>> {
+        let mut handler = self.traps.handlers.remove(&vect)?;
+        let result = handler(self);
+        self.traps.handlers.entry(vect).or_insert(handler);
+        Some(result)
+    }
+}
+
+fn getc(lc3: &mut LC3) -> Result<(), Fault> {
+    let c = lc3.journaled(None, |lc3| lc3.console.get_char().map(u16::from).ok_or(Fault::EndOfInput))?;
+    lc3.registers[0] = c as i16;
+    Ok(())
+}
+
+fn out(lc3: &mut LC3) -> Result<(), Fault> {
+    lc3.console.put(&[lc3.registers[0] as u8]);
+    Ok(())
+}
+
+fn puts(lc3: &mut LC3) -> Result<(), Fault> {
+    let mut buf = Vec::new();
+    let mut spot = lc3.registers[0] as u16;
+    while lc3.read(spot)? != 0x0000 && buf.len() < lc3.memory.len() {
+        buf.push(lc3.read(spot)? as u8);
+        spot = spot.wrapping_add(1);
+    }
+    lc3.console.put(&buf);
+    Ok(())
+}
+
+fn input(lc3: &mut LC3) -> Result<(), Fault> {
+    lc3.console.put(b"Input one character: ");
+    let c = lc3.journaled(None, |lc3| lc3.console.get_line_char().map(u16::from).ok_or(Fault::EndOfInput))?;
+    lc3.registers[0] = c as i16;
+    Ok(())
+}
+
+fn putsp(lc3: &mut LC3) -> Result<(), Fault> {
+    let mut buf = Vec::new();
+    let mut spot = lc3.registers[0] as u16;
+    while lc3.read(spot)? != 0x0000 && buf.len() < 2 * lc3.memory.len() {
+        let word = lc3.read(spot)?;
+        buf.push(word as u8);
+        if word >> 8 != 0x0000 {
+            buf.push((word >> 8) as u8);
+        }
+        spot = spot.wrapping_add(1);
+    }
+    lc3.console.put(&buf);
+    Ok(())
+}
+
+fn halt(lc3: &mut LC3) -> Result<(), Fault> {
+    lc3.halted = true;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::assemble;
+    use crate::console::Console;
+    use crate::{Fault, LC3};
+
+    fn machine(src: &str) -> LC3 {
+        let mut lc3 = LC3 {
+            console: Console::buffer(&[]),
+            ..LC3::default()
+        };
+        assemble(src).unwrap().load_into(&mut lc3);
+        lc3
+    }
+
+    #[test]
+    fn test_custom_and_overridden_traps() {
+        let src = ".ORIG x3000\nLD R0, N\nTRAP x30\nAND R0, R0, #0\nADD R0, R0, #-7\nTRAP x30\nOUT\nHALT\nN .FILL 1234\n.END";
+        let mut lc3 = machine(src);
+        lc3.traps.register(0x30, |lc3: &mut LC3| {
+            let text = lc3.registers[0].to_string();
+            lc3.console.put(text.as_bytes());
+            Ok(())
+        });
+        let mut count = 0;
+        lc3.traps.register(0x21, move |lc3: &mut LC3| {
+            count += 1;
+            lc3.console.put(format!("<out {}>", count).as_bytes());
+            Ok(())
+        });
+        lc3.run().unwrap();
+        assert_eq!(lc3.console.output(), b"1234-7<out 1>");
+        assert!(lc3.traps.contains(0x30));
+    }
+
+    #[test]
+    fn test_assertion_trap() {
+        let src = ".ORIG x3000\nAND R1, R1, #0\nADD R1, R1, #2\nTRAP x40\nADD R1, R1, #1\nTRAP x40\nHALT\n.END";
+        let mut lc3 = machine(src);
+        lc3.traps.register(0x40, |lc3: &mut LC3| match lc3.registers[1] {
+            2 => Ok(()),
+            r1 => Err(Fault::Trap(0x40, format!("expected R1 to be 2, found {}", r1))),
+        });
+        let fault = lc3.run().unwrap_err();
+        assert_eq!(fault.to_string(), "trap x40 failed: expected R1 to be 2, found 3");
+        assert_eq!(lc3.pc, 0x3005);
+    }
+
+    #[test]
+    fn test_unregistered_traps() {
+        let mut lc3 = machine(".ORIG x3000\nTRAP x26\nHALT\n.END");
+        assert_eq!(lc3.run(), Err(Fault::UnknownTrap(0x26)));
+
+        // without a handler, OUT goes through the vector table
+        let src = ".ORIG x3000\nOUT\nHALT\nMYOUT ADD R5, R5, #1\nRTI\n.END";
+        let mut lc3 = machine(src);
+        assert!(lc3.traps.remove(0x21));
+        lc3.memory[0x21] = 0x3002;
+        lc3.run().unwrap();
+        assert_eq!(lc3.registers[5], 1);
+        assert_eq!(lc3.console.output(), b"");
+
+        // the OS clears the built in services, but more can be added
+        let mut lc3 = LC3::with_os();
+        assert_eq!(lc3.traps.vectors(), vec![]);
+        lc3.console = Console::buffer(&[]);
+        assemble(".ORIG x3000\nTRAP x26\nHALT\n.END").unwrap().load_into(&mut lc3);
+        lc3.traps.register(0x26, |lc3: &mut LC3| {
+            lc3.console.put(b"native");
+            Ok(())
+        });
+        lc3.run().unwrap();
+        assert_eq!(lc3.console.output(), b"native\n--- halting the LC-3 ---\n");
+    }
+}
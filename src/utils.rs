@@ -6,7 +6,7 @@ pub fn sext(val: i16, bits: i32) -> i16 {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub struct Condition {
     pub n: bool,
     pub z: bool,
@@ -19,21 +19,57 @@ impl Condition {
     }
 }
 
-impl Default for Condition {
-    fn default() -> Self {
-        Condition {
-            n: false,
-            z: false,
-            p: false,
+// Parses LC-3 style numeric literals: x3000, #-5, b0101 or plain decimal.
+pub fn parse_number(s: &str) -> Option<i32> {
+    let (neg, s) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let val = if let Some(hex) = s.strip_prefix('x').or_else(|| s.strip_prefix('X')) {
+        i32::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = s.strip_prefix('b').or_else(|| s.strip_prefix('B')) {
+        i32::from_str_radix(bin, 2).ok()?
+    } else {
+        s.strip_prefix('#').unwrap_or(s).parse::<i32>().ok()?
+    };
+    Some(if neg { -val } else { val })
+}
+
+// Parses a double-quoted string literal with C-style escapes.
+pub fn unescape(s: &str) -> Option<Vec<u8>> {
+    let inner = s.strip_prefix('"')?.strip_suffix('"')?;
+    let mut out = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next()? {
+            'n' => out.push(b'\n'),
+            'r' => out.push(b'\r'),
+            't' => out.push(b'\t'),
+            'e' => out.push(0x1b),
+            '0' => out.push(0),
+            '\\' => out.push(b'\\'),
+            '"' => out.push(b'"'),
+            'x' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                out.push(u8::from_str_radix(&hex, 16).ok()?);
+            }
+            _ => return None,
         }
     }
+    Some(out)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Condition;
+    use super::{parse_number, unescape, Condition};
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_condition_is_satisfied() {
         let cond = Condition { n: true, z: false, p: false };
         let test = Condition { n: true, z: true, p: false };
@@ -46,4 +82,21 @@ mod tests {
         assert_eq!(test.is_satisfied_by(&cond), false);
 
     }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("x3000"), Some(0x3000));
+        assert_eq!(parse_number("#-5"), Some(-5));
+        assert_eq!(parse_number("x-1"), Some(-1));
+        assert_eq!(parse_number("b101"), Some(5));
+        assert_eq!(parse_number("12"), Some(12));
+        assert_eq!(parse_number("R1"), None);
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape("\"hi\\n\""), Some(b"hi\n".to_vec()));
+        assert_eq!(unescape("\"\\x41\\\"\""), Some(b"A\"".to_vec()));
+        assert_eq!(unescape("no quotes"), None);
+    }
 }
@@ -0,0 +1,402 @@
+use std::fmt::Display;
+use std::io::{self, Write};
+
+use crate::micro::Datapath;
+use crate::{device, disk, serial, timer, Fault, Stop, LC3};
+
+// Value change dumps of a run, for looking at in a waveform viewer such as
+// GTKWave. Time counts instructions when the machine runs normally, or clock
+// cycles when it runs on the datapath in `micro`.
+//
+// Without the datapath there is no MAR, MDR, bus or state to show, so they
+// stay unknown, and IR is the instruction each step ran: the handler's
+// first when the step takes an interrupt.
+// Device registers are dumped as `LC3::peek` sees them, so watching them
+// changes nothing, though some (a terminal's KBDR) can't be seen that way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Pc,
+    Ir,
+    Mar,
+    Mdr,
+    Bus,
+    State,
+    Register(u8),
+    N,
+    Z,
+    P,
+    Psr,
+    // a device register or any other word of memory
+    Word(u16),
+}
+
+const DEVICE_REGISTERS: [(&str, u16); 17] = [
+    ("KBSR", device::KBSR),
+    ("KBDR", device::KBDR),
+    ("DSR", device::DSR),
+    ("DDR", device::DDR),
+    ("TMCR", timer::TMCR),
+    ("TMSR", timer::TMSR),
+    ("TMIR", timer::TMIR),
+    ("TMCNT", timer::TMCNT),
+    ("DKBR", disk::DKBR),
+    ("DKAR", disk::DKAR),
+    ("DKCR", disk::DKCR),
+    ("DKSR", disk::DKSR),
+    ("SRSR", serial::BASE + serial::SRSR),
+    ("SRDR", serial::BASE + serial::SRDR),
+    ("STSR", serial::BASE + serial::STSR),
+    ("STDR", serial::BASE + serial::STDR),
+    ("MCR", device::MCR),
+];
+
+impl Signal {
+    // By the name it is dumped under, or a device register's address.
+    pub fn parse(name: &str) -> Option<Signal> {
+        let name = name.to_ascii_uppercase();
+        Some(match name.as_str() {
+            "PC" => Signal::Pc,
+            "IR" => Signal::Ir,
+            "MAR" => Signal::Mar,
+            "MDR" => Signal::Mdr,
+            "BUS" => Signal::Bus,
+            "STATE" => Signal::State,
+            "N" => Signal::N,
+            "Z" => Signal::Z,
+            "P" => Signal::P,
+            "PSR" => Signal::Psr,
+            _ => match name.as_bytes() {
+                [b'R', n @ b'0'..=b'7'] => Signal::Register(n - b'0'),
+                [b'X', ..] => Signal::Word(u16::from_str_radix(&name[1..], 16).ok()?),
+                _ => Signal::Word(DEVICE_REGISTERS.iter().find(|(n, _)| *n == name)?.1),
+            },
+        })
+    }
+
+    // Everything, with the registers of whichever devices are attached.
+    pub fn all(lc3: &LC3, cycles: bool) -> Vec<Signal> {
+        let mut signals = vec![Signal::Pc, Signal::Ir, Signal::Mar, Signal::Mdr];
+        if cycles {
+            signals.extend([Signal::Bus, Signal::State]);
+        }
+        signals.extend((0..8).map(Signal::Register));
+        signals.extend([Signal::N, Signal::Z, Signal::P, Signal::Psr]);
+        let ranges = lc3.devices.ranges();
+        let attached = DEVICE_REGISTERS.iter().filter(|(_, addr)| ranges.iter().any(|r| r.contains(addr)));
+        signals.extend(attached.map(|&(_, addr)| Signal::Word(addr)));
+        signals
+    }
+
+    fn width(self) -> u8 {
+        match self {
+            Signal::N | Signal::Z | Signal::P => 1,
+            Signal::State => 6,
+            _ => 16,
+        }
+    }
+}
+
+impl Display for Signal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Signal::Pc => write!(f, "PC"),
+            Signal::Ir => write!(f, "IR"),
+            Signal::Mar => write!(f, "MAR"),
+            Signal::Mdr => write!(f, "MDR"),
+            Signal::Bus => write!(f, "BUS"),
+            Signal::State => write!(f, "STATE"),
+            Signal::Register(n) => write!(f, "R{}", n),
+            Signal::N => write!(f, "N"),
+            Signal::Z => write!(f, "Z"),
+            Signal::P => write!(f, "P"),
+            Signal::Psr => write!(f, "PSR"),
+            Signal::Word(addr) => match DEVICE_REGISTERS.iter().find(|(_, a)| a == addr) {
+                Some((name, _)) => write!(f, "{}", name),
+                None => write!(f, "x{:04X}", addr),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Level {
+    Value(u16),
+    Unknown,
+    // nothing driving the bus
+    Floating,
+}
+
+// What there is to look at after an instruction or a cycle.
+struct Probe<'a> {
+    lc3: &'a LC3,
+    datapath: Option<&'a Datapath>,
+    ir: u16,
+    bus: Option<u16>,
+    state: u8,
+}
+
+impl Probe<'_> {
+    fn level(&self, signal: Signal) -> Level {
+        let c = self.lc3.condition;
+        let value = match (signal, self.datapath) {
+            (Signal::Pc, _) => self.lc3.pc,
+            (Signal::Ir, _) => self.ir,
+            (Signal::Mar, Some(datapath)) => datapath.mar,
+            (Signal::Mdr, Some(datapath)) => datapath.mdr,
+            (Signal::Bus, Some(_)) => return self.bus.map_or(Level::Floating, Level::Value),
+            (Signal::State, Some(_)) => self.state as u16,
+            (Signal::Mar | Signal::Mdr | Signal::Bus | Signal::State, None) => return Level::Unknown,
+            (Signal::Register(n), _) => self.lc3.registers[n as usize] as u16,
+            (Signal::N, _) => c.n as u16,
+            (Signal::Z, _) => c.z as u16,
+            (Signal::P, _) => c.p as u16,
+            (Signal::Psr, _) => self.lc3.psr(),
+            (Signal::Word(addr), _) => return self.lc3.peek(addr).map_or(Level::Unknown, Level::Value),
+        };
+        Level::Value(value)
+    }
+}
+
+pub struct Vcd<W: Write> {
+    out: W,
+    signals: Vec<Signal>,
+    last: Vec<Level>,
+    time: u64,
+    ir: u16,
+    // the first write that failed, after which nothing more is written
+    error: Option<io::Error>,
+}
+
+// Identifiers are strings of the printable characters.
+fn id(mut i: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push((b'!' + (i % 94) as u8) as char);
+        i /= 94;
+        if i == 0 {
+            return id;
+        }
+        i -= 1;
+    }
+}
+
+impl<W: Write> Vcd<W> {
+    // Writes the header; nothing else is written until the first sample.
+    pub fn new(mut out: W, signals: Vec<Signal>, cycles: bool) -> io::Result<Vcd<W>> {
+        let unit = if cycles { "clock cycle" } else { "instruction" };
+        writeln!(out, "$comment lc3_vm, one time unit per {} $end", unit)?;
+        writeln!(out, "$timescale 1 ns $end")?;
+        writeln!(out, "$scope module lc3 $end")?;
+        for (i, signal) in signals.iter().enumerate() {
+            let kind = if *signal == Signal::Bus { "wire" } else { "reg" };
+            writeln!(out, "$var {} {} {} {} $end", kind, signal.width(), id(i), signal)?;
+        }
+        writeln!(out, "$upscope $end")?;
+        writeln!(out, "$enddefinitions $end")?;
+        Ok(Vcd {
+            out,
+            last: Vec::new(),
+            signals,
+            time: 0,
+            ir: 0,
+            error: None,
+        })
+    }
+
+    // Dumps every signal the first time, then only the ones that changed.
+    fn sample(&mut self, probe: Probe) {
+        let first = self.last.is_empty();
+        let mut text = String::new();
+        for (i, &signal) in self.signals.iter().enumerate() {
+            let level = probe.level(signal);
+            if !first && self.last[i] == level {
+                continue;
+            }
+            let bits = match level {
+                Level::Value(v) if signal.width() == 1 => v.to_string(),
+                Level::Value(v) => format!("b{:b} ", v),
+                Level::Unknown if signal.width() == 1 => "x".to_string(),
+                Level::Unknown => "bx ".to_string(),
+                Level::Floating => "bz ".to_string(),
+            };
+            text += &format!("{}{}\n", bits, id(i));
+            if first {
+                self.last.push(level);
+            } else {
+                self.last[i] = level;
+            }
+        }
+        if first {
+            text = format!("#0\n$dumpvars\n{}$end\n", text);
+        } else if !text.is_empty() {
+            text = format!("#{}\n{}", self.time, text);
+        }
+        self.time += 1;
+        if self.error.is_none() {
+            self.error = self.out.write_all(text.as_bytes()).err();
+        }
+    }
+
+    fn start(&mut self, lc3: &LC3, datapath: Option<&Datapath>) {
+        if self.last.is_empty() {
+            self.ir = datapath.map_or(lc3.memory[lc3.pc as usize], |d| d.ir);
+            let state = datapath.map_or(0, |d| d.state);
+            self.sample(Probe {
+                lc3,
+                datapath,
+                ir: self.ir,
+                bus: None,
+                state,
+            });
+        }
+    }
+
+    // Runs one instruction, sampling once it is done.
+    pub fn step(&mut self, lc3: &mut LC3) -> Result<(), Fault> {
+        self.start(lc3, None);
+        let pc = match lc3.peek_interrupt() {
+            Some((entry, _)) => lc3.memory[entry as usize],
+            None => lc3.pc,
+        };
+        self.ir = lc3.memory[pc as usize];
+        let result = lc3.run_step();
+        self.sample(Probe {
+            lc3,
+            datapath: None,
+            ir: self.ir,
+            bus: None,
+            state: 0,
+        });
+        result
+    }
+
+    // Runs one instruction on the datapath, sampling after every cycle.
+    pub fn step_cycles(&mut self, datapath: &mut Datapath, lc3: &mut LC3) -> Result<(), Fault> {
+        self.start(lc3, Some(datapath));
+        datapath.step_with(lc3, |datapath, lc3, cycle| {
+            self.sample(Probe {
+                lc3,
+                datapath: Some(datapath),
+                ir: datapath.ir,
+                bus: cycle.bus,
+                state: cycle.state,
+            })
+        })
+    }
+
+    pub fn run_for(&mut self, lc3: &mut LC3, mut datapath: Option<&mut Datapath>, max_steps: u64) -> Stop {
+        for _ in 0..max_steps {
+            if lc3.halted {
+                return Stop::Halted;
+            }
+            let result = match &mut datapath {
+                Some(datapath) => self.step_cycles(datapath, lc3),
+                None => self.step(lc3),
+            };
+            if let Err(fault) = result {
+                return Stop::Fault(fault);
+            }
+        }
+        if lc3.halted {
+            Stop::Halted
+        } else {
+            Stop::StepLimit
+        }
+    }
+
+    // Ends the dump at the time after the last sample.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        writeln!(self.out, "#{}", self.time)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::console::Console;
+
+    fn machine(src: &str) -> LC3 {
+        let mut lc3 = LC3 {
+            console: Console::buffer(b"q"),
+            ..LC3::default()
+        };
+        assemble(src).unwrap().load_into(&mut lc3);
+        lc3
+    }
+
+    const COUNT: &str = ".ORIG x3000\nAND R1, R1, #0\nADD R1, R1, #2\nLOOP ADD R1, R1, #-1\nBRp LOOP\nHALT\n.END";
+
+    #[test]
+    fn test_instruction_dump() {
+        let mut lc3 = machine(COUNT);
+        let signals = ["PC", "ir", "R1", "N", "Z", "MAR", "KBSR", "x3001"].map(|s| Signal::parse(s).unwrap());
+        let mut vcd = Vcd::new(Vec::new(), signals.to_vec(), false).unwrap();
+        assert_eq!(vcd.run_for(&mut lc3, None, 100), Stop::Halted);
+        let text = String::from_utf8(vcd.finish().unwrap()).unwrap();
+
+        assert!(text.starts_with("$comment lc3_vm, one time unit per instruction $end\n"));
+        assert!(text.contains("$var reg 16 ! PC $end\n$var reg 16 \" IR $end\n$var reg 16 # R1 $end\n"));
+        assert!(text.contains("$var reg 1 $ N $end\n"));
+        assert!(text.contains("$var reg 16 ' KBSR $end\n$var reg 16 ( x3001 $end\n"));
+        let dump = text.split("$enddefinitions $end\n").nth(1).unwrap();
+        assert_eq!(
+            dump,
+            "#0\n$dumpvars\nb11000000000000 !\nb101001001100000 \"\nb0 #\n0$\n0%\nbx &\nb1000000000000000 '\nb1001001100010 (\n$end\n\
+             #1\nb11000000000001 !\n1%\n\
+             #2\nb11000000000010 !\nb1001001100010 \"\nb10 #\n0%\n\
+             #3\nb11000000000011 !\nb1001001111111 \"\nb1 #\n\
+             #4\nb11000000000010 !\nb1111111110 \"\n\
+             #5\nb11000000000011 !\nb1001001111111 \"\nb0 #\n1%\n\
+             #6\nb11000000000100 !\nb1111111110 \"\n\
+             #7\nb11000000000101 !\nb1111000000100101 \"\n\
+             #8\n"
+        );
+
+        // the step taking a keyboard interrupt runs the handler's LDI
+        let mut lc3 = machine(COUNT);
+        lc3.memory[0x0180] = 0x1000;
+        lc3.memory[0x1000..0x1003].copy_from_slice(&[0xA001, 0x8000, 0xFE02]);
+        lc3.devices.get_mut::<device::Keyboard>().unwrap().interrupt_enable = true;
+        let mut vcd = Vcd::new(Vec::new(), vec![Signal::Pc, Signal::Ir], false).unwrap();
+        vcd.step(&mut lc3).unwrap();
+        vcd.step(&mut lc3).unwrap();
+        let text = String::from_utf8(vcd.finish().unwrap()).unwrap();
+        let dump = text.split("$enddefinitions $end\n").nth(1).unwrap();
+        assert_eq!(
+            dump,
+            "#0\n$dumpvars\nb11000000000000 !\nb101001001100000 \"\n$end\n\
+             #1\nb1000000000001 !\nb1010000000000001 \"\n\
+             #2\nb11000000000000 !\nb1000000000000000 \"\n\
+             #3\n"
+        );
+        assert_eq!(lc3.registers[0], b'q' as i16);
+    }
+
+    #[test]
+    fn test_cycle_dump() {
+        let mut lc3 = machine(COUNT);
+        let mut datapath = Datapath::default();
+        let signals = Signal::all(&lc3, true);
+        assert_eq!(signals.len(), 4 + 2 + 8 + 4 + 9);
+        let mut vcd = Vcd::new(Vec::new(), signals, true).unwrap();
+        vcd.step_cycles(&mut datapath, &mut lc3).unwrap();
+        let text = String::from_utf8(vcd.finish().unwrap()).unwrap();
+
+        assert!(text.contains("$var wire 16 % BUS $end\n$var reg 6 & STATE $end\n"));
+        // MAR loaded in 18, the bus floating while memory answers in 33, then
+        // 35, 32 and the AND itself in state 5
+        let cycles = text.split("$end\n").last().unwrap();
+        assert_eq!(
+            cycles,
+            "#1\nb11000000000000 #\nb11000000000000 %\n#2\nbz %\nb100001 &\n#6\nb101001001100000 $\n\
+             #7\nb11000000000001 !\nb101001001100000 \"\nb101001001100000 %\nb100011 &\n#8\nbz %\nb100000 &\n\
+             #9\nb0 %\nb101 &\n10\nb1000000000000010 2\n#10\n"
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use std::io;
+use std::path::Path;
+
+use crate::LC3;
+
+// The PennSim video memory: 128x124 pixels from xC000 up to the device
+// registers, row by row, each pixel xRRRRRGGGGGBBBBB.
+pub const START: u16 = 0xC000;
+pub const WIDTH: usize = 128;
+pub const HEIGHT: usize = 124;
+pub const END: u16 = START + (WIDTH * HEIGHT) as u16;
+
+// Widens a 5-bit channel to 8 bits, so full intensity stays full.
+fn widen(v: u16) -> u8 {
+    let v = (v & 0x1F) as u8;
+    (v << 3) | (v >> 2)
+}
+
+// A copy of the framebuffer, for saving or comparing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub pixels: Vec<u16>,
+}
+
+impl Frame {
+    pub fn capture(lc3: &LC3) -> Frame {
+        Frame {
+            pixels: lc3.memory[START as usize..END as usize].to_vec(),
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u16 {
+        self.pixels[y * WIDTH + x]
+    }
+
+    pub fn rgb(&self, x: usize, y: usize) -> [u8; 3] {
+        let p = self.pixel(x, y);
+        [widen(p >> 10), widen(p >> 5), widen(p)]
+    }
+
+    fn rgb_bytes(&self) -> Vec<u8> {
+        (0..HEIGHT)
+            .flat_map(|y| (0..WIDTH).map(move |x| (x, y)))
+            .flat_map(|(x, y)| self.rgb(x, y))
+            .collect()
+    }
+
+    // A binary PPM (P6) image.
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut out = format!("P6\n{} {}\n255\n", WIDTH, HEIGHT).into_bytes();
+        out.extend(self.rgb_bytes());
+        out
+    }
+
+    // A PAM (P7) image, for tools that prefer the newer netpbm format.
+    pub fn to_pam(&self) -> Vec<u8> {
+        let header = format!(
+            "P7\nWIDTH {}\nHEIGHT {}\nDEPTH 3\nMAXVAL 255\nTUPLTYPE RGB\nENDHDR\n",
+            WIDTH, HEIGHT
+        );
+        let mut out = header.into_bytes();
+        out.extend(self.rgb_bytes());
+        out
+    }
+
+    // Reads back a frame written by `to_ppm`, such as a reference image to
+    // grade against.
+    pub fn from_ppm(bytes: &[u8]) -> Option<Frame> {
+        let header = format!("P6\n{} {}\n255\n", WIDTH, HEIGHT);
+        let data = bytes.strip_prefix(header.as_bytes())?;
+        if data.len() != WIDTH * HEIGHT * 3 {
+            return None;
+        }
+        let pixels = data
+            .chunks(3)
+            .map(|c| (c[0] as u16 >> 3) << 10 | (c[1] as u16 >> 3) << 5 | c[2] as u16 >> 3)
+            .collect();
+        Some(Frame { pixels })
+    }
+
+    // Writes a PAM file if `path` ends in .pam and a PPM file otherwise.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let bytes = match path.extension().and_then(|e| e.to_str()) {
+            Some("pam") => self.to_pam(),
+            _ => self.to_ppm(),
+        };
+        std::fs::write(path, bytes)
+    }
+
+    pub fn differences(&self, other: &Frame) -> usize {
+        self.pixels.iter().zip(&other.pixels).filter(|(a, b)| a != b).count()
+    }
+
+    // The frame drawn with 24-bit ANSI colours, two rows of pixels to a line
+    // using the upper half block.
+    pub fn to_ansi(&self) -> String {
+        let mut out = String::new();
+        for y in (0..HEIGHT).step_by(2) {
+            for x in 0..WIDTH {
+                let [r, g, b] = self.rgb(x, y);
+                let [br, bg, bb] = self.rgb(x, y + 1);
+                out += &format!("\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}", r, g, b, br, bg, bb);
+            }
+            out += "\x1b[0m\n";
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::console::Console;
+
+    // Draws a red pixel at (0, 0), a white one at (127, 0) and a blue one at
+    // (1, 123).
+    const DRAW: &str = r#"
+        .ORIG x3000
+        LD R0, VIDEO
+        LD R1, RED
+        STR R1, R0, #0
+        LD R1, WHITE
+        LD R2, ROW_END
+        ADD R2, R0, R2
+        STR R1, R2, #0
+        LD R2, LAST_ROW
+        ADD R2, R0, R2
+        AND R1, R1, #0
+        ADD R1, R1, #15
+        ADD R1, R1, #15
+        ADD R1, R1, #1
+        STR R1, R2, #1
+        HALT
+VIDEO    .FILL xC000
+RED      .FILL x7C00
+WHITE    .FILL x7FFF
+ROW_END  .FILL #127
+LAST_ROW .FILL #15744
+        .END
+    "#;
+
+    fn draw() -> LC3 {
+        let mut lc3 = LC3 {
+            console: Console::buffer(&[]),
+            ..LC3::default()
+        };
+        assemble(DRAW).unwrap().load_into(&mut lc3);
+        assert!(!lc3.video_dirty);
+        lc3.run().unwrap();
+        lc3
+    }
+
+    #[test]
+    fn test_capture_and_export() {
+        let lc3 = draw();
+        assert!(lc3.video_dirty);
+        let frame = Frame::capture(&lc3);
+        assert_eq!(frame.rgb(0, 0), [255, 0, 0]);
+        assert_eq!(frame.rgb(127, 0), [255, 255, 255]);
+        assert_eq!(frame.rgb(1, 123), [0, 0, 255]);
+        assert_eq!(frame.rgb(1, 1), [0, 0, 0]);
+
+        let ppm = frame.to_ppm();
+        assert!(ppm.starts_with(b"P6\n128 124\n255\n"));
+        assert_eq!(ppm.len(), 15 + 128 * 124 * 3);
+        assert_eq!(&ppm[15..18], &[255, 0, 0]);
+        assert_eq!(Frame::from_ppm(&ppm), Some(frame.clone()));
+        assert_eq!(Frame::from_ppm(&ppm[..100]), None);
+
+        let pam = frame.to_pam();
+        assert!(pam.starts_with(b"P7\nWIDTH 128\nHEIGHT 124\nDEPTH 3\nMAXVAL 255\nTUPLTYPE RGB\nENDHDR\n"));
+        assert!(pam.ends_with(&ppm[15..]));
+
+        let path = std::env::temp_dir().join(format!("lc3-video-{}.pam", std::process::id()));
+        frame.save(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), pam);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_compare_and_render() {
+        let frame = Frame::capture(&draw());
+        let mut other = frame.clone();
+        other.pixels[5] = 0x03E0;
+        assert_eq!(frame.differences(&other), 1);
+        assert_eq!(frame.differences(&frame), 0);
+
+        let ansi = frame.to_ansi();
+        assert_eq!(ansi.lines().count(), HEIGHT / 2);
+        let first = ansi.lines().next().unwrap();
+        assert!(first.starts_with("\x1b[38;2;255;0;0m\x1b[48;2;0;0;0m\u{2580}"));
+        assert!(first.ends_with("\x1b[38;2;255;255;255m\x1b[48;2;0;0;0m\u{2580}\x1b[0m"));
+        let last = ansi.lines().last().unwrap();
+        assert!(last.contains("\x1b[38;2;0;0;0m\x1b[48;2;0;0;255m"));
+    }
+}